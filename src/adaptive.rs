@@ -0,0 +1,162 @@
+//! Adaptive mosaics, where detailed regions of the image get more, smaller tiles and flat regions
+//! get fewer, larger ones
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+use eyre::Result;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImage, GenericImageView, GrayImage, Rgba};
+use indicatif::{ParallelProgressIterator, ProgressIterator};
+use rayon::prelude::*;
+
+use crate::make_pbar;
+use crate::matching::{distance, pick_image_for_pixel, ChannelWeights, CoarseIndex};
+use crate::tiles::{average_color, Tile};
+
+/// A square region of the target image that gets a single tile
+struct Leaf {
+    x: u32,
+    y: u32,
+    size: u32,
+    color: Rgba<u8>,
+}
+
+/// Calculate how much the pixels in the region differ from their average color, as the mean
+/// `distance` from it
+fn variance(region: &DynamicImage, average: Rgba<u8>, weights: ChannelWeights) -> i64 {
+    let pixel_count = i64::from(region.width() * region.height());
+    let total = region
+        .pixels()
+        .map(|(_x, _y, pixel)| distance(pixel, average, weights))
+        .sum::<i64>();
+    total / pixel_count.max(1)
+}
+
+/// Calculate the mean importance of the region of the saliency map, from 0 to 1
+fn mean_saliency(saliency: &GrayImage, x: u32, y: u32, size: u32) -> f64 {
+    let region = saliency.view(x, y, size, size);
+    let total = region
+        .pixels()
+        .map(|(_x, _y, pixel)| u64::from(pixel.0[0]))
+        .sum::<u64>();
+    total as f64 / (f64::from(size * size) * 255.)
+}
+
+/// Recursively split the region at the given position into quadrants while it's too detailed,
+/// or while it doesn't fit in the image
+///
+/// With a saliency map, the threshold is lowered in proportion to how important the region is,
+/// down to 0 for fully important regions, which get split for the slightest detail.
+#[allow(clippy::too_many_arguments)]
+fn subdivide(
+    img: &DynamicImage,
+    saliency: Option<&GrayImage>,
+    x: u32,
+    y: u32,
+    size: u32,
+    threshold: i64,
+    weights: ChannelWeights,
+    leaves: &mut Vec<Leaf>,
+) {
+    if x >= img.width() || y >= img.height() {
+        return;
+    }
+
+    let fits = x + size <= img.width() && y + size <= img.height();
+    let (color, detailed) = if fits {
+        let region = img.crop_imm(x, y, size, size);
+        let color = average_color(&region);
+        let threshold = match saliency {
+            Some(saliency) => {
+                (threshold as f64 * (1. - mean_saliency(saliency, x, y, size))).round() as i64
+            }
+            None => threshold,
+        };
+        (color, variance(&region, color, weights) > threshold)
+    } else {
+        (Rgba([0; 4]), true)
+    };
+
+    if size > 1 && detailed {
+        let half = size / 2;
+        for (dx, dy) in [(0, 0), (half, 0), (0, half), (half, half)] {
+            subdivide(
+                img,
+                saliency,
+                x + dx,
+                y + dy,
+                half,
+                threshold,
+                weights,
+                leaves,
+            );
+        }
+    } else {
+        leaves.push(Leaf { x, y, size, color });
+    }
+}
+
+/// The most times cells may be split, as blocks of `2^MAX_DEPTH` pixels are already far bigger
+/// than any image's detail
+pub const MAX_DEPTH: u32 = 8;
+
+/// Build a mosaic whose cells start out as blocks of `2^max_depth` pixels of the target image
+/// and get split into quadrants, down to single pixels, as long as their variance is above the
+/// threshold
+///
+/// Blocks straddling the edge of the image are always split, so the mosaic has the same size as
+/// a regular one. A saliency map, the same size as the image, lowers the threshold where it's
+/// bright.
+#[allow(clippy::too_many_arguments)]
+pub fn assemble(
+    img: &DynamicImage,
+    saliency: Option<&GrayImage>,
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+    index: Option<&CoarseIndex>,
+    tile_size: u32,
+    max_depth: u32,
+    threshold: i64,
+) -> Result<DynamicImage> {
+    let block = 1 << max_depth;
+    let mut leaves = Vec::new();
+    for y in (0..img.height()).step_by(block as usize) {
+        for x in (0..img.width()).step_by(block as usize) {
+            subdivide(img, saliency, x, y, block, threshold, weights, &mut leaves);
+        }
+    }
+
+    let unique_colors = leaves.iter().map(|leaf| leaf.color).collect::<HashSet<_>>();
+    let len = unique_colors.len();
+    let tiles = unique_colors
+        .into_par_iter()
+        .progress_with(make_pbar("pixels", len as _))
+        .filter_map(|color| {
+            let tile = pick_image_for_pixel(color, possible_tiles, weights, index)?;
+            Some((color, tile))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut mosaic = DynamicImage::new_rgba8(img.width() * tile_size, img.height() * tile_size);
+    let mut resized = HashMap::new();
+    let len = leaves.len();
+    for leaf in leaves
+        .iter()
+        .progress_with(make_pbar("adaptive cells", len as _))
+    {
+        let tile = tiles[&leaf.color];
+        let side = leaf.size * tile_size;
+        let image = match resized.entry((tile, leaf.size)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(possible_tiles[tile].image()?.resize_exact(
+                side,
+                side,
+                FilterType::Triangle,
+            )),
+        };
+        mosaic.copy_from(&*image, leaf.x * tile_size, leaf.y * tile_size)?;
+    }
+
+    Ok(mosaic)
+}
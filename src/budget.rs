@@ -0,0 +1,216 @@
+//! Keeping the total cost of a mosaic's tiles under a budget, for mosaics made of real tiles
+//!
+//! Cells start out with the tiles that match them best, and while the mosaic costs too much,
+//! the cell whose next cheaper tile loses the least quality for the money saved gets it. The
+//! tiles each cell can step down to are those on the lower convex hull of every tile's cost and
+//! error for it, so that a cell's steps only ever get worse value and all the steps of all the
+//! cells can be taken in a single sorted pass. This is the usual greedy heuristic for this kind
+//! of knapsack problem: it's not optimal, but it's never far off when there are many cells.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use eyre::{eyre, Result};
+use image::Rgba;
+use rayon::prelude::*;
+
+use crate::matching::{distance, ChannelWeights, Placement};
+use crate::tiles::Tile;
+
+/// Read a file listing a tile's file name and cost on each line, e.g. `red-glass.png 0.25`.
+/// Empty lines and lines starting with `#` are ignored
+pub fn load_costs(path: &Path) -> Result<HashMap<String, f64>> {
+    let mut costs = HashMap::new();
+    for (line_number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, cost) = line.rsplit_once(char::is_whitespace).ok_or_else(|| {
+            eyre!(
+                "line {} of {}: expected a file name and a cost",
+                line_number + 1,
+                path.display()
+            )
+        })?;
+        let cost = cost
+            .parse::<f64>()
+            .ok()
+            .filter(|cost| cost.is_finite() && *cost >= 0.)
+            .ok_or_else(|| {
+                eyre!(
+                    "line {} of {}: {:?} is not a non-negative cost",
+                    line_number + 1,
+                    path.display(),
+                    cost
+                )
+            })?;
+        costs.insert(name.trim_end().to_owned(), cost);
+    }
+    Ok(costs)
+}
+
+/// Look up the cost of every tile by its file name, tiles that aren't listed being free
+pub fn tile_costs(possible_tiles: &[Tile], costs: &HashMap<String, f64>) -> Vec<f64> {
+    let mut unlisted = 0;
+    let tile_costs = possible_tiles
+        .iter()
+        .map(|tile| {
+            let name = tile.path.file_name().unwrap_or_default().to_string_lossy();
+            costs.get(name.as_ref()).copied().unwrap_or_else(|| {
+                unlisted += 1;
+                0.
+            })
+        })
+        .collect();
+    if unlisted > 0 {
+        eprintln!("warning: {unlisted} tiles have no cost, they're counted as free");
+    }
+    tile_costs
+}
+
+/// A tile a cell can have, along with its cost and how far off it is
+#[derive(Debug, Clone, Copy)]
+struct Choice {
+    tile: usize,
+    cost: f64,
+    error: i64,
+}
+
+/// The options a cell steps down through from the tile it has, cheapest last: the lower convex
+/// hull of the tiles cheaper than it by cost and error
+fn steps_down(
+    pixel: Rgba<u8>,
+    current: usize,
+    possible_tiles: &[Tile],
+    costs: &[f64],
+    weights: ChannelWeights,
+) -> Vec<Choice> {
+    let choice = |tile: usize| Choice {
+        tile,
+        cost: costs[tile],
+        error: distance(pixel, possible_tiles[tile].average, weights),
+    };
+    let start = choice(current);
+    let mut cheaper = (0..possible_tiles.len())
+        .filter(|&tile| costs[tile] < start.cost)
+        .map(choice)
+        .collect::<Vec<_>>();
+    cheaper.sort_by(|a, b| {
+        a.cost
+            .total_cmp(&b.cost)
+            .then(a.error.cmp(&b.error))
+            .then(a.tile.cmp(&b.tile))
+    });
+    cheaper.dedup_by(|b, a| a.cost == b.cost);
+
+    // Walk the hull from the cheapest tile to the current one, then reverse it
+    let mut hull = Vec::<Choice>::new();
+    for point in cheaper.into_iter().chain([start]) {
+        while let [.., a, b] = hull[..] {
+            let cross = (b.cost - a.cost) * (point.error - a.error) as f64
+                - (b.error - a.error) as f64 * (point.cost - a.cost);
+            if cross > 0. {
+                break;
+            }
+            hull.pop();
+        }
+        hull.push(point);
+    }
+    hull.pop();
+    hull.reverse();
+    hull
+}
+
+/// How a mosaic fit its budget
+#[derive(Debug)]
+pub struct BudgetReport {
+    pub cost_before: f64,
+    pub cost_after: f64,
+    pub mean_error_before: f64,
+    pub mean_error_after: f64,
+    pub cells_changed: usize,
+}
+
+/// Swap the tiles of cells for cheaper ones until the mosaic's total cost is at most `budget`,
+/// or it can't get any cheaper
+pub fn fit_budget(
+    pixels: &[Rgba<u8>],
+    cells: &mut [Placement],
+    possible_tiles: &[Tile],
+    costs: &[f64],
+    budget: f64,
+    weights: ChannelWeights,
+) -> BudgetReport {
+    let mean_error = |cells: &[Placement]| {
+        pixels
+            .iter()
+            .zip(cells.iter())
+            .map(|(&pixel, cell)| distance(pixel, possible_tiles[cell.tile].average, weights))
+            .sum::<i64>() as f64
+            / cells.len().max(1) as f64
+    };
+    let cost_before = cells.iter().map(|cell| costs[cell.tile]).sum::<f64>();
+    let mean_error_before = mean_error(cells);
+
+    let original = cells.iter().map(|cell| cell.tile).collect::<Vec<_>>();
+    let mut cost = cost_before;
+    if cost > budget {
+        // Cells with the same color and tile step down the same way
+        let keys = pixels
+            .iter()
+            .zip(cells.iter())
+            .map(|(&pixel, cell)| (pixel, cell.tile))
+            .collect::<Vec<_>>();
+        let mut unique_keys = keys.clone();
+        unique_keys.sort_unstable_by_key(|&(Rgba(pixel), tile)| (pixel, tile));
+        unique_keys.dedup();
+        let hulls = unique_keys
+            .par_iter()
+            .map(|&(pixel, tile)| {
+                let hull = steps_down(pixel, tile, possible_tiles, costs, weights);
+                ((pixel, tile), hull)
+            })
+            .collect::<HashMap<_, _>>();
+
+        // Every step of every cell, by how much error it adds for each unit of cost it saves,
+        // which never goes down from one step of a cell to the next but for rounding errors
+        let mut steps = Vec::new();
+        for (cell, key) in keys.iter().enumerate() {
+            let (pixel, tile) = *key;
+            let mut from = Choice {
+                tile,
+                cost: costs[tile],
+                error: distance(pixel, possible_tiles[tile].average, weights),
+            };
+            let mut ratio = f64::NEG_INFINITY;
+            for (nth, &to) in hulls[key].iter().enumerate() {
+                ratio = ratio.max((to.error - from.error) as f64 / (from.cost - to.cost));
+                steps.push((ratio, cell, nth, to, from.cost - to.cost));
+                from = to;
+            }
+        }
+        steps.sort_by(|a, b| a.0.total_cmp(&b.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+
+        for (_ratio, cell, _nth, to, saving) in steps {
+            if cost <= budget {
+                break;
+            }
+            cells[cell] = Placement::new(to.tile);
+            cost -= saving;
+        }
+    }
+
+    BudgetReport {
+        cost_before,
+        cost_after: cells.iter().map(|cell| costs[cell.tile]).sum(),
+        mean_error_before,
+        mean_error_after: mean_error(cells),
+        cells_changed: cells
+            .iter()
+            .zip(&original)
+            .filter(|(cell, &tile)| cell.tile != tile)
+            .count(),
+    }
+}
@@ -0,0 +1,85 @@
+//! Remembering which outputs a run finished, so that the next one can pick up where it stopped
+//!
+//! Each line of the checkpoint is the path of an output, relative to the output directory, that
+//! was saved along with everything else made from its input. Outputs that are there but aren't
+//! listed were only partly made, e.g. by a run killed by a second Ctrl-C, and are made again.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use eyre::Result;
+
+/// The name of the checkpoint in the output directory
+const FILE_NAME: &str = ".themis-checkpoint";
+
+pub struct Checkpoint {
+    output_dir: PathBuf,
+    done: HashSet<String>,
+
+    /// Whether the output directory has no checkpoint yet, in which case the outputs already
+    /// there were made before checkpoints existed and are taken as done
+    fresh: bool,
+
+    /// Whether the checkpoint's last line was cut short, and the next one must start on a line
+    /// of its own
+    cut_short: bool,
+}
+
+impl Checkpoint {
+    pub fn load(output_dir: &Path) -> Result<Self> {
+        let (done, fresh, cut_short) = match fs::read_to_string(output_dir.join(FILE_NAME)) {
+            // A line cut short by an abort isn't an output that got finished
+            Ok(checkpoint) => (
+                checkpoint
+                    .split_inclusive('\n')
+                    .filter_map(|line| line.strip_suffix('\n'))
+                    .map(str::to_owned)
+                    .collect(),
+                false,
+                !checkpoint.is_empty() && !checkpoint.ends_with('\n'),
+            ),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (HashSet::new(), true, false),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            output_dir: output_dir.to_owned(),
+            done,
+            fresh,
+            cut_short,
+        })
+    }
+
+    fn key(&self, output: &Path) -> String {
+        output
+            .strip_prefix(&self.output_dir)
+            .unwrap_or(output)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Whether the output was finished by an earlier run
+    pub fn is_done(&self, output: &Path) -> bool {
+        output.exists() && (self.fresh || self.done.contains(&self.key(output)))
+    }
+
+    /// Record that the output, and everything else made from its input, has been saved
+    pub fn finish(&mut self, output: &Path) -> Result<()> {
+        let key = self.key(output);
+        if self.done.contains(&key) {
+            return Ok(());
+        }
+        let mut checkpoint = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.output_dir.join(FILE_NAME))?;
+        if self.cut_short {
+            checkpoint.write_all(b"\n")?;
+            self.cut_short = false;
+        }
+        checkpoint.write_all(format!("{key}\n").as_bytes())?;
+        self.done.insert(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+//! Decoding images, working around the formats the `image` crate gets wrong
+//!
+//! CMYK JPEGs come in two flavors: the ones written by Adobe software store their inks inverted
+//! and say so with an APP14 marker, while others store them as-is. `image` treats every one of
+//! them as inverted, so JPEGs without the marker come out as a negative, and it converts the
+//! inverted YCCK ones as if their colors weren't. Those are decoded and converted to RGB here.
+//!
+//! `image` also only ever decodes the first page of a TIFF, so the others are decoded here with
+//! the `tiff` crate directly.
+
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+
+use eyre::{bail, eyre, Result};
+use image::{DynamicImage, ImageBuffer, ImageFormat, RgbImage};
+use jpeg_decoder::PixelFormat;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::ColorType;
+
+/// How the four channels of a JPEG are stored, according to its APP14 marker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AdobeTransform {
+    /// Inverted CMYK
+    Cmyk,
+
+    /// Inverted CMY encoded as YCbCr, followed by inverted K
+    Ycck,
+}
+
+/// Find the transform of the Adobe APP14 segment among the segments before the image data,
+/// `None` meaning that there's no such segment
+fn adobe_transform(jpeg: &[u8]) -> Option<AdobeTransform> {
+    // Skip the start of image marker
+    let mut segments = jpeg.get(2..)?;
+    while segments.len() >= 4 && segments[0] == 0xff {
+        let marker = segments[1];
+        // Start of scan, after which the image data starts
+        if marker == 0xda {
+            return None;
+        }
+        let len = usize::from(u16::from_be_bytes([segments[2], segments[3]]));
+        let data = segments.get(4..2 + len)?;
+        if marker == 0xee && data.starts_with(b"Adobe") {
+            return match data.get(11)? {
+                2 => Some(AdobeTransform::Ycck),
+                _ => Some(AdobeTransform::Cmyk),
+            };
+        }
+        segments = segments.get(2 + len..)?;
+    }
+    None
+}
+
+/// Convert the output of `jpeg_decoder` for a four channel JPEG to RGB
+///
+/// `jpeg_decoder` inverts every channel of CMYK JPEGs, and decodes the YCbCr part of YCCK ones
+/// then inverts their K.
+fn cmyk_to_rgb(pixels: &[u8], transform: Option<AdobeTransform>) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(pixels.len() / 4 * 3);
+    for pixel in pixels.chunks_exact(4) {
+        let [a, b, c, d] = [pixel[0], pixel[1], pixel[2], pixel[3]].map(u16::from);
+        // How much light gets through each of the CMY inks, and through the black one
+        let (light, black) = match transform {
+            // The inks themselves, having been inverted twice
+            Some(AdobeTransform::Cmyk) => ([255 - a, 255 - b, 255 - c], 255 - d),
+            // The inverted CMY, turned back from YCbCr, and the black ink itself
+            Some(AdobeTransform::Ycck) => ([a, b, c], 255 - d),
+            // The inverted inks
+            None => ([a, b, c], d),
+        };
+        rgb.extend(light.map(|channel| (channel * black / 255) as u8));
+    }
+    rgb
+}
+
+/// Open and decode an image like `image::open`, converting CMYK JPEGs to RGB correctly
+pub fn open(path: &Path) -> Result<DynamicImage> {
+    let format = image::io::Reader::open(path)?
+        .with_guessed_format()?
+        .format();
+    if format != Some(ImageFormat::Jpeg) {
+        return Ok(image::open(path)?);
+    }
+
+    let jpeg = fs::read(path)?;
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(&jpeg));
+    decoder.read_info()?;
+    match decoder.info() {
+        Some(info) if info.pixel_format == PixelFormat::CMYK32 => {
+            let pixels = decoder.decode()?;
+            let rgb = cmyk_to_rgb(&pixels, adobe_transform(&jpeg));
+            let image = RgbImage::from_raw(u32::from(info.width), u32::from(info.height), rgb)
+                .expect("the decoded pixels should fill the image");
+            Ok(DynamicImage::ImageRgb8(image))
+        }
+        _ => Ok(image::load_from_memory_with_format(
+            &jpeg,
+            ImageFormat::Jpeg,
+        )?),
+    }
+}
+
+/// Count the pages of a TIFF, up to `max_pages`, or return `None` if the file isn't one
+pub fn tiff_pages(path: &Path, max_pages: usize) -> Result<Option<usize>> {
+    let reader = image::io::Reader::open(path)?.with_guessed_format()?;
+    if reader.format() != Some(ImageFormat::Tiff) {
+        return Ok(None);
+    }
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let mut pages = 1;
+    while pages < max_pages && decoder.more_images() {
+        decoder.next_image()?;
+        pages += 1;
+    }
+    Ok(Some(pages))
+}
+
+/// Decode a page of a TIFF, counting from 0, which may be 8 or 16 bits per channel gray or RGB,
+/// with or without alpha
+pub fn open_tiff_page(path: &Path, page: usize) -> Result<DynamicImage> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    for _ in 0..page {
+        decoder.next_image()?;
+    }
+    let (width, height) = decoder.dimensions()?;
+    let image = match (decoder.colortype()?, decoder.read_image()?) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::GrayA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA16)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16)
+        }
+        (color_type, _) => bail!(
+            "page {} of {} is {:?}, which can't be decoded",
+            page + 1,
+            path.display(),
+            color_type
+        ),
+    };
+    image.ok_or_else(|| eyre!("page {} of {} is truncated", page + 1, path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgba};
+
+    use super::*;
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// Check that the left patch of a fixture is red and the right one a darkened cyan, within
+    /// what YCbCr's rounding can shift them by
+    fn assert_patches(image: &DynamicImage) {
+        assert_eq!(image.dimensions(), (16, 8));
+        for (x, expected) in [(3, [255, 0, 0]), (12, [0, 191, 191])] {
+            let Rgba([r, g, b, _a]) = image.get_pixel(x, 4);
+            let close = [r, g, b]
+                .iter()
+                .zip(expected)
+                .all(|(&c, expected)| (i16::from(c) - expected).abs() <= 2);
+            assert!(close, "{:?} instead of {:?}", [r, g, b], expected);
+        }
+    }
+
+    #[test]
+    fn adobe_transforms_are_read_from_the_app14_marker() {
+        let transform = |name| adobe_transform(&fs::read(fixture(name)).unwrap());
+        assert_eq!(transform("adobe-cmyk.jpg"), Some(AdobeTransform::Cmyk));
+        assert_eq!(transform("adobe-ycck.jpg"), Some(AdobeTransform::Ycck));
+        assert_eq!(transform("plain-cmyk.jpg"), None);
+    }
+
+    #[test]
+    fn cmyk_to_rgb_multiplies_the_light_through_every_ink() {
+        // Half of the light through the cyan ink and half through the black one
+        assert_eq!(
+            cmyk_to_rgb(&[127, 0, 0, 127], Some(AdobeTransform::Cmyk)),
+            [64, 128, 128]
+        );
+        assert_eq!(cmyk_to_rgb(&[128, 255, 255, 128], None), [64, 128, 128]);
+        assert_eq!(
+            cmyk_to_rgb(&[128, 255, 255, 127], Some(AdobeTransform::Ycck)),
+            [64, 128, 128]
+        );
+    }
+
+    #[test]
+    fn adobe_cmyk_jpegs_decode_to_rgb() {
+        assert_patches(&open(&fixture("adobe-cmyk.jpg")).unwrap());
+    }
+
+    #[test]
+    fn adobe_ycck_jpegs_decode_to_rgb() {
+        assert_patches(&open(&fixture("adobe-ycck.jpg")).unwrap());
+    }
+
+    #[test]
+    fn cmyk_jpegs_without_a_marker_decode_to_rgb() {
+        assert_patches(&open(&fixture("plain-cmyk.jpg")).unwrap());
+    }
+}
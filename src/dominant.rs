@@ -0,0 +1,146 @@
+//! Describing cells and tiles by their few dominant colors rather than their average, for
+//! `--dominant-colors`
+//!
+//! A cell that's half black and half white averages out to gray, and so does a gray tile, but
+//! their dominant colors tell them apart. Each image is first downscaled to
+//! `DOMINANT_SAMPLES`x`DOMINANT_SAMPLES` pixels, which are then clustered with k-means.
+
+use image::{DynamicImage, GenericImageView, Rgba};
+
+use crate::matching::{distance, ChannelWeights};
+
+/// How many pixels along each side images are downscaled to before clustering their colors
+pub const DOMINANT_SAMPLES: u32 = 8;
+
+/// The most dominant colors allowed, since sets of them are compared by trying every way of
+/// pairing them up
+pub const MAX_DOMINANT_COLORS: usize = 4;
+
+/// How many rounds of k-means to run, which is plenty for so few pixels
+const ITERATIONS: usize = 8;
+
+/// A dominant color along with how many of the samples it's the closest to
+pub type Dominant = (Rgba<u8>, u32);
+
+/// Find the `k` dominant colors of an image, by clustering the colors of its downscale
+///
+/// The clusters are seeded with farthest point sampling, starting from the first pixel, so the
+/// same image always has the same dominant colors. Clusters that end up empty are dropped.
+pub fn dominant_colors(image: &DynamicImage, k: usize, weights: ChannelWeights) -> Vec<Dominant> {
+    let samples = image
+        .thumbnail_exact(DOMINANT_SAMPLES, DOMINANT_SAMPLES)
+        .pixels()
+        .map(|(_x, _y, pixel)| pixel)
+        .collect::<Vec<_>>();
+
+    let mut centers = vec![samples[0]];
+    while centers.len() < k {
+        let farthest = samples
+            .iter()
+            .copied()
+            .max_by_key(|&sample| {
+                centers
+                    .iter()
+                    .map(|&center| distance(sample, center, weights))
+                    .min()
+            })
+            .unwrap();
+        centers.push(farthest);
+    }
+
+    let nearest = |centers: &[Rgba<u8>], sample: Rgba<u8>| {
+        (0..centers.len())
+            .min_by_key(|&idx| distance(sample, centers[idx], weights))
+            .unwrap()
+    };
+    let mut sums = vec![([0u32; 4], 0u32); k];
+    for _ in 0..ITERATIONS {
+        sums.iter_mut().for_each(|sum| *sum = ([0; 4], 0));
+        for &sample in &samples {
+            let (sum, count) = &mut sums[nearest(&centers, sample)];
+            for (sum, channel) in sum.iter_mut().zip(sample.0) {
+                *sum += u32::from(channel);
+            }
+            *count += 1;
+        }
+        for (center, (sum, count)) in centers.iter_mut().zip(&sums) {
+            if *count > 0 {
+                *center = Rgba(sum.map(|sum| ((sum + count / 2) / count) as u8));
+            }
+        }
+    }
+
+    // Count the samples once more, against the final centers
+    let mut counts = vec![0; k];
+    for &sample in &samples {
+        counts[nearest(&centers, sample)] += 1;
+    }
+    centers
+        .into_iter()
+        .zip(counts)
+        .filter(|&(_center, count)| count > 0)
+        .collect()
+}
+
+/// Compare two sets of dominant colors, by pairing up their colors in whichever way makes them
+/// closest and adding up the paired colors' distances, each weighted by the share of the pixels
+/// the pair covers
+///
+/// Colors left without a pair, when one set has more than the other, are compared against the
+/// closest color of the other set instead.
+pub fn dominant_distance(a: &[Dominant], b: &[Dominant], weights: ChannelWeights) -> i64 {
+    let (a, b) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    let (total_a, total_b) = (
+        a.iter()
+            .map(|&(_color, count)| i64::from(count))
+            .sum::<i64>(),
+        b.iter()
+            .map(|&(_color, count)| i64::from(count))
+            .sum::<i64>(),
+    );
+    let share = |(_color, count): Dominant, of: i64| i64::from(count) * 1024 / of.max(1);
+
+    // Every way of giving each color of `a` a different color of `b`, as indices into `b`
+    let mut best = i64::MAX;
+    let mut pairing = (0..b.len()).collect::<Vec<_>>();
+    loop {
+        let paired = a
+            .iter()
+            .zip(&pairing)
+            .map(|(&dominant, &idx)| {
+                let other = b[idx];
+                distance(dominant.0, other.0, weights)
+                    * (share(dominant, total_a) + share(other, total_b))
+            })
+            .sum::<i64>();
+        let unpaired = pairing[a.len()..]
+            .iter()
+            .map(|&idx| {
+                let other = b[idx];
+                let closest = a
+                    .iter()
+                    .map(|&(color, _count)| distance(color, other.0, weights))
+                    .min()
+                    .unwrap_or(0);
+                closest * share(other, total_b)
+            })
+            .sum::<i64>();
+        best = best.min(paired + unpaired);
+        if !next_permutation(&mut pairing) {
+            break;
+        }
+    }
+    best / 2048
+}
+
+/// Rearrange the slice into the next permutation in lexicographic order, returning whether
+/// there was one
+fn next_permutation(items: &mut [usize]) -> bool {
+    let Some(pivot) = items.windows(2).rposition(|pair| pair[0] < pair[1]) else {
+        return false;
+    };
+    let successor = items.iter().rposition(|&item| item > items[pivot]).unwrap();
+    items.swap(pivot, successor);
+    items[pivot + 1..].reverse();
+    true
+}
@@ -0,0 +1,188 @@
+//! Spreading out the tones of the image before matching, so that flat, poorly exposed photos
+//! still use the whole range of the tileset
+//!
+//! Only the luminance is equalized: every pixel's channels are scaled by how much brighter or
+//! darker it became, which keeps its hue.
+
+use std::str::FromStr;
+
+use eyre::{bail, Result};
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+/// How to equalize the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Equalize {
+    /// With a single histogram for the whole image
+    Global,
+
+    /// With contrast limited adaptive histogram equalization (CLAHE): a histogram for each
+    /// region of the image, clipped so that uniform areas don't turn into noise
+    Clahe,
+}
+
+impl FromStr for Equalize {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "global" => Ok(Self::Global),
+            "clahe" => Ok(Self::Clahe),
+            _ => bail!("unknown equalization {:?}", s),
+        }
+    }
+}
+
+/// How many regions CLAHE splits each side of the image into, at most
+const CLAHE_REGIONS: u32 = 8;
+
+/// How many times the average count a bin of a CLAHE histogram may hold, and never less than a
+/// single pixel, the excess being spread over every bin
+const CLAHE_CLIP_LIMIT: f64 = 2.;
+
+/// The luminance of a pixel, from 0 to 255
+fn luminance(Rgba([r, g, b, _a]): Rgba<u8>) -> u8 {
+    ((218 * u32::from(r) + 732 * u32::from(g) + 74 * u32::from(b)) >> 10) as u8
+}
+
+/// Turn a histogram into the mapping from each luminance to its equalized one
+fn mapping(histogram: &[f64; 256]) -> [u8; 256] {
+    let total = histogram.iter().sum::<f64>();
+    let mut mapping = [0; 256];
+    let mut cumulative = 0.;
+    for (value, &count) in mapping.iter_mut().zip(histogram) {
+        cumulative += count;
+        *value = (cumulative / total.max(1.) * 255.).round() as u8;
+    }
+    mapping
+}
+
+/// Clip every bin of a histogram at `CLAHE_CLIP_LIMIT` times the average, spreading what was
+/// clipped evenly over all of them
+fn clip(histogram: &mut [f64; 256]) {
+    let limit = (histogram.iter().sum::<f64>() / 256. * CLAHE_CLIP_LIMIT).max(1.);
+    let excess = histogram
+        .iter_mut()
+        .map(|count| {
+            let excess = (*count - limit).max(0.);
+            *count -= excess;
+            excess
+        })
+        .sum::<f64>();
+    histogram
+        .iter_mut()
+        .for_each(|count| *count += excess / 256.);
+}
+
+/// Give a pixel the new luminance, scaling its channels to keep its hue
+fn relight(pixel: Rgba<u8>, from: u8, to: u8) -> Rgba<u8> {
+    let Rgba([r, g, b, a]) = pixel;
+    if from == 0 {
+        return Rgba([to, to, to, a]);
+    }
+    let scale = f64::from(to) / f64::from(from);
+    let scale = |c: u8| (f64::from(c) * scale).round().min(255.) as u8;
+    Rgba([scale(r), scale(g), scale(b), a])
+}
+
+/// Equalize the histogram of the image's luminance
+pub fn equalize(img: &DynamicImage, method: Equalize) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let pixels = img.to_rgba8();
+    let lums = pixels
+        .pixels()
+        .map(|&pixel| luminance(pixel))
+        .collect::<Vec<_>>();
+
+    let equalized = match method {
+        Equalize::Global => {
+            let mut histogram = [0.; 256];
+            lums.iter()
+                .for_each(|&lum| histogram[usize::from(lum)] += 1.);
+            let mapping = mapping(&histogram);
+            RgbaImage::from_fn(width, height, |x, y| {
+                let lum = lums[(y * width + x) as usize];
+                relight(*pixels.get_pixel(x, y), lum, mapping[usize::from(lum)])
+            })
+        }
+
+        Equalize::Clahe => {
+            let (cols, rows) = (
+                CLAHE_REGIONS.min(width).max(1),
+                CLAHE_REGIONS.min(height).max(1),
+            );
+            let region = |x: u32, y: u32| ((y * rows / height) * cols + x * cols / width) as usize;
+            let mut histograms = vec![[0.; 256]; (cols * rows) as usize];
+            for (idx, &lum) in lums.iter().enumerate() {
+                let (x, y) = (idx as u32 % width, idx as u32 / width);
+                histograms[region(x, y)][usize::from(lum)] += 1.;
+            }
+            let mappings = histograms
+                .iter_mut()
+                .map(|histogram| {
+                    clip(histogram);
+                    mapping(histogram)
+                })
+                .collect::<Vec<_>>();
+
+            // Interpolate between the mappings of the four regions whose centers surround each
+            // pixel, so that there are no seams between regions
+            let (region_width, region_height) = (
+                f64::from(width) / f64::from(cols),
+                f64::from(height) / f64::from(rows),
+            );
+            let neighbors = |position: f64, size: f64, count: u32| {
+                let position = (position + 0.5) / size - 0.5;
+                let before = position.floor().clamp(0., f64::from(count - 1));
+                let after = (before + 1.).min(f64::from(count - 1));
+                let t = (position - before).clamp(0., 1.);
+                (before as usize, after as usize, t)
+            };
+            RgbaImage::from_fn(width, height, |x, y| {
+                let lum = lums[(y * width + x) as usize];
+                let (left, right, tx) = neighbors(f64::from(x), region_width, cols);
+                let (top, bottom, ty) = neighbors(f64::from(y), region_height, rows);
+                let at = |col: usize, row: usize| {
+                    f64::from(mappings[row * cols as usize + col][usize::from(lum)])
+                };
+                let upper = at(left, top) * (1. - tx) + at(right, top) * tx;
+                let lower = at(left, bottom) * (1. - tx) + at(right, bottom) * tx;
+                let to = (upper * (1. - ty) + lower * ty).round() as u8;
+                relight(*pixels.get_pixel(x, y), lum, to)
+            })
+        }
+    };
+    DynamicImage::ImageRgba8(equalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The range of luminance between the 5th and the 95th percentile of an image's pixels
+    fn spread(img: &DynamicImage) -> u8 {
+        let mut lums = img
+            .to_rgba8()
+            .pixels()
+            .map(|&pixel| luminance(pixel))
+            .collect::<Vec<_>>();
+        lums.sort_unstable();
+        lums[lums.len() * 95 / 100] - lums[lums.len() * 5 / 100]
+    }
+
+    #[test]
+    fn equalizing_broadens_the_luminance_histogram() {
+        // A dull, hazy texture with all its tones from 100 to 140, over regions large enough for
+        // CLAHE's clip limit to let them spread out
+        let flat = DynamicImage::ImageRgba8(RgbaImage::from_fn(256, 256, |x, y| {
+            let lum = 100 + ((x * 7919 + y * 104_729) % 41) as u8;
+            Rgba([lum + 10, lum, lum - 10, 255])
+        }));
+        let before = spread(&flat);
+        assert!(before <= 40);
+
+        let global = spread(&equalize(&flat, Equalize::Global));
+        assert!(global >= 200, "{} from {}", global, before);
+        let clahe = spread(&equalize(&flat, Equalize::Clahe));
+        assert!(clahe >= 2 * before, "{} from {}", clahe, before);
+    }
+}
@@ -0,0 +1,203 @@
+//! User supplied distance formulas, parsed once and evaluated for every candidate tile
+//!
+//! Formulas are arithmetic expressions over the channels of the two colors being compared:
+//! `r1`, `g1`, `b1`, `a1` for the image's and `r2`, `g2`, `b2`, `a2` for the tile's, each from 0
+//! to 255. They support `+`, `-`, `*`, `/`, `^` (power), parentheses and the functions `abs`,
+//! `sqrt`, `min` and `max`.
+
+use std::iter::Peekable;
+use std::str::{CharIndices, FromStr};
+
+use eyre::{bail, eyre, Result};
+use image::Rgba;
+
+/// The names of the variables, in the order `Node::Var` indexes them
+const VARIABLES: [&str; 8] = ["r1", "g1", "b1", "a1", "r2", "g2", "b2", "a2"];
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f64),
+    Var(usize),
+    Neg(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Call(fn(f64, f64) -> f64, Vec<Node>),
+}
+
+impl Node {
+    fn eval(&self, vars: &[f64; 8]) -> f64 {
+        match self {
+            Self::Number(n) => *n,
+            Self::Var(idx) => vars[*idx],
+            Self::Neg(node) => -node.eval(vars),
+            Self::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.eval(vars), rhs.eval(vars));
+                match op {
+                    '+' => lhs + rhs,
+                    '-' => lhs - rhs,
+                    '*' => lhs * rhs,
+                    '/' => lhs / rhs,
+                    '^' => lhs.powf(rhs),
+                    _ => unreachable!("unknown operator {:?}", op),
+                }
+            }
+            Self::Call(function, args) => {
+                let lhs = args[0].eval(vars);
+                let rhs = args.get(1).map_or(0., |arg| arg.eval(vars));
+                function(lhs, rhs)
+            }
+        }
+    }
+}
+
+/// A recursive descent parser, from lowest to highest precedence
+struct Parser<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|(_, c)| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.chars.next();
+                Ok(())
+            }
+            Some(c) => bail!("expected {:?}, found {:?}", expected, c),
+            None => bail!("expected {:?}, but the expression ended", expected),
+        }
+    }
+
+    /// Take the longest run of characters matching the predicate
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let start = self.chars.peek().map_or(self.source.len(), |&(idx, _)| idx);
+        let mut end = start;
+        while let Some(&(idx, c)) = self.chars.peek() {
+            if !predicate(c) {
+                break;
+            }
+            end = idx + c.len_utf8();
+            self.chars.next();
+        }
+        &self.source[start..end]
+    }
+
+    fn sum(&mut self) -> Result<Node> {
+        let mut node = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.chars.next();
+            node = Node::Binary(op, Box::new(node), Box::new(self.product()?));
+        }
+        Ok(node)
+    }
+
+    fn product(&mut self) -> Result<Node> {
+        let mut node = self.unary()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.chars.next();
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node> {
+        if self.peek() == Some('-') {
+            self.chars.next();
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<Node> {
+        let base = self.atom()?;
+        if self.peek() == Some('^') {
+            self.chars.next();
+            // Right associative, and binding tighter than a negation on its right
+            return Ok(Node::Binary('^', Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        match self.peek() {
+            Some('(') => {
+                self.chars.next();
+                let node = self.sum()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                Ok(Node::Number(number.parse().map_err(|_| {
+                    eyre!("{:?} is not a valid number", number)
+                })?))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let name = self.take_while(|c| c.is_ascii_alphanumeric());
+                if let Some(idx) = VARIABLES.iter().position(|&var| var == name) {
+                    return Ok(Node::Var(idx));
+                }
+
+                let (function, arity): (fn(f64, f64) -> f64, _) = match name {
+                    "abs" => (|x, _| x.abs(), 1),
+                    "sqrt" => (|x, _| x.sqrt(), 1),
+                    "min" => (f64::min, 2),
+                    "max" => (f64::max, 2),
+                    _ => bail!(
+                        "unknown variable or function {:?}, the variables are {}",
+                        name,
+                        VARIABLES.join(", ")
+                    ),
+                };
+                self.expect('(')?;
+                let mut args = vec![self.sum()?];
+                while args.len() < arity {
+                    self.expect(',')?;
+                    args.push(self.sum()?);
+                }
+                self.expect(')')?;
+                Ok(Node::Call(function, args))
+            }
+            Some(c) => bail!("unexpected {:?}", c),
+            None => bail!("the expression ended unexpectedly"),
+        }
+    }
+}
+
+/// A distance formula between two colors
+#[derive(Debug, Clone)]
+pub struct DistanceExpr(Node);
+
+impl DistanceExpr {
+    /// Evaluate the formula for the given pair of colors
+    pub fn eval(&self, Rgba([r1, g1, b1, a1]): Rgba<u8>, Rgba([r2, g2, b2, a2]): Rgba<u8>) -> f64 {
+        self.0
+            .eval(&[r1, g1, b1, a1, r2, g2, b2, a2].map(f64::from))
+    }
+}
+
+impl FromStr for DistanceExpr {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            source: s,
+            chars: s.char_indices().peekable(),
+        };
+        let node = parser.sum()?;
+        if let Some(c) = parser.peek() {
+            bail!("unexpected {:?} after the end of the expression", c);
+        }
+        Ok(Self(node))
+    }
+}
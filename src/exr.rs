@@ -0,0 +1,39 @@
+//! Saving the mosaic in linear light as OpenEXR, for compositing pipelines
+//!
+//! The tiles are assembled in 8-bit sRGB like any other mosaic, and only converted to linear
+//! floats when saving, so the file holds the same 8-bit data as the mosaic, linear-encoded: it
+//! has no more tonal range or precision than the PNG. Viewers that don't tone map show linear
+//! images much darker than they are.
+
+use std::path::Path;
+
+use eyre::Result;
+use image::{DynamicImage, ImageFormat, Rgba, Rgba32FImage};
+
+/// Undo the sRGB transfer function of a channel, giving its linear intensity from 0 to 1
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = f32::from(c) / 255.;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Save the mosaic as an OpenEXR file of linear 32-bit float RGBA, with alpha left as it is
+pub fn save_linear_exr(mosaic: &DynamicImage, path: &Path) -> Result<()> {
+    let lookup = (0..=255).map(srgb_to_linear).collect::<Vec<_>>();
+    let mosaic = mosaic.to_rgba8();
+    let linear = Rgba32FImage::from_fn(mosaic.width(), mosaic.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *mosaic.get_pixel(x, y);
+        Rgba([
+            lookup[usize::from(r)],
+            lookup[usize::from(g)],
+            lookup[usize::from(b)],
+            f32::from(a) / 255.,
+        ])
+    });
+    DynamicImage::ImageRgba32F(linear).save_with_format(path, ImageFormat::OpenExr)?;
+    eprintln!("Saved a linear OpenEXR copy to {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Plotting which colors a tileset can reproduce, for `--gamut-plot`
+//!
+//! Every tile's average color is projected onto a plane of colors, either the a*b* plane of
+//! CIELAB or a wheel of hues and saturations, and counted in one of `BINS`x`BINS` bins. Each
+//! bin is painted with the color it stands for, faded out if no tile falls in it and the more
+//! vivid the more tiles do, so the faded regions are the colors the tileset is missing.
+
+use std::str::FromStr;
+
+use eyre::{bail, Result};
+use image::{Rgba, RgbaImage};
+
+use crate::matching::{from_lab, to_lab};
+use crate::placement::{from_hsl, to_hsl};
+use crate::tiles::Tile;
+
+/// The side length of the plot, in pixels
+const PLOT_SIZE: u32 = 512;
+
+/// How many bins the plot has along each side
+const BINS: u32 = 64;
+
+/// The lightness the colors of the a*b* plane are shown at, from 0 to 100
+const PLANE_LIGHTNESS: f64 = 65.;
+
+/// How far the a*b* plane goes from the grays at its center along each axis
+const AB_RANGE: f64 = 128.;
+
+/// The color of the parts of the plot that don't stand for any color
+const EMPTY: Rgba<u8> = Rgba([24, 24, 24, 255]);
+
+/// Which plane of colors to project the tiles' colors onto
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GamutProjection {
+    /// The a*b* plane of CIELAB, green to red from left to right and blue to yellow from
+    /// bottom to top, ignoring lightness
+    Ab,
+
+    /// A wheel with hue going around it, starting with red on the right, and saturation
+    /// growing from gray at its center, ignoring lightness
+    HueSaturation,
+}
+
+impl FromStr for GamutProjection {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ab" => Ok(Self::Ab),
+            "hue-saturation" => Ok(Self::HueSaturation),
+            _ => bail!("expected `ab` or `hue-saturation`, got {:?}", s),
+        }
+    }
+}
+
+impl GamutProjection {
+    /// Where a color lands on the plane, with both coordinates from -1 to 1 and up being
+    /// positive
+    fn project(self, color: Rgba<u8>) -> (f64, f64) {
+        match self {
+            Self::Ab => {
+                let [_l, a, b] = to_lab(color);
+                (a / AB_RANGE, b / AB_RANGE)
+            }
+            Self::HueSaturation => {
+                let (hue, saturation, _lightness) = to_hsl(color);
+                let angle = f64::from(hue.unwrap_or(0.)) * std::f64::consts::FRAC_PI_3;
+                let radius = f64::from(saturation);
+                (radius * angle.cos(), radius * angle.sin())
+            }
+        }
+    }
+
+    /// The color a point of the plane stands for, if any
+    fn color_at(self, x: f64, y: f64) -> Option<Rgba<u8>> {
+        match self {
+            Self::Ab => from_lab([PLANE_LIGHTNESS, x * AB_RANGE, y * AB_RANGE]),
+            Self::HueSaturation => {
+                let radius = x.hypot(y);
+                if radius > 1. {
+                    return None;
+                }
+                let hue = (y.atan2(x) / std::f64::consts::FRAC_PI_3).rem_euclid(6.);
+                Some(from_hsl(hue as f32, radius as f32, 0.5, 255))
+            }
+        }
+    }
+}
+
+/// A plot of where the tiles' colors fall on a plane of colors
+pub struct GamutPlot {
+    pub image: RgbaImage,
+
+    /// The share of the plot's bins that stand for a color and have at least one tile in them
+    pub coverage: f64,
+}
+
+/// Plot the average colors of the tiles on the given plane
+pub fn plot(possible_tiles: &[Tile], projection: GamutProjection) -> GamutPlot {
+    let mut counts = vec![0u32; (BINS * BINS) as usize];
+    for tile in possible_tiles {
+        let (x, y) = projection.project(tile.average);
+        let bin = |v: f64| (((v + 1.) / 2. * f64::from(BINS)) as u32).min(BINS - 1);
+        counts[((BINS - 1 - bin(y)) * BINS + bin(x)) as usize] += 1;
+    }
+
+    // Each bin stands for the color at its center
+    let center = |bin: u32| (f64::from(bin) + 0.5) / f64::from(BINS) * 2. - 1.;
+    let colors = (0..BINS * BINS)
+        .map(|idx| projection.color_at(center(idx % BINS), -center(idx / BINS)))
+        .collect::<Vec<_>>();
+    let (mut colored, mut covered) = (0, 0);
+    for (color, &count) in colors.iter().zip(&counts) {
+        if color.is_some() {
+            colored += 1;
+            covered += usize::from(count > 0);
+        }
+    }
+
+    let most = f64::from(counts.iter().copied().max().unwrap_or(0));
+    let bin_size = PLOT_SIZE / BINS;
+    let image = RgbaImage::from_fn(PLOT_SIZE, PLOT_SIZE, |x, y| {
+        let idx = ((y / bin_size) * BINS + x / bin_size) as usize;
+        let Some(Rgba([r, g, b, _a])) = colors[idx] else {
+            return EMPTY;
+        };
+        // Faded towards the empty color by how few tiles fall in the bin, if any
+        let strength = match counts[idx] {
+            0 => 0.2,
+            count => 0.5 + 0.5 * (1. + f64::from(count)).ln() / (1. + most).ln(),
+        };
+        let fade = |c: u8, empty: u8| {
+            (f64::from(c) * strength + f64::from(empty) * (1. - strength)).round() as u8
+        };
+        Rgba([fade(r, EMPTY[0]), fade(g, EMPTY[1]), fade(b, EMPTY[2]), 255])
+    });
+    GamutPlot {
+        image,
+        coverage: covered as f64 / f64::from(colored.max(1)),
+    }
+}
@@ -0,0 +1,143 @@
+//! Comparing mosaics against golden images known to be right, for `--compare-golden`
+
+use std::path::Path;
+
+use eyre::Result;
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+use crate::decode;
+
+/// The most cells with differences listed, the rest only being counted
+const MAX_LISTED_CELLS: usize = 10;
+
+/// How a mosaic differs from its golden image
+#[derive(Debug)]
+pub struct Comparison {
+    /// The pixels with a channel further than the tolerance from the golden image's
+    pub pixels: usize,
+
+    /// The cells with at least one such pixel, as column and row, in reading order
+    pub cells: Vec<(u32, u32)>,
+
+    /// The smallest rectangle holding every such pixel, as its left, top, right and bottom
+    /// edges, inclusive
+    pub bounds: Option<(u32, u32, u32, u32)>,
+
+    /// An image of the golden one, faded, with the pixels that differ in red
+    pub diff: RgbaImage,
+}
+
+/// Compare a mosaic to its golden image pixel by pixel, counting those with a channel further
+/// than `tolerance` from the golden one's as different. Returns `None` if their sizes differ
+pub fn compare(
+    mosaic: &DynamicImage,
+    golden: &DynamicImage,
+    tile_size: u32,
+    tolerance: u8,
+) -> Option<Comparison> {
+    if mosaic.dimensions() != golden.dimensions() {
+        return None;
+    }
+    let (mosaic, golden) = (mosaic.to_rgba8(), golden.to_rgba8());
+    let (width, height) = mosaic.dimensions();
+    let columns = width.div_ceil(tile_size);
+
+    let mut pixels = 0;
+    let mut bounds = None::<(u32, u32, u32, u32)>;
+    let mut changed_cells = vec![false; (columns * height.div_ceil(tile_size)) as usize];
+    let diff = RgbaImage::from_fn(width, height, |x, y| {
+        let (ours, theirs) = (mosaic.get_pixel(x, y), golden.get_pixel(x, y));
+        let differs = ours
+            .0
+            .iter()
+            .zip(theirs.0)
+            .any(|(&a, b)| a.abs_diff(b) > tolerance);
+        if !differs {
+            let Rgba([r, g, b, _a]) = *theirs;
+            let gray = ((u32::from(r) + u32::from(g) + u32::from(b)) / 3) as u8;
+            return Rgba([gray / 4 + 96, gray / 4 + 96, gray / 4 + 96, 255]);
+        }
+
+        pixels += 1;
+        bounds = Some(match bounds {
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x), bottom.max(y))
+            }
+            None => (x, y, x, y),
+        });
+        changed_cells[((y / tile_size) * columns + x / tile_size) as usize] = true;
+        Rgba([255, 0, 0, 255])
+    });
+
+    let cells = changed_cells
+        .iter()
+        .enumerate()
+        .filter(|&(_idx, &changed)| changed)
+        .map(|(idx, _changed)| (idx as u32 % columns, idx as u32 / columns))
+        .collect();
+    Some(Comparison {
+        pixels,
+        cells,
+        bounds,
+        diff,
+    })
+}
+
+/// Compare a mosaic to the golden image at the given path and report how they differ, saving
+/// the differences to `diff_path` if there are any. Returns whether they matched
+pub fn check(
+    mosaic: &DynamicImage,
+    golden_path: &Path,
+    tile_size: u32,
+    tolerance: u8,
+    diff_path: Option<&Path>,
+) -> Result<bool> {
+    let golden = decode::open(golden_path)?;
+    let Some(comparison) = compare(mosaic, &golden, tile_size, tolerance) else {
+        eprintln!(
+            "The mosaic is {}x{} but {} is {}x{}",
+            mosaic.width(),
+            mosaic.height(),
+            golden_path.display(),
+            golden.width(),
+            golden.height()
+        );
+        return Ok(false);
+    };
+
+    let Some((left, top, right, bottom)) = comparison.bounds else {
+        eprintln!("The mosaic matches {}", golden_path.display());
+        return Ok(true);
+    };
+    eprintln!(
+        "{} pixels in {} cells differ from {}, from ({}, {}) to ({}, {})",
+        comparison.pixels,
+        comparison.cells.len(),
+        golden_path.display(),
+        left,
+        top,
+        right,
+        bottom
+    );
+    let listed = comparison
+        .cells
+        .iter()
+        .take(MAX_LISTED_CELLS)
+        .map(|(column, row)| format!("({column}, {row})"))
+        .collect::<Vec<_>>();
+    let unlisted = comparison.cells.len() - listed.len();
+    if unlisted > 0 {
+        eprintln!(
+            "Cells that differ: {} and {} more",
+            listed.join(", "),
+            unlisted
+        );
+    } else {
+        eprintln!("Cells that differ: {}", listed.join(", "));
+    }
+    if let Some(diff_path) = diff_path {
+        comparison.diff.save(diff_path)?;
+        eprintln!("Saved the differences to {}", diff_path.display());
+    }
+    Ok(false)
+}
@@ -0,0 +1,155 @@
+//! Describing which tile went in each cell as an HTML page, where hovering over a cell of the
+//! mosaic shows the file its tile came from
+
+use std::fs;
+use std::path::Path;
+
+use eyre::Result;
+
+use crate::matching::Placement;
+use crate::tiles::Tile;
+
+/// Escape a string for use in HTML text or a quoted attribute
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Encode bytes as standard, padded base64
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let mut group = [0; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let group = u32::from(group[0]) << 16 | u32::from(group[1]) << 8 | u32::from(group[2]);
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * idx) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Percent-encode everything but unreserved characters and slashes, to use a path in a URL
+fn url_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Decide how the page at `page` should refer to the mosaic saved at `mosaic`: either by
+/// embedding the whole file as a data URL, or with a link relative to the page if they're in the
+/// same directory and an absolute one otherwise
+pub fn image_source(page: &Path, mosaic: &Path, embed: bool) -> Result<String> {
+    if embed {
+        let mime = match mosaic
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("png") => "image/png",
+            Some("jpg" | "jpeg") => "image/jpeg",
+            Some("gif") => "image/gif",
+            Some("webp") => "image/webp",
+            Some("bmp") => "image/bmp",
+            Some("tif" | "tiff") => "image/tiff",
+            _ => "application/octet-stream",
+        };
+        return Ok(format!(
+            "data:{};base64,{}",
+            mime,
+            base64(&fs::read(mosaic)?)
+        ));
+    }
+
+    if page.parent() == mosaic.parent() {
+        if let Some(file_name) = mosaic.file_name() {
+            return Ok(url_path(&file_name.to_string_lossy()));
+        }
+    }
+    let mosaic = fs::canonicalize(mosaic)?;
+    Ok(format!(
+        "file://{}",
+        url_path(&mosaic.to_string_lossy().replace('\\', "/"))
+    ))
+}
+
+/// Render a page showing the mosaic with a transparent box over each cell, whose tooltip is the
+/// path of the tile placed there
+///
+/// The boxes are positioned relative to the mosaic's size, so that the page can be scaled freely.
+/// With `offset_rows` every other row is shifted by half a cell, as the tiles were.
+pub fn make_html_map(
+    image_src: &str,
+    title: &str,
+    cells: &[Placement],
+    tiles: &[Tile],
+    width: u32,
+    height: u32,
+    offset_rows: bool,
+) -> String {
+    let cell_width = 100. / f64::from(width);
+    let cell_height = 100. / f64::from(height);
+
+    let mut boxes = String::new();
+    for (idx, placement) in cells.iter().enumerate() {
+        let (x, y) = (idx as u32 % width, idx as u32 / width);
+        let offset = if offset_rows && y % 2 == 1 { 0.5 } else { 0. };
+        boxes.push_str(&format!(
+            "<div style=\"left:{:.4}%;top:{:.4}%\" title=\"{}\"></div>\n",
+            (f64::from(x) + offset) * cell_width,
+            f64::from(y) * cell_height,
+            escape(&tiles[placement.tile].path.to_string_lossy()),
+        ));
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+.mosaic {{ position: relative; display: inline-block; overflow: hidden; line-height: 0; }}
+.mosaic img {{ max-width: 100%; }}
+.mosaic div {{ position: absolute; width: {cell_width:.4}%; height: {cell_height:.4}%; box-sizing: border-box; }}
+.mosaic div:hover {{ outline: 2px solid #fff; outline-offset: -2px; }}
+</style>
+</head>
+<body>
+<div class="mosaic">
+<img src="{src}" alt="{title}">
+{boxes}</div>
+</body>
+</html>
+"#,
+        title = escape(title),
+        src = escape(image_src),
+        cell_width = cell_width,
+        cell_height = cell_height,
+        boxes = boxes,
+    )
+}
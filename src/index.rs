@@ -0,0 +1,290 @@
+//! Saving the colors of a tileset to an index file, so that later runs don't have to decode and
+//! average every tile again
+//!
+//! The index is a little endian binary file: a header with the options the tiles were loaded
+//! with, including the ones that leave tiles out of the tileset, followed by each tile's path,
+//! average color, halves' colors, signatures and aspect ratio. Paths are relative to the index's
+//! directory, so that an index can be moved along with its tiles to another machine.
+
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use eyre::{bail, eyre, Result};
+use image::Rgba;
+
+use crate::tiles::{LoadOptions, Orientation, SmallTiles, Tile};
+
+/// What every index starts with
+const MAGIC: &[u8; 8] = b"THEMISIX";
+
+/// The version of the format, to be bumped whenever it changes
+const VERSION: u32 = 5;
+
+/// The options that decide a tile's colors or which tiles are in the tileset, as stored in an
+/// index
+#[derive(Debug, PartialEq)]
+struct IndexedOptions {
+    tile_side: u32,
+    average_inset: f64,
+    subregions: Option<u32>,
+    variants: bool,
+    normalize_white_balance: bool,
+    small_tiles: SmallTiles,
+    min_contrast: f64,
+    focus_masks: bool,
+}
+
+/// Every way of handling small tiles, by their number in an index
+const SMALL_TILES: [SmallTiles; 3] = [SmallTiles::Upscale, SmallTiles::Skip, SmallTiles::Nearest];
+
+impl IndexedOptions {
+    fn new(options: &LoadOptions) -> Self {
+        Self {
+            tile_side: options.tile_side,
+            average_inset: options.average_inset,
+            subregions: options.subregions,
+            variants: options.variants,
+            normalize_white_balance: options.normalize_white_balance,
+            small_tiles: options.small_tiles,
+            min_contrast: options.min_contrast,
+            focus_masks: options.focus_masks,
+        }
+    }
+
+    /// Describe how these options differ from the given ones, as command line flags
+    fn differences(&self, other: &Self) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self.tile_side != other.tile_side {
+            differences.push(format!("--tile-size {}", self.tile_side));
+        }
+        if self.average_inset != other.average_inset {
+            differences.push(format!("--average-inset {}", self.average_inset));
+        }
+        if self.subregions != other.subregions {
+            differences.push(match self.subregions {
+                Some(side) => format!("--subregions {side}"),
+                None => "no --subregions".to_owned(),
+            });
+        }
+        let flag = |set: bool, flag: &str| {
+            if set {
+                flag.to_owned()
+            } else {
+                format!("no {flag}")
+            }
+        };
+        if self.variants != other.variants {
+            differences.push(flag(self.variants, "--tile-variants"));
+        }
+        if self.normalize_white_balance != other.normalize_white_balance {
+            differences.push(flag(self.normalize_white_balance, "--normalize-wb"));
+        }
+        if self.small_tiles != other.small_tiles {
+            differences.push(
+                match self.small_tiles {
+                    SmallTiles::Upscale => "neither --no-upscale nor --upscale-nearest",
+                    SmallTiles::Skip => "--no-upscale",
+                    SmallTiles::Nearest => "--upscale-nearest",
+                }
+                .to_owned(),
+            );
+        }
+        if self.min_contrast != other.min_contrast {
+            differences.push(if self.min_contrast > 0. {
+                format!("--min-contrast {}", self.min_contrast)
+            } else {
+                "no --min-contrast".to_owned()
+            });
+        }
+        if self.focus_masks != other.focus_masks {
+            differences.push(flag(self.focus_masks, "--focus-masks"));
+        }
+        differences
+    }
+}
+
+/// A cursor over the bytes of an index
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            bail!("the tile index is truncated");
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn color(&mut self) -> Result<Rgba<u8>> {
+        Ok(Rgba(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+/// The path that leads from the directory `base` to `path`, both absolute, going up with `..` as
+/// far as needed, or `path` itself if they don't share a root, e.g. on different drives
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let (mut path_components, mut base_components) = (path.components(), base.components());
+    if path_components.next() != base_components.next() {
+        return path.to_owned();
+    }
+    loop {
+        let (rest, other) = (path_components.clone(), base_components.clone());
+        match (path_components.next(), base_components.next()) {
+            (Some(a), Some(b)) if a == b => {}
+            _ => return other.map(|_| Component::ParentDir).chain(rest).collect(),
+        }
+    }
+}
+
+/// Save the colors of the given tiles, loaded with the given options, to an index file
+pub fn save_index(path: &Path, tiles: &[Tile], options: &LoadOptions) -> Result<()> {
+    let IndexedOptions {
+        tile_side,
+        average_inset,
+        subregions,
+        variants,
+        normalize_white_balance,
+        small_tiles,
+        min_contrast,
+        focus_masks,
+    } = IndexedOptions::new(options);
+
+    let mut index = MAGIC.to_vec();
+    index.extend(VERSION.to_le_bytes());
+    index.extend(tile_side.to_le_bytes());
+    index.extend(average_inset.to_bits().to_le_bytes());
+    index.extend(subregions.unwrap_or(0).to_le_bytes());
+    index.extend([u8::from(variants), u8::from(normalize_white_balance)]);
+    let small_tiles = SMALL_TILES.iter().position(|&other| other == small_tiles);
+    index.push(small_tiles.unwrap() as u8);
+    index.extend(min_contrast.to_bits().to_le_bytes());
+    index.push(u8::from(focus_masks));
+
+    let base = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    let base = fs::canonicalize(base.unwrap_or_else(|| Path::new(".")))?;
+    index.extend((tiles.len() as u64).to_le_bytes());
+    for tile in tiles {
+        let path = relative_to(&fs::canonicalize(&tile.path)?, &base);
+        let path = path
+            .to_str()
+            .ok_or_else(|| eyre!("the path of the tile {} is not valid UTF-8", path.display()))?;
+        index.extend((path.len() as u32).to_le_bytes());
+        index.extend(path.as_bytes());
+
+        index.extend(tile.average.0);
+        for half in tile.halves {
+            index.extend(half.0);
+        }
+        index.push(tile.signatures.len() as u8);
+        for (orientation, signature) in &tile.signatures {
+            let orientation = Orientation::ALL
+                .iter()
+                .position(|other| other == orientation)
+                .unwrap();
+            index.push(orientation as u8);
+            index.extend((signature.len() as u32).to_le_bytes());
+            for color in signature {
+                index.extend(color.0);
+            }
+        }
+        index.extend(tile.aspect.to_bits().to_le_bytes());
+    }
+
+    fs::write(path, index)?;
+    eprintln!("Indexed {} tiles into {}", tiles.len(), path.display());
+    Ok(())
+}
+
+/// Load the tiles listed in an index file, without loading their images until they're placed
+///
+/// The index must have been built with the same options as the ones given, as they change the
+/// tiles' colors.
+pub fn load_index(path: &Path, options: &LoadOptions) -> Result<Vec<Tile>> {
+    let bytes = fs::read(path)?;
+    let mut reader = Reader(&bytes);
+
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        bail!("{} is not a tile index", path.display());
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        bail!(
+            "{} is a version {} tile index, but only version {} is supported, rebuild it",
+            path.display(),
+            version,
+            VERSION
+        );
+    }
+
+    let indexed = IndexedOptions {
+        tile_side: reader.u32()?,
+        average_inset: f64::from_bits(reader.u64()?),
+        subregions: Some(reader.u32()?).filter(|&side| side > 0),
+        variants: reader.u8()? != 0,
+        normalize_white_balance: reader.u8()? != 0,
+        small_tiles: *SMALL_TILES
+            .get(usize::from(reader.u8()?))
+            .ok_or_else(|| eyre!("the tile index has an unknown way of handling small tiles"))?,
+        min_contrast: f64::from_bits(reader.u64()?),
+        focus_masks: reader.u8()? != 0,
+    };
+    let differences = indexed.differences(&IndexedOptions::new(options));
+    if !differences.is_empty() {
+        bail!(
+            "{} was built with different options ({}), rebuild it or use the same ones",
+            path.display(),
+            differences.join(", ")
+        );
+    }
+
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let len = reader.u64()?;
+    let mut tiles = Vec::new();
+    for _ in 0..len {
+        let path_len = reader.u32()? as usize;
+        let tile_path = std::str::from_utf8(reader.take(path_len)?)?;
+        let average = reader.color()?;
+        let halves = [reader.color()?, reader.color()?];
+
+        let signature_count = reader.u8()?;
+        let mut signatures = Vec::with_capacity(usize::from(signature_count));
+        for _ in 0..signature_count {
+            let orientation = *Orientation::ALL
+                .get(usize::from(reader.u8()?))
+                .ok_or_else(|| eyre!("the tile index has an unknown orientation"))?;
+            let signature_len = reader.u32()?;
+            let signature = (0..signature_len)
+                .map(|_| reader.color())
+                .collect::<Result<Vec<_>>>()?;
+            signatures.push((orientation, signature));
+        }
+        let aspect = f64::from_bits(reader.u64()?);
+
+        tiles.push(Tile::unloaded(
+            base.join(tile_path),
+            average,
+            halves,
+            signatures,
+            aspect,
+            options,
+        ));
+    }
+
+    Ok(tiles)
+}
@@ -0,0 +1,38 @@
+//! Stopping cleanly on Ctrl-C, once the image being worked on is finished
+//!
+//! The first Ctrl-C only raises a flag that the input loop checks between images, so that the
+//! current mosaic still gets saved. A second one exits right away.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle_sigint(_signal: libc::c_int) {
+    const MESSAGE: &[u8] =
+        b"\nInterrupted, finishing the current image first. Press Ctrl-C again to abort\n";
+
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // SAFETY: `_exit` is async-signal-safe, unlike `std::process::exit`
+        unsafe { libc::_exit(130) };
+    }
+    // SAFETY: `write` is async-signal-safe and the message outlives the call
+    unsafe { libc::write(libc::STDERR_FILENO, MESSAGE.as_ptr().cast(), MESSAGE.len()) };
+}
+
+/// Catch Ctrl-C from now on, instead of being killed by it
+///
+/// Only supported on Unix, elsewhere Ctrl-C still stops the process immediately.
+pub fn install_handler() {
+    #[cfg(unix)]
+    // SAFETY: the handler only touches an atomic and calls async-signal-safe functions
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = handle_sigint;
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+/// Whether Ctrl-C has been pressed
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
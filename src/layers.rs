@@ -0,0 +1,25 @@
+//! Saving the components of a mosaic as separate pages of a TIFF, for editing elsewhere
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use eyre::Result;
+use image::DynamicImage;
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::tags::Tag;
+
+/// The TIFF tag telling the name of a page
+const PAGE_NAME: Tag = Tag::Unknown(285);
+
+/// Save the given images as pages of a single TIFF, in order, each named after its label
+pub fn save_layered_tiff(path: &Path, layers: &[(&str, &DynamicImage)]) -> Result<()> {
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+    for &(name, layer) in layers {
+        let layer = layer.to_rgba8();
+        let mut page = encoder.new_image::<colortype::RGBA8>(layer.width(), layer.height())?;
+        page.encoder().write_tag(PAGE_NAME, name)?;
+        page.write_data(layer.as_raw())?;
+    }
+    Ok(())
+}
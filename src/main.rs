@@ -1,74 +1,1264 @@
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
-use eyre::Result;
-use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+use eyre::{bail, eyre, Result, WrapErr};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImage, GenericImageView, ImageFormat, Rgba, RgbaImage};
 use indicatif::{
     ParallelProgressIterator, ProgressBar, ProgressFinish, ProgressIterator, ProgressStyle,
 };
 use rayon::prelude::*;
+use structopt::clap::{self, AppSettings};
 use structopt::StructOpt;
 
-/// Calculate the average color of a given image by averaging all of its pixels together (including alpha)
-fn average_color(image: &DynamicImage) -> Rgba<u8> {
-    let pixel_count = image.width() as f64 * image.height() as f64;
+mod adaptive;
+mod budget;
+mod checkpoint;
+mod decode;
+mod dominant;
+mod equalize;
+mod expr;
+#[cfg(feature = "exr")]
+mod exr;
+mod font;
+mod gamut;
+mod golden;
+mod html;
+mod index;
+mod interrupt;
+mod layers;
+mod match_cache;
+mod matching;
+mod metadata;
+mod palette;
+mod placement;
+mod profile;
+mod pyramid;
+mod quantize;
+#[cfg(feature = "url")]
+mod remote;
+#[cfg(feature = "server")]
+mod server;
+mod sidecar;
+mod stats;
+mod tiles;
+mod transition;
+mod validate;
 
-    let (mut r, mut g, mut b, mut a) = (0., 0., 0., 0.);
-    for (_x, _y, Rgba([pr, pg, pb, pa])) in image.pixels() {
-        r += pr as f64 * pr as f64;
-        g += pg as f64 * pg as f64;
-        b += pb as f64 * pb as f64;
-        a += pa as f64 * pa as f64;
+use equalize::Equalize;
+use gamut::GamutProjection;
+use matching::{
+    distance, nearest_by_channel, pick_image_for_dominants, pick_image_for_halves,
+    pick_image_for_pixel, pick_image_for_pixel_among, pick_image_for_pixel_at,
+    pick_image_for_pixel_avoiding, pick_image_for_pixel_by, pick_image_for_pixel_sequential,
+    pick_image_for_pixel_within, pick_image_for_signature, pick_image_for_signature_refined,
+    pick_image_for_tone, pick_tiles_dithered, pick_tiles_in_scan_order, rank_tiles,
+    signature_distance, ChannelWeights, CoarseIndex, Dither, MatchMode, MatchParallelism,
+    Placement, ScanOptions,
+};
+use placement::{BrightnessScale, LargerTiles, PlacementOptions, Recolor, TileShape};
+use tiles::{load_images, LoadOptions, Orientation, SmallTiles, Tile, TileCache, TileSort};
+
+/// An aspect ratio, written as `W:H`
+#[derive(Debug, Clone, Copy)]
+struct AspectRatio {
+    width: u32,
+    height: u32,
+}
+
+impl FromStr for AspectRatio {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (width, height) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("expected an aspect ratio like 16:9, got {:?}", s))?;
+        let (width, height) = (width.trim().parse()?, height.trim().parse()?);
+        if width == 0 || height == 0 {
+            bail!("aspect ratio sides must be positive, got {:?}", s);
+        }
+        Ok(Self { width, height })
+    }
+}
+
+/// The position of a cell in the mosaic's grid, written as `X,Y`
+#[derive(Debug, Clone, Copy)]
+struct CellPosition {
+    x: u32,
+    y: u32,
+}
+
+impl FromStr for CellPosition {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (x, y) = s
+            .split_once(',')
+            .ok_or_else(|| eyre!("expected a cell position like 3,4, got {:?}", s))?;
+        Ok(Self {
+            x: x.trim().parse()?,
+            y: y.trim().parse()?,
+        })
+    }
+}
+
+/// A rectangle of the source image, in pixels, written as `X,Y,W,H`
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Region {
+    /// Crop an image to the region, or to as much of it as is inside the image
+    fn crop(self, image: &DynamicImage) -> Result<DynamicImage> {
+        let (width, height) = image.dimensions();
+        if self.x >= width || self.y >= height {
+            bail!(
+                "the region starts at {},{}, outside of the {}x{} image",
+                self.x,
+                self.y,
+                width,
+                height
+            );
+        }
+        Ok(image.crop_imm(
+            self.x,
+            self.y,
+            self.width.min(width - self.x),
+            self.height.min(height - self.y),
+        ))
+    }
+}
+
+impl FromStr for Region {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let numbers = s
+            .split(',')
+            .map(|n| n.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()?;
+        match numbers[..] {
+            [_, _, 0, _] | [_, _, _, 0] => bail!("the region can't be empty, got {:?}", s),
+            [x, y, width, height] => Ok(Self {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => bail!("expected a region like 100,50,640,480, got {:?}", s),
+        }
+    }
+}
+
+/// The number of columns and rows of tiles in an atlas, written as `COLSxROWS`
+#[derive(Debug, Clone, Copy)]
+struct AtlasGrid {
+    columns: u32,
+    rows: u32,
+}
+
+impl FromStr for AtlasGrid {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (columns, rows) = s
+            .split_once('x')
+            .ok_or_else(|| eyre!("expected a grid size like 16x8, got {:?}", s))?;
+        let (columns, rows) = (columns.trim().parse()?, rows.trim().parse()?);
+        if columns == 0 || rows == 0 {
+            bail!(
+                "the grid must have at least one column and row, got {:?}",
+                s
+            );
+        }
+        Ok(Self { columns, rows })
+    }
+}
+
+/// Blend two images together, from all of `a` at 0 to all of `b` at 1
+///
+/// Unless `resize` is set, the images must be the same size, otherwise `b` is stretched to `a`'s.
+fn blend_images(a: &DynamicImage, b: &DynamicImage, t: f32, resize: bool) -> Result<DynamicImage> {
+    let b = if a.dimensions() == b.dimensions() {
+        b.to_rgba8()
+    } else if resize {
+        imageops::resize(b, a.width(), a.height(), FilterType::Triangle)
+    } else {
+        bail!(
+            "can't blend a {}x{} image with a {}x{} one, pass --blend-resize to stretch the second",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        );
+    };
+
+    let mut blended = a.to_rgba8();
+    for (pixel, Rgba(other)) in blended.pixels_mut().zip(b.pixels()) {
+        for (c, &other) in pixel.0.iter_mut().zip(other) {
+            *c = (f32::from(*c) * (1. - t) + f32::from(other) * t).round() as u8;
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(blended))
+}
+
+/// A pool of tiles to pick from, along with the structures that speed up matching against it
+struct TilePool {
+    tiles: Vec<Tile>,
+    coarse_index: Option<CoarseIndex>,
+    /// The `--delta-e-threshold`, along with every tile's color in CIELAB to compare against
+    delta_e_matching: Option<(f64, Vec<[f64; 3]>)>,
+}
+
+impl TilePool {
+    fn new(
+        tiles: Vec<Tile>,
+        coarse_bins: Option<u32>,
+        coarse_lab: bool,
+        delta_e_threshold: Option<f64>,
+    ) -> Self {
+        Self {
+            coarse_index: coarse_bins.map(|bins| CoarseIndex::new(&tiles, bins, coarse_lab)),
+            delta_e_matching: delta_e_threshold.map(|threshold| {
+                let tiles_lab = tiles
+                    .iter()
+                    .map(|tile| matching::to_lab(tile.average))
+                    .collect::<Vec<_>>();
+                (threshold, tiles_lab)
+            }),
+            tiles,
+        }
+    }
+}
+
+/// A color written in hex as `RRGGBB` or `RRGGBBAA`, optionally preceded by `#`
+#[derive(Debug, Clone, Copy)]
+struct HexColor(Rgba<u8>);
+
+impl FromStr for HexColor {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if !matches!(hex.len(), 6 | 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("expected a color like #ff8800 or #ff880080, got {:?}", s);
+        }
+        let channel = |idx: usize| u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16);
+        let alpha = if hex.len() == 8 { channel(3)? } else { 255 };
+        Ok(Self(Rgba([channel(0)?, channel(1)?, channel(2)?, alpha])))
+    }
+}
+
+/// Where to take a crop from when it has to cut off the top and/or bottom of an image
+#[derive(Debug, Clone, Copy)]
+enum Gravity {
+    Center,
+    Top,
+    Bottom,
+}
+
+impl FromStr for Gravity {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "center" => Ok(Self::Center),
+            "top" => Ok(Self::Top),
+            "bottom" => Ok(Self::Bottom),
+            _ => bail!("unknown gravity {:?}", s),
+        }
+    }
+}
+
+/// A corner of an image
+#[derive(Debug, Clone, Copy)]
+enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl FromStr for Corner {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "top-left" => Ok(Self::TopLeft),
+            "top-right" => Ok(Self::TopRight),
+            "bottom-left" => Ok(Self::BottomLeft),
+            "bottom-right" => Ok(Self::BottomRight),
+            _ => bail!("unknown corner {:?}", s),
+        }
+    }
+}
+
+/// Ranges of hues, in degrees, written as a comma separated list of `FROM-TO` ranges, e.g.
+/// `0-60,60-180,180-360`. A range whose end comes before its start wraps around red, e.g.
+/// `300-30`
+#[derive(Debug, Clone)]
+struct HueBands(Vec<(f32, f32)>);
+
+impl HueBands {
+    /// The indices of the tiles whose average hue is in each band, leaving out gray tiles,
+    /// which have no hue
+    fn partition(&self, possible_tiles: &[Tile]) -> Vec<Vec<usize>> {
+        self.0
+            .iter()
+            .map(|&(from, to)| {
+                possible_tiles
+                    .iter()
+                    .enumerate()
+                    .filter(|(_idx, tile)| {
+                        placement::to_hsl(tile.average).0.is_some_and(|hue| {
+                            let hue = hue * 60.;
+                            if from <= to {
+                                from <= hue && hue < to
+                            } else {
+                                from <= hue || hue < to
+                            }
+                        })
+                    })
+                    .map(|(idx, _tile)| idx)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl FromStr for HueBands {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split(',')
+            .map(|band| {
+                let (from, to) = band
+                    .split_once('-')
+                    .ok_or_else(|| eyre!("expected a range of hues like 0-60, got {:?}", band))?;
+                let (from, to) = (from.trim().parse::<f32>()?, to.trim().parse::<f32>()?);
+                if !(0. ..=360.).contains(&from) || !(0. ..=360.).contains(&to) {
+                    bail!("hues go from 0 to 360 degrees, got {:?}", band);
+                }
+                Ok((from, to))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+}
+
+/// Factors to downscale the finished mosaic by, written as a comma separated list, e.g.
+/// `1,0.5,0.25`
+#[derive(Debug, Clone)]
+struct OutputScales(Vec<f64>);
+
+impl FromStr for OutputScales {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split(',')
+            .map(|scale| {
+                let scale = scale.trim().parse::<f64>()?;
+                if !(scale > 0. && scale <= 1.) {
+                    bail!("expected scales above 0 and at most 1, got {}", scale);
+                }
+                Ok(scale)
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+}
+
+/// What to do with the cells of the last column and row, when the image's sides aren't a
+/// multiple of the cells' and they stick out of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EdgeCells {
+    /// Extend the image's edge pixels into the part sticking out
+    Clamp,
+
+    /// Average only the pixels inside the image
+    Shrink,
+
+    /// Leave them out of the mosaic
+    Crop,
+}
+
+impl FromStr for EdgeCells {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "clamp" => Ok(Self::Clamp),
+            "shrink" => Ok(Self::Shrink),
+            "crop" => Ok(Self::Crop),
+            _ => bail!("unknown edge cell handling {:?}", s),
+        }
+    }
+}
+
+/// The columns and rows of cells whose aspect ratio is closest to an image's, for `--snap-grid`
+///
+/// The grid's longer side is from 90% of `mosaic_size` up to it, its shorter side being the
+/// image's aspect ratio of that rounded to the nearest cell, and the longest of the grids that
+/// come closest is chosen.
+fn snapped_grid(width: u32, height: u32, mosaic_size: u32) -> (u32, u32) {
+    let ratio = f64::from(width.min(height)) / f64::from(width.max(height));
+    let shortest = ((f64::from(mosaic_size) * 0.9).ceil() as u32).max(1);
+    let (long, short) = (shortest..=mosaic_size.max(1))
+        .rev()
+        .map(|long| (long, ((f64::from(long) * ratio).round() as u32).max(1)))
+        .min_by(|&(long_a, short_a), &(long_b, short_b)| {
+            let error = |long: u32, short: u32| (f64::from(short) / f64::from(long) - ratio).abs();
+            error(long_a, short_a).total_cmp(&error(long_b, short_b))
+        })
+        .unwrap();
+    if width >= height {
+        (long, short)
+    } else {
+        (short, long)
+    }
+}
+
+/// Shrink an image to one pixel per cell of a `columns`x`rows` grid of whole pixels, each the
+/// average of the pixels it covers
+///
+/// Cells are as many pixels wide and tall as it takes for the grid to cover the whole image, so
+/// the last column and row stick out of it unless its sides are multiples of the grid's, and
+/// `edge` decides what happens to them.
+fn average_cells(image: &DynamicImage, columns: u32, rows: u32, edge: EdgeCells) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let (cell_width, cell_height) = (width.div_ceil(columns), height.div_ceil(rows));
+    let (columns, rows) = match edge {
+        EdgeCells::Crop => ((width / cell_width).max(1), (height / cell_height).max(1)),
+        EdgeCells::Clamp | EdgeCells::Shrink => {
+            (width.div_ceil(cell_width), height.div_ceil(cell_height))
+        }
+    };
+    let pixels = image.to_rgba8();
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(columns, rows, |column, row| {
+        let (xs, ys) = (
+            column * cell_width..(column + 1) * cell_width,
+            row * cell_height..(row + 1) * cell_height,
+        );
+        let (xs, ys) = match edge {
+            EdgeCells::Clamp => (xs, ys),
+            EdgeCells::Shrink | EdgeCells::Crop => {
+                (xs.start..xs.end.min(width), ys.start..ys.end.min(height))
+            }
+        };
+        let mut sums = [0u64; 4];
+        let mut count = 0;
+        for y in ys {
+            for x in xs.clone() {
+                let pixel = pixels.get_pixel(x.min(width - 1), y.min(height - 1));
+                for (sum, channel) in sums.iter_mut().zip(pixel.0) {
+                    *sum += u64::from(channel);
+                }
+                count += 1;
+            }
+        }
+        Rgba(sums.map(|sum| ((sum + count / 2) / count.max(1)) as u8))
+    }))
+}
+
+/// Crop an image to the largest region with the given aspect ratio
+///
+/// Gravity only matters when cutting off rows, horizontal crops are always centered.
+fn crop_to_aspect(image: &DynamicImage, aspect: AspectRatio, gravity: Gravity) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let (aspect_width, aspect_height) = (u64::from(aspect.width), u64::from(aspect.height));
+    let crop_width =
+        (u64::from(height) * aspect_width / aspect_height).clamp(1, u64::from(width)) as u32;
+    let crop_height =
+        (u64::from(width) * aspect_height / aspect_width).clamp(1, u64::from(height)) as u32;
+
+    let x = (width - crop_width) / 2;
+    let y = match gravity {
+        Gravity::Center => (height - crop_height) / 2,
+        Gravity::Top => 0,
+        Gravity::Bottom => height - crop_height,
+    };
+    image.crop_imm(x, y, crop_width, crop_height)
+}
+
+/// Fill in the placeholders of an output filename template: `{stem}` (the input's file name
+/// without its extension), `{kind}` (`mosaic` or `sprites`), `{size}` (the mosaic size) and
+/// `{tile_size}`
+fn render_output_template(
+    template: &str,
+    stem: &str,
+    kind: &str,
+    size: u32,
+    tile_size: u32,
+) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| eyre!("unclosed placeholder in output template {:?}", template))?;
+        match &rest[start + 1..start + end] {
+            "stem" => rendered.push_str(stem),
+            "kind" => rendered.push_str(kind),
+            "size" => rendered.push_str(&size.to_string()),
+            "tile_size" => rendered.push_str(&tile_size.to_string()),
+            placeholder => bail!(
+                "unknown placeholder {{{}}} in output template {:?}",
+                placeholder,
+                template
+            ),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Derive the path of a per-input artifact from the path given on the command line, by
+/// inserting the input's file stem before the extension (e.g. `heatmap.png` -> `heatmap.photo.png`)
+fn per_input_path(path: &Path, input_stem: &str) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{input_stem}"));
+    if let Some(ext) = path.extension() {
+        file_name.push(".");
+        file_name.push(ext);
+    }
+    path.with_file_name(file_name)
+}
+
+/// Read the paths listed in a file, or on standard input if it's `-`, one per line, skipping
+/// empty lines and lines starting with `#`
+fn read_input_list(path: &Path) -> Result<Vec<PathBuf>> {
+    let list = if path == Path::new("-") {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(path)?
+    };
+    Ok(list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Render the per-cell match errors as an image, going from green (good match) to red (poor
+/// match) relative to the worst match in the image, upscaled so that it's comfortable to view
+fn make_error_heatmap(width: u32, height: u32, errors: &[i64]) -> DynamicImage {
+    const MIN_SIDE: u32 = 512;
+
+    let max_error = (errors.iter().copied().max().unwrap_or(0) as f64).sqrt();
+    let heatmap = RgbaImage::from_fn(width, height, |x, y| {
+        let error = errors[(y * width + x) as usize] as f64;
+        let t = if max_error > 0. {
+            error.sqrt() / max_error
+        } else {
+            0.
+        };
+        let r = (2. * t).min(1.) * 255.;
+        let g = (2. * (1. - t)).min(1.) * 255.;
+        Rgba([r as u8, g as u8, 0, 255])
+    });
+
+    let scale = (MIN_SIDE / width.max(height).max(1)).max(1);
+    DynamicImage::ImageRgba8(imageops::resize(
+        &heatmap,
+        width * scale,
+        height * scale,
+        FilterType::Nearest,
+    ))
+}
+
+/// Quote a CSV field if it needs to be, i.e. if it holds a comma, a quote or a line break
+fn csv_field(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// A CSV of a row for every cell, with its position, its color, the path of the tile chosen for
+/// it and how far that tile's average color is from it, written row by row as cells get their
+/// tiles rather than kept around until the end
+struct CellsCsv {
+    path: PathBuf,
+    csv: BufWriter<fs::File>,
+    rows: usize,
+}
+
+impl CellsCsv {
+    fn create(path: PathBuf) -> Result<Self> {
+        let mut csv = BufWriter::new(fs::File::create(&path)?);
+        writeln!(csv, "x,y,r,g,b,tile,error")?;
+        Ok(Self { path, csv, rows: 0 })
+    }
+
+    fn row(
+        &mut self,
+        x: u32,
+        y: u32,
+        pixel: Rgba<u8>,
+        tile: &Tile,
+        weights: ChannelWeights,
+    ) -> Result<()> {
+        let Rgba([r, g, b, _a]) = pixel;
+        writeln!(
+            self.csv,
+            "{},{},{},{},{},{},{}",
+            x,
+            y,
+            r,
+            g,
+            b,
+            csv_field(&tile.path.to_string_lossy()),
+            distance(pixel, tile.average, weights)
+        )?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Write the rows of every cell in one go, unless they were written while matching already
+    fn write_cells(
+        &mut self,
+        img: &DynamicImage,
+        cells: &[Placement],
+        possible_tiles: &[Tile],
+        weights: ChannelWeights,
+    ) -> Result<()> {
+        if self.rows > 0 {
+            return Ok(());
+        }
+        for ((x, y, pixel), placement) in img.pixels().zip(cells) {
+            self.row(x, y, pixel, &possible_tiles[placement.tile], weights)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<PathBuf> {
+        self.csv.flush()?;
+        Ok(self.path)
+    }
+}
+
+/// Split a CSV into its records and their fields, undoing `csv_field`'s quoting, so that quoted
+/// fields can hold commas, quotes and line breaks. Each record comes with the line it starts on
+fn parse_csv(csv: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut records = Vec::new();
+    let (mut record, mut field) = (Vec::new(), String::new());
+    let (mut line, mut record_line) = (1, 1);
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => {
+                        line += usize::from(c == '\n');
+                        field.push(c);
+                    }
+                    None => bail!(
+                        "the quoted field starting on line {} never ends",
+                        record_line
+                    ),
+                }
+            },
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push((record_line, std::mem::take(&mut record)));
+                line += 1;
+                record_line = line;
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((record_line, record));
     }
-    let r = (r / pixel_count).sqrt() as u8;
-    let g = (g / pixel_count).sqrt() as u8;
-    let b = (b / pixel_count).sqrt() as u8;
-    let a = (a / pixel_count).sqrt() as u8;
-    Rgba([r, g, b, a])
+    Ok(records)
 }
 
-/// Calculate the distance (squared) between two colors
-/// Code adapted from https://stackoverflow.com/a/9085524/13204109
-fn distance(Rgba([r1, g1, b1, _]): Rgba<u8>, Rgba([r2, g2, b2, _]): Rgba<u8>) -> i64 {
-    let rmean = (i64::from(r1) + i64::from(r2)) / 2;
-    let r = i64::from(r1) - i64::from(r2);
-    let g = i64::from(g1) - i64::from(g2);
-    let b = i64::from(b1) - i64::from(b2);
-    (((512 + rmean) * r * r) >> 8) + 4 * g * g + (((767 - rmean) * b * b) >> 8)
+/// Read the path of the tile chosen for every cell from a CSV written by `CellsCsv`, by
+/// the cell's column and row
+fn read_cells_csv(path: &Path) -> Result<HashMap<(u32, u32), String>> {
+    let csv = fs::read_to_string(path)?;
+    let records = parse_csv(&csv).wrap_err_with(|| format!("{} is malformed", path.display()))?;
+    let mut tiles = HashMap::new();
+    for (line, mut fields) in records.into_iter().skip(1) {
+        let position = match fields.as_slice() {
+            [x, y, _r, _g, _b, _tile, _error] => x.parse().ok().zip(y.parse().ok()),
+            _ => None,
+        };
+        let position =
+            position.ok_or_else(|| eyre!("line {} of {} is malformed", line, path.display()))?;
+        tiles.insert(position, fields.swap_remove(5));
+    }
+    Ok(tiles)
+}
+
+/// Format a color as a hex triplet, with alpha
+fn hex_color(Rgba([r, g, b, a]): Rgba<u8>) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}{a:02x}")
 }
 
-/// Choose the image in the given tileset whose average color is closest to the given pixel
-fn pick_image_for_pixel(
+/// Print why the cell at the given position got its tile: what it looks like, what the chosen
+/// tile looks like and how close the runners-up came
+///
+/// When matching by structure, distances are between signatures rather than average colors.
+fn explain_cell(
+    position: CellPosition,
     pixel: Rgba<u8>,
-    possible_tiles: &[(DynamicImage, Rgba<u8>)],
-) -> Option<&DynamicImage> {
-    possible_tiles
-        .into_par_iter()
-        .min_by_key(|(_img, avg)| distance(*avg, pixel))
-        .map(|(img, _avg)| img)
+    signature: Option<&[Rgba<u8>]>,
+    placement: Placement,
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+) {
+    const RUNNERS_UP: usize = 5;
+
+    let score = |tile: &Tile| match signature {
+        Some(signature) => tile
+            .signatures
+            .iter()
+            .map(|(_orientation, tile_signature)| {
+                signature_distance(signature, tile_signature, weights)
+            })
+            .min()
+            .unwrap_or(i64::MAX),
+        None => distance(pixel, tile.average, weights),
+    };
+    let describe = |idx: usize, score: i64| {
+        let tile = &possible_tiles[idx];
+        format!(
+            "{} (average {}, distance {score})",
+            tile.path.display(),
+            hex_color(tile.average)
+        )
+    };
+
+    eprintln!(
+        "Cell {},{}: target {}",
+        position.x,
+        position.y,
+        hex_color(pixel)
+    );
+    let chosen = &possible_tiles[placement.tile];
+    eprintln!("  chosen: {}", describe(placement.tile, score(chosen)));
+    if placement.orientation != Orientation::Identity {
+        eprintln!("  oriented: {:?}", placement.orientation);
+    }
+    eprintln!("  runners-up:");
+    let runners_up = rank_tiles(possible_tiles, RUNNERS_UP + 1, score)
+        .into_iter()
+        .filter(|&(idx, _score)| idx != placement.tile)
+        .take(RUNNERS_UP);
+    for (rank, (idx, score)) in runners_up.enumerate() {
+        eprintln!("    {}. {}", rank + 1, describe(idx, score));
+    }
+}
+
+/// Quote a string for use in JSON
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Pack the tiles that are actually used in the mosaic into a sprite sheet, and describe which
+/// sprite goes in each cell as JSON
+///
+/// Sprites are numbered in order of first appearance, and laid out left to right, top to
+/// bottom, in a roughly square grid. The JSON also records which file each sprite came from;
+/// differently oriented copies of the same tile get separate sprites.
+fn make_sprite_sheet(
+    possible_tiles: &[Tile],
+    cells: &[Placement],
+    width: u32,
+    tile_size: u32,
+) -> Result<(DynamicImage, String)> {
+    let mut sprite_of_tile = HashMap::new();
+    let mut sprites = Vec::new();
+    let cells = cells
+        .iter()
+        .map(|&placement| {
+            *sprite_of_tile.entry(placement).or_insert_with(|| {
+                sprites.push(placement);
+                sprites.len() - 1
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let columns = (sprites.len() as f64).sqrt().ceil().max(1.) as u32;
+    let rows = (sprites.len() as u32).div_ceil(columns);
+    let mut sheet = DynamicImage::new_rgba8(columns * tile_size, rows * tile_size);
+    for (idx, placement) in sprites.iter().enumerate() {
+        let idx = idx as u32;
+        sheet.copy_from(
+            &*placement
+                .orientation
+                .apply(&*possible_tiles[placement.tile].image()?),
+            idx % columns * tile_size,
+            idx / columns * tile_size,
+        )?;
+    }
+
+    let rows_json = cells
+        .chunks(width as usize)
+        .map(|row| {
+            let row = row.iter().map(ToString::to_string).collect::<Vec<_>>();
+            format!("[{}]", row.join(","))
+        })
+        .collect::<Vec<_>>();
+    let paths_json = sprites
+        .iter()
+        .map(|placement| json_string(&possible_tiles[placement.tile].path.to_string_lossy()))
+        .collect::<Vec<_>>();
+    let index = format!(
+        "{{\"tile_size\":{},\"columns\":{},\"sprites\":[{}],\"width\":{},\"height\":{},\"cells\":[{}]}}\n",
+        tile_size,
+        columns,
+        paths_json.join(","),
+        width,
+        rows_json.len(),
+        rows_json.join(","),
+    );
+
+    Ok((sheet, index))
 }
 
-/// Load the tiles from the given directory
-fn load_images<P: AsRef<Path>>(dir: P, tile_side: u32) -> Result<Vec<(DynamicImage, Rgba<u8>)>> {
-    let dir = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
-    let len = dir.len();
+/// Match every `tile_size` cell of a mosaic against the tileset by its average color, and
+/// describe which tile each one most likely is as JSON, along with how far off it is
+///
+/// Tiles are numbered in order of first appearance, and the JSON records which file each one
+/// came from. Leftover pixels on the right and at the bottom are ignored, with a warning.
+fn inspect_mosaic(
+    mosaic: &DynamicImage,
+    possible_tiles: &[Tile],
+    tile_size: u32,
+    weights: ChannelWeights,
+) -> Result<String> {
+    let (width, height) = (mosaic.width() / tile_size, mosaic.height() / tile_size);
+    if width == 0 || height == 0 {
+        bail!(
+            "the {}x{} mosaic is smaller than a single {}x{} tile",
+            mosaic.width(),
+            mosaic.height(),
+            tile_size,
+            tile_size
+        );
+    }
+    if !mosaic.width().is_multiple_of(tile_size) || !mosaic.height().is_multiple_of(tile_size) {
+        eprintln!(
+            "warning: the {}x{} mosaic isn't a whole number of {}x{} tiles, ignoring the leftover pixels on the right and at the bottom",
+            mosaic.width(),
+            mosaic.height(),
+            tile_size,
+            tile_size
+        );
+    }
+
+    let positions = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .collect::<Vec<_>>();
+    let len = positions.len();
+    let matches = positions
+        .into_par_iter()
+        .progress_with(make_pbar("cells", len as _))
+        .map(|(x, y)| {
+            let cell = mosaic.crop_imm(x * tile_size, y * tile_size, tile_size, tile_size);
+            let average = tiles::average_color(&cell);
+            let tile = pick_image_for_pixel(average, possible_tiles, weights, None)
+                .ok_or_else(|| eyre!("there are no tiles to pick from"))?;
+            Ok((
+                tile,
+                distance(possible_tiles[tile].average, average, weights),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut number_of_tile = HashMap::new();
+    let mut used = Vec::new();
+    let cells = matches
+        .iter()
+        .map(|&(tile, _distance)| {
+            *number_of_tile.entry(tile).or_insert_with(|| {
+                used.push(tile);
+                used.len() - 1
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let rows_json = |values: &[String]| {
+        values
+            .chunks(width as usize)
+            .map(|row| format!("[{}]", row.join(",")))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let paths_json = used
+        .iter()
+        .map(|&tile| json_string(&possible_tiles[tile].path.to_string_lossy()))
+        .collect::<Vec<_>>();
+    Ok(format!(
+        "{{\"tile_size\":{},\"tiles\":[{}],\"width\":{},\"height\":{},\"cells\":[{}],\"distances\":[{}]}}\n",
+        tile_size,
+        paths_json.join(","),
+        width,
+        height,
+        rows_json(&cells.iter().map(ToString::to_string).collect::<Vec<_>>()),
+        rows_json(
+            &matches
+                .iter()
+                .map(|(_tile, distance)| distance.to_string())
+                .collect::<Vec<_>>()
+        ),
+    ))
+}
 
-    Ok(dir
+/// Describe which tile the built-in distance picks for every color of a quantized color cube as
+/// JSON, so that other programs can build mosaics from the tileset without matching
+///
+/// Each channel is quantized into `levels` levels, level `i` covering the values from
+/// `i * 256 / levels` up to but excluding `(i + 1) * 256 / levels`, and each color is matched by
+/// the middle of its levels. The JSON has the number of `levels`, the `tiles`' paths and the
+/// `lut` itself, which holds an index into `tiles` for every color, the color with the levels
+/// `r`, `g` and `b` being at `(r * levels + g) * levels + b`.
+fn make_lut(possible_tiles: &[Tile], levels: u32, weights: ChannelWeights) -> String {
+    let middle = |level: u32| ((2 * level + 1) * 256 / (2 * levels)) as u8;
+    let colors = (0..levels.pow(3))
+        .map(|idx| {
+            let (r, g, b) = (idx / levels / levels, idx / levels % levels, idx % levels);
+            Rgba([middle(r), middle(g), middle(b), 255])
+        })
+        .collect::<Vec<_>>();
+    let len = colors.len();
+    let lut = colors
         .into_par_iter()
-        .progress_with(make_pbar("images loaded", len as _))
-        .filter_map(|entry| {
-            let img = image::open(entry.path())
-                .ok()?
-                .thumbnail_exact(tile_side, tile_side);
-            let avg = average_color(&img);
-            Some((img, avg))
+        .progress_with(make_pbar("colors", len as _))
+        .map(|color| {
+            pick_image_for_pixel(color, possible_tiles, weights, None)
+                .expect("the tileset shouldn't be empty")
+                .to_string()
         })
-        .collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let paths_json = possible_tiles
+        .iter()
+        .map(|tile| json_string(&tile.path.to_string_lossy()))
+        .collect::<Vec<_>>();
+    format!(
+        "{{\"levels\":{},\"tiles\":[{}],\"lut\":[{}]}}\n",
+        levels,
+        paths_json.join(","),
+        lut.join(","),
+    )
+}
+
+/// Sharpen an image with an unsharp mask, adding back `amount` times the difference between
+/// the image and a slightly blurred copy of it
+fn sharpen(image: &DynamicImage, amount: f32) -> DynamicImage {
+    const SIGMA: f32 = 1.;
+
+    let mut sharpened = image.to_rgba8();
+    let blurred = imageops::blur(&sharpened, SIGMA);
+    for (pixel, blurred) in sharpened.pixels_mut().zip(blurred.pixels()) {
+        for (c, &b) in pixel.0.iter_mut().zip(&blurred.0).take(3) {
+            let (v, b) = (f32::from(*c), f32::from(b));
+            *c = (v + amount * (v - b)).round().clamp(0., 255.) as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(sharpened)
+}
+
+/// The color temperature of daylight, at which `--color-temperature` leaves images unchanged
+const DAYLIGHT_KELVIN: f64 = 6500.;
+
+/// The lowest and highest color temperatures allowed, the light of lower ones having next to no blue
+const MIN_KELVIN: f64 = 2000.;
+const MAX_KELVIN: f64 = 40000.;
+
+/// The color light of the given temperature in Kelvin takes on, as the share of each channel,
+/// by Tanner Helland's curve fit of the blackbody spectrum
+fn blackbody(kelvin: f64) -> [f64; 3] {
+    let t = kelvin / 100.;
+    let red = if t <= 66. {
+        255.
+    } else {
+        329.698_727_446 * (t - 60.).powf(-0.133_204_759_2)
+    };
+    let green = if t <= 66. {
+        99.470_802_586_1 * t.ln() - 161.119_568_166_1
+    } else {
+        288.122_169_528_3 * (t - 60.).powf(-0.075_514_849_2)
+    };
+    let blue = if t >= 66. {
+        255.
+    } else if t <= 19. {
+        0.
+    } else {
+        138.517_731_223_1 * (t - 10.).ln() - 305.044_792_730_7
+    };
+    [red, green, blue].map(|c| c.clamp(0., 255.) / 255.)
+}
+
+/// White balance an image as if it had been shot under light of the given temperature in Kelvin,
+/// scaling each channel by how much more of it daylight has than that light, so that higher
+/// temperatures warm it and lower ones cool it, like a photo editor's temperature slider, and
+/// 6500K leaves it as it is
+fn adjust_temperature(image: &DynamicImage, kelvin: f64) -> DynamicImage {
+    let (light, daylight) = (blackbody(kelvin), blackbody(DAYLIGHT_KELVIN));
+    let mut adjusted = image.to_rgba8();
+    for pixel in adjusted.pixels_mut() {
+        for ((c, light), daylight) in pixel.0.iter_mut().zip(light).zip(daylight) {
+            *c = (f64::from(*c) * daylight / light).round().clamp(0., 255.) as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(adjusted)
+}
+
+/// Check whether the format an image would be saved in by its path can be transparent
+fn supports_alpha(path: &Path) -> bool {
+    matches!(
+        ImageFormat::from_path(path),
+        Ok(ImageFormat::Png | ImageFormat::WebP | ImageFormat::Tiff)
+    )
+}
+
+/// Which edges of the mosaic to fade, written as a comma separated list of `top`, `bottom`,
+/// `left` and `right`, or `all`
+#[derive(Debug, Clone, Copy)]
+struct Edges {
+    top: bool,
+    bottom: bool,
+    left: bool,
+    right: bool,
+}
+
+impl FromStr for Edges {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut edges = Self {
+            top: false,
+            bottom: false,
+            left: false,
+            right: false,
+        };
+        for edge in s.split(',') {
+            match edge.trim() {
+                "top" => edges.top = true,
+                "bottom" => edges.bottom = true,
+                "left" => edges.left = true,
+                "right" => edges.right = true,
+                "all" => {
+                    edges = Self {
+                        top: true,
+                        bottom: true,
+                        left: true,
+                        right: true,
+                    }
+                }
+                edge => bail!("unknown edge {:?}", edge),
+            }
+        }
+        Ok(edges)
+    }
+}
+
+/// Fade the outer `width` pixels of the given edges of an image to transparent, linearly
+///
+/// Where two faded edges meet, their fades multiply, so corners fade out smoothly.
+fn fade_edges(image: &DynamicImage, width: u32, edges: Edges) -> DynamicImage {
+    // How opaque a pixel this many pixels away from a faded edge stays
+    let opacity = |distance: u32| ((f64::from(distance) + 0.5) / f64::from(width)).min(1.);
+
+    let mut faded = image.to_rgba8();
+    let (image_width, image_height) = faded.dimensions();
+    for (x, y, pixel) in faded.enumerate_pixels_mut() {
+        let mut alpha = 1.;
+        if edges.top {
+            alpha *= opacity(y);
+        }
+        if edges.bottom {
+            alpha *= opacity(image_height - 1 - y);
+        }
+        if edges.left {
+            alpha *= opacity(x);
+        }
+        if edges.right {
+            alpha *= opacity(image_width - 1 - x);
+        }
+        pixel[3] = (f64::from(pixel[3]) * alpha).round() as u8;
+    }
+    DynamicImage::ImageRgba8(faded)
+}
+
+/// How to lay out the source image and the mosaic in a side by side comparison
+#[derive(Debug, Clone, Copy)]
+enum Arrangement {
+    Horizontal,
+    Vertical,
+}
+
+impl FromStr for Arrangement {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "horizontal" => Ok(Self::Horizontal),
+            "vertical" => Ok(Self::Vertical),
+            _ => bail!("unknown arrangement {:?}", s),
+        }
+    }
+}
+
+/// Draw text into a corner of the image, a margin the height of a glyph away from its edges, in
+/// the built-in font scaled by `scale`, or in proportion to the image's width if it's `None`
+fn draw_watermark(
+    image: &DynamicImage,
+    text: &str,
+    corner: Corner,
+    scale: Option<u32>,
+    color: Rgba<u8>,
+) -> DynamicImage {
+    let mut image = image.to_rgba8();
+    let (width, height) = image.dimensions();
+    let scale = scale.unwrap_or((width / 400).max(1));
+    let (text_width, text_height) = (
+        i64::from(font::text_width(text, scale)),
+        i64::from(font::GLYPH_HEIGHT * scale),
+    );
+    let margin = text_height;
+    let x = match corner {
+        Corner::TopLeft | Corner::BottomLeft => margin,
+        Corner::TopRight | Corner::BottomRight => i64::from(width) - margin - text_width,
+    };
+    let y = match corner {
+        Corner::TopLeft | Corner::TopRight => margin,
+        Corner::BottomLeft | Corner::BottomRight => i64::from(height) - margin - text_height,
+    };
+    font::draw_text(&mut image, text, x, y, scale, color);
+    DynamicImage::ImageRgba8(image)
+}
+
+/// Draw a bar with the given text centered in it
+fn make_label_bar(width: u32, text: &str) -> RgbaImage {
+    let scale = (width / 300).max(1);
+    let padding = 3 * scale;
+    let mut bar = RgbaImage::from_pixel(
+        width,
+        font::GLYPH_HEIGHT * scale + 2 * padding,
+        Rgba([32, 32, 32, 255]),
+    );
+    // Centered, unless it's too long to fit, in which case its end gets cut off instead
+    let x = ((i64::from(width) - i64::from(font::text_width(text, scale))) / 2).max(0);
+    font::draw_text(
+        &mut bar,
+        text,
+        x,
+        i64::from(padding),
+        scale,
+        Rgba([255, 255, 255, 255]),
+    );
+    bar
+}
+
+/// Put the source image, scaled up to the size of the mosaic, next to the mosaic, optionally
+/// labeling both
+fn make_side_by_side(
+    source: &DynamicImage,
+    mosaic: &DynamicImage,
+    arrangement: Arrangement,
+    labels: bool,
+) -> Result<DynamicImage> {
+    let (width, height) = mosaic.dimensions();
+    let source = source.resize_exact(width, height, FilterType::Triangle);
+
+    let bar_height = if labels {
+        make_label_bar(width, "").height()
+    } else {
+        0
+    };
+    let (panel_width, panel_height) = (width, height + bar_height);
+    let (offset_x, offset_y) = match arrangement {
+        Arrangement::Horizontal => (panel_width, 0),
+        Arrangement::Vertical => (0, panel_height),
+    };
+
+    let mut comparison = DynamicImage::new_rgba8(panel_width + offset_x, panel_height + offset_y);
+    for (idx, &(panel, label)) in [(&source, "original"), (mosaic, "mosaic")]
+        .iter()
+        .enumerate()
+    {
+        let (x, y) = (idx as u32 * offset_x, idx as u32 * offset_y);
+        comparison.copy_from(panel, x, y)?;
+        if labels {
+            comparison.copy_from(&make_label_bar(width, label), x, y + height)?;
+        }
+    }
+    Ok(comparison)
+}
+
+/// Arrange thumbnails of the mosaics in a grid of the given number of columns, left to right
+/// then top to bottom, each captioned with its file name
+fn make_contact_sheet(
+    previews: &[(String, DynamicImage)],
+    columns: u32,
+    thumbnail_size: u32,
+) -> Result<DynamicImage> {
+    let columns = columns.min(previews.len() as u32).max(1);
+    let rows = (previews.len() as u32).div_ceil(columns);
+    let bar_height = make_label_bar(thumbnail_size, "").height();
+    let (cell_width, cell_height) = (thumbnail_size, thumbnail_size + bar_height);
+
+    let mut sheet = DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+        columns * cell_width,
+        rows * cell_height,
+        Rgba([32, 32, 32, 255]),
+    ));
+    for (idx, (name, preview)) in previews.iter().enumerate() {
+        let idx = idx as u32;
+        let (x, y) = (idx % columns * cell_width, idx / columns * cell_height);
+        let thumbnail = preview.thumbnail(thumbnail_size, thumbnail_size);
+        imageops::overlay(
+            &mut sheet,
+            &thumbnail,
+            i64::from(x + (thumbnail_size - thumbnail.width()) / 2),
+            i64::from(y + (thumbnail_size - thumbnail.height()) / 2),
+        );
+        sheet.copy_from(&make_label_bar(thumbnail_size, name), x, y + thumbnail_size)?;
+    }
+    Ok(sheet)
 }
 
 /// Create a styled progress bar
-fn make_pbar(msg: &'static str, len: u64) -> ProgressBar {
+pub(crate) fn make_pbar(msg: &'static str, len: u64) -> ProgressBar {
     let bar = ProgressBar::new(len);
     bar.set_message(msg);
     bar.set_style(
@@ -94,95 +1284,2641 @@ fn make_spinner(msg: &'static str, done_msg: &'static str) -> ProgressBar {
     bar
 }
 
-#[derive(StructOpt)]
-struct Opt {
-    /// The image to turn into a mosaic
-    #[structopt(short, long, parse(from_os_str))]
-    input_dir: PathBuf,
-
-    /// The directory containing the tiles to utilize
-    #[structopt(short, long, parse(from_os_str))]
-    tiles_dir: PathBuf,
-
-    /// Where to save the finished mosaic
-    #[structopt(short, long, parse(from_os_str), default_value = "output")]
-    output_dir: PathBuf,
+/// Parse a percentage, which must be in the range (0, 100]
+fn parse_percentage(s: &str) -> Result<f64> {
+    let percent = s.parse::<f64>()?;
+    if !(percent > 0. && percent <= 100.) {
+        bail!("expected a percentage between 0 and 100, got {}", s);
+    }
+    Ok(percent)
+}
 
-    /// The side length that the target image'll be resized to.
-    #[structopt(short, long, default_value = "128")]
-    mosaic_size: u32,
+/// Parse a finite, non-negative number
+fn parse_non_negative(s: &str) -> Result<f64> {
+    let n = s.parse::<f64>()?;
+    if !(n.is_finite() && n >= 0.) {
+        bail!("expected a non-negative number, got {}", s);
+    }
+    Ok(n)
+}
 
-    /// The side length of each tile.
-    #[structopt(long, default_value = "26")]
-    tile_size: u32,
+/// Parse a fraction, which must be in the range [0, 1]
+fn parse_fraction(s: &str) -> Result<f32> {
+    let n = s.parse::<f32>()?;
+    if !(0. ..=1.).contains(&n) {
+        bail!("expected a number between 0 and 1, got {}", n);
+    }
+    Ok(n)
+}
 
-    /// Keep the image's aspect ratio
-    #[structopt(short, long)]
-    keep_aspect_ratio: bool,
+/// Parse a strictly positive integer
+fn parse_nonzero(s: &str) -> Result<u32> {
+    match s.parse()? {
+        0 => bail!("expected a positive number, got 0"),
+        n => Ok(n),
+    }
+}
+
+/// Parse a color temperature in Kelvin, from `MIN_KELVIN` to `MAX_KELVIN`
+fn parse_temperature(s: &str) -> Result<f64> {
+    let kelvin = s.parse::<f64>()?;
+    if !(MIN_KELVIN..=MAX_KELVIN).contains(&kelvin) {
+        bail!(
+            "expected a temperature from {}K to {}K, got {}",
+            MIN_KELVIN,
+            MAX_KELVIN,
+            s
+        );
+    }
+    Ok(kelvin)
+}
+
+/// Parse a number of colors that fits in a palette, from 2 to 256
+fn parse_palette_size(s: &str) -> Result<u32> {
+    let colors = s.parse()?;
+    if !(2..=256).contains(&colors) {
+        bail!("expected from 2 to 256 colors, got {}", colors);
+    }
+    Ok(colors)
+}
+
+/// Parse a number of times to split adaptive cells, up to `adaptive::MAX_DEPTH`
+fn parse_adaptive_depth(s: &str) -> Result<u32> {
+    let depth = s.parse()?;
+    if depth > adaptive::MAX_DEPTH {
+        bail!(
+            "expected at most {} splits, got {}",
+            adaptive::MAX_DEPTH,
+            depth
+        );
+    }
+    Ok(depth)
+}
+
+/// Parse a number of dominant colors, from 1 to `MAX_DOMINANT_COLORS`
+fn parse_dominant_colors(s: &str) -> Result<usize> {
+    let k = s.parse()?;
+    if !(1..=dominant::MAX_DOMINANT_COLORS).contains(&k) {
+        bail!(
+            "expected from 1 to {} dominant colors, got {}",
+            dominant::MAX_DOMINANT_COLORS,
+            k
+        );
+    }
+    Ok(k)
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Check a tiles directory for tiles that fail to decode, are fully transparent, single
+    /// colored, duplicated or very far from square, without building any mosaic. Files without
+    /// an image's extension are reported too, but don't fail the check. Subdirectories are
+    /// skipped, and so are focus masks if `--focus-masks` goes before the subcommand
+    ValidateTiles {
+        /// The directory containing the tiles to check
+        #[structopt(short, long, parse(from_os_str))]
+        tiles_dir: PathBuf,
+    },
+
+    /// Load the tiles once and save their colors to an index file, for `--tile-index` to use
+    /// instead of loading every tile again. Options that change how tiles are loaded, like
+    /// `--tile-size` or `--subregions`, go before the subcommand and must match later runs'
+    Index {
+        /// The directory containing the tiles to index
+        #[structopt(short, long, parse(from_os_str))]
+        tiles_dir: PathBuf,
+
+        /// Where to save the index
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    /// Recover which tile most likely went in each cell of an existing mosaic, by splitting it
+    /// into a grid of `--tile-size` cells and matching each one against the tileset, and
+    /// describe the grid as JSON. Tile loading options go before the subcommand and should match
+    /// the ones the mosaic was made with
+    Inspect {
+        /// The mosaic to inspect
+        #[structopt(short, long, parse(from_os_str))]
+        mosaic: PathBuf,
+
+        /// The directory containing the tiles the mosaic was made of
+        #[structopt(short, long, parse(from_os_str))]
+        tiles_dir: PathBuf,
+
+        /// Where to save the JSON, rather than printing it
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    /// Print the provenance a mosaic was saved with by `--embed-provenance`: the version of
+    /// themis, its source and the command line it was made with
+    Provenance {
+        /// The mosaic to read the provenance of
+        #[structopt(short, long, parse(from_os_str))]
+        mosaic: PathBuf,
+
+        /// Where to save the thumbnail of the source, as a PNG
+        #[structopt(long, parse(from_os_str))]
+        thumbnail: Option<PathBuf>,
+    },
+
+    /// Load the tiles once and serve mosaics over HTTP: POST an image to `/mosaic` to get its
+    /// mosaic back as a PNG, matched by average color. The query string can override
+    /// `--mosaic-size` with `mosaic-size=N` and turn on `--flat` with `flat`, e.g.
+    /// `/mosaic?mosaic-size=64`. Other options go before the subcommand and apply to every
+    /// request
+    #[cfg(feature = "server")]
+    Serve {
+        /// The directory containing the tiles to make mosaics of
+        #[structopt(short, long, parse(from_os_str))]
+        tiles_dir: PathBuf,
+
+        /// The address to listen on
+        #[structopt(short, long, default_value = "127.0.0.1:8080")]
+        address: String,
+
+        /// How many requests to handle at once, the others waiting for their turn, which bounds
+        /// how much memory the server can use
+        #[structopt(short, long, default_value = "4", parse(try_from_str = parse_nonzero))]
+        workers: u32,
+
+        /// How many seconds a client can go without sending or receiving anything before its
+        /// request is given up on, freeing its worker
+        #[structopt(long, default_value = "30", parse(try_from_str = parse_nonzero))]
+        timeout: u32,
+    },
+}
+
+#[derive(StructOpt)]
+#[structopt(setting = AppSettings::SubcommandsNegateReqs)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    /// The image to turn into a mosaic, or a directory of them. With the `url` feature, this can
+    /// also be the URL of a single image. Required unless a subcommand, `--input-a` or
+    /// `--input-list` is given
+    #[structopt(short, long, parse(from_os_str))]
+    input_dir: Option<PathBuf>,
+
+    /// The directory containing the tiles to utilize. With the `url` feature, this can also be
+    /// the URL of a manifest listing the URL of each tile, one per line, though not of an
+    /// archive of tiles such as a zip file. Required unless a subcommand, `--tile-index` or
+    /// `--tile-atlas` is given
+    #[structopt(short, long, parse(from_os_str))]
+    tiles_dir: Option<PathBuf>,
+
+    /// Where to save the finished mosaic. Inputs whose mosaic was finished by an earlier run,
+    /// as recorded in `.themis-checkpoint` there, are skipped, so that an interrupted run picks
+    /// up where it left off
+    #[structopt(short, long, parse(from_os_str), default_value = "output")]
+    output_dir: PathBuf,
+
+    /// The name of each finished mosaic, relative to the output directory. `{stem}` is
+    /// replaced by the input's name without its extension, `{kind}` by `mosaic` or `sprites`,
+    /// `{size}` by the mosaic size and `{tile_size}` by the tile size. It can contain
+    /// directories, e.g. `{stem}/{size}.png`, and its extension decides the image format
+    #[structopt(long, default_value = "{stem}.{kind}{size}.png")]
+    output_template: String,
+
+    /// The side length that the target image'll be resized to.
+    #[structopt(short, long, default_value = "128")]
+    mosaic_size: u32,
+
+    /// The side length of each tile.
+    #[structopt(long, default_value = "26")]
+    tile_size: u32,
+
+    /// Keep the image's aspect ratio
+    #[structopt(short, long)]
+    keep_aspect_ratio: bool,
+
+    /// Relative weights of the red, green and blue channels when matching tiles, as R,G,B
+    #[structopt(long, default_value = "1,1,1")]
+    channel_weights: ChannelWeights,
+
+    /// Also save a heatmap of how well each cell was matched, from green (good) to red (poor).
+    /// The input's name is inserted before the extension, e.g. `heatmap.photo.png`
+    #[structopt(long, parse(from_os_str))]
+    error_heatmap: Option<PathBuf>,
+
+    /// Speed up matching against large tilesets by bucketing the tiles by their average color,
+    /// quantizing each channel into this many levels, and only comparing a cell against the
+    /// tiles in the buckets around its own. The tile found is the same as without it, and 16
+    /// is a good starting point for how many levels are fastest
+    #[structopt(long)]
+    coarse_bins: Option<u32>,
+
+    /// Flip the tiles in every other column horizontally
+    #[structopt(long)]
+    mirror_cols: bool,
+
+    /// Flip the tiles in every other row vertically
+    #[structopt(long)]
+    mirror_rows: bool,
+
+    /// Shorthand for `--mirror-cols --mirror-rows`, for a woven look
+    #[structopt(long)]
+    mirror_alternating: bool,
+
+    /// Compute each tile's average color from only its central region, covering this percentage
+    /// of each side, to ignore borders and frames. The whole tile is still placed
+    #[structopt(long, default_value = "100", parse(try_from_str = parse_percentage))]
+    average_inset: f64,
+
+    /// Instead of the mosaic, save a sprite sheet of the tiles it uses along with a JSON file
+    /// telling which sprite goes in each cell
+    #[structopt(long)]
+    sprite_sheet: bool,
+
+    /// Crop the image to this aspect ratio (e.g. 16:9) before turning it into a mosaic, instead
+    /// of stretching it. The cropped image's aspect ratio is kept
+    #[structopt(long)]
+    crop_aspect: Option<AspectRatio>,
+
+    /// Which part of the image to keep when `--crop-aspect` cuts off rows
+    #[structopt(long, default_value = "center", possible_values = &["center", "top", "bottom"])]
+    crop_gravity: Gravity,
+
+    /// The order to load tiles in, which decides e.g. which of two equally good tiles is used
+    #[structopt(long, default_value = "name", possible_values = &["name", "modified", "size"])]
+    tile_sort: TileSort,
+
+    /// Sharpen the finished mosaic with an unsharp mask of this strength, clamped to [0, 5].
+    /// Around 0.5 gives a subtle crispness, above 2 halos start to show
+    #[structopt(long, default_value = "0")]
+    sharpen: f32,
+
+    /// Match tiles by structure rather than just by color, by comparing the average colors of
+    /// this many subregions per side of each cell and tile
+    #[structopt(long, parse(try_from_str = parse_nonzero))]
+    subregions: Option<u32>,
+
+    /// Also consider every rotation and flip of each tile, picking the orientation that best
+    /// fits each cell's structure. Only makes a difference with `--subregions`
+    #[structopt(long)]
+    tile_variants: bool,
+
+    /// Where to keep downloaded inputs and tiles, so that they aren't downloaded again on the
+    /// next run. Tile manifests are downloaded again every time, so that edits to them are
+    /// picked up. Defaults to a directory in the system's temporary directory
+    #[cfg(feature = "url")]
+    #[structopt(long, parse(from_os_str))]
+    cache_dir: Option<PathBuf>,
+
+    /// Also save a comparison of the source image and the mosaic, side by side, laid out either
+    /// horizontally or vertically
+    #[structopt(long, possible_values = &["horizontal", "vertical"])]
+    side_by_side: Option<Arrangement>,
+
+    /// Label the source image and the mosaic in the side by side comparison
+    #[structopt(long)]
+    side_by_side_labels: bool,
+
+    /// Adapt the size of tiles to the image: start with cells 2^N times bigger than usual and
+    /// split them into quadrants, up to N times, wherever the image is detailed. Options that
+    /// work on a uniform grid of cells, like `--subregions` or `--sprite-sheet`, are ignored.
+    /// N can be at most 8
+    #[structopt(long, parse(try_from_str = parse_adaptive_depth))]
+    adaptive_depth: Option<u32>,
+
+    /// How much a cell's colors must vary for `--adaptive-depth` to split it, as the mean
+    /// distance of its pixels from their average color
+    #[structopt(long, default_value = "1000")]
+    adaptive_threshold: i64,
+
+    /// How much to penalize tiles whose transparency differs from the image's, relative to the
+    /// color channels. At 0 transparency is ignored, so a mostly transparent tile can match an
+    /// opaque pixel and leave a hole
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_non_negative))]
+    alpha_weight: f64,
+
+    /// Also save a multi-page TIFF with the mosaic and the source image, scaled up to the size
+    /// of the mosaic, on separate pages, for touching up in an image editor
+    #[structopt(long)]
+    layered_tiff: bool,
+
+    /// Print why the cell at the given grid position, written as X,Y, got the tile it did,
+    /// along with the tiles that came closest after it
+    #[structopt(long)]
+    explain: Option<CellPosition>,
+
+    /// Balance each tile's white with the gray world assumption before computing its colors,
+    /// so that tiles shot under differently colored light match consistently. This changes
+    /// which colors tiles are matched by, and so which tiles get picked
+    #[structopt(long)]
+    normalize_wb: bool,
+
+    /// With `--normalize-wb`, place the white balanced tiles rather than the original ones
+    #[structopt(long)]
+    place_normalized: bool,
+
+    /// Blur the resized image with a gaussian of this standard deviation, in cells, before
+    /// matching, so that noise doesn't make neighboring cells pick wildly different tiles
+    #[structopt(long, default_value = "0")]
+    pre_blur: f32,
+
+    /// Reduce the tileset to at most this many tiles, picked to cover its range of colors as
+    /// well as possible, to speed up matching against huge tilesets
+    #[structopt(long)]
+    max_tiles: Option<usize>,
+
+    /// Fill each cell with its tile's average color rather than the tile itself, for a pixel
+    /// art look limited to the tileset's palette
+    #[structopt(long)]
+    flat: bool,
+
+    /// Embed this ICC color profile in the mosaic rather than the source image's own. Profiles
+    /// are only kept in PNG, JPEG and TIFF outputs
+    #[structopt(long, parse(from_os_str))]
+    icc: Option<PathBuf>,
+
+    /// Use the tiles listed in an index made by the `index` subcommand instead of the tiles
+    /// directory, so that tiles are only decoded once they're placed. The index finds the tiles
+    /// relative to itself, so it keeps working when moved along with them
+    #[structopt(long, parse(from_os_str))]
+    tile_index: Option<PathBuf>,
+
+    /// Use the tiles in a single atlas image, split into the grid given by `--atlas-grid`,
+    /// instead of the tiles directory
+    #[structopt(long, parse(from_os_str), requires = "atlas-grid")]
+    tile_atlas: Option<PathBuf>,
+
+    /// How many columns and rows of tiles the atlas has, written as COLSxROWS
+    #[structopt(long)]
+    atlas_grid: Option<AtlasGrid>,
+
+    /// What to compare cells and tiles by: their average colors, or the average colors of their
+    /// top and bottom halves, which captures vertical gradients like a horizon for little cost.
+    /// `tone-then-color` only compares how light they are, then paints each tile with its cell's
+    /// color like `--recolor color`, which reproduces the image even from a monochrome tileset or
+    /// one lacking its hues. Ignored with `--subregions`
+    #[structopt(
+        long = "match",
+        default_value = "average",
+        possible_values = &["average", "vertical-split", "tone-then-color"]
+    )]
+    match_mode: MatchMode,
+
+    /// Record this resolution, in dots per inch, in the mosaic so that it prints at the right
+    /// physical size. The pixels stay the same. Only PNG, JPEG and TIFF outputs can record it
+    #[structopt(long, parse(try_from_str = parse_nonzero))]
+    dpi: Option<u32>,
+
+    /// Prefer tiles that look like their already placed neighbors, by this much relative to
+    /// how well they match their own cell. This trades accuracy for a cleaner look: at 0 every
+    /// cell gets its closest tile, around 1 noisy areas settle into smoother patches. Cells are
+    /// then matched one by one, which is slower. Only applies to `--match average`
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_non_negative))]
+    coherence: f64,
+
+    /// Lay the tiles out like bricks, shifting every other row right by half a tile. The half
+    /// tile sticking out of the right edge wraps around to fill the gap on the left one. Colors
+    /// are sampled from the shifted cells when matching by average color
+    #[structopt(long)]
+    offset_rows: bool,
+
+    /// Only match the cells and report how well the tiles cover the image's colors, without
+    /// building or saving the mosaic, e.g. to judge a tileset
+    #[structopt(long, conflicts_with = "adaptive-depth")]
+    stats_only: bool,
+
+    /// The match error below which `--stats-only` counts a cell as well matched. Around 1000
+    /// means each channel is off by about 10
+    #[structopt(long, default_value = "1000")]
+    stats_threshold: i64,
+
+    /// Print the `--stats-only` report as one JSON object per input instead
+    #[structopt(long)]
+    stats_json: bool,
+
+    /// Recolor each tile towards its cell's color. `hue` gives every pixel of the tile the
+    /// cell's hue while keeping its own saturation and lightness, so that colors follow the
+    /// image closely while the texture still comes from the tiles. Gray cells, which have no
+    /// hue, keep their tile as it is. `color` also gives them the cell's saturation, keeping only
+    /// their lightness, so that even gray tiles take on the cell's color
+    #[structopt(long, possible_values = &["hue", "color"])]
+    recolor: Option<Recolor>,
+
+    /// Keep the mosaic under this many kilobytes (of 1024 bytes), for the web. JPEG outputs are
+    /// saved at the highest quality that fits, other formats only get a warning if they don't
+    #[structopt(long, parse(try_from_str = parse_nonzero))]
+    max_file_size: Option<u32>,
+
+    /// Pick tiles with a formula of your own for the distance between a cell's color and a
+    /// tile's, instead of the built-in one, e.g. `(r1-r2)^2 + (g1-g2)^2 + (b1-b2)^2`. The image's
+    /// channels are `r1`, `g1`, `b1`, `a1` and the tile's `r2`, `g2`, `b2`, `a2`, from 0 to 255;
+    /// `abs`, `sqrt`, `min` and `max` are available. Slower than the built-in distance, and only
+    /// used to pick the closest tile by average color, so it can't be combined with flags that
+    /// match cells some other way, like `--dither`. Reports still use the built-in distance
+    #[structopt(long)]
+    distance_expr: Option<expr::DistanceExpr>,
+
+    /// A file listing tiles' file names along with a weight, one per line like `sunset.jpg 2`,
+    /// to make them show up more (above 1) or less (below 1) often. Unlisted tiles weigh 1 and
+    /// those weighing 0 aren't used at all
+    #[structopt(long, parse(from_os_str))]
+    tile_weights: Option<PathBuf>,
+
+    /// Instead of `--input-dir`, turn a blend of this image and `--input-b` into a mosaic, e.g.
+    /// to script a morph between the two over a range of `--blend` values
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with = "input-dir",
+        requires = "input-b"
+    )]
+    input_a: Option<PathBuf>,
+
+    /// The image to blend `--input-a` with
+    #[structopt(long, parse(from_os_str), requires = "input-a")]
+    input_b: Option<PathBuf>,
+
+    /// How far to blend from `--input-a`, at 0, to `--input-b`, at 1. The output's stem is both
+    /// inputs' stems followed by this, e.g. `cat-dog-0.3`
+    #[structopt(long, default_value = "0.5", parse(try_from_str = parse_fraction))]
+    blend: f32,
+
+    /// Stretch `--input-b` to the size of `--input-a` if they differ, rather than giving up
+    #[structopt(long)]
+    blend_resize: bool,
+
+    /// Quantize the resized image to at most this many colors with median cut before matching,
+    /// so that photos with thousands of unique colors need far fewer lookups, at little cost to
+    /// how the mosaic looks as long as it isn't too low
+    #[structopt(long, parse(try_from_str = parse_nonzero))]
+    max_unique_colors: Option<u32>,
+
+    /// Cut every tile into a shape, either `circle` or `rounded N` for a square with its corners
+    /// rounded by N pixels, for a bubbly look. The background shows through around them
+    #[structopt(long, conflicts_with = "adaptive-depth")]
+    tile_shape: Option<TileShape>,
+
+    /// The color to fill the mosaic with behind the tiles, as RRGGBB or RRGGBBAA. It shows
+    /// wherever tiles are transparent or cut into a shape. Defaults to transparent
+    #[structopt(long)]
+    background: Option<HexColor>,
+
+    /// Also save an HTML page showing the mosaic, where hovering over a cell shows which tile
+    /// file was placed there. With several inputs, each input's stem is inserted before the
+    /// extension. The page links to the saved mosaic unless `--html-embed` is given
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["adaptive-depth", "sprite-sheet", "stats-only"]
+    )]
+    html_map: Option<PathBuf>,
+
+    /// Embed the mosaic in the `--html-map` page, so that it doesn't depend on any other file
+    #[structopt(long, requires = "html-map")]
+    html_embed: bool,
+
+    /// Make tiles less attractive the more they're used, adding this much to a tile's match error
+    /// for every cell it's already in, so that areas of one color spread across the tiles that
+    /// resemble it instead of repeating the closest one. Errors are measured like
+    /// `--stats-threshold`, so around 100 only swaps in near-identical tiles. Cells are then
+    /// matched one by one, which is slower. Only applies to `--match average`
+    #[structopt(long, default_value = "0")]
+    usage_penalty: u32,
+
+    /// Leave out tiles smaller than the tile size along either side, rather than upscaling them
+    /// into blurry blocks, and report how many were left out
+    #[structopt(long)]
+    no_upscale: bool,
+
+    /// Upscale tiles smaller than the tile size with nearest neighbor sampling, rather than
+    /// smoothly, for a crisp pixel art look
+    #[structopt(long, conflicts_with = "no-upscale")]
+    upscale_nearest: bool,
+
+    /// Once every input is done, also save a grid of thumbnails of all the mosaics, captioned
+    /// with their file names, for reviewing a batch at a glance. Mosaics saved by earlier runs
+    /// are included too
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["sprite-sheet", "stats-only"]
+    )]
+    contact_sheet: Option<PathBuf>,
+
+    /// How many thumbnails to fit in each row of the `--contact-sheet`
+    #[structopt(long, default_value = "4", parse(try_from_str = parse_nonzero))]
+    contact_sheet_columns: u32,
+
+    /// The side length of the `--contact-sheet`'s thumbnails, which keep their aspect ratio
+    #[structopt(long, default_value = "256", parse(try_from_str = parse_nonzero))]
+    contact_sheet_size: u32,
+
+    /// With `--flat`, save the mosaic as an indexed color PNG whose palette is the tiles'
+    /// average colors, for tiny files. Fails if it has more than 256 colors, unless
+    /// `--quantize-palette` is given
+    #[structopt(long, requires = "flat")]
+    indexed_png: bool,
+
+    /// Quantize `--indexed-png` mosaics with more than 256 colors down to that many, rather than
+    /// giving up on them
+    #[structopt(long, requires = "indexed-png")]
+    quantize_palette: bool,
+
+    /// A grayscale map of which parts of the image matter most, e.g. faces in a portrait, where
+    /// `--adaptive-depth` splits cells more eagerly: the threshold goes down in proportion to
+    /// how bright the map is, down to 0 where it's white. It's stretched over the image as it's
+    /// matched, after any cropping
+    #[structopt(long, parse(from_os_str), requires = "adaptive-depth")]
+    saliency: Option<PathBuf>,
+
+    /// Rotate each tile by a random angle of up to this many degrees either way, for a hand
+    /// placed look, clamped to [0, 45]. Rotated tiles overlap their neighbors a little and leave
+    /// small gaps at the corners of their cells, where `--background` shows through
+    #[structopt(
+        long,
+        parse(try_from_str = parse_non_negative),
+        conflicts_with = "adaptive-depth"
+    )]
+    jitter_rotation: Option<f64>,
+
+    /// The seed for everything random, such as `--jitter-rotation`'s angles. The same seed gives
+    /// the same mosaic every time
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+
+    /// Pick tiles by their CIELAB color difference (ΔE) from each cell instead of the built-in
+    /// distance, taking the first tile within this difference rather than searching for the
+    /// closest, which is much faster with large tilesets. Around 2.3 is just noticeable, and
+    /// the tile picked is never more than this much worse than the best one. Only used to pick
+    /// the closest tile by average color, so it can't be combined with flags that match cells
+    /// some other way, like `--dither`. Reports still use the built-in distance
+    #[structopt(
+        long,
+        parse(try_from_str = parse_non_negative),
+        conflicts_with_all = &["distance-expr", "coarse-bins"]
+    )]
+    delta_e_threshold: Option<f64>,
+
+    /// Also slice the mosaic into a pyramid of 256x256 map tiles, from a single tile for the
+    /// whole mosaic up to its full size, saved in a directory named after the input inside this
+    /// one as `{zoom}/{x}/{y}.png` along with a `pyramid.json`, for panning around huge mosaics
+    /// in tiled viewers like Leaflet or OpenSeadragon
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["sprite-sheet", "stats-only"]
+    )]
+    tile_pyramid: Option<PathBuf>,
+
+    /// An artistic effect: build three mosaics, each from the tiles whose red, green or blue
+    /// channel alone best matches the image's, and keep only that channel of each. Wherever
+    /// the tiles picked for each channel differ, their shapes show up as colored fringes, much
+    /// like chromatic aberration
+    #[structopt(
+        long,
+        conflicts_with_all = &["adaptive-depth", "stats-only", "sprite-sheet", "html-map"]
+    )]
+    channel_split: bool,
+
+    /// Time the phases of the run (loading the tiles, building the index, and preparing,
+    /// matching, assembling and encoding each input) and save the timings to this file in the
+    /// folded stack format, one line per phase with the microseconds spent in it, for
+    /// flamegraph tools
+    #[structopt(long, parse(from_os_str))]
+    profile: Option<PathBuf>,
+
+    /// Like `--subregions`, but only compare the structure of the tiles whose average colors are
+    /// closest to each cell's, which is much faster with large tilesets: each cell and tile is
+    /// also downscaled to this many pixels per side, and the tiles shortlisted by average are
+    /// told apart by those
+    #[structopt(long, parse(try_from_str = parse_nonzero), conflicts_with = "subregions")]
+    signature_size: Option<u32>,
+
+    /// Also save an animation of the source cross-fading into the mosaic to this path, for
+    /// sharing. Its extension decides whether it's an animated GIF or PNG, and the input's name
+    /// is inserted before it, e.g. `transition.photo.gif`
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["stats-only", "sprite-sheet"])]
+    transition: Option<PathBuf>,
+
+    /// How many frames the `--transition` has, including the first and last
+    #[structopt(long, default_value = "12", parse(try_from_str = parse_nonzero))]
+    transition_frames: u32,
+
+    /// How long the `--transition` lasts in total, in milliseconds
+    #[structopt(long, default_value = "2000", parse(try_from_str = parse_nonzero))]
+    transition_duration: u32,
+
+    /// Force visible variety by avoiding tiles whose average color is within this distance of
+    /// the tile to their left or above, falling back to the closest tile only when every tile is
+    /// that close. Distances are measured like `--stats-threshold`. Cells are then matched one by
+    /// one, which is slower. Only applies to `--match average`
+    #[structopt(long, default_value = "0")]
+    min_tile_distance: u32,
+
+    /// Also save the mosaic in linear light as a 32-bit float OpenEXR file, for compositing
+    /// pipelines that want linear input. It's the same 8-bit data as the mosaic, linear-encoded,
+    /// so it holds no more tonal range than it. The input's name is inserted before the
+    /// extension, e.g. `linear.photo.exr`. Viewers without a tone mapper show it much darker
+    /// than the mosaic
+    #[cfg(feature = "exr")]
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["stats-only", "sprite-sheet"])]
+    linear_exr: Option<PathBuf>,
+
+    /// Make some inputs out of their own tiles, according to a file pairing a pattern for the
+    /// inputs' file names with a subdirectory of `--tiles-dir` on each line, like
+    /// `"beach-*.jpg" = "summer"`, where `*` stands for anything and `?` for any one character.
+    /// Each input uses the subdirectory of the first pattern it matches, and inputs that match
+    /// none use the tiles directly in `--tiles-dir`
+    #[structopt(
+        long,
+        parse(from_os_str),
+        requires = "tiles-dir",
+        conflicts_with_all = &["tile-index", "tile-atlas"]
+    )]
+    pairing: Option<PathBuf>,
+
+    /// Fade the outer this many pixels of the finished mosaic to transparent, so that it blends
+    /// into the page behind it. Only formats with transparency, like PNG, WebP or TIFF, show it
+    #[structopt(long, parse(try_from_str = parse_nonzero))]
+    fade_edges: Option<u32>,
+
+    /// Which edges `--fade-edges` fades, as a comma separated list of `top`, `bottom`, `left` and
+    /// `right`
+    #[structopt(long, default_value = "all")]
+    fade_sides: Edges,
+
+    /// What to spread across threads when matching cells by their average color: `both` the
+    /// cells and the scan through the tiles for each one, `pixels` only the cells or `tiles` only
+    /// the scan. `pixels` saves the overhead of splitting up each scan, which makes it a little
+    /// faster whenever there are more distinct colors than threads. `tiles` only helps with
+    /// fewer distinct colors than threads and a huge tileset, and is several times slower
+    /// otherwise. Only applies to the built-in distance without `--coarse-bins`
+    #[structopt(long, default_value = "both", possible_values = &["both", "pixels", "tiles"])]
+    match_parallelism: MatchParallelism,
+
+    /// Instead of `--input-dir`, turn every image listed in this file into a mosaic, in order,
+    /// one path per line, or those listed on standard input given `-`. Empty lines and lines
+    /// starting with `#` are ignored. As usual, inputs whose mosaic was already finished are
+    /// skipped, so an interrupted list picks up where it left off when run again
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["input-dir", "input-a"])]
+    input_list: Option<PathBuf>,
+
+    /// Place each tile in at most this many cells, handing the cells that want it after that
+    /// their next best tile, and report how many cells that happened to and how much worse their
+    /// tiles match them. Once every tile is used up, the limit is lifted. Cells are then matched
+    /// one by one, which is slower. Only applies to `--match average`
+    #[structopt(long, parse(try_from_str = parse_nonzero))]
+    max_reuse: Option<u32>,
+
+    /// Save which tile every color of a quantized color cube gets to this file as JSON, for
+    /// other programs to build mosaics from the tileset without matching. `lut` holds an index
+    /// into `tiles` for every color, whose channels' levels `r`, `g` and `b` (each value `v` in
+    /// level `v * levels / 256`) are at `(r * levels + g) * levels + b`. No input is needed
+    #[structopt(long, parse(from_os_str))]
+    export_lut: Option<PathBuf>,
+
+    /// How many levels `--export-lut` quantizes each channel into, up to 256
+    #[structopt(long, default_value = "32", parse(try_from_str = parse_nonzero))]
+    lut_levels: u32,
+
+    /// Ignore the inputs' sidecar files. Otherwise, a file named after an input followed by
+    /// `.themis.toml`, e.g. `photo.jpg.themis.toml`, overrides some settings for that input alone:
+    /// each of its lines is like `mosaic-size = 256`, naming one of `mosaic-size`, `sharpen`,
+    /// `pre-blur`, `coherence`, `usage-penalty` or `recolor`. Settings in a sidecar take
+    /// precedence over the command line, which takes precedence over the defaults
+    #[structopt(long)]
+    no_sidecars: bool,
+
+    /// Soften the hard edges between tiles for a painterly look, by mixing the outer quarter of
+    /// every cell with the tiles of the neighboring cells, up to half and half at the border.
+    /// This takes another pass over the whole mosaic, a few times slower than placing the tiles
+    #[structopt(
+        long,
+        conflicts_with_all = &["adaptive-depth", "offset-rows", "jitter-rotation", "sprite-sheet"]
+    )]
+    blend_boundaries: bool,
+
+    /// Split the mosaic into horizontal bands of equal height, one per range of hues in this
+    /// comma separated list (in degrees, e.g. `0-60,60-180,180-360`), and make each band out of
+    /// the tiles whose average hue is in its range, from top to bottom, for a controlled flow of
+    /// color whatever the image. A range like `300-30` wraps around red. Gray tiles are in no
+    /// band, and bands without any tiles use the whole tileset. Only applies to `--match average`,
+    /// and doesn't use `--match-cache`, since a cell's tile depends on more than its color
+    #[structopt(
+        long,
+        conflicts_with_all = &["adaptive-depth", "channel-split", "subregions", "match-cache"]
+    )]
+    band_tiles: Option<HueBands>,
+
+    /// Refuse the options whose output can differ between runs or machines, guaranteeing the
+    /// same bytes out for the same files in: `--tile-sort modified`, since modification times
+    /// change whenever the tiles are copied. Everything else already is reproducible: tiles are
+    /// loaded in a fixed order, ties between equally good tiles go to the first one loaded, and
+    /// everything random comes from `--seed`
+    #[structopt(long)]
+    deterministic: bool,
+
+    /// Leave the cells that are transparent in the image empty, instead of matching tiles to
+    /// them, so that e.g. a logo on a transparent background makes a mosaic that can be laid
+    /// over something else. Only the background shows in them, transparent unless
+    /// `--background` is given
+    #[structopt(long, conflicts_with = "adaptive-depth")]
+    respect_source_alpha: bool,
+
+    /// The alpha, from 0 to 255, below which `--respect-source-alpha` leaves a cell empty
+    #[structopt(long, default_value = "128")]
+    source_alpha_threshold: u8,
+
+    /// Equalize the histogram of the image's luminance before matching, so that flat or poorly
+    /// exposed photos use the whole range of the tileset: `global` spreads out the tones of the
+    /// whole image at once, `clahe` those of each region, with less contrast in uniform areas
+    #[structopt(long, possible_values = &["global", "clahe"], conflicts_with = "adaptive-depth")]
+    equalize: Option<Equalize>,
+
+    /// Also save a CSV with a row for every cell: its position, its color, the path of the tile
+    /// chosen for it and how far off that tile is, measured like `--stats-threshold`. The
+    /// input's name is inserted before the extension, e.g. `cells.photo.csv`
+    #[structopt(long, parse(from_os_str), conflicts_with = "adaptive-depth")]
+    csv: Option<PathBuf>,
+
+    /// Only make a mosaic of this region of each input, given in the input's pixels as
+    /// X,Y,W,H, e.g. to try out settings on a detail quicker than on the whole image. The
+    /// region is added to the input's name in the output's, e.g. `photo-100_50_640x480`
+    #[structopt(long)]
+    region: Option<Region>,
+
+    /// A file listing tiles' file names along with what each one costs, one per line like
+    /// `red-glass.png 0.25`, for mosaics made of real tiles. The total cost of each mosaic is
+    /// reported, and `--budget` caps it. Unlisted tiles are free
+    #[structopt(long, parse(from_os_str), conflicts_with = "adaptive-depth")]
+    tile_costs: Option<PathBuf>,
+
+    /// The most the tiles of each mosaic may cost in total, by `--tile-costs`. While a mosaic
+    /// costs more, the cells whose cheaper tiles lose the least quality for the cost they save
+    /// get them, which is a quick heuristic rather than the best possible trade-off
+    #[structopt(long, requires = "tile-costs", parse(try_from_str = parse_non_negative))]
+    budget: Option<f64>,
+
+    /// Match cells and tiles by their this many dominant colors, found by clustering their
+    /// colors, rather than by their average color, so that e.g. a half black, half white cell
+    /// gets a two-toned tile instead of a gray one. From 1 to 4, 2 or 3 work best. This is a
+    /// few times slower than matching by the average color
+    #[structopt(
+        long,
+        parse(try_from_str = parse_dominant_colors),
+        conflicts_with_all = &["adaptive-depth", "channel-split", "subregions", "band-tiles"]
+    )]
+    dominant_colors: Option<usize>,
+
+    /// Invert the colors of the image before matching, making a mosaic of its photographic
+    /// negative
+    #[structopt(long)]
+    invert_target: bool,
+
+    /// Invert the colors of the tiles as they're placed, which with `--invert-target` gives back
+    /// the image made of negatives of the tiles
+    #[structopt(long, conflicts_with = "adaptive-depth")]
+    invert_tiles: bool,
+
+    /// Make the mosaic tile seamlessly, e.g. for wallpapers and textures, by mirroring it into
+    /// a 2x2 grid so that each edge meets its mirror image when it's repeated. This doubles the
+    /// mosaic's width and height, and flips the tiles in all but its top left quarter
+    #[structopt(long, conflicts_with_all = &["fade-edges", "html-map", "sprite-sheet"])]
+    seamless: bool,
+
+    /// Lay the tiles over the image itself, stretched to the mosaic's size, at this opacity from
+    /// 0 to 1, instead of over an empty canvas, so that the image shows through them like
+    /// through stained glass. At 1 the mosaic is as usual, but for showing the image wherever
+    /// the tiles are transparent or cut into a shape
+    #[structopt(long, parse(try_from_str = parse_fraction), conflicts_with = "background")]
+    tile_opacity: Option<f32>,
+
+    /// Also save a tiny preview of each mosaic, to review it over a slow connection: shrunk to
+    /// fit in `--preview-size` and reduced to `--preview-colors` colors, as an indexed `png` or
+    /// a `gif`, named like the mosaic with `.preview` before the extension
+    #[structopt(long, possible_values = &["png", "gif"])]
+    low_bandwidth_preview: Option<String>,
+
+    /// The side length that `--low-bandwidth-preview` fits the preview in, keeping its aspect
+    /// ratio
+    #[structopt(long, default_value = "256", parse(try_from_str = parse_nonzero))]
+    preview_size: u32,
+
+    /// How many colors `--low-bandwidth-preview` reduces the preview to, at most 256
+    #[structopt(long, default_value = "64", parse(try_from_str = parse_palette_size))]
+    preview_colors: u32,
+
+    /// Favor the tiles closest to square, which fill their cells with the least stretching, by
+    /// adding this times the share of the cell each tile would leave empty if it kept its shape
+    /// to the distance to it, measured like `--stats-threshold`: e.g. at 2000 a tile twice as
+    /// wide as it is tall counts as 1000 farther away
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_non_negative))]
+    aspect_penalty: f64,
+
+    /// Write this text onto each finished mosaic, e.g. for attribution, in the built-in font,
+    /// which only has glyphs for ASCII and draws others as `?`
+    #[structopt(long)]
+    watermark: Option<String>,
+
+    /// Which corner of the mosaic `--watermark` goes in
+    #[structopt(
+        long,
+        default_value = "bottom-right",
+        possible_values = &["top-left", "top-right", "bottom-left", "bottom-right"]
+    )]
+    watermark_corner: Corner,
+
+    /// How many pixels each pixel of the `--watermark`'s font takes up along each side, the
+    /// font being 7 pixels tall. Defaults to one for every 400 pixels of the mosaic's width
+    #[structopt(long, parse(try_from_str = parse_nonzero))]
+    watermark_size: Option<u32>,
+
+    /// The color of the `--watermark`, as RRGGBB or RRGGBBAA
+    #[structopt(long, default_value = "ffffff")]
+    watermark_color: HexColor,
+
+    /// How opaque the `--watermark` is, from 0 to 1, on top of its color's own alpha
+    #[structopt(long, default_value = "0.8", parse(try_from_str = parse_fraction))]
+    watermark_opacity: f32,
+
+    /// Remember the tile each color was matched to in this file, and reuse the matches it already
+    /// has, so that running again over similar inputs with the same tiles and matching settings
+    /// skips most of the matching. Colors are rounded to 64 levels per channel first, so that
+    /// similar colors share their matches. The file keeps the matches of every tileset and every
+    /// set of settings it's used with apart, and only speeds up matching each cell by its
+    /// average color
+    #[structopt(long)]
+    match_cache: Option<PathBuf>,
+
+    /// Shrink each tile by how bright its cell is and center it there, over `--background`,
+    /// like the dots of a halftone made of images: `bright` gives the brightest cells the
+    /// largest tiles and `dark` the darkest ones
+    #[structopt(long, possible_values = &["bright", "dark"])]
+    brightness_scale: Option<LargerTiles>,
+
+    /// The share of its cell the smallest tile of `--brightness-scale` fills along each side,
+    /// from 0, which leaves the cell empty, to 1
+    #[structopt(long, default_value = "0.1", parse(try_from_str = parse_fraction))]
+    min_tile_scale: f32,
+
+    /// The share of its cell the largest tile of `--brightness-scale` fills along each side,
+    /// from 0 to 1
+    #[structopt(long, default_value = "1", parse(try_from_str = parse_fraction))]
+    max_tile_scale: f32,
+
+    /// Compare every mosaic against a golden image known to be right before saving it, e.g. in
+    /// CI along with `--deterministic`, reporting where they differ and exiting with an error
+    /// once every input is done if any of them did. If this is a directory, each mosaic is
+    /// compared against the file of the same name in it. Mosaics are built even if their output
+    /// already exists
+    #[structopt(long)]
+    compare_golden: Option<PathBuf>,
+
+    /// How far each channel of a pixel may be from the golden image's for `--compare-golden`
+    /// to still count it as the same, from 0 to 255
+    #[structopt(long, default_value = "0")]
+    golden_tolerance: u8,
+
+    /// Save an image of where each mosaic differs from its golden image, in red, if it does.
+    /// The input's name is inserted before the extension, e.g. `diff.photo.png`
+    #[structopt(long, requires = "compare-golden")]
+    golden_diff: Option<PathBuf>,
+
+    /// Also save each mosaic downscaled by these factors, as a comma separated list from above
+    /// 0 to 1, e.g. `0.5,0.25`, without matching or placing the tiles again. Each copy has its
+    /// size inserted before the extension, e.g. `photo.mosaic128.1024x768.png`, and a factor of
+    /// 1 is the mosaic itself
+    #[structopt(long)]
+    output_scales: Option<OutputScales>,
+
+    /// Nudge the tiles into a rainbow across the mosaic, on top of matching their colors: the
+    /// tiles are sorted by hue, from red through green and blue to magenta with grays last, and
+    /// each cell counts tiles as farther away the farther their place in that order is from the
+    /// cell's place from left to right, by up to this much at opposite ends, measured like
+    /// `--stats-threshold`. Small values only settle close calls, so that warm tiles gather on
+    /// the left and cool ones on the right wherever they fit about as well, while large ones
+    /// paint a rainbow over the image. Only applies to `--match average`
+    #[structopt(
+        long,
+        parse(try_from_str = parse_non_negative),
+        conflicts_with_all = &["adaptive-depth", "channel-split", "subregions", "band-tiles"]
+    )]
+    position_bias: Option<f64>,
+
+    /// Make a mosaic of at most this many pages of each multi-page TIFF input, from the first,
+    /// e.g. to try settings on a long scan quickly. Every page of a multi-page TIFF gets its own
+    /// mosaic, with the page's number after the input's name, e.g. `scan-page2.mosaic128.png`
+    #[structopt(long, parse(try_from_str = parse_nonzero))]
+    max_pages: Option<u32>,
+
+    /// Leave out the tiles with less contrast than this, as the standard deviation of their
+    /// pixels' luminance from 0 to 255, e.g. 8 to drop tiles of nearly a single flat color, which
+    /// add no texture to the mosaic. A `--tile-index` must have been built with the same one
+    #[structopt(long, parse(try_from_str = parse_non_negative), conflicts_with = "flat")]
+    min_contrast: Option<f64>,
+
+    /// Match each tile by the average color of its subject only, when it has a focus mask: a
+    /// grayscale PNG next to it named after it, e.g. `cat.mask.png` for `cat.jpg`, white where
+    /// the subject is and black where the background is, which takes precedence over
+    /// `--average-inset`. The whole tile is still placed, and masks aren't tiles themselves
+    #[structopt(long)]
+    focus_masks: bool,
+
+    /// Average each cell over a whole number of the image's pixels, rather than resampling the
+    /// image down to the mosaic's size, which shares the pixels on the boundaries between cells.
+    /// Cells are as many pixels wide and tall as it takes to cover the image in `--mosaic-size`
+    /// of them, which can make for a few fewer of them when that's close to the image's size.
+    /// Unless the image's sides are multiples of the cells', the last column and row of cells
+    /// then stick out of it, see `--edge-cells`
+    #[structopt(long)]
+    cell_average: bool,
+
+    /// What `--cell-average` does with the cells of the last column and row when they stick out
+    /// of the image: `clamp` extends its edge pixels into them, `shrink` averages only the pixels
+    /// they do cover, and `crop` leaves them out of the mosaic
+    #[structopt(
+        long,
+        default_value = "shrink",
+        possible_values = &["clamp", "shrink", "crop"]
+    )]
+    edge_cells: EdgeCells,
+
+    /// Embed a record of what each mosaic was made from in it: the version of themis, the
+    /// input's path and the command line as text, along with a thumbnail of the input. PNGs
+    /// hold them in an `iTXt` chunk and a private `thMb` chunk, JPEGs in a comment and an APP15
+    /// segment, and TIFFs in their description and a private tag. Other formats get a warning
+    #[structopt(long)]
+    embed_provenance: bool,
+
+    /// Match the cells with error diffusion, carrying how far each one's tile is from its color
+    /// over to the cells after it, so that regions average out to their color even between the
+    /// tileset's colors, like dithering: `floyd-steinberg` goes through every row from left to
+    /// right, while `serpentine` goes back and forth, which avoids the diagonal streaks the
+    /// former leaves in smooth gradients. Cells are then matched one by one, which is slower.
+    /// Only applies to `--match average`
+    #[structopt(
+        long,
+        possible_values = &["floyd-steinberg", "serpentine"],
+        conflicts_with_all = &["adaptive-depth", "channel-split", "subregions", "band-tiles", "position-bias"]
+    )]
+    dither: Option<Dither>,
+
+    /// Update a mosaic made before instead of placing every tile again: matching is redone,
+    /// but only the cells whose tile changed since are placed onto the prior mosaic at this
+    /// path, the others being kept as they are. Which tile every cell of the prior mosaic got
+    /// is read from the `--csv` it was made with, given with `--prior-csv`. If this is a
+    /// directory, each mosaic updates the file of the same name in it. Mosaics are built even
+    /// if their output already exists, so that this can be the output directory itself
+    #[structopt(
+        long,
+        requires = "prior-csv",
+        conflicts_with_all = &["adaptive-depth", "channel-split"]
+    )]
+    update_from: Option<PathBuf>,
+
+    /// The `--csv` of the mosaics given to `--update-from`, with the input's name inserted
+    /// before the extension the same way
+    #[structopt(long, requires = "update-from")]
+    prior_csv: Option<PathBuf>,
+
+    /// Add this much to the match error of the tile each cell had in the previous frame, when
+    /// making mosaics of the frames of a video, so that cells which look the same from one frame
+    /// to the next don't sit still on the same tile but shimmer between the tiles that fit them
+    /// about as well. Frames are the pages of a multi-page TIFF, or the inputs one after the
+    /// other, and a frame only follows the previous one if it has as many columns and rows of
+    /// cells. Errors are measured like `--usage-penalty`. Only applies to `--match average`, and
+    /// doesn't use `--match-cache`, since a cell's tile depends on more than its color
+    #[structopt(
+        long,
+        conflicts_with_all = &["adaptive-depth", "channel-split", "subregions", "band-tiles", "position-bias", "dither", "match-cache"]
+    )]
+    temporal_jitter: Option<u32>,
+
+    /// Round the grid of cells kept to the image's aspect ratio by `--keep-aspect-ratio` or
+    /// `--crop-aspect` to the one closest to it, rather than only rounding its shorter side,
+    /// which can leave it a cell off. Its longer side may then be up to 10% shorter than
+    /// `--mosaic-size`, e.g. a 16:9 image at 100 cells gets 96x54 of them instead of 100x56.
+    /// The longest of the grids that come closest is used
+    #[structopt(long)]
+    snap_grid: bool,
+
+    /// Keep only the colors of the tiles in memory, not their images, decoding each tile again
+    /// whenever it's placed unless it's one of the last `--tile-cache-size` ones used. This
+    /// makes enormous tilesets fit in memory, at the cost of placing the tiles more slowly
+    #[structopt(long, conflicts_with = "tile-atlas")]
+    lazy_tiles: bool,
+
+    /// How many tiles `--lazy-tiles` keeps the images of, the least recently used being
+    /// dropped first
+    #[structopt(long, default_value = "256", parse(try_from_str = parse_nonzero))]
+    tile_cache_size: u32,
+
+    /// Warm or cool the image before matching, by white balancing it as if it had been shot
+    /// under light of this color temperature in Kelvin rather than daylight's 6500K, from 2000
+    /// to 40000, like a photo editor's temperature slider: higher ones bring out warmer tiles,
+    /// e.g. 10000, and lower ones cooler tiles, e.g. 4000
+    #[structopt(long, parse(try_from_str = parse_temperature))]
+    color_temperature: Option<f64>,
+
+    /// Save a plot of the colors the tileset can reproduce to this image: where the tiles'
+    /// average colors fall on the plane of `--gamut-projection`, each part of it vivid the more
+    /// tiles have its color and faded if none do, to see which colors the tileset is missing.
+    /// No input is needed
+    #[structopt(long, parse(from_os_str))]
+    gamut_plot: Option<PathBuf>,
+
+    /// The plane of colors `--gamut-plot` projects the tiles onto: `ab`, the a*b* plane of
+    /// CIELAB, with green to red from left to right and blue to yellow from bottom to top, or
+    /// `hue-saturation`, a color wheel with gray at its center
+    #[structopt(
+        long,
+        default_value = "ab",
+        possible_values = &["ab", "hue-saturation"]
+    )]
+    gamut_projection: GamutProjection,
+
+    /// Make each mosaic out of only this many different tiles, the ones that reproduce its
+    /// image best, e.g. to build it out of a limited stock of real tiles. Unlike `--max-tiles`,
+    /// which narrows down the tileset once for every input by its own colors alone, the tiles
+    /// are chosen for each input by how close every one of its cells can get to one of them.
+    /// The chosen tiles are listed along with how many cells each one is the closest to
+    #[structopt(long, parse(try_from_str = parse_nonzero))]
+    palette_size: Option<u32>,
+
+    /// Bucket the tiles of `--coarse-bins` by their average color in CIELAB and match them by
+    /// ΔE, the perceptual difference between colors, instead of the usual distance
+    #[structopt(long, requires = "coarse-bins")]
+    coarse_lab: bool,
+}
+
+/// Exit with clap's usual error for a missing required argument
+fn missing_argument(arg: &str) -> ! {
+    clap::Error::with_description(
+        &format!("The following required argument was not provided: {arg}"),
+        clap::ErrorKind::MissingRequiredArgument,
+    )
+    .exit()
+}
+
+/// Exit with clap's usual error for arguments that can't be used together
+fn conflicting_arguments(arg: &str, other: &str) -> ! {
+    clap::Error::with_description(
+        &format!("The argument '{arg}' cannot be used with '{other}'"),
+        clap::ErrorKind::ArgumentConflict,
+    )
+    .exit()
+}
+
+/// Refuse a flag that only applies when matching cells by their average color alone along with
+/// any other flag given that matches them some other way, rather than silently ignore it
+fn check_average_matching(flag: &str, given: bool, other_matching: &[(&str, bool)]) {
+    let other = other_matching
+        .iter()
+        .find(|&&(other, other_given)| other_given && other != flag);
+    if let (true, Some((other, _given))) = (given, other) {
+        conflicting_arguments(flag, other);
+    }
 }
 
 fn main() -> Result<()> {
     let Opt {
+        command,
         input_dir,
         tiles_dir,
         mosaic_size,
         tile_size,
         keep_aspect_ratio,
         output_dir,
+        output_template,
+        channel_weights,
+        error_heatmap,
+        coarse_bins,
+        mirror_cols,
+        mirror_rows,
+        mirror_alternating,
+        average_inset,
+        sprite_sheet,
+        crop_aspect,
+        crop_gravity,
+        tile_sort,
+        sharpen: sharpen_amount,
+        subregions,
+        tile_variants,
+        #[cfg(feature = "url")]
+        cache_dir,
+        side_by_side,
+        side_by_side_labels,
+        adaptive_depth,
+        adaptive_threshold,
+        alpha_weight,
+        layered_tiff,
+        explain,
+        normalize_wb,
+        place_normalized,
+        pre_blur,
+        max_tiles,
+        flat,
+        icc,
+        tile_index,
+        tile_atlas,
+        atlas_grid,
+        match_mode,
+        dpi,
+        coherence,
+        offset_rows,
+        stats_only,
+        stats_threshold,
+        stats_json,
+        recolor,
+        max_file_size,
+        distance_expr,
+        tile_weights,
+        input_a,
+        input_b,
+        blend,
+        blend_resize,
+        max_unique_colors,
+        tile_shape,
+        background,
+        html_map,
+        html_embed,
+        usage_penalty,
+        no_upscale,
+        upscale_nearest,
+        contact_sheet,
+        contact_sheet_columns,
+        contact_sheet_size,
+        indexed_png,
+        quantize_palette,
+        saliency,
+        jitter_rotation,
+        seed,
+        delta_e_threshold,
+        tile_pyramid,
+        channel_split,
+        profile,
+        signature_size,
+        transition,
+        transition_frames,
+        transition_duration,
+        min_tile_distance,
+        #[cfg(feature = "exr")]
+        linear_exr,
+        pairing,
+        fade_edges: fade_width,
+        fade_sides,
+        match_parallelism,
+        input_list,
+        max_reuse,
+        export_lut,
+        lut_levels,
+        no_sidecars,
+        blend_boundaries,
+        band_tiles,
+        deterministic,
+        respect_source_alpha,
+        source_alpha_threshold,
+        equalize,
+        csv,
+        region,
+        tile_costs,
+        budget,
+        dominant_colors,
+        invert_target,
+        invert_tiles,
+        seamless,
+        tile_opacity,
+        low_bandwidth_preview,
+        preview_size,
+        preview_colors,
+        aspect_penalty,
+        watermark,
+        watermark_corner,
+        watermark_size,
+        watermark_color,
+        watermark_opacity,
+        match_cache,
+        brightness_scale,
+        min_tile_scale,
+        max_tile_scale,
+        compare_golden,
+        golden_tolerance,
+        golden_diff,
+        output_scales,
+        position_bias,
+        max_pages,
+        min_contrast,
+        focus_masks,
+        cell_average,
+        edge_cells,
+        embed_provenance,
+        dither,
+        update_from,
+        prior_csv,
+        temporal_jitter,
+        snap_grid,
+        lazy_tiles,
+        tile_cache_size,
+        color_temperature,
+        gamut_plot,
+        gamut_projection,
+        palette_size,
+        coarse_lab,
     } = Opt::from_args();
+    // The flags that make cells pick their tiles some other way than the closest one by average
+    // color, each of which goes its own way and leaves whatever only applies to that one out
+    let other_matching = [
+        ("--adaptive-depth", adaptive_depth.is_some()),
+        ("--channel-split", channel_split),
+        ("--dominant-colors", dominant_colors.is_some()),
+        ("--subregions", subregions.is_some()),
+        (
+            "--match vertical-split",
+            match_mode == MatchMode::VerticalSplit,
+        ),
+        (
+            "--match tone-then-color",
+            match_mode == MatchMode::ToneThenColor,
+        ),
+        ("--dither", dither.is_some()),
+        ("--position-bias", position_bias.is_some()),
+        ("--temporal-jitter", temporal_jitter.is_some()),
+        ("--band-tiles", band_tiles.is_some()),
+        ("--coherence", coherence > 0.),
+        ("--usage-penalty", usage_penalty > 0),
+        ("--min-tile-distance", min_tile_distance > 0),
+        ("--max-reuse", max_reuse.is_some()),
+    ];
+    // The flags that only apply to picking the closest tile by average color
+    let average_matching = [
+        ("--distance-expr", distance_expr.is_some()),
+        ("--delta-e-threshold", delta_e_threshold.is_some()),
+    ];
+    for (flag, given) in average_matching {
+        check_average_matching(flag, given, &other_matching);
+    }
+    // Cells are matched one way only, so these don't mix with the others either
+    let single_matching = [
+        ("--temporal-jitter", temporal_jitter.is_some()),
+        ("--band-tiles", band_tiles.is_some()),
+    ];
+    for (flag, given) in single_matching {
+        check_average_matching(flag, given, &other_matching);
+    }
+    if deterministic && matches!(tile_sort, TileSort::Modified) {
+        bail!("--tile-sort modified isn't reproducible, since it depends on when the tiles were copied");
+    }
+    if profile.is_some() {
+        profile::enable();
+    }
+    let subregions = subregions.or(signature_size);
+    let channel_weights = channel_weights.with_alpha(alpha_weight);
+
+    let load_options = LoadOptions {
+        tile_side: tile_size,
+        average_inset,
+        sort: tile_sort,
+        subregions,
+        variants: tile_variants,
+        normalize_white_balance: normalize_wb,
+        place_normalized,
+        small_tiles: if no_upscale {
+            SmallTiles::Skip
+        } else if upscale_nearest {
+            SmallTiles::Nearest
+        } else {
+            SmallTiles::Upscale
+        },
+        min_contrast: min_contrast.unwrap_or(0.),
+        focus_masks,
+        cache: lazy_tiles.then(|| Arc::new(TileCache::new(tile_cache_size as usize))),
+    };
+
+    if brightness_scale.is_some() && min_tile_scale > max_tile_scale {
+        bail!("--min-tile-scale {min_tile_scale} is larger than --max-tile-scale {max_tile_scale}");
+    }
+    let placement_options = PlacementOptions {
+        tile_size,
+        mirror_cols: mirror_cols || mirror_alternating,
+        mirror_rows: mirror_rows || mirror_alternating,
+        flat,
+        offset_rows,
+        recolor: if match_mode == MatchMode::ToneThenColor {
+            Some(Recolor::Color)
+        } else {
+            recolor
+        },
+        shape: tile_shape,
+        background: background.map(|HexColor(color)| color),
+        jitter_rotation: jitter_rotation.unwrap_or(0.),
+        seed,
+        min_alpha: Some(source_alpha_threshold).filter(|_| respect_source_alpha),
+        invert: invert_tiles,
+        brightness_scale: brightness_scale.map(|larger| BrightnessScale {
+            larger,
+            min: f64::from(min_tile_scale),
+            max: f64::from(max_tile_scale),
+        }),
+    };
+
+    match command {
+        Some(Command::ValidateTiles { tiles_dir }) => {
+            return validate::validate_tiles(&tiles_dir, focus_masks)
+        }
+        Some(Command::Index { tiles_dir, output }) => {
+            let tiles = load_images(tiles_dir, &load_options)?;
+            return index::save_index(&output, &tiles, &load_options);
+        }
+        Some(Command::Inspect {
+            mosaic,
+            tiles_dir,
+            output,
+        }) => {
+            let tiles = load_images(tiles_dir, &load_options)?;
+            let json = inspect_mosaic(&decode::open(&mosaic)?, &tiles, tile_size, channel_weights)?;
+            match output {
+                Some(output) => fs::write(output, json)?,
+                None => print!("{json}"),
+            }
+            return Ok(());
+        }
+        Some(Command::Provenance { mosaic, thumbnail }) => {
+            let provenance = metadata::read_provenance(&mosaic)?
+                .ok_or_else(|| eyre!("{} has no provenance embedded in it", mosaic.display()))?;
+            println!("{}", provenance.description);
+            if let Some(thumbnail) = thumbnail {
+                if provenance.thumbnail.is_empty() {
+                    eprintln!(
+                        "warning: {} has no thumbnail embedded in it",
+                        mosaic.display()
+                    );
+                } else {
+                    fs::write(thumbnail, provenance.thumbnail)?;
+                }
+            }
+            return Ok(());
+        }
+        #[cfg(feature = "server")]
+        Some(Command::Serve {
+            tiles_dir,
+            address,
+            workers,
+            timeout,
+        }) => {
+            let tiles = load_images(tiles_dir, &load_options)?;
+            let options = server::ServeOptions {
+                mosaic_size,
+                keep_aspect_ratio,
+                channel_weights,
+                placement: placement_options,
+                timeout: std::time::Duration::from_secs(u64::from(timeout)),
+            };
+            return server::serve(&address, workers as usize, &tiles, &options);
+        }
+        None => {}
+    }
+    let input_dir = match (input_dir, input_a) {
+        (Some(input_dir), _) | (None, Some(input_dir)) => Some(input_dir),
+        (None, None) if input_list.is_some() || export_lut.is_some() || gamut_plot.is_some() => {
+            None
+        }
+        (None, None) => missing_argument("--input-dir <input-dir>"),
+    };
+    if tiles_dir.is_none() && tile_index.is_none() && tile_atlas.is_none() {
+        missing_argument("--tiles-dir <tiles-dir>");
+    }
+
+    let sharpen_amount = sharpen_amount.clamp(0., 5.);
+    let icc_profile = icc.map(fs::read).transpose()?;
+    let saliency = saliency.map(|path| decode::open(&path)).transpose()?;
 
     fs::create_dir_all(&output_dir)?;
+    // Catch mistakes in the template before doing any work
+    render_output_template(&output_template, "", "", 0, 0)?;
 
-    let possible_tiles = load_images(tiles_dir, tile_size)?;
+    #[cfg(feature = "url")]
+    let (input_dir, tiles_dir) = {
+        let cache_dir = cache_dir.unwrap_or_else(remote::default_cache_dir);
+        let input_dir = match input_dir {
+            Some(input_dir) => Some(match input_dir.to_str() {
+                Some(url) if remote::is_url(&input_dir) => remote::download(url, &cache_dir)?,
+                _ => input_dir,
+            }),
+            None => None,
+        };
+        let tiles_dir = match tiles_dir {
+            Some(tiles_dir) => Some(match tiles_dir.to_str() {
+                Some(url) if remote::is_url(&tiles_dir) => remote::download_tiles(url, &cache_dir)?,
+                _ => tiles_dir,
+            }),
+            None => None,
+        };
+        (input_dir, tiles_dir)
+    };
 
-    let input_dir = fs::read_dir(input_dir)?.collect::<Result<Vec<_>, _>>()?;
+    #[cfg(not(feature = "url"))]
+    for path in input_dir.iter().chain(&tiles_dir) {
+        if path.to_str().is_some_and(|path| path.contains("://")) {
+            bail!(
+                "{} looks like a URL, but themis was built without the `url` feature",
+                path.display()
+            );
+        }
+    }
 
-    for image in input_dir {
-        let input_path = image.path();
-        eprintln!("Processing {}", input_path.display());
+    // Weigh and reduce a freshly loaded pool of tiles
+    let prepare_pool = |possible_tiles: Vec<Tile>| -> Result<Vec<Tile>> {
+        let mut possible_tiles = match &tile_weights {
+            Some(tile_weights) => tiles::apply_weights(possible_tiles, tile_weights)?,
+            None => possible_tiles,
+        };
+        if aspect_penalty > 0. {
+            tiles::penalize_aspect(&mut possible_tiles, aspect_penalty);
+        }
+        let possible_tiles = match max_tiles {
+            Some(max_tiles) if possible_tiles.len() > max_tiles => {
+                let len = possible_tiles.len();
+                let possible_tiles =
+                    tiles::reduce_tileset(possible_tiles, max_tiles, channel_weights);
+                eprintln!(
+                    "Reduced the tileset from {} to {} tiles",
+                    len,
+                    possible_tiles.len()
+                );
+                possible_tiles
+            }
+            _ => possible_tiles,
+        };
+        if possible_tiles.is_empty() {
+            bail!("there are no tiles to pick from");
+        }
+        let possible_tiles = match dominant_colors {
+            Some(k) => {
+                let len = possible_tiles.len();
+                possible_tiles
+                    .into_par_iter()
+                    .progress_with(make_pbar("dominant colors", len as _))
+                    .map(|mut tile| {
+                        tile.dominants =
+                            dominant::dominant_colors(&*tile.image()?, k, channel_weights);
+                        Ok(tile)
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            }
+            None => possible_tiles,
+        };
+        Ok(possible_tiles)
+    };
 
-        let output = output_dir.join(format!(
-            "{}.mosaic{mosaic_size}.png",
-            input_path.file_stem().unwrap().to_string_lossy()
-        ));
-        if output.exists() {
+    let span = profile::span("load_tiles");
+    let pairing = pairing.as_deref().map(tiles::Pairing::load).transpose()?;
+    let mut pools = vec![prepare_pool(
+        match (&tile_index, &tile_atlas, atlas_grid, &tiles_dir) {
+            (Some(tile_index), _, _, _) => index::load_index(tile_index, &load_options)?,
+            (None, Some(tile_atlas), Some(grid), _) => {
+                tiles::load_atlas(tile_atlas, grid.columns, grid.rows, &load_options)?
+            }
+            (None, None, _, Some(tiles_dir)) => load_images(tiles_dir, &load_options)?,
+            _ => unreachable!("a source of tiles is required"),
+        },
+    )?];
+    // The pools of the subdirectories paired with some inputs, in order, after the default one
+    if let (Some(pairing), Some(tiles_dir)) = (&pairing, &tiles_dir) {
+        for subdir in pairing.subdirs() {
+            let dir = tiles_dir.join(subdir);
+            eprintln!("Loading the tiles in {}", dir.display());
+            pools.push(
+                prepare_pool(load_images(&dir, &load_options)?)
+                    .wrap_err_with(|| format!("in {}", dir.display()))?,
+            );
+        }
+    }
+    drop(span);
+
+    // Building the structures that speed up matching can take a while with large tilesets
+    let span = profile::span("build_index");
+    let spinner = (coarse_bins.is_some() || delta_e_threshold.is_some())
+        .then(|| make_spinner("Building index", "Built the index!"));
+    let pools = pools
+        .into_iter()
+        .map(|possible_tiles| {
+            TilePool::new(possible_tiles, coarse_bins, coarse_lab, delta_e_threshold)
+        })
+        .collect::<Vec<_>>();
+    if let Some(spinner) = spinner {
+        spinner.finish_using_style();
+    }
+    drop(span);
+
+    if let Some(export_lut) = &export_lut {
+        let levels = lut_levels.min(256);
+        fs::write(
+            export_lut,
+            make_lut(&pools[0].tiles, levels, channel_weights),
+        )?;
+        eprintln!(
+            "Saved the tiles of {} colors to {}",
+            levels.pow(3),
+            export_lut.display()
+        );
+    }
+
+    if let Some(gamut_plot) = &gamut_plot {
+        let plot = gamut::plot(&pools[0].tiles, gamut_projection);
+        plot.image.save(gamut_plot)?;
+        eprintln!(
+            "Saved the gamut of the tiles to {}, covering {:.1}% of its colors",
+            gamut_plot.display(),
+            plot.coverage * 100.
+        );
+    }
+
+    let inputs = match (input_list, input_dir) {
+        (Some(input_list), _) => read_input_list(&input_list)?,
+        (None, Some(input_dir)) if input_dir.is_file() => vec![input_dir],
+        (None, Some(input_dir)) => {
+            let mut inputs = fs::read_dir(input_dir)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            inputs.retain(|path| !sidecar::is_sidecar(path));
+            inputs.sort();
+            inputs
+        }
+        (None, None) => Vec::new(),
+    };
+
+    // Every page of a multi-page TIFF is an input of its own
+    let mut pages = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let max_pages = max_pages.map_or(usize::MAX, |max_pages| max_pages as usize);
+        match decode::tiff_pages(&input, max_pages) {
+            Ok(Some(count)) if count > 1 => {
+                pages.extend((0..count).map(|page| (input.clone(), Some(page))));
+            }
+            _ => pages.push((input, None)),
+        }
+    }
+    let inputs = pages;
+
+    interrupt::install_handler();
+    let mut checkpoint = checkpoint::Checkpoint::load(&output_dir)?;
+    let input_count = inputs.len();
+    let mut previews = Vec::new();
+    let costs = tile_costs.as_deref().map(budget::load_costs).transpose()?;
+    let mut golden_mismatches = 0;
+    // The cell grid of the last frame and the tile of each of its cells, for `--temporal-jitter`,
+    // by path since the tiles to pick from can differ from one input to the next
+    let mut previous_frame = None::<((u32, u32), Vec<PathBuf>)>;
+    let mut cache = match_cache
+        .as_deref()
+        .map(match_cache::MatchCache::load)
+        .transpose()?;
+    let match_settings = format!(
+        "{:?} {:?} {:?} {:?} {:?}",
+        channel_weights, coarse_bins, coarse_lab, delta_e_threshold, distance_expr
+    );
+
+    for (done, (input_path, page)) in inputs.into_iter().enumerate() {
+        if interrupt::interrupted() {
+            eprintln!(
+                "Stopped after {done} of {input_count} inputs, run again to continue with the rest"
+            );
+            break;
+        }
+        match page {
+            Some(page) => eprintln!("Processing page {} of {}", page + 1, input_path.display()),
+            None => eprintln!("Processing {}", input_path.display()),
+        }
+
+        // Use the pool of the subdirectory that the input is paired with, if any
+        let pool = pairing
+            .as_ref()
+            .and_then(|pairing| pairing.subdir_for(&input_path))
+            .map_or(0, |subdir| subdir + 1);
+        let TilePool {
+            tiles: possible_tiles,
+            coarse_index,
+            delta_e_matching,
+        } = &pools[pool];
+
+        // Let the input's sidecar, if it has one, override some of the settings
+        let overrides = if no_sidecars {
+            sidecar::Overrides::default()
+        } else {
+            sidecar::load_overrides(&input_path)?
+        };
+        let mosaic_size = overrides.mosaic_size.unwrap_or(mosaic_size);
+        let sharpen_amount = overrides
+            .sharpen
+            .map_or(sharpen_amount, |sharpen| sharpen.clamp(0., 5.));
+        let pre_blur = overrides.pre_blur.unwrap_or(pre_blur);
+        let coherence = overrides.coherence.unwrap_or(coherence);
+        let usage_penalty = overrides.usage_penalty.unwrap_or(usage_penalty);
+        if let Some((flag, _given)) = average_matching
+            .iter()
+            .find(|&&(_flag, given)| given && (coherence > 0. || usage_penalty > 0))
+        {
+            bail!(
+                "the sidecar of {} sets a coherence or usage penalty, which {} can't be combined with",
+                input_path.display(),
+                flag
+            );
+        }
+        let placement_options = PlacementOptions {
+            recolor: overrides.recolor.or(placement_options.recolor),
+            ..placement_options
+        };
+
+        let stem = input_path.file_stem().unwrap().to_string_lossy();
+        let stem = match page {
+            Some(page) => format!("{stem}-page{}", page + 1),
+            None => stem.into_owned(),
+        };
+        let stem = match &input_b {
+            Some(input_b) => format!(
+                "{stem}-{}-{blend}",
+                input_b.file_stem().unwrap().to_string_lossy()
+            ),
+            None => stem,
+        };
+        let stem = match region {
+            Some(Region {
+                x,
+                y,
+                width,
+                height,
+            }) => format!("{stem}-{x}_{y}_{width}x{height}"),
+            None => stem,
+        };
+
+        let output = output_dir.join(render_output_template(
+            &output_template,
+            &stem,
+            if sprite_sheet { "sprites" } else { "mosaic" },
+            mosaic_size,
+            tile_size,
+        )?);
+        let remake = stats_only || compare_golden.is_some() || update_from.is_some();
+        if checkpoint.is_done(&output) && !remake {
+            // Outputs from before the checkpoint existed get recorded as they're found
+            checkpoint.finish(&output)?;
+            if contact_sheet.is_some() {
+                previews.push((
+                    output
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                    decode::open(&output)?.thumbnail(contact_sheet_size, contact_sheet_size),
+                ));
+            }
+            // Which tiles its cells got isn't known, so the next frame can't follow it
+            previous_frame = None;
             continue;
         }
+        if output.exists() && !remake {
+            eprintln!(
+                "Making {} again, the run that saved it stopped before finishing",
+                output.display()
+            );
+        }
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Read before anything is saved, since the prior mosaic may well be the output itself
+        let prior = match (&update_from, &prior_csv) {
+            (Some(prior_path), Some(prior_csv)) => {
+                let prior_path = if prior_path.is_dir() {
+                    prior_path.join(output.file_name().unwrap_or_default())
+                } else {
+                    prior_path.clone()
+                };
+                let prior_mosaic = decode::open(&prior_path)?;
+                let prior_tiles = read_cells_csv(&per_input_path(prior_csv, &stem))?;
+                Some((prior_path, prior_mosaic, prior_tiles))
+            }
+            _ => None,
+        };
 
-        let img = image::open(&input_path)?;
-        let img = if keep_aspect_ratio {
-            img.thumbnail(mosaic_size, mosaic_size)
+        let _input_span = profile::span("input");
+        let span = profile::span("prepare");
+        let source = match page {
+            Some(page) => decode::open_tiff_page(&input_path, page)?,
+            None => decode::open(&input_path)?,
+        };
+        let source = match &input_b {
+            Some(input_b) => blend_images(&source, &decode::open(input_b)?, blend, blend_resize)?,
+            None => source,
+        };
+        let source_profile = match icc_profile {
+            Some(_) => None,
+            None => metadata::read_profile(&input_path).unwrap_or_else(|err| {
+                eprintln!("warning: couldn't read the color profile of the input: {err}");
+                None
+            }),
+        };
+        let provenance = embed_provenance
+            .then(|| metadata::Provenance::new(&source, &input_path))
+            .transpose()?;
+        let output_metadata = metadata::Metadata {
+            profile: icc_profile.as_deref().or(source_profile.as_deref()),
+            dpi,
+            provenance: provenance.as_ref(),
+        };
+        let source = match region {
+            Some(region) => region.crop(&source)?,
+            None => source,
+        };
+        let source = if invert_target {
+            let mut inverted = source;
+            inverted.invert();
+            inverted
         } else {
-            img.thumbnail_exact(mosaic_size, mosaic_size)
-        };
-
-        // For every unique pixel in the image, find its most appropiate tile
-        let unique_pixels = img
-            .pixels()
-            .map(|(_x, _y, pixel)| pixel)
-            .collect::<HashSet<_>>();
-        let len = unique_pixels.len();
-        let tiles = unique_pixels
-            .into_par_iter()
-            .progress_with(make_pbar("pixels", len as _))
-            .filter_map(|pixel| {
-                let tile = pick_image_for_pixel(pixel, &possible_tiles)?;
-                Some((pixel, tile))
-            })
-            .collect::<HashMap<_, _>>();
+            source
+        };
+        let source = match crop_aspect {
+            Some(aspect) => crop_to_aspect(&source, aspect, crop_gravity),
+            None => source,
+        };
+        let keep_aspect = keep_aspect_ratio || crop_aspect.is_some();
+        let (columns, rows) = if keep_aspect {
+            let (width, height) = source.dimensions();
+            if snap_grid {
+                snapped_grid(width, height, mosaic_size)
+            } else {
+                let scale = f64::from(mosaic_size) / f64::from(width.max(height));
+                (
+                    ((f64::from(width) * scale).round() as u32).max(1),
+                    ((f64::from(height) * scale).round() as u32).max(1),
+                )
+            }
+        } else {
+            (mosaic_size, mosaic_size)
+        };
+        let img = if cell_average {
+            average_cells(&source, columns, rows, edge_cells)
+        } else if keep_aspect && snap_grid {
+            source.thumbnail_exact(columns, rows)
+        } else if keep_aspect {
+            source.thumbnail(mosaic_size, mosaic_size)
+        } else {
+            source.thumbnail_exact(mosaic_size, mosaic_size)
+        };
+        let img = if offset_rows {
+            placement::stagger_rows(&source, &img)
+        } else {
+            img
+        };
+        let img = if pre_blur > 0. {
+            img.blur(pre_blur)
+        } else {
+            img
+        };
+        let img = match color_temperature {
+            Some(kelvin) => adjust_temperature(&img, kelvin),
+            None => img,
+        };
+        let img = match equalize {
+            Some(method) => equalize::equalize(&img, method),
+            None => img,
+        };
+        let img = match max_unique_colors {
+            Some(max_colors) => {
+                let (img, before, after) = quantize::median_cut(&img, max_colors as usize);
+                eprintln!("Quantized the image from {before} to {after} unique colors");
+                img
+            }
+            None => img,
+        };
+
+        // Narrow the tileset down to the tiles that reproduce this image best
+        let palette_pool;
+        let (possible_tiles, coarse_index, delta_e_matching) = match palette_size {
+            Some(palette_size) => {
+                let pixels = img
+                    .pixels()
+                    .map(|(_x, _y, pixel)| pixel)
+                    .collect::<Vec<_>>();
+                let palette = palette::choose_palette(
+                    &pixels,
+                    possible_tiles,
+                    palette_size as usize,
+                    channel_weights,
+                );
+                eprintln!(
+                    "Chose {} tiles for the image, at a mean error of {:.0}:",
+                    palette.tiles.len(),
+                    palette.mean_error
+                );
+                for (&tile, cells) in palette.tiles.iter().zip(&palette.cells) {
+                    eprintln!(
+                        "  {} ({} cells)",
+                        possible_tiles[tile].path.display(),
+                        cells
+                    );
+                }
+                let tiles = palette
+                    .tiles
+                    .iter()
+                    .map(|&tile| possible_tiles[tile].clone())
+                    .collect();
+                palette_pool = TilePool::new(tiles, coarse_bins, coarse_lab, delta_e_threshold);
+                (
+                    &palette_pool.tiles,
+                    &palette_pool.coarse_index,
+                    &palette_pool.delta_e_matching,
+                )
+            }
+            None => (possible_tiles, coarse_index, delta_e_matching),
+        };
+
+        drop(span);
+
+        // Kept around for `--html-map`, which needs to know which tile went in each cell
+        let mut placed_cells = None;
+        let mut mosaic = if let Some(max_depth) = adaptive_depth {
+            let _span = profile::span("assemble");
+            let saliency = saliency.as_ref().map(|saliency| {
+                saliency
+                    .resize_exact(img.width(), img.height(), FilterType::Triangle)
+                    .into_luma8()
+            });
+            adaptive::assemble(
+                &img,
+                saliency.as_ref(),
+                possible_tiles,
+                channel_weights,
+                coarse_index.as_ref(),
+                tile_size,
+                max_depth,
+                adaptive_threshold,
+            )?
+        } else if channel_split {
+            let _span = profile::span("assemble");
+            let mosaics = (0..3)
+                .map(|channel| {
+                    let nearest = nearest_by_channel(possible_tiles, channel)
+                        .ok_or_else(|| eyre!("there are no tiles to pick from"))?;
+                    let cells = img
+                        .pixels()
+                        .map(|(_x, _y, pixel)| Placement::new(nearest[usize::from(pixel[channel])]))
+                        .collect::<Vec<_>>();
+                    placement::place_tiles(&img, &cells, possible_tiles, &placement_options)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            placement::combine_channels([&mosaics[0], &mosaics[1], &mosaics[2]])
+        } else {
+            let span = profile::span("match");
+            let signatures = subregions.map(|side| {
+                let detail = source.thumbnail_exact(img.width() * side, img.height() * side);
+                let detail = if pre_blur > 0. {
+                    detail.blur(pre_blur * side as f32)
+                } else {
+                    detail
+                };
+                img.pixels()
+                    .map(|(x, y, _pixel)| {
+                        tiles::signature(&detail.crop_imm(x * side, y * side, side, side), side)
+                    })
+                    .collect::<Vec<_>>()
+            });
+
+            let mut cells_csv = csv
+                .as_ref()
+                .map(|csv| CellsCsv::create(per_input_path(csv, &stem)))
+                .transpose()?;
+            let mut cells = if let Some(k) = dominant_colors {
+                // Match every cell by the dominant colors of its part of the full size image
+                let side = dominant::DOMINANT_SAMPLES;
+                let detail = source.thumbnail_exact(img.width() * side, img.height() * side);
+                let dominants = img
+                    .pixels()
+                    .map(|(x, y, _pixel)| {
+                        let cell = detail.crop_imm(x * side, y * side, side, side);
+                        dominant::dominant_colors(&cell, k, channel_weights)
+                    })
+                    .collect::<Vec<_>>();
+                let unique_cells = dominants.iter().collect::<HashSet<_>>();
+                let len = unique_cells.len();
+                let tiles = unique_cells
+                    .into_par_iter()
+                    .progress_with(make_pbar("cells", len as _))
+                    .filter_map(|cell| {
+                        let tile = pick_image_for_dominants(cell, possible_tiles, channel_weights)?;
+                        Some((cell, Placement::new(tile)))
+                    })
+                    .collect::<HashMap<_, _>>();
+                dominants.iter().map(|cell| tiles[cell]).collect::<Vec<_>>()
+            } else if let (Some(signatures), Some(_)) = (&signatures, signature_size) {
+                // Shortlist the tiles closest to each cell on average, then pick the one among
+                // them whose downscale resembles the cell's the most
+                let unique_cells = img
+                    .pixels()
+                    .map(|(_x, _y, pixel)| pixel)
+                    .zip(signatures)
+                    .collect::<HashSet<_>>();
+                let len = unique_cells.len();
+                let placements = unique_cells
+                    .into_par_iter()
+                    .progress_with(make_pbar("cells", len as _))
+                    .filter_map(|cell| {
+                        let (pixel, signature) = cell;
+                        let placement = pick_image_for_signature_refined(
+                            pixel,
+                            signature,
+                            possible_tiles,
+                            channel_weights,
+                        )?;
+                        Some((cell, placement))
+                    })
+                    .collect::<HashMap<_, _>>();
+                img.pixels()
+                    .map(|(_x, _y, pixel)| pixel)
+                    .zip(signatures)
+                    .map(|cell| placements[&cell])
+                    .collect::<Vec<_>>()
+            } else if let Some(signatures) = &signatures {
+                // Split every cell into subregions and find the tile, and orientation thereof, whose
+                // own subregions resemble them the most
+                let unique_signatures = signatures.iter().collect::<HashSet<_>>();
+                let len = unique_signatures.len();
+                let placements = unique_signatures
+                    .into_par_iter()
+                    .progress_with(make_pbar("cells", len as _))
+                    .filter_map(|signature| {
+                        let placement =
+                            pick_image_for_signature(signature, possible_tiles, channel_weights)?;
+                        Some((signature, placement))
+                    })
+                    .collect::<HashMap<_, _>>();
+                signatures
+                    .iter()
+                    .map(|signature| placements[signature])
+                    .collect::<Vec<_>>()
+            } else if match_mode == MatchMode::VerticalSplit {
+                // Split every cell into its top and bottom halves, and find the tile whose own
+                // halves resemble them the most
+                let detail = source.thumbnail_exact(img.width() * 2, img.height() * 2);
+                let detail = if pre_blur > 0. {
+                    detail.blur(pre_blur * 2.)
+                } else {
+                    detail
+                };
+                let halves = img
+                    .pixels()
+                    .map(|(x, y, _pixel)| tiles::halves(&detail.crop_imm(x * 2, y * 2, 2, 2)))
+                    .collect::<Vec<_>>();
+                let unique_halves = halves.iter().copied().collect::<HashSet<_>>();
+                let len = unique_halves.len();
+                let tiles = unique_halves
+                    .into_par_iter()
+                    .progress_with(make_pbar("cells", len as _))
+                    .filter_map(|cell| {
+                        let tile = pick_image_for_halves(cell, possible_tiles, channel_weights)?;
+                        Some((cell, Placement::new(tile)))
+                    })
+                    .collect::<HashMap<_, _>>();
+                halves.iter().map(|cell| tiles[cell]).collect::<Vec<_>>()
+            } else if match_mode == MatchMode::ToneThenColor {
+                // Find the tile whose tone is closest to every unique pixel's, the color being
+                // painted back when placing it
+                let unique_pixels = img
+                    .pixels()
+                    .map(|(_x, _y, pixel)| pixel)
+                    .collect::<HashSet<_>>();
+                let len = unique_pixels.len();
+                let tiles = unique_pixels
+                    .into_par_iter()
+                    .progress_with(make_pbar("pixels", len as _))
+                    .filter_map(|pixel| {
+                        let tile = pick_image_for_tone(pixel, possible_tiles)?;
+                        Some((pixel, Placement::new(tile)))
+                    })
+                    .collect::<HashMap<_, _>>();
+                img.pixels()
+                    .map(|(_x, _y, pixel)| tiles[&pixel])
+                    .collect::<Vec<_>>()
+            } else if let Some(dither) = dither {
+                // Go through the cells in order, as each one gets the error of those before it
+                let pixels = img
+                    .pixels()
+                    .map(|(_x, _y, pixel)| pixel)
+                    .collect::<Vec<_>>();
+                pick_tiles_dithered(
+                    &pixels,
+                    img.width() as usize,
+                    possible_tiles,
+                    channel_weights,
+                    dither,
+                )
+                .ok_or_else(|| eyre!("there are no tiles to pick from"))?
+                .into_iter()
+                .map(Placement::new)
+                .collect::<Vec<_>>()
+            } else if let Some(position_bias) = position_bias.filter(|&bias| bias > 0.) {
+                // Match every cell by its color and its place from left to right
+                let places = tiles::rainbow_places(possible_tiles);
+                let place_of = |x: u32| f64::from(x) / f64::from(img.width().max(2) - 1);
+                let unique_cells = img
+                    .pixels()
+                    .map(|(x, _y, pixel)| (x, pixel))
+                    .collect::<HashSet<_>>();
+                let len = unique_cells.len();
+                let tiles = unique_cells
+                    .into_par_iter()
+                    .progress_with(make_pbar("cells", len as _))
+                    .filter_map(|(x, pixel)| {
+                        let tile = pick_image_for_pixel_at(
+                            pixel,
+                            possible_tiles,
+                            channel_weights,
+                            &places,
+                            place_of(x),
+                            position_bias,
+                        )?;
+                        Some(((x, pixel), Placement::new(tile)))
+                    })
+                    .collect::<HashMap<_, _>>();
+                img.pixels()
+                    .map(|(x, _y, pixel)| tiles[&(x, pixel)])
+                    .collect::<Vec<_>>()
+            } else if let Some((previous, temporal_jitter)) = previous_frame
+                .as_ref()
+                .filter(|(dimensions, _tiles)| *dimensions == img.dimensions())
+                .zip(temporal_jitter)
+            {
+                // Match every cell with the tile it had in the previous frame held back, if it
+                // can still be picked
+                let indices = possible_tiles
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, tile)| (tile.path.as_path(), idx))
+                    .collect::<HashMap<_, _>>();
+                let previous = previous
+                    .1
+                    .iter()
+                    .map(|path| indices.get(path.as_path()).copied())
+                    .collect::<Vec<_>>();
+                let unique_cells = img
+                    .pixels()
+                    .zip(&previous)
+                    .map(|((_x, _y, pixel), &previous)| (pixel, previous))
+                    .collect::<HashSet<_>>();
+                let len = unique_cells.len();
+                let tiles = unique_cells
+                    .into_par_iter()
+                    .progress_with(make_pbar("cells", len as _))
+                    .filter_map(|(pixel, previous)| {
+                        let tile = pick_image_for_pixel_avoiding(
+                            pixel,
+                            possible_tiles,
+                            channel_weights,
+                            previous,
+                            temporal_jitter.into(),
+                        )?;
+                        Some(((pixel, previous), Placement::new(tile)))
+                    })
+                    .collect::<HashMap<_, _>>();
+                img.pixels()
+                    .zip(&previous)
+                    .map(|((_x, _y, pixel), &previous)| tiles[&(pixel, previous)])
+                    .collect::<Vec<_>>()
+            } else if let Some(band_tiles) = &band_tiles {
+                // Match every cell only against the tiles of its band, the whole tileset
+                // standing in for empty bands
+                let bands = band_tiles.partition(possible_tiles);
+                let all_tiles = (0..possible_tiles.len()).collect::<Vec<_>>();
+                for (band, candidates) in band_tiles.0.iter().zip(&bands) {
+                    if candidates.is_empty() {
+                        eprintln!(
+                            "warning: no tile has a hue from {} to {}, using every tile in its band",
+                            band.0, band.1
+                        );
+                    }
+                }
+                let band_of = |y: u32| y as usize * bands.len() / img.height() as usize;
+                let unique_cells = img
+                    .pixels()
+                    .map(|(_x, y, pixel)| (band_of(y), pixel))
+                    .collect::<HashSet<_>>();
+                let len = unique_cells.len();
+                let tiles = unique_cells
+                    .into_par_iter()
+                    .progress_with(make_pbar("cells", len as _))
+                    .filter_map(|(band, pixel)| {
+                        let candidates = match &bands[band] {
+                            candidates if candidates.is_empty() => &all_tiles,
+                            candidates => candidates,
+                        };
+                        let tile = pick_image_for_pixel_among(
+                            pixel,
+                            possible_tiles,
+                            candidates,
+                            channel_weights,
+                        )?;
+                        Some(((band, pixel), Placement::new(tile)))
+                    })
+                    .collect::<HashMap<_, _>>();
+                img.pixels()
+                    .map(|(_x, y, pixel)| tiles[&(band_of(y), pixel)])
+                    .collect::<Vec<_>>()
+            } else if coherence > 0.
+                || usage_penalty > 0
+                || min_tile_distance > 0
+                || max_reuse.is_some()
+            {
+                // Go through the cells in order, as each one depends on the tiles placed before it
+                let pixels = img
+                    .pixels()
+                    .map(|(_x, _y, pixel)| pixel)
+                    .collect::<Vec<_>>();
+                let (cells, spillover) = pick_tiles_in_scan_order(
+                    &pixels,
+                    img.width() as usize,
+                    possible_tiles,
+                    channel_weights,
+                    ScanOptions {
+                        coherence,
+                        usage_penalty,
+                        min_tile_distance,
+                        max_reuse,
+                    },
+                )
+                .ok_or_else(|| eyre!("there are no tiles to pick from"))?;
+                if let Some(max_reuse) = max_reuse {
+                    if spillover.cells == 0 {
+                        eprintln!("Every cell got its best tile within --max-reuse {max_reuse}");
+                    } else {
+                        eprintln!(
+                            "{} of {} cells had to do without their best tile, used up by --max-reuse {}, at {:.1} more error on average",
+                            spillover.cells,
+                            cells.len(),
+                            max_reuse,
+                            spillover.extra_error as f64 / spillover.cells as f64
+                        );
+                    }
+                }
+                cells.into_iter().map(Placement::new).collect::<Vec<_>>()
+            } else {
+                // For every unique pixel in the image, find its most appropiate tile, rounding
+                // them like the match cache does if there's one
+                let quantized = cache.is_some();
+                let key = |pixel| {
+                    if quantized {
+                        match_cache::quantize(pixel)
+                    } else {
+                        pixel
+                    }
+                };
+                let mut unique_pixels = img
+                    .pixels()
+                    .map(|(_x, _y, pixel)| key(pixel))
+                    .collect::<HashSet<_>>();
+
+                // Take the pixels the match cache already knows about out of the ones to match,
+                // finding the tiles it has by their paths
+                let fingerprint = cache
+                    .as_ref()
+                    .map(|_| match_cache::fingerprint(possible_tiles, &match_settings));
+                let mut cached = HashMap::new();
+                if let (Some(cache), Some(fingerprint)) = (&cache, fingerprint) {
+                    let indices = possible_tiles
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, tile)| (tile.path.as_path(), idx))
+                        .collect::<HashMap<_, _>>();
+                    let colors = unique_pixels.len();
+                    unique_pixels.retain(|&pixel| {
+                        match cache
+                            .get(fingerprint, pixel)
+                            .and_then(|path| indices.get(path))
+                        {
+                            Some(&tile) => {
+                                cached.insert(pixel, Placement::new(tile));
+                                false
+                            }
+                            None => true,
+                        }
+                    });
+                    eprintln!(
+                        "{} of {} colors were in the match cache ({:.1}%)",
+                        cached.len(),
+                        colors,
+                        cached.len() as f64 * 100. / colors.max(1) as f64
+                    );
+                }
+
+                let len = unique_pixels.len();
+                let pick = |pixel| {
+                    let tile = match (&distance_expr, delta_e_matching) {
+                        (Some(expr), _) => pick_image_for_pixel_by(pixel, possible_tiles, expr)?,
+                        (None, Some((threshold, tiles_lab))) => pick_image_for_pixel_within(
+                            pixel,
+                            possible_tiles,
+                            tiles_lab,
+                            *threshold,
+                        )?,
+                        (None, None)
+                            if coarse_index.is_none()
+                                && match_parallelism == MatchParallelism::Pixels =>
+                        {
+                            pick_image_for_pixel_sequential(pixel, possible_tiles, channel_weights)?
+                        }
+                        (None, None) => pick_image_for_pixel(
+                            pixel,
+                            possible_tiles,
+                            channel_weights,
+                            coarse_index.as_ref(),
+                        )?,
+                    };
+                    Some((pixel, Placement::new(tile)))
+                };
+                let mut tiles = if match_parallelism == MatchParallelism::Tiles {
+                    unique_pixels
+                        .into_iter()
+                        .progress_with(make_pbar("pixels", len as _))
+                        .filter_map(pick)
+                        .collect::<HashMap<_, _>>()
+                } else {
+                    unique_pixels
+                        .into_par_iter()
+                        .progress_with(make_pbar("pixels", len as _))
+                        .filter_map(pick)
+                        .collect::<HashMap<_, _>>()
+                };
+                if let (Some(cache), Some(fingerprint)) = (&mut cache, fingerprint) {
+                    for (&pixel, placement) in &tiles {
+                        cache.insert(fingerprint, pixel, &possible_tiles[placement.tile].path);
+                    }
+                }
+                tiles.extend(cached);
+                // Write every cell's row as it gets its tile, unless the budget may swap it after
+                let mut streamed = cells_csv.as_mut().filter(|_| costs.is_none());
+                img.pixels()
+                    .map(|(x, y, pixel)| {
+                        let placement = tiles[&key(pixel)];
+                        if let Some(csv) = &mut streamed {
+                            let tile = &possible_tiles[placement.tile];
+                            csv.row(x, y, pixel, tile, channel_weights)?;
+                        }
+                        Ok(placement)
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            };
+            drop(span);
+
+            if let Some(costs) = &costs {
+                let costs = budget::tile_costs(possible_tiles, costs);
+                let pixels = img
+                    .pixels()
+                    .map(|(_x, _y, pixel)| pixel)
+                    .collect::<Vec<_>>();
+                let report = budget::fit_budget(
+                    &pixels,
+                    &mut cells,
+                    possible_tiles,
+                    &costs,
+                    budget.unwrap_or(f64::INFINITY),
+                    channel_weights,
+                );
+                match budget {
+                    Some(budget) if report.cells_changed > 0 || report.cost_before > budget => {
+                        eprintln!(
+                            "Brought the cost of the tiles from {:.2} down to {:.2} (budget {:.2}) by swapping {} cells, the mean error going from {:.0} to {:.0}",
+                            report.cost_before,
+                            report.cost_after,
+                            budget,
+                            report.cells_changed,
+                            report.mean_error_before,
+                            report.mean_error_after
+                        );
+                        if report.cost_after > budget {
+                            eprintln!("warning: even the cheapest tiles cost more than the budget");
+                        }
+                    }
+                    _ => eprintln!(
+                        "The tiles cost {:.2} in total, with a mean error of {:.0}",
+                        report.cost_after, report.mean_error_after
+                    ),
+                }
+            }
+
+            if temporal_jitter.is_some() {
+                let tiles = cells
+                    .iter()
+                    .map(|placement| possible_tiles[placement.tile].path.clone())
+                    .collect();
+                previous_frame = Some((img.dimensions(), tiles));
+            }
+
+            if let Some(position) = explain {
+                if position.x < img.width() && position.y < img.height() {
+                    let idx = (position.y * img.width() + position.x) as usize;
+                    explain_cell(
+                        position,
+                        img.get_pixel(position.x, position.y),
+                        signatures.as_ref().map(|signatures| &signatures[idx][..]),
+                        cells[idx],
+                        possible_tiles,
+                        channel_weights,
+                    );
+                } else {
+                    eprintln!(
+                        "warning: can't explain cell {},{}, the mosaic is only {}x{} cells",
+                        position.x,
+                        position.y,
+                        img.width(),
+                        img.height()
+                    );
+                }
+            }
+
+            let errors = (error_heatmap.is_some() || stats_only).then(|| {
+                img.pixels()
+                    .zip(&cells)
+                    .map(|((_x, _y, pixel), placement)| {
+                        distance(
+                            pixel,
+                            possible_tiles[placement.tile].average,
+                            channel_weights,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            });
+
+            if let (Some(heatmap_path), Some(errors)) = (&error_heatmap, &errors) {
+                make_error_heatmap(img.width(), img.height(), errors)
+                    .save(per_input_path(heatmap_path, &stem))?;
+            }
+
+            if let Some(mut csv) = cells_csv {
+                csv.write_cells(&img, &cells, possible_tiles, channel_weights)?;
+                let csv = csv.finish()?;
+                eprintln!("Saved the matches of every cell to {}", csv.display());
+            }
+
+            if let (true, Some(errors)) = (stats_only, &errors) {
+                let pixels = img
+                    .pixels()
+                    .map(|(_x, _y, pixel)| pixel)
+                    .collect::<Vec<_>>();
+                let stats = stats::CoverageStats::new(&pixels, errors, stats_threshold);
+                let name = input_path.display().to_string();
+                if stats_json {
+                    println!("{}", stats.to_json(&name));
+                } else {
+                    stats.print_report(&name);
+                }
+                continue;
+            }
+
+            if sprite_sheet {
+                let (sheet, index) =
+                    make_sprite_sheet(possible_tiles, &cells, img.width(), tile_size)?;
+                fs::write(output.with_extension("json"), index)?;
+                metadata::save_with_metadata(&sheet, &output, metadata::Metadata::default(), None)?;
+                checkpoint.finish(&output)?;
+                continue;
+            }
+
+            // Apply the mapping previously calculated and save the mosaic
+            let span = profile::span("assemble");
+            let mosaic = if let Some((prior_path, prior_mosaic, prior_tiles)) = &prior {
+                let changed = img
+                    .pixels()
+                    .zip(&cells)
+                    .map(|((x, y, _pixel), placement)| {
+                        let tile = possible_tiles[placement.tile].path.to_string_lossy();
+                        prior_tiles.get(&(x, y)).map(String::as_str) != Some(&*tile)
+                    })
+                    .collect::<Vec<_>>();
+                let changed_count = changed.iter().filter(|&&changed| changed).count();
+                eprintln!(
+                    "{} of {} cells changed since {} ({:.2}%)",
+                    changed_count,
+                    changed.len(),
+                    prior_path.display(),
+                    changed_count as f64 / changed.len().max(1) as f64 * 100.
+                );
+                placement::patch_tiles(
+                    prior_mosaic,
+                    &img,
+                    &cells,
+                    &changed,
+                    possible_tiles,
+                    &placement_options,
+                )?
+            } else {
+                placement::place_tiles(&img, &cells, possible_tiles, &placement_options)?
+            };
+            drop(span);
+            if html_map.is_some() {
+                placed_cells = Some(cells);
+            }
+            mosaic
+        };
 
-        // Apply the mapping previously calculated and save the mosaic
-        let mut mosaic = DynamicImage::new_rgba8(img.width() * tile_size, img.height() * tile_size);
-        for (x, y, pixel) in img.pixels().progress_with(make_pbar(
-            "actual pixels",
-            u64::from(img.width() * img.height()),
-        )) {
-            mosaic.copy_from(&**tiles.get(&pixel).unwrap(), x * tile_size, y * tile_size)?;
+        if blend_boundaries {
+            mosaic = placement::blend_boundaries(&mosaic, tile_size);
         }
 
+        if let Some(opacity) = tile_opacity {
+            mosaic = placement::over_source(&mosaic, &source, opacity);
+        }
+
+        if seamless {
+            mosaic = placement::make_seamless(&mosaic);
+        }
+
+        if sharpen_amount > 0. {
+            mosaic = sharpen(&mosaic, sharpen_amount);
+        }
+
+        if let Some(fade_width) = fade_width {
+            mosaic = fade_edges(&mosaic, fade_width, fade_sides);
+            if !supports_alpha(&output) {
+                eprintln!(
+                    "warning: {} can't be transparent, so its faded edges won't show",
+                    output.display()
+                );
+            }
+        }
+
+        if let Some(watermark) = &watermark {
+            let HexColor(mut color) = watermark_color;
+            color[3] = (f32::from(color[3]) * watermark_opacity).round() as u8;
+            mosaic = draw_watermark(&mosaic, watermark, watermark_corner, watermark_size, color);
+        }
+
+        if respect_source_alpha && background.is_none() && !supports_alpha(&output) {
+            eprintln!(
+                "warning: {} can't be transparent, so the cells left empty won't show as such",
+                output.display()
+            );
+        }
+
+        if let Some(arrangement) = side_by_side {
+            metadata::save_with_metadata(
+                &make_side_by_side(&source, &mosaic, arrangement, side_by_side_labels)?,
+                &output.with_file_name(format!("{}.side-by-side{mosaic_size}.png", stem)),
+                output_metadata,
+                None,
+            )?;
+        }
+
+        if layered_tiff {
+            let source = source.resize_exact(mosaic.width(), mosaic.height(), FilterType::Triangle);
+            layers::save_layered_tiff(
+                &output.with_file_name(format!("{}.layers{mosaic_size}.tiff", stem)),
+                &[("mosaic", &mosaic), ("source", &source)],
+            )?;
+        }
+
+        if let Some(golden) = &compare_golden {
+            let golden = if golden.is_dir() {
+                golden.join(output.file_name().unwrap_or_default())
+            } else {
+                golden.clone()
+            };
+            let diff = golden_diff
+                .as_deref()
+                .map(|diff| per_input_path(diff, &stem));
+            if !golden::check(
+                &mosaic,
+                &golden,
+                tile_size,
+                golden_tolerance,
+                diff.as_deref(),
+            )? {
+                golden_mismatches += 1;
+            }
+        }
+
+        let span = profile::span("encode");
         let spinner = make_spinner("Saving", "Saved!");
-        mosaic.save(output)?;
+        if indexed_png {
+            metadata::save_indexed_png(&mosaic, &output, output_metadata, quantize_palette)?;
+        } else {
+            metadata::save_with_metadata(
+                &mosaic,
+                &output,
+                output_metadata,
+                max_file_size.map(|kb| u64::from(kb) * 1024),
+            )?;
+        }
+        for &scale in output_scales.iter().flat_map(|scales| &scales.0) {
+            let (width, height) = (
+                ((f64::from(mosaic.width()) * scale).round() as u32).max(1),
+                ((f64::from(mosaic.height()) * scale).round() as u32).max(1),
+            );
+            if (width, height) == mosaic.dimensions() {
+                continue;
+            }
+            let scaled = mosaic.resize_exact(width, height, FilterType::Triangle);
+            let path = output.with_extension(format!(
+                "{}x{}.{}",
+                width,
+                height,
+                output.extension().unwrap_or_default().to_string_lossy()
+            ));
+            if indexed_png {
+                metadata::save_indexed_png(&scaled, &path, output_metadata, quantize_palette)?;
+            } else {
+                metadata::save_with_metadata(
+                    &scaled,
+                    &path,
+                    output_metadata,
+                    max_file_size.map(|kb| u64::from(kb) * 1024),
+                )?;
+            }
+        }
         spinner.finish_using_style();
+        drop(span);
+
+        if let (Some(html_map), Some(cells)) = (&html_map, &placed_cells) {
+            let page = per_input_path(html_map, &stem);
+            let image_src = html::image_source(&page, &output, html_embed)?;
+            fs::write(
+                &page,
+                html::make_html_map(
+                    &image_src,
+                    &stem,
+                    cells,
+                    possible_tiles,
+                    img.width(),
+                    img.height(),
+                    offset_rows,
+                ),
+            )?;
+        }
+
+        if let Some(format) = &low_bandwidth_preview {
+            let preview = output.with_extension(format!("preview.{format}"));
+            let (small, _before, _after) = quantize::median_cut(
+                &mosaic.thumbnail(preview_size, preview_size),
+                preview_colors as usize,
+            );
+            if format == "png" {
+                metadata::save_indexed_png(&small, &preview, metadata::Metadata::default(), false)?;
+            } else {
+                small.save(&preview)?;
+            }
+            eprintln!(
+                "Saved a preview of {} bytes to {}",
+                fs::metadata(&preview)?.len(),
+                preview.display()
+            );
+        }
+
+        if let Some(tile_pyramid) = &tile_pyramid {
+            pyramid::save_pyramid(&mosaic, &tile_pyramid.join(&stem))?;
+        }
+
+        #[cfg(feature = "exr")]
+        if let Some(linear_exr) = &linear_exr {
+            exr::save_linear_exr(&mosaic, &per_input_path(linear_exr, &stem))?;
+        }
+
+        if let Some(transition) = &transition {
+            transition::save_transition(
+                &source,
+                &mosaic,
+                &per_input_path(transition, &stem),
+                transition_frames,
+                transition_duration,
+            )?;
+        }
+
+        if contact_sheet.is_some() {
+            previews.push((
+                output
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+                mosaic.thumbnail(contact_sheet_size, contact_sheet_size),
+            ));
+        }
+        checkpoint.finish(&output)?;
+    }
+
+    if let Some(contact_sheet) = contact_sheet.filter(|_| !previews.is_empty()) {
+        make_contact_sheet(&previews, contact_sheet_columns, contact_sheet_size)?
+            .save(&contact_sheet)?;
+        eprintln!(
+            "Saved a contact sheet of {} mosaics to {}",
+            previews.len(),
+            contact_sheet.display()
+        );
+    }
+
+    if let (Some(cache), Some(match_cache)) = (&cache, &match_cache) {
+        cache.save(match_cache)?;
+    }
+
+    if let Some(profile) = profile {
+        profile::write_folded(&profile)?;
+    }
+
+    if golden_mismatches > 0 {
+        bail!("{golden_mismatches} mosaics didn't match their golden images");
     }
 
     Ok(())
@@ -0,0 +1,227 @@
+//! Remembering which tile every color was matched to across runs, for `--match-cache`
+//!
+//! Colors are rounded to `LEVELS` levels per channel before they're matched, so that the many
+//! slightly different colors of photos share their matches, from one image to the next. Each
+//! one gets the tile closest to its rounded color, which is never more than half a level off.
+//!
+//! The cache is a little endian binary file holding any number of sections, each one for a
+//! tileset matched with some settings: its fingerprint, the paths of the tiles it picked, and
+//! then each rounded color matched along with which of those paths it got. The fingerprint
+//! covers every tile's path, color and weight as well as the settings, so a section only ever
+//! gets used for the exact same tileset matched the same way, and is otherwise left alone.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, Result};
+use image::Rgba;
+
+use crate::tiles::Tile;
+
+/// What every match cache starts with
+const MAGIC: &[u8; 8] = b"THEMISMC";
+
+/// The version of the format, to be bumped whenever it changes
+const VERSION: u32 = 2;
+
+/// How many levels each channel of a color is rounded to before it's matched
+pub const LEVELS: u8 = 64;
+
+/// Round a color to the middle of its range of `LEVELS` levels per channel
+pub fn quantize(color: Rgba<u8>) -> Rgba<u8> {
+    let step = (256 / u16::from(LEVELS)) as u8;
+    Rgba(color.0.map(|channel| channel / step * step + step / 2))
+}
+
+/// The hash FNV-1a starts from, before hashing any bytes
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Hash bytes with 64-bit FNV-1a, which unlike the standard library's hasher is guaranteed to
+/// give the same hash on every run and every machine
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Fingerprint a tileset along with a description of the settings it's matched with
+pub fn fingerprint(possible_tiles: &[Tile], settings: &str) -> u64 {
+    let mut hash = fnv1a(FNV_OFFSET, settings.as_bytes());
+    for tile in possible_tiles {
+        hash = fnv1a(hash, tile.path.to_string_lossy().as_bytes());
+        hash = fnv1a(hash, &tile.average.0);
+        hash = fnv1a(hash, &tile.weight.to_bits().to_le_bytes());
+        hash = fnv1a(hash, &tile.penalty.to_le_bytes());
+    }
+    hash
+}
+
+/// The tiles chosen for the colors matched against one tileset with some settings
+#[derive(Debug, Default)]
+struct Section {
+    /// The path of every tile chosen, once
+    paths: Vec<PathBuf>,
+
+    /// Where each of `paths` is in it
+    path_ids: HashMap<PathBuf, u32>,
+
+    /// The tile chosen for every color, in `paths`
+    matches: HashMap<Rgba<u8>, u32>,
+}
+
+impl Section {
+    fn insert(&mut self, color: Rgba<u8>, path: &Path) {
+        let id = match self.path_ids.get(path) {
+            Some(&id) => id,
+            None => {
+                let id = self.paths.len() as u32;
+                self.paths.push(path.to_owned());
+                self.path_ids.insert(path.to_owned(), id);
+                id
+            }
+        };
+        self.matches.insert(color, id);
+    }
+}
+
+/// The tiles chosen for colors, by the fingerprint of the tileset and settings they were
+/// matched with
+#[derive(Debug, Default)]
+pub struct MatchCache {
+    sections: HashMap<u64, Section>,
+}
+
+impl MatchCache {
+    /// Load a match cache, or start an empty one if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let bytes = fs::read(path)?;
+        let mut bytes = &bytes[..];
+        let mut take = |len: usize| -> Result<&[u8]> {
+            if bytes.len() < len {
+                bail!("the match cache {} is truncated", path.display());
+            }
+            let (taken, rest) = bytes.split_at(len);
+            bytes = rest;
+            Ok(taken)
+        };
+
+        if take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            bail!("{} is not a match cache", path.display());
+        }
+        let version = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        if version != VERSION {
+            eprintln!(
+                "warning: {} is a version {} match cache, starting over with version {}",
+                path.display(),
+                version,
+                VERSION
+            );
+            return Ok(Self::default());
+        }
+
+        let mut sections = HashMap::new();
+        let section_count = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        for _ in 0..section_count {
+            let fingerprint = u64::from_le_bytes(take(8)?.try_into().unwrap());
+            let mut section = Section::default();
+            let path_count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+            for id in 0..path_count {
+                let len = u32::from_le_bytes(take(4)?.try_into().unwrap());
+                let path = PathBuf::from(String::from_utf8_lossy(take(len as usize)?).into_owned());
+                section.paths.push(path.clone());
+                section.path_ids.insert(path, id);
+            }
+            let len = u64::from_le_bytes(take(8)?.try_into().unwrap());
+            for _ in 0..len {
+                let color = Rgba(take(4)?.try_into().unwrap());
+                let id = u32::from_le_bytes(take(4)?.try_into().unwrap());
+                if id >= path_count {
+                    bail!("the match cache {} is corrupt", path.display());
+                }
+                section.matches.insert(color, id);
+            }
+            sections.insert(fingerprint, section);
+        }
+        Ok(Self { sections })
+    }
+
+    /// Save the cache, sorted so that the same cache always makes the same file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend((self.sections.len() as u64).to_le_bytes());
+        let mut sections = self.sections.iter().collect::<Vec<_>>();
+        sections.sort_unstable_by_key(|&(&fingerprint, _matches)| fingerprint);
+        for (fingerprint, section) in sections {
+            bytes.extend(fingerprint.to_le_bytes());
+            bytes.extend((section.paths.len() as u32).to_le_bytes());
+            for path in &section.paths {
+                let path = path.to_string_lossy();
+                bytes.extend((path.len() as u32).to_le_bytes());
+                bytes.extend(path.as_bytes());
+            }
+            bytes.extend((section.matches.len() as u64).to_le_bytes());
+            let mut matches = section.matches.iter().collect::<Vec<_>>();
+            matches.sort_unstable_by_key(|&(color, _id)| color.0);
+            for (color, id) in matches {
+                bytes.extend(color.0);
+                bytes.extend(id.to_le_bytes());
+            }
+        }
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Look up the path of the tile chosen for a rounded color
+    pub fn get(&self, fingerprint: u64, color: Rgba<u8>) -> Option<&Path> {
+        let section = self.sections.get(&fingerprint)?;
+        let id = section.matches.get(&color)?;
+        Some(&section.paths[*id as usize])
+    }
+
+    /// Remember the path of the tile chosen for a rounded color
+    pub fn insert(&mut self, fingerprint: u64, color: Rgba<u8>, path: &Path) {
+        self.sections
+            .entry(fingerprint)
+            .or_default()
+            .insert(color, path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantized_colors_are_half_a_level_off_at_most() {
+        for channel in 0..=255 {
+            let quantized = quantize(Rgba([channel; 4]))[0];
+            assert!((i32::from(quantized) - i32::from(channel)).abs() <= 2);
+            assert_eq!(quantize(Rgba([quantized; 4]))[0], quantized);
+        }
+    }
+
+    #[test]
+    fn matches_survive_saving_and_loading() {
+        let path = std::env::temp_dir().join(format!("themis-match-cache-{}", std::process::id()));
+        let (red, blue) = (Rgba([254, 2, 2, 254]), Rgba([2, 2, 254, 254]));
+        let mut cache = MatchCache::default();
+        cache.insert(1, red, Path::new("tiles/red, really.png"));
+        cache.insert(1, blue, Path::new("tiles/blue.png"));
+        cache.insert(2, red, Path::new("other/red.png"));
+        cache.save(&path).unwrap();
+
+        let loaded = MatchCache::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get(1, red), Some(Path::new("tiles/red, really.png")));
+        assert_eq!(loaded.get(1, blue), Some(Path::new("tiles/blue.png")));
+        assert_eq!(loaded.get(2, red), Some(Path::new("other/red.png")));
+        assert_eq!(loaded.get(2, blue), None);
+        assert_eq!(loaded.get(3, red), None);
+    }
+}
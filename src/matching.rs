@@ -0,0 +1,1304 @@
+//! Picking the most appropiate tile for each pixel of the target image
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use eyre::{bail, Result};
+use image::Rgba;
+use rayon::prelude::*;
+
+use crate::dominant::{dominant_distance, Dominant};
+use crate::expr::DistanceExpr;
+use crate::make_pbar;
+use crate::tiles::{Orientation, Tile};
+
+/// Per-channel multipliers applied on top of the weighted RGB metric in `distance`
+///
+/// The color weights are normalized so that they sum to 3, which means that `1,1,1` (the
+/// default) reproduces the unweighted metric exactly. Alpha isn't taken into account unless
+/// given a weight with `with_alpha`. They're stored as fixed point numbers with 8 fractional
+/// bits so that `distance` can stay in integer arithmetic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelWeights([i64; 4]);
+
+impl ChannelWeights {
+    /// Also take alpha into account, weighing it like an unweighted green channel at 1
+    pub fn with_alpha(self, weight: f64) -> Self {
+        let Self([r, g, b, _a]) = self;
+        Self([r, g, b, (weight * 256.).round() as i64])
+    }
+}
+
+impl FromStr for ChannelWeights {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let weights = s
+            .split(',')
+            .map(|w| w.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let weights: [f64; 3] = match weights[..] {
+            [r, g, b] => [r, g, b],
+            _ => bail!(
+                "expected three comma-separated weights (R,G,B), got {:?}",
+                s
+            ),
+        };
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.) {
+            bail!("channel weights must be non-negative, got {:?}", s);
+        }
+        let sum = weights.iter().sum::<f64>();
+        if sum == 0. {
+            bail!("channel weights must not all be zero");
+        }
+        let [r, g, b] = weights.map(|w| (w / sum * 3. * 256.).round() as i64);
+        Ok(Self([r, g, b, 0]))
+    }
+}
+
+/// What cells and tiles are compared by when matching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchMode {
+    /// Their average colors
+    Average,
+
+    /// The average colors of their top and bottom halves, to capture vertical gradients
+    VerticalSplit,
+
+    /// The luminance of their average colors alone, leaving the color to be painted back onto
+    /// the tiles
+    ToneThenColor,
+}
+
+impl FromStr for MatchMode {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "average" => Ok(Self::Average),
+            "vertical-split" => Ok(Self::VerticalSplit),
+            "tone-then-color" => Ok(Self::ToneThenColor),
+            _ => bail!("unknown match mode {:?}", s),
+        }
+    }
+}
+
+/// What the matching pass spreads across threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchParallelism {
+    /// Both the cells and the scan through the tiles for each one, leaving it up to rayon
+    Both,
+
+    /// Only the cells, scanning the tiles for each one on a single thread
+    Pixels,
+
+    /// Only the scan through the tiles, matching one cell at a time
+    Tiles,
+}
+
+impl FromStr for MatchParallelism {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "both" => Ok(Self::Both),
+            "pixels" => Ok(Self::Pixels),
+            "tiles" => Ok(Self::Tiles),
+            _ => bail!("unknown match parallelism {:?}", s),
+        }
+    }
+}
+
+/// Calculate the distance (squared) between two colors
+/// Code adapted from https://stackoverflow.com/a/9085524/13204109
+pub fn distance(
+    Rgba([r1, g1, b1, a1]): Rgba<u8>,
+    Rgba([r2, g2, b2, a2]): Rgba<u8>,
+    ChannelWeights([wr, wg, wb, wa]): ChannelWeights,
+) -> i64 {
+    let rmean = (i64::from(r1) + i64::from(r2)) / 2;
+    let r = i64::from(r1) - i64::from(r2);
+    let g = i64::from(g1) - i64::from(g2);
+    let b = i64::from(b1) - i64::from(b2);
+    let a = i64::from(a1) - i64::from(a2);
+    ((((512 + rmean) * r * r) >> 8) * wr
+        + 4 * g * g * wg
+        + (((767 - rmean) * b * b) >> 8) * wb
+        + 4 * a * a * wa)
+        >> 8
+}
+
+/// Convert an sRGB color to CIELAB under the D65 white point, ignoring alpha
+pub fn to_lab(Rgba([r, g, b, _a]): Rgba<u8>) -> [f64; 3] {
+    let linear = |c: u8| {
+        let c = f64::from(c) / 255.;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f64| {
+        if t > 216. / 24389. {
+            t.cbrt()
+        } else {
+            (24389. / 27. * t + 16.) / 116.
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116. * fy - 16., 500. * (fx - fy), 200. * (fy - fz)]
+}
+
+/// Convert a CIELAB color under the D65 white point back to sRGB, or `None` if it's outside of
+/// what sRGB can show
+pub fn from_lab([l, a, b]: [f64; 3]) -> Option<Rgba<u8>> {
+    let fy = (l + 16.) / 116.;
+    let (fx, fz) = (fy + a / 500., fy - b / 200.);
+    let f_inverse = |f: f64| {
+        if f.powi(3) > 216. / 24389. {
+            f.powi(3)
+        } else {
+            (116. * f - 16.) * 27. / 24389.
+        }
+    };
+    let (x, y, z) = (
+        f_inverse(fx) * 0.95047,
+        f_inverse(fy),
+        f_inverse(fz) * 1.08883,
+    );
+    let r = 3.2406 * x - 1.5372 * y - 0.4986 * z;
+    let g = -0.9689 * x + 1.8758 * y + 0.0415 * z;
+    let b = 0.0557 * x - 0.2040 * y + 1.0570 * z;
+
+    let gamma = |c: f64| {
+        if !(-0.001..=1.001).contains(&c) {
+            return None;
+        }
+        let c = c.clamp(0., 1.);
+        let c = if c <= 0.003_130_8 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1. / 2.4) - 0.055
+        };
+        Some((c * 255.).round() as u8)
+    };
+    Some(Rgba([gamma(r)?, gamma(g)?, gamma(b)?, 255]))
+}
+
+/// Calculate the CIE76 color difference between two CIELAB colors, where a difference of
+/// around 2.3 is just noticeable
+pub fn delta_e([l1, a1, b1]: [f64; 3], [l2, a2, b2]: [f64; 3]) -> f64 {
+    ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt()
+}
+
+/// An index of the tileset which buckets tiles by their quantized average color, so that only
+/// the tiles in the buckets around a pixel need to be compared against it
+///
+/// Buckets are searched in shells of growing radius around the pixel's own, and the search
+/// stops once even the closest a tile in the next shell could possibly be is farther than the
+/// best tile found so far, so the tile found is always the one an exhaustive search would find.
+pub struct CoarseIndex {
+    bins: u32,
+
+    /// Whether tiles are bucketed and ranked by their average color in CIELAB, by ΔE, rather
+    /// than in RGB by `distance`
+    lab: bool,
+
+    buckets: HashMap<[u32; 3], Vec<usize>>,
+
+    /// Every tile's average color in CIELAB, when ranking by it
+    tiles_lab: Vec<[f64; 3]>,
+
+    /// The largest weight and the smallest penalty of any tile, to bound how close the tiles of
+    /// far away buckets can get
+    max_weight: f64,
+    min_penalty: i64,
+}
+
+/// Where the a* and b* axes of CIELAB are bucketed from and to, which covers all of sRGB
+const LAB_AB_RANGE: (f64, f64) = (-128., 128.);
+
+impl CoarseIndex {
+    /// Build the index, quantizing each channel into `bins` levels, in CIELAB if `lab` is set
+    pub fn new(possible_tiles: &[Tile], bins: u32, lab: bool) -> Self {
+        let bins = bins.clamp(1, 256);
+        let tiles_lab = if lab {
+            possible_tiles
+                .iter()
+                .map(|tile| to_lab(tile.average))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mut buckets = HashMap::<_, Vec<_>>::new();
+        for (idx, tile) in possible_tiles.iter().enumerate() {
+            let bin = if lab {
+                Self::lab_bin_of(tiles_lab[idx], bins)
+            } else {
+                Self::bin_of(tile.average, bins)
+            };
+            buckets.entry(bin).or_default().push(idx);
+        }
+        Self {
+            bins,
+            lab,
+            buckets,
+            tiles_lab,
+            max_weight: possible_tiles
+                .iter()
+                .map(|tile| tile.weight)
+                .fold(f64::MIN_POSITIVE, f64::max),
+            min_penalty: possible_tiles
+                .iter()
+                .map(|tile| tile.penalty)
+                .min()
+                .unwrap_or(0),
+        }
+    }
+
+    fn bin_of(Rgba([r, g, b, _]): Rgba<u8>, bins: u32) -> [u32; 3] {
+        [r, g, b].map(|c| u32::from(c) * bins / 256)
+    }
+
+    fn lab_bin_of([l, a, b]: [f64; 3], bins: u32) -> [u32; 3] {
+        let (lo, hi) = LAB_AB_RANGE;
+        let bin = |v: f64, lo: f64, hi: f64| {
+            (((v - lo) / (hi - lo) * f64::from(bins)) as u32).min(bins - 1)
+        };
+        [bin(l, 0., 100.), bin(a, lo, hi), bin(b, lo, hi)]
+    }
+
+    /// The smallest distance a tile `radius` buckets away from the pixel's along some channel
+    /// could be at, before weighing it
+    fn lower_bound(&self, radius: u32, weights: ChannelWeights) -> f64 {
+        if radius <= 1 {
+            return 0.;
+        }
+        if self.lab {
+            // The lightness axis has the narrowest buckets
+            f64::from(radius - 1) * 100. / f64::from(self.bins)
+        } else {
+            // Colors whose channel is in buckets that far apart differ by more than this in it,
+            // and the channel adds the least to `distance` when red and blue are at their
+            // smallest and largest respectively
+            let d = i64::from((radius - 1) * 256 / self.bins);
+            let ChannelWeights([wr, wg, wb, _wa]) = weights;
+            ((2 * d * d * wr).min(4 * d * d * wg).min(2 * d * d * wb) >> 8) as f64
+        }
+    }
+
+    /// How well a tile matches the pixel, lower being better, weighed like `Tile::weigh`
+    fn score(
+        &self,
+        pixel: Rgba<u8>,
+        pixel_lab: [f64; 3],
+        tile: &Tile,
+        idx: usize,
+        weights: ChannelWeights,
+    ) -> f64 {
+        if self.lab {
+            tile.weigh_f64(delta_e(pixel_lab, self.tiles_lab[idx]))
+        } else {
+            tile.weigh(distance(tile.average, pixel, weights)) as f64
+        }
+    }
+
+    /// Whether no tile in the buckets the given number of bins away from the pixel's can beat
+    /// the best one found so far
+    fn beyond(&self, radius: u32, weights: ChannelWeights, best: Option<(f64, usize)>) -> bool {
+        best.is_some_and(|(best, _idx)| {
+            let bound = self.lower_bound(radius, weights) + self.min_penalty as f64;
+            let bound = if self.lab {
+                bound / self.max_weight
+            } else {
+                (bound / self.max_weight).round()
+            };
+            bound > best
+        })
+    }
+
+    /// Find the tile closest to the pixel, the one with the lowest index among equally close
+    /// ones, looking through as few buckets as it takes to be sure of it
+    pub fn nearest(
+        &self,
+        pixel: Rgba<u8>,
+        possible_tiles: &[Tile],
+        weights: ChannelWeights,
+    ) -> Option<usize> {
+        let pixel_lab = to_lab(pixel);
+        let center = if self.lab {
+            Self::lab_bin_of(pixel_lab, self.bins)
+        } else {
+            Self::bin_of(pixel, self.bins)
+        };
+        let mut best = None::<(f64, usize)>;
+        let consider = |best: &mut Option<(f64, usize)>, tiles: &[usize]| {
+            for &idx in tiles {
+                let score = self.score(pixel, pixel_lab, &possible_tiles[idx], idx, weights);
+                let better = best.is_none_or(|(best, best_idx)| {
+                    score.total_cmp(&best).then(idx.cmp(&best_idx)).is_lt()
+                });
+                if better {
+                    *best = Some((score, idx));
+                }
+            }
+        };
+
+        for radius in 0..self.bins {
+            if self.beyond(radius, weights, best) {
+                break;
+            }
+
+            // Past some radius, going through the shell's buckets one by one takes longer than
+            // going through every bucket there is in order of how far away they are
+            let side = 2 * u64::from(radius) + 1;
+            if side.pow(3) > self.buckets.len() as u64 {
+                let mut rest = self
+                    .buckets
+                    .iter()
+                    .map(|(bin, tiles)| {
+                        let far = bin
+                            .iter()
+                            .zip(&center)
+                            .map(|(&c, &center)| c.abs_diff(center))
+                            .max()
+                            .unwrap_or(0);
+                        (far, tiles)
+                    })
+                    .filter(|&(far, _tiles)| far >= radius)
+                    .collect::<Vec<_>>();
+                rest.sort_unstable_by_key(|&(far, _tiles)| far);
+                for (far, tiles) in rest {
+                    if self.beyond(far, weights, best) {
+                        break;
+                    }
+                    consider(&mut best, tiles);
+                }
+                break;
+            }
+
+            let lo = center.map(|c| c.saturating_sub(radius));
+            let hi = center.map(|c| (c + radius).min(self.bins - 1));
+            for r in lo[0]..=hi[0] {
+                for g in lo[1]..=hi[1] {
+                    for b in lo[2]..=hi[2] {
+                        let on_shell = [r, g, b]
+                            .iter()
+                            .zip(&center)
+                            .any(|(&c, &center)| c.abs_diff(center) == radius);
+                        if !on_shell {
+                            continue;
+                        }
+                        if let Some(tiles) = self.buckets.get(&[r, g, b]) {
+                            consider(&mut best, tiles);
+                        }
+                    }
+                }
+            }
+        }
+        best.map(|(_score, idx)| idx)
+    }
+}
+
+/// Choose the tile in the given tileset whose average color is closest to the given pixel,
+/// returning its index
+///
+/// If an index is given, only the tiles in the buckets around the pixel are compared, which is
+/// much faster for large tilesets, and they're compared by ΔE if the index is in CIELAB.
+pub fn pick_image_for_pixel(
+    pixel: Rgba<u8>,
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+    index: Option<&CoarseIndex>,
+) -> Option<usize> {
+    match index {
+        Some(index) => index.nearest(pixel, possible_tiles, weights),
+
+        None => possible_tiles
+            .into_par_iter()
+            .enumerate()
+            .min_by_key(|&(idx, tile)| (tile.weigh(distance(tile.average, pixel, weights)), idx))
+            .map(|(idx, _tile)| idx),
+    }
+}
+
+/// Like `pick_image_for_pixel` without an index, but scanning the tiles on the current thread,
+/// for when the pixels are already spread across threads
+pub fn pick_image_for_pixel_sequential(
+    pixel: Rgba<u8>,
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+) -> Option<usize> {
+    possible_tiles
+        .iter()
+        .enumerate()
+        .min_by_key(|(_idx, tile)| tile.weigh(distance(tile.average, pixel, weights)))
+        .map(|(idx, _tile)| idx)
+}
+
+/// Choose the tile whose average color is closest to the given pixel by a user supplied
+/// formula, returning its index
+///
+/// Tiles whose distance comes out as NaN are never picked.
+pub fn pick_image_for_pixel_by(
+    pixel: Rgba<u8>,
+    possible_tiles: &[Tile],
+    expr: &DistanceExpr,
+) -> Option<usize> {
+    possible_tiles
+        .into_par_iter()
+        .enumerate()
+        .map(|(idx, tile)| (idx, tile.weigh_f64(expr.eval(pixel, tile.average))))
+        .filter(|(_idx, distance)| !distance.is_nan())
+        .min_by(|(a_idx, a), (b_idx, b)| a.total_cmp(b).then(a_idx.cmp(b_idx)))
+        .map(|(idx, _distance)| idx)
+}
+
+/// Choose the tile whose average color is closest to the given pixel in CIELAB, returning its
+/// index, given every tile's average color in CIELAB
+///
+/// Tiles are compared one by one and the first one within `threshold` of the pixel is picked
+/// right away, as anything closer would look the same, so the tile picked is never more than
+/// `threshold` farther than the closest one.
+pub fn pick_image_for_pixel_within(
+    pixel: Rgba<u8>,
+    possible_tiles: &[Tile],
+    tiles_lab: &[[f64; 3]],
+    threshold: f64,
+) -> Option<usize> {
+    let pixel = to_lab(pixel);
+    let mut best = None;
+    for (idx, (tile, &lab)) in possible_tiles.iter().zip(tiles_lab).enumerate() {
+        let difference = tile.weigh_f64(delta_e(pixel, lab));
+        if difference <= threshold {
+            return Some(idx);
+        }
+        if best.is_none_or(|(_idx, best)| difference < best) {
+            best = Some((idx, difference));
+        }
+    }
+    best.map(|(idx, _difference)| idx)
+}
+
+/// Choose the tile among the given candidates whose average color is closest to the given pixel,
+/// returning its index in the whole tileset
+pub fn pick_image_for_pixel_among(
+    pixel: Rgba<u8>,
+    possible_tiles: &[Tile],
+    candidates: &[usize],
+    weights: ChannelWeights,
+) -> Option<usize> {
+    candidates.par_iter().copied().min_by_key(|&idx| {
+        let tile = &possible_tiles[idx];
+        (tile.weigh(distance(tile.average, pixel, weights)), idx)
+    })
+}
+
+/// Choose the tile whose average color is closest to the given pixel, after adding `bias` times
+/// how far the tile's place along the tileset is from the cell's place along the image, both
+/// from 0 to 1
+pub fn pick_image_for_pixel_at(
+    pixel: Rgba<u8>,
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+    places: &[f64],
+    place: f64,
+    bias: f64,
+) -> Option<usize> {
+    possible_tiles
+        .par_iter()
+        .zip(places)
+        .enumerate()
+        .min_by_key(|&(idx, (tile, &tile_place))| {
+            let offset = (bias * (tile_place - place).abs()).round() as i64;
+            (
+                tile.weigh(distance(tile.average, pixel, weights) + offset),
+                idx,
+            )
+        })
+        .map(|(idx, _tile)| idx)
+}
+
+/// Like `pick_image_for_pixel_sequential`, but adding `penalty` to the distance of the tile the
+/// cell had in the previous frame, if any, so that it changes from frame to frame wherever
+/// another tile fits about as well
+pub fn pick_image_for_pixel_avoiding(
+    pixel: Rgba<u8>,
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+    previous: Option<usize>,
+    penalty: i64,
+) -> Option<usize> {
+    possible_tiles
+        .iter()
+        .enumerate()
+        .min_by_key(|&(idx, tile)| {
+            let penalty = if Some(idx) == previous { penalty } else { 0 };
+            (
+                tile.weigh(distance(tile.average, pixel, weights) + penalty),
+                idx,
+            )
+        })
+        .map(|(idx, _tile)| idx)
+}
+
+/// For every value of a single channel, choose the tile whose average color's value for that
+/// channel is closest to it, returning their indices
+pub fn nearest_by_channel(possible_tiles: &[Tile], channel: usize) -> Option<[usize; 256]> {
+    let mut nearest = [0; 256];
+    for (value, nearest) in nearest.iter_mut().enumerate() {
+        *nearest = possible_tiles
+            .iter()
+            .enumerate()
+            .min_by_key(|(_idx, tile)| {
+                let difference = i64::from(tile.average[channel]) - value as i64;
+                tile.weigh(difference * difference)
+            })
+            .map(|(idx, _tile)| idx)?;
+    }
+    Some(nearest)
+}
+
+/// How to diffuse the error of each cell's tile onto the cells after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// Floyd–Steinberg, going through every row from left to right
+    FloydSteinberg,
+
+    /// Floyd–Steinberg, going through rows from left to right and right to left in turn, which
+    /// keeps the error from drifting in one direction and leaving diagonal streaks
+    Serpentine,
+}
+
+impl FromStr for Dither {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "floyd-steinberg" => Ok(Self::FloydSteinberg),
+            "serpentine" => Ok(Self::Serpentine),
+            _ => bail!("unknown dithering {:?}", s),
+        }
+    }
+}
+
+/// Choose a tile for every cell with error diffusion, returning their indices
+///
+/// Each cell is matched to the tile closest to its color plus the error diffused onto it so far,
+/// and how far that tile's average is from it is then spread over the cells to its right and
+/// below, in Floyd–Steinberg's proportions. Over a region, the tiles then average out to its
+/// color even when no single tile is close to it, trading banding for texture.
+pub fn pick_tiles_dithered(
+    pixels: &[Rgba<u8>],
+    width: usize,
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+    dither: Dither,
+) -> Option<Vec<usize>> {
+    let height = pixels.len() / width.max(1);
+    let mut errors = vec![[0f64; 3]; pixels.len()];
+    let mut cells = vec![0; pixels.len()];
+    // The colors cells end up wanting repeat a lot, so their tiles are remembered
+    let mut picks = HashMap::new();
+    let pbar = make_pbar("pixels", pixels.len() as _);
+    for y in 0..height {
+        let backwards = dither == Dither::Serpentine && y % 2 == 1;
+        for step in 0..width {
+            let x = if backwards { width - 1 - step } else { step };
+            let idx = y * width + x;
+            let Rgba([r, g, b, a]) = pixels[idx];
+            let wanted = [r, g, b]
+                .iter()
+                .zip(errors[idx])
+                .map(|(&channel, error)| (f64::from(channel) + error).round().clamp(0., 255.))
+                .collect::<Vec<_>>();
+            let target = Rgba([wanted[0] as u8, wanted[1] as u8, wanted[2] as u8, a]);
+            let tile = match picks.get(&target) {
+                Some(&tile) => tile,
+                None => {
+                    let tile = pick_image_for_pixel(target, possible_tiles, weights, None)?;
+                    picks.insert(target, tile);
+                    tile
+                }
+            };
+            cells[idx] = tile;
+
+            // Ahead is the direction the row is being gone through in
+            let average = possible_tiles[tile].average;
+            let error = [0, 1, 2].map(|channel| wanted[channel] - f64::from(average[channel]));
+            let ahead = |x: usize| {
+                if backwards {
+                    x.checked_sub(1)
+                } else {
+                    Some(x + 1)
+                }
+            };
+            let behind = |x: usize| {
+                if backwards {
+                    Some(x + 1)
+                } else {
+                    x.checked_sub(1)
+                }
+            };
+            let neighbors = [
+                (ahead(x), y, 7.),
+                (behind(x), y + 1, 3.),
+                (Some(x), y + 1, 5.),
+                (ahead(x), y + 1, 1.),
+            ];
+            for (nx, ny, share) in neighbors {
+                if let Some(nx) = nx.filter(|&nx| nx < width && ny < height) {
+                    let neighbor = &mut errors[ny * width + nx];
+                    for (neighbor, error) in neighbor.iter_mut().zip(error) {
+                        *neighbor += error * share / 16.;
+                    }
+                }
+            }
+            pbar.inc(1);
+        }
+    }
+    pbar.finish_using_style();
+    Some(cells)
+}
+
+/// How `pick_tiles_in_scan_order` takes the tiles already placed into account
+#[derive(Debug, Clone, Copy)]
+pub struct ScanOptions {
+    pub coherence: f64,
+    pub usage_penalty: u32,
+    pub min_tile_distance: u32,
+    pub max_reuse: Option<u32>,
+}
+
+/// The cells whose best tile had already been placed `max_reuse` times, so that they got the
+/// next best one instead
+#[derive(Debug, Default)]
+pub struct Spillover {
+    pub cells: usize,
+
+    /// How much further the tiles they got are from their cells, in total, than their best ones
+    pub extra_error: i64,
+}
+
+/// Choose a tile for every cell in scan order, taking into account the tiles already placed,
+/// returning their indices
+///
+/// Each tile's distance from its cell is increased by `coherence` times its mean distance from
+/// the tiles placed to the left and above, which gives smoother regions at the cost of following
+/// the image less closely, and by `usage_penalty` for every cell it's already been placed in,
+/// which spreads the cells of a color across the tiles that resemble it. With both at 0 every cell
+/// gets its closest tile as usual.
+///
+/// Tiles whose average is within `min_tile_distance` of a tile placed to the left or above are
+/// only picked if every tile is, in which case the best of them is. Likewise, tiles already
+/// placed `max_reuse` times are only picked once every tile has been, and the cells that had to
+/// do without their best tile because of it are counted in the returned spillover.
+pub fn pick_tiles_in_scan_order(
+    pixels: &[Rgba<u8>],
+    width: usize,
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+    options: ScanOptions,
+) -> Option<(Vec<usize>, Spillover)> {
+    let ScanOptions {
+        coherence,
+        usage_penalty,
+        min_tile_distance,
+        max_reuse,
+    } = options;
+    let coherence = (coherence * 256.).round() as i64;
+    let mut uses = vec![0i64; possible_tiles.len()];
+    let mut cells = Vec::<usize>::with_capacity(pixels.len());
+    let mut spillover = Spillover::default();
+    let pbar = make_pbar("pixels", pixels.len() as _);
+    for (idx, &pixel) in pixels.iter().enumerate() {
+        let left = (idx % width > 0).then(|| cells[idx - 1]);
+        let above = idx.checked_sub(width).map(|above| cells[above]);
+        let neighbors = left.into_iter().chain(above).collect::<Vec<_>>();
+
+        let score = |idx: usize, tile: &Tile| {
+            let fidelity = distance(tile.average, pixel, weights);
+            let overuse = uses[idx] * i64::from(usage_penalty);
+            let distances = neighbors
+                .iter()
+                .map(|&neighbor| distance(tile.average, possible_tiles[neighbor].average, weights))
+                .collect::<Vec<_>>();
+            let too_close = distances
+                .iter()
+                .any(|&distance| distance < i64::from(min_tile_distance));
+            if distances.is_empty() || coherence == 0 {
+                return (too_close, tile.weigh(fidelity + overuse));
+            }
+            let dissimilarity = distances.iter().sum::<i64>() / distances.len() as i64;
+            (
+                too_close,
+                tile.weigh(fidelity + overuse + ((coherence * dissimilarity) >> 8)),
+            )
+        };
+        let used_up = |idx: usize| max_reuse.is_some_and(|max_reuse| uses[idx] >= max_reuse.into());
+
+        let best = possible_tiles
+            .into_par_iter()
+            .enumerate()
+            .min_by_key(|&(idx, tile)| score(idx, tile))
+            .map(|(idx, _tile)| idx)?;
+        let tile = if used_up(best) {
+            let tile = possible_tiles
+                .into_par_iter()
+                .enumerate()
+                .min_by_key(|&(idx, tile)| (used_up(idx), score(idx, tile)))
+                .map(|(idx, _tile)| idx)?;
+            if tile != best {
+                spillover.cells += 1;
+                spillover.extra_error += distance(possible_tiles[tile].average, pixel, weights)
+                    - distance(possible_tiles[best].average, pixel, weights);
+            }
+            tile
+        } else {
+            best
+        };
+        uses[tile] += 1;
+        cells.push(tile);
+        pbar.inc(1);
+    }
+    pbar.finish_using_style();
+    Some((cells, spillover))
+}
+
+/// Choose the tile whose average color's luminance is closest to the given pixel's, whatever
+/// its color, returning its index
+pub fn pick_image_for_tone(pixel: Rgba<u8>, possible_tiles: &[Tile]) -> Option<usize> {
+    // Relative luminance, from 0 to 1020
+    let tone = |Rgba([r, g, b, _a]): Rgba<u8>| {
+        (218 * i64::from(r) + 732 * i64::from(g) + 74 * i64::from(b)) >> 8
+    };
+    let target = tone(pixel);
+    possible_tiles
+        .into_par_iter()
+        .enumerate()
+        .min_by_key(|&(idx, tile)| {
+            let difference = tone(tile.average) - target;
+            (tile.weigh(difference * difference), idx)
+        })
+        .map(|(idx, _tile)| idx)
+}
+
+/// Choose the tile whose top and bottom halves are closest to the given cell's, by the sum of
+/// both halves' distances, returning its index
+pub fn pick_image_for_halves(
+    [top, bottom]: [Rgba<u8>; 2],
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+) -> Option<usize> {
+    possible_tiles
+        .into_par_iter()
+        .enumerate()
+        .min_by_key(|&(idx, tile)| {
+            let [tile_top, tile_bottom] = tile.halves;
+            let score = distance(top, tile_top, weights) + distance(bottom, tile_bottom, weights);
+            (tile.weigh(score), idx)
+        })
+        .map(|(idx, _tile)| idx)
+}
+
+/// Choose the tile whose dominant colors are closest to the given cell's, returning its index
+pub fn pick_image_for_dominants(
+    dominants: &[Dominant],
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+) -> Option<usize> {
+    possible_tiles
+        .into_par_iter()
+        .enumerate()
+        .min_by_key(|&(idx, tile)| {
+            let score = dominant_distance(dominants, &tile.dominants, weights);
+            (tile.weigh(score), idx)
+        })
+        .map(|(idx, _tile)| idx)
+}
+
+/// Which tile goes in a cell, and how it's oriented
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Placement {
+    pub tile: usize,
+    pub orientation: Orientation,
+}
+
+impl Placement {
+    /// Place the tile as-is
+    pub fn new(tile: usize) -> Self {
+        Self {
+            tile,
+            orientation: Orientation::Identity,
+        }
+    }
+}
+
+/// Calculate the distance between two signatures, as the sum of the distances between their
+/// corresponding subregions
+pub fn signature_distance(a: &[Rgba<u8>], b: &[Rgba<u8>], weights: ChannelWeights) -> i64 {
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| distance(a, b, weights))
+        .sum()
+}
+
+/// Choose the tile, and the orientation of it, whose signature is closest to the given cell's
+pub fn pick_image_for_signature(
+    signature: &[Rgba<u8>],
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+) -> Option<Placement> {
+    possible_tiles
+        .into_par_iter()
+        .enumerate()
+        .flat_map_iter(|(idx, tile)| {
+            tile.signatures
+                .iter()
+                .enumerate()
+                .map(move |(nth, (orientation, tile_signature))| {
+                    (idx, tile, nth, *orientation, tile_signature)
+                })
+        })
+        .min_by_key(|&(idx, tile, nth, _orientation, tile_signature)| {
+            let score = tile.weigh(signature_distance(signature, tile_signature, weights));
+            (score, idx, nth)
+        })
+        .map(|(tile, _tile, _nth, orientation, _signature)| Placement { tile, orientation })
+}
+
+/// How many of the tiles closest to a cell on average `pick_image_for_signature_refined` compares
+/// the signatures of
+const SIGNATURE_SHORTLIST: usize = 32;
+
+/// Choose the tile, and the orientation of it, whose signature is closest to the given cell's
+/// among the `SIGNATURE_SHORTLIST` tiles whose average colors are closest to the cell's
+pub fn pick_image_for_signature_refined(
+    pixel: Rgba<u8>,
+    signature: &[Rgba<u8>],
+    possible_tiles: &[Tile],
+    weights: ChannelWeights,
+) -> Option<Placement> {
+    rank_tiles(possible_tiles, SIGNATURE_SHORTLIST, |tile| {
+        tile.weigh(distance(tile.average, pixel, weights))
+    })
+    .into_iter()
+    .flat_map(|(idx, _score)| {
+        possible_tiles[idx]
+            .signatures
+            .iter()
+            .map(move |(orientation, tile_signature)| (idx, *orientation, tile_signature))
+    })
+    .min_by_key(|(idx, _orientation, tile_signature)| {
+        possible_tiles[*idx].weigh(signature_distance(signature, tile_signature, weights))
+    })
+    .map(|(tile, orientation, _signature)| Placement { tile, orientation })
+}
+
+/// Score every tile and return the `count` best ones, best first, along with their scores
+pub fn rank_tiles<F>(possible_tiles: &[Tile], count: usize, score: F) -> Vec<(usize, i64)>
+where
+    F: Fn(&Tile) -> i64 + Sync,
+{
+    let mut scores = possible_tiles
+        .par_iter()
+        .enumerate()
+        .map(|(idx, tile)| (idx, score(tile)))
+        .collect::<Vec<_>>();
+    let by_score =
+        |&(a_idx, a): &(usize, i64), &(b_idx, b): &(usize, i64)| a.cmp(&b).then(a_idx.cmp(&b_idx));
+    if count < scores.len() {
+        scores.select_nth_unstable_by(count, by_score);
+        scores.truncate(count);
+    }
+    scores.sort_unstable_by(by_score);
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    use super::*;
+
+    /// A xorshift generator, so that the tests' random colors are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn color(&mut self) -> Rgba<u8> {
+            let [r, g, b, ..] = self.next().to_le_bytes();
+            Rgba([r, g, b, 255])
+        }
+    }
+
+    fn random_tiles(rng: &mut Rng, count: usize) -> Vec<Tile> {
+        (0..count)
+            .map(|_| {
+                let mut tile = Tile::solid(rng.color());
+                tile.weight = [1., 1., 0.5, 2.][rng.next() as usize % 4];
+                tile.penalty = [0, 0, 0, 300][rng.next() as usize % 4];
+                tile
+            })
+            .collect()
+    }
+
+    fn nearest_lab(pixel: Rgba<u8>, possible_tiles: &[Tile]) -> Option<usize> {
+        let pixel = to_lab(pixel);
+        (0..possible_tiles.len()).min_by(|&a, &b| {
+            let score = |idx: usize| {
+                let tile = &possible_tiles[idx];
+                tile.weigh_f64(delta_e(pixel, to_lab(tile.average)))
+            };
+            score(a).total_cmp(&score(b)).then(a.cmp(&b))
+        })
+    }
+
+    #[test]
+    fn coarse_index_finds_the_same_tile_as_an_exhaustive_search() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let possible_tiles = random_tiles(&mut rng, 300);
+        let pixels = (0..1000).map(|_| rng.color()).collect::<Vec<_>>();
+        for weights in ["1,1,1", "3,1,0.5", "0,1,1"] {
+            let weights = weights.parse::<ChannelWeights>().unwrap();
+            for bins in [1, 4, 16, 64] {
+                let index = CoarseIndex::new(&possible_tiles, bins, false);
+                for &pixel in &pixels {
+                    assert_eq!(
+                        pick_image_for_pixel(pixel, &possible_tiles, weights, Some(&index)),
+                        pick_image_for_pixel(pixel, &possible_tiles, weights, None),
+                        "{pixel:?} with {bins} bins and weights {weights:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn coarse_lab_index_finds_the_closest_tile_by_delta_e() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let possible_tiles = random_tiles(&mut rng, 300);
+        let weights = ChannelWeights::from_str("1,1,1").unwrap();
+        for bins in [1, 4, 16, 64] {
+            let index = CoarseIndex::new(&possible_tiles, bins, true);
+            for _ in 0..1000 {
+                let pixel = rng.color();
+                assert_eq!(
+                    index.nearest(pixel, &possible_tiles, weights),
+                    nearest_lab(pixel, &possible_tiles),
+                    "{pixel:?} with {bins} bins"
+                );
+            }
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored coarse_index_speedup --nocapture`
+    #[test]
+    #[ignore]
+    fn coarse_index_speedup() {
+        let mut rng = Rng(0x1234_5678_9abc_def1);
+        let possible_tiles = random_tiles(&mut rng, 20_000);
+        let pixels = (0..20_000).map(|_| rng.color()).collect::<Vec<_>>();
+        let weights = ChannelWeights::from_str("1,1,1").unwrap();
+
+        let start = Instant::now();
+        let exhaustive = pixels
+            .par_iter()
+            .map(|&pixel| pick_image_for_pixel_sequential(pixel, &possible_tiles, weights))
+            .collect::<Vec<_>>();
+        let exhaustive_time = start.elapsed();
+
+        for bins in [8, 16, 32] {
+            let start = Instant::now();
+            let index = CoarseIndex::new(&possible_tiles, bins, false);
+            let indexed = pixels
+                .par_iter()
+                .map(|&pixel| index.nearest(pixel, &possible_tiles, weights))
+                .collect::<Vec<_>>();
+            let indexed_time = start.elapsed();
+            assert_eq!(indexed, exhaustive);
+            println!(
+                "{} cells against {} tiles: {:?} exhaustively, {:?} with {} bins ({:.1}x)",
+                pixels.len(),
+                possible_tiles.len(),
+                exhaustive_time,
+                indexed_time,
+                bins,
+                exhaustive_time.as_secs_f64() / indexed_time.as_secs_f64()
+            );
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored match_cache_hit_rate --nocapture`
+    #[test]
+    #[ignore]
+    fn match_cache_hit_rate() {
+        use crate::match_cache::{self, MatchCache};
+
+        let mut rng = Rng(0x0bad_5eed_f00d_cafe);
+        let mut possible_tiles = random_tiles(&mut rng, 2_000);
+        for (idx, tile) in possible_tiles.iter_mut().enumerate() {
+            tile.path = format!("{idx}.png").into();
+        }
+        let weights = ChannelWeights::from_str("1,1,1").unwrap();
+        let fingerprint = match_cache::fingerprint(&possible_tiles, "");
+
+        // Photos of sorts: smooth gradients between two random colors, with some noise
+        let photos = (0..20)
+            .map(|_| {
+                let (from, to) = (rng.color(), rng.color());
+                (0..256 * 256)
+                    .map(|idx| {
+                        let t = f64::from(idx % 256 + idx / 256) / 510.;
+                        let noise = (rng.next() % 17) as f64 - 8.;
+                        Rgba([0, 1, 2, 3].map(|channel| {
+                            let (from, to) = (f64::from(from[channel]), f64::from(to[channel]));
+                            (from + (to - from) * t + noise).clamp(0., 255.) as u8
+                        }))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        for photo in &photos {
+            let colors = photo.iter().copied().collect::<HashSet<_>>();
+            colors.par_iter().for_each(|&color| {
+                pick_image_for_pixel_sequential(color, &possible_tiles, weights);
+            });
+        }
+        let uncached_time = start.elapsed();
+
+        // Going over the photos twice, as if running again over the same folder
+        let mut cache = MatchCache::default();
+        for run in ["first", "second"] {
+            let (mut hits, mut colors_seen) = (0, 0);
+            let start = Instant::now();
+            for photo in &photos {
+                let colors = photo
+                    .iter()
+                    .map(|&color| match_cache::quantize(color))
+                    .collect::<HashSet<_>>();
+                let missed = colors
+                    .iter()
+                    .copied()
+                    .filter(|&color| cache.get(fingerprint, color).is_none())
+                    .collect::<Vec<_>>();
+                let matched = missed
+                    .par_iter()
+                    .map(|&color| pick_image_for_pixel_sequential(color, &possible_tiles, weights))
+                    .collect::<Vec<_>>();
+                for (&color, tile) in missed.iter().zip(matched) {
+                    cache.insert(fingerprint, color, &possible_tiles[tile.unwrap()].path);
+                }
+                hits += colors.len() - missed.len();
+                colors_seen += colors.len();
+            }
+            let cached_time = start.elapsed();
+            println!(
+                "{} photos against {} tiles: {:?} matching every color, {:?} on the {} run with the cache, which had {:.1}% of the rounded colors ({:.1}x)",
+                photos.len(),
+                possible_tiles.len(),
+                uncached_time,
+                cached_time,
+                run,
+                hits as f64 * 100. / colors_seen as f64,
+                uncached_time.as_secs_f64() / cached_time.as_secs_f64()
+            );
+        }
+    }
+
+    #[test]
+    fn alpha_weight_prefers_opaque_tiles() {
+        let possible_tiles = [
+            Tile::solid(Rgba([255, 0, 0, 128])),
+            Tile::solid(Rgba([255, 0, 0, 255])),
+        ];
+        let red = Rgba([255, 0, 0, 255]);
+        let weights = ChannelWeights::from_str("1,1,1").unwrap();
+        assert_eq!(
+            pick_image_for_pixel(red, &possible_tiles, weights, None),
+            Some(0)
+        );
+        assert_eq!(
+            pick_image_for_pixel(red, &possible_tiles, weights.with_alpha(1.), None),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn vertical_split_tells_apart_tiles_with_the_same_average() {
+        let blue = Rgba([40, 80, 220, 255]);
+        let green = Rgba([40, 180, 60, 255]);
+        let two_tone =
+            |top, bottom| image::RgbaImage::from_fn(8, 8, |_x, y| if y < 4 { top } else { bottom });
+        let possible_tiles = [
+            Tile::from_image("green-on-blue.png", two_tone(green, blue)),
+            Tile::from_image("blue-on-green.png", two_tone(blue, green)),
+        ];
+        assert_eq!(possible_tiles[0].average, possible_tiles[1].average);
+
+        let weights = ChannelWeights::from_str("1,1,1").unwrap();
+        assert_eq!(
+            pick_image_for_halves([blue, green], &possible_tiles, weights),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn delta_e_threshold_stays_within_the_threshold_of_the_closest_tile() {
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        let possible_tiles = (0..500)
+            .map(|_| Tile::solid(rng.color()))
+            .collect::<Vec<_>>();
+        let tiles_lab = possible_tiles
+            .iter()
+            .map(|tile| to_lab(tile.average))
+            .collect::<Vec<_>>();
+        for threshold in [0., 2.3, 10.] {
+            for _ in 0..1000 {
+                let pixel = rng.color();
+                let pixel_lab = to_lab(pixel);
+                let closest = tiles_lab
+                    .iter()
+                    .map(|&lab| delta_e(pixel_lab, lab))
+                    .fold(f64::INFINITY, f64::min);
+                let picked =
+                    pick_image_for_pixel_within(pixel, &possible_tiles, &tiles_lab, threshold)
+                        .unwrap();
+                let picked = delta_e(pixel_lab, tiles_lab[picked]);
+                assert!(
+                    picked <= closest + threshold,
+                    "{:?} got a tile {} away at a threshold of {}, the closest is {} away",
+                    pixel,
+                    picked,
+                    threshold,
+                    closest
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_average_picker_adds_the_tiles_penalties() {
+        let gray = |shade| Rgba([shade, shade, shade, 255]);
+        let mut penalized = Tile::solid(gray(100));
+        penalized.penalty = 10_000;
+        let possible_tiles = [penalized, Tile::solid(gray(110))];
+        let tiles_lab = possible_tiles
+            .iter()
+            .map(|tile| to_lab(tile.average))
+            .collect::<Vec<_>>();
+        let expr = "(r1-r2)^2 + (g1-g2)^2 + (b1-b2)^2"
+            .parse::<DistanceExpr>()
+            .unwrap();
+        let (pixel, weights) = (gray(100), ChannelWeights::from_str("1,1,1").unwrap());
+
+        assert_eq!(
+            pick_image_for_pixel(pixel, &possible_tiles, weights, None),
+            Some(1)
+        );
+        assert_eq!(
+            pick_image_for_pixel_sequential(pixel, &possible_tiles, weights),
+            Some(1)
+        );
+        assert_eq!(
+            pick_image_for_pixel_by(pixel, &possible_tiles, &expr),
+            Some(1)
+        );
+        assert_eq!(
+            pick_image_for_pixel_within(pixel, &possible_tiles, &tiles_lab, 0.),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn signatures_match_structure_that_averages_miss() {
+        let (black, white) = (Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255]));
+        let split = |left_black: bool| {
+            image::RgbaImage::from_fn(8, 8, |x, y| {
+                let first_half = if left_black { x < 4 } else { y < 4 };
+                if first_half {
+                    black
+                } else {
+                    white
+                }
+            })
+        };
+        let cell = image::DynamicImage::ImageRgba8(split(true));
+        let pixel = crate::tiles::average_color(&cell);
+        let signature = crate::tiles::signature(&cell, 2);
+
+        let mut possible_tiles = vec![
+            Tile::solid(pixel),
+            Tile::from_image("left-black.png", split(true)),
+            Tile::from_image("top-black.png", split(false)),
+        ];
+        for tile in &mut possible_tiles {
+            let image = tile.image().unwrap();
+            tile.signatures = vec![(Orientation::Identity, crate::tiles::signature(&image, 2))];
+        }
+
+        let weights = ChannelWeights::from_str("1,1,1").unwrap();
+        assert_eq!(
+            pick_image_for_pixel(pixel, &possible_tiles, weights, None),
+            Some(0)
+        );
+        let refined =
+            pick_image_for_signature_refined(pixel, &signature, &possible_tiles, weights).unwrap();
+        assert_eq!(refined.tile, 1);
+    }
+
+    /// Run with `cargo test --release -- --ignored match_parallelism_speed --nocapture`
+    #[test]
+    #[ignore]
+    fn match_parallelism_speed() {
+        let mut rng = Rng(0x0bad_c0de_1234_5678);
+        let weights = ChannelWeights::from_str("1,1,1").unwrap();
+        for (pixel_count, tile_count) in [(8, 200_000), (20_000, 256)] {
+            let possible_tiles = random_tiles(&mut rng, tile_count);
+            let pixels = (0..pixel_count).map(|_| rng.color()).collect::<Vec<_>>();
+            let time = |match_pixel: &(dyn Fn(Rgba<u8>) -> Option<usize> + Sync), parallel| {
+                let start = Instant::now();
+                let tiles = if parallel {
+                    pixels.par_iter().map(|&pixel| match_pixel(pixel)).collect()
+                } else {
+                    pixels
+                        .iter()
+                        .map(|&pixel| match_pixel(pixel))
+                        .collect::<Vec<_>>()
+                };
+                (start.elapsed(), tiles)
+            };
+            let nested = |pixel| pick_image_for_pixel(pixel, &possible_tiles, weights, None);
+            let sequential =
+                |pixel| pick_image_for_pixel_sequential(pixel, &possible_tiles, weights);
+            let (both, expected) = time(&nested, true);
+            let (pixels_only, tiles) = time(&sequential, true);
+            assert_eq!(tiles, expected);
+            let (tiles_only, tiles) = time(&nested, false);
+            assert_eq!(tiles, expected);
+            println!(
+                "{} colors against {} tiles on {} threads: both {:?}, pixels {:?}, tiles {:?}",
+                pixel_count,
+                tile_count,
+                rayon::current_num_threads(),
+                both,
+                pixels_only,
+                tiles_only
+            );
+        }
+    }
+}
@@ -0,0 +1,585 @@
+//! Embedding metadata that the `image` crate can't write: ICC color profiles, carried over from
+//! the source image, the physical resolution to print at, and a record of what the mosaic was
+//! made from. Indexed color PNGs, which it can't write either, are saved here too
+//!
+//! Profiles are dropped when decoding too, so they're read and written straight from the
+//! underlying formats, for the ones that can hold them.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Cursor, Write};
+use std::path::Path;
+
+use eyre::{bail, Result};
+use image::codecs::jpeg::{JpegEncoder, PixelDensity};
+use image::io::Reader;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat, RgbaImage};
+use tiff::encoder::{colortype, Rational, TiffEncoder};
+use tiff::tags::{ResolutionUnit, Tag};
+
+/// The TIFF tag holding an ICC profile
+const ICC_PROFILE: Tag = Tag::Unknown(34675);
+
+/// The identifier of the JPEG APP2 segments holding an ICC profile
+const JPEG_ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+
+/// How many bytes of the profile fit in a single JPEG APP2 segment
+const JPEG_ICC_CHUNK: usize = 65535 - 2 - JPEG_ICC_MARKER.len() - 2;
+
+/// The quality JPEGs are saved at unless they have to fit in a size, the same as `image`'s
+const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+/// How many meters there are in an inch, PNG resolutions being per meter
+const METERS_PER_INCH: f64 = 0.0254;
+
+/// The keyword of the PNG text chunk holding the provenance's description
+const PROVENANCE_KEYWORD: &[u8] = b"themis:provenance";
+
+/// The private PNG chunk holding the provenance's thumbnail: ancillary, private and safe to copy
+const PNG_THUMBNAIL_CHUNK: png::chunk::ChunkType = png::chunk::ChunkType(*b"thMb");
+
+/// The identifier of the JPEG APP15 segment holding the provenance's thumbnail
+const JPEG_THUMBNAIL_MARKER: &[u8] = b"THEMIS_THUMBNAIL\0";
+
+/// The private TIFF tag holding the provenance's thumbnail
+const TIFF_THUMBNAIL: Tag = Tag::Unknown(65000);
+
+/// How many pixels along its longest side the provenance's thumbnail of the source has
+const PROVENANCE_THUMBNAIL_SIZE: u32 = 128;
+
+/// A record of what a mosaic was made from, for `--embed-provenance`
+#[derive(Debug, Clone)]
+pub struct Provenance {
+    /// The version of themis, the source's path and the command line the mosaic was made with
+    pub description: String,
+
+    /// A small thumbnail of the source, as a PNG
+    pub thumbnail: Vec<u8>,
+}
+
+impl Provenance {
+    /// Describe the mosaic of the given source, made with the current command line
+    pub fn new(source: &DynamicImage, source_path: &Path) -> Result<Self> {
+        let arguments = std::env::args()
+            .skip(1)
+            .map(|arg| {
+                if arg.is_empty() || arg.contains(char::is_whitespace) || arg.contains('"') {
+                    format!("{arg:?}")
+                } else {
+                    arg
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let description = format!(
+            "themis {}\nsource: {}\narguments: {}",
+            env!("CARGO_PKG_VERSION"),
+            source_path.display(),
+            arguments
+        );
+
+        let mut thumbnail = Vec::new();
+        source
+            .thumbnail(PROVENANCE_THUMBNAIL_SIZE, PROVENANCE_THUMBNAIL_SIZE)
+            .write_to(&mut Cursor::new(&mut thumbnail), ImageOutputFormat::Png)?;
+        Ok(Self {
+            description,
+            thumbnail,
+        })
+    }
+}
+
+/// Metadata to embed in a saved image, besides its pixels
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Metadata<'a> {
+    /// The ICC color profile that the pixels are in
+    pub profile: Option<&'a [u8]>,
+
+    /// The resolution to print at, in dots per inch
+    pub dpi: Option<u32>,
+
+    /// What the mosaic was made from
+    pub provenance: Option<&'a Provenance>,
+}
+
+/// Read the ICC profile embedded in the image at the given path, if it has one and its format
+/// is one of PNG, JPEG or TIFF
+pub fn read_profile(path: &Path) -> Result<Option<Vec<u8>>> {
+    let format = Reader::open(path)?.with_guessed_format()?.format();
+    let reader = BufReader::new(File::open(path)?);
+    Ok(match format {
+        Some(ImageFormat::Png) => read_png_profile(&fs::read(path)?),
+        Some(ImageFormat::Jpeg) => {
+            let mut decoder = jpeg_decoder::Decoder::new(reader);
+            decoder.read_info()?;
+            decoder.icc_profile()
+        }
+        Some(ImageFormat::Tiff) => {
+            tiff::decoder::Decoder::new(reader)?.find_tag_unsigned_vec(ICC_PROFILE)?
+        }
+        _ => None,
+    })
+}
+
+/// Find the iCCP chunk among the chunks before the image data and decompress its profile
+///
+/// This is done by hand because the `png` crate doesn't always flush the decompressed profile.
+fn read_png_profile(png: &[u8]) -> Option<Vec<u8>> {
+    // Skip the signature
+    let mut chunks = png.get(8..)?;
+    while chunks.len() >= 12 {
+        let len = u32::from_be_bytes(chunks[..4].try_into().unwrap()) as usize;
+        let kind = &chunks[4..8];
+        let data = chunks.get(8..8 + len)?;
+        match kind {
+            b"iCCP" => {
+                // The profile's name, then the compression method
+                let name_len = data.iter().position(|&b| b == 0)?;
+                let compressed = data.get(name_len + 2..)?;
+                return miniz_oxide::inflate::decompress_to_vec_zlib(compressed).ok();
+            }
+            b"IDAT" => return None,
+            _ => chunks = chunks.get(8 + len + 4..)?,
+        }
+    }
+    None
+}
+
+/// Read the provenance embedded in the image at the given path by `--embed-provenance`, if it
+/// has one and its format is one of PNG, JPEG or TIFF. A JPEG whose thumbnail was too large to
+/// embed comes back without one
+pub fn read_provenance(path: &Path) -> Result<Option<Provenance>> {
+    let format = Reader::open(path)?.with_guessed_format()?.format();
+    let (description, thumbnail) = match format {
+        Some(ImageFormat::Png) => read_png_provenance(&fs::read(path)?),
+        Some(ImageFormat::Jpeg) => read_jpeg_provenance(&fs::read(path)?),
+        Some(ImageFormat::Tiff) => {
+            let mut decoder = tiff::decoder::Decoder::new(BufReader::new(File::open(path)?))?;
+            let description = decoder
+                .find_tag(Tag::ImageDescription)?
+                .map(|description| description.into_string())
+                .transpose()?;
+            let thumbnail = decoder.find_tag_unsigned_vec(TIFF_THUMBNAIL)?;
+            (description, thumbnail)
+        }
+        _ => (None, None),
+    };
+    Ok(description.map(|description| Provenance {
+        description,
+        thumbnail: thumbnail.unwrap_or_default(),
+    }))
+}
+
+/// Find the provenance's chunks among the chunks before the image data
+fn read_png_provenance(png: &[u8]) -> (Option<String>, Option<Vec<u8>>) {
+    let (mut description, mut thumbnail) = (None, None);
+    // Skip the signature
+    let mut chunks = png.get(8..).unwrap_or_default();
+    while chunks.len() >= 12 {
+        let len = u32::from_be_bytes(chunks[..4].try_into().unwrap()) as usize;
+        let (kind, data) = match chunks.get(8..8 + len) {
+            Some(data) => (&chunks[4..8], data),
+            None => break,
+        };
+        match kind {
+            // The keyword, then no compression, the compression method, and empty language and
+            // translated keyword fields
+            b"iTXt" if data.starts_with(PROVENANCE_KEYWORD) => {
+                let text = data.get(PROVENANCE_KEYWORD.len() + 5..).unwrap_or_default();
+                description = Some(String::from_utf8_lossy(text).into_owned());
+            }
+            kind if kind == PNG_THUMBNAIL_CHUNK.0 => thumbnail = Some(data.to_vec()),
+            b"IDAT" => break,
+            _ => {}
+        }
+        chunks = chunks.get(8 + len + 4..).unwrap_or_default();
+    }
+    (description, thumbnail)
+}
+
+/// Find the provenance's comment and APP15 segments among the segments before the image data
+fn read_jpeg_provenance(jpeg: &[u8]) -> (Option<String>, Option<Vec<u8>>) {
+    let (mut description, mut thumbnail) = (None, None);
+    // Skip the start of image marker
+    let mut segments = jpeg.get(2..).unwrap_or_default();
+    while segments.len() >= 4 && segments[0] == 0xFF {
+        let marker = segments[1];
+        let len = usize::from(u16::from_be_bytes([segments[2], segments[3]]));
+        let data = match segments.get(4..2 + len) {
+            Some(data) if len >= 2 => data,
+            _ => break,
+        };
+        match marker {
+            0xFE if description.is_none() => {
+                description = Some(String::from_utf8_lossy(data).into_owned());
+            }
+            0xEF => {
+                if let Some(png) = data.strip_prefix(JPEG_THUMBNAIL_MARKER) {
+                    thumbnail = Some(png.to_vec());
+                }
+            }
+            // The start of scan, after which the image data comes
+            0xDA => break,
+            _ => {}
+        }
+        segments = &segments[2 + len..];
+    }
+    (description, thumbnail)
+}
+
+/// Save the image, embedding the given metadata if the path's format can hold it and warning
+/// about whatever it can't
+///
+/// Given a maximum size in bytes, JPEG outputs are saved at the highest quality that fits it,
+/// while other formats, which can't trade quality for size, only get a warning if they exceed it.
+pub fn save_with_metadata(
+    image: &DynamicImage,
+    path: &Path,
+    metadata: Metadata,
+    max_bytes: Option<u64>,
+) -> Result<()> {
+    let format = ImageFormat::from_path(path)?;
+    match (format, max_bytes) {
+        (ImageFormat::Jpeg, Some(max_bytes)) => {
+            return save_jpeg_within(image, path, metadata, max_bytes)
+        }
+        (_, None) => save_with_format(image, path, format, metadata)?,
+        (_, Some(max_bytes)) => {
+            save_with_format(image, path, format, metadata)?;
+            let len = fs::metadata(path)?.len();
+            if len > max_bytes {
+                eprintln!(
+                    "warning: {} is {} KB, over the maximum file size, but only JPEG outputs can \
+                     be shrunk to fit",
+                    path.display(),
+                    len / 1024
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Save to a file next to the path, which is only moved there once it's complete, so that an
+/// interrupted save doesn't leave a truncated file behind for the next run to take as finished
+pub fn save_atomically<F>(path: &Path, save: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    let partial = path.with_extension("partial");
+    if let Err(err) = save(&partial) {
+        let _ = fs::remove_file(&partial);
+        return Err(err);
+    }
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+fn save_with_format(
+    image: &DynamicImage,
+    path: &Path,
+    format: ImageFormat,
+    metadata: Metadata,
+) -> Result<()> {
+    let save_plain = |partial: &Path| Ok(image.save_with_format(partial, format)?);
+    if metadata.profile.is_none() && metadata.dpi.is_none() && metadata.provenance.is_none() {
+        return save_atomically(path, save_plain);
+    }
+
+    match format {
+        ImageFormat::Png => save_atomically(path, |partial| save_png(image, partial, metadata)),
+        ImageFormat::Jpeg => {
+            let jpeg = encode_jpeg(image, metadata, DEFAULT_JPEG_QUALITY)?;
+            save_atomically(path, |partial| Ok(fs::write(partial, jpeg)?))
+        }
+        ImageFormat::Tiff => save_atomically(path, |partial| save_tiff(image, partial, metadata)),
+        format => {
+            let kinds = [
+                metadata.profile.map(|_| "a color profile"),
+                metadata.dpi.map(|_| "a resolution"),
+                metadata.provenance.map(|_| "a provenance"),
+            ];
+            for kind in kinds.iter().flatten() {
+                eprintln!(
+                    "warning: {:?} images can't hold {}, saving {} without one",
+                    format,
+                    kind,
+                    path.display()
+                );
+            }
+            save_atomically(path, save_plain)
+        }
+    }
+}
+
+fn save_png(image: &DynamicImage, path: &Path, metadata: Metadata) -> Result<()> {
+    let (color, data, width, height) = if image.color().has_alpha() {
+        let image = image.to_rgba8();
+        let (width, height) = image.dimensions();
+        (png::ColorType::Rgba, image.into_raw(), width, height)
+    } else {
+        let image = image.to_rgb8();
+        let (width, height) = image.dimensions();
+        (png::ColorType::Rgb, image.into_raw(), width, height)
+    };
+
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    write_png_metadata(&mut writer, metadata)?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+/// Collect the image's colors in order of first appearance, along with each one's index
+fn palette_of(image: &RgbaImage) -> (Vec<[u8; 4]>, HashMap<[u8; 4], usize>) {
+    let mut palette = Vec::new();
+    let mut indices = HashMap::new();
+    for pixel in image.pixels() {
+        indices.entry(pixel.0).or_insert_with(|| {
+            palette.push(pixel.0);
+            palette.len() - 1
+        });
+    }
+    (palette, indices)
+}
+
+/// Save the image as an indexed color PNG, whose pixels are indices into a palette of its
+/// colors, packed as tightly as the palette's size allows
+///
+/// PNG palettes hold at most 256 colors, so the image is first quantized down to that many if
+/// `quantize` is set, and refused if it has more otherwise.
+pub fn save_indexed_png(
+    image: &DynamicImage,
+    path: &Path,
+    metadata: Metadata,
+    quantize: bool,
+) -> Result<()> {
+    const MAX_COLORS: usize = 256;
+
+    if ImageFormat::from_path(path).ok() != Some(ImageFormat::Png) {
+        bail!(
+            "{} isn't a PNG, but indexed color output is only supported for PNGs",
+            path.display()
+        );
+    }
+
+    let mut image = image.to_rgba8();
+    let (mut palette, mut indices) = palette_of(&image);
+    if palette.len() > MAX_COLORS {
+        if !quantize {
+            bail!(
+                "{} has {} colors, more than the {} an indexed PNG can hold, use \
+                 `--quantize-palette` to reduce them",
+                path.display(),
+                palette.len(),
+                MAX_COLORS
+            );
+        }
+        let (quantized, before, after) =
+            crate::quantize::median_cut(&DynamicImage::ImageRgba8(image), MAX_COLORS);
+        eprintln!("Quantized the mosaic from {before} to {after} colors for its palette");
+        image = quantized.to_rgba8();
+        (palette, indices) = palette_of(&image);
+    }
+
+    let bits = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let (width, height) = image.dimensions();
+    let row_len = (width as usize * bits).div_ceil(8);
+    let mut data = vec![0; row_len * height as usize];
+    for (y, row) in image.rows().enumerate() {
+        let packed = &mut data[y * row_len..(y + 1) * row_len];
+        for (x, pixel) in row.enumerate() {
+            // The leftmost pixel goes in the highest bits
+            let bit = x * bits;
+            let shift = 8 - bits - bit % 8;
+            packed[bit / 8] |= (indices[&pixel.0] as u8) << shift;
+        }
+    }
+
+    save_atomically(path, |partial| {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(partial)?), width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(match bits {
+            1 => png::BitDepth::One,
+            2 => png::BitDepth::Two,
+            4 => png::BitDepth::Four,
+            _ => png::BitDepth::Eight,
+        });
+        encoder.set_palette(
+            palette
+                .iter()
+                .flat_map(|&[r, g, b, _a]| [r, g, b])
+                .collect::<Vec<_>>(),
+        );
+        // The alpha of each palette entry, which can stop at the last one that isn't opaque
+        let opaque_from = palette
+            .iter()
+            .rposition(|color| color[3] != 255)
+            .map_or(0, |idx| idx + 1);
+        if opaque_from > 0 {
+            encoder.set_trns(
+                palette[..opaque_from]
+                    .iter()
+                    .map(|color| color[3])
+                    .collect::<Vec<_>>(),
+            );
+        }
+        let mut writer = encoder.write_header()?;
+        write_png_metadata(&mut writer, metadata)?;
+        writer.write_image_data(&data)?;
+        Ok(())
+    })
+}
+
+/// Write the chunks holding the metadata, which must come before the image data
+fn write_png_metadata<W: Write>(writer: &mut png::Writer<W>, metadata: Metadata) -> Result<()> {
+    if let Some(profile) = metadata.profile {
+        // The profile's name, then the compression method (zlib, the only one there is)
+        let mut iccp = b"ICC Profile\0\0".to_vec();
+        iccp.extend(miniz_oxide::deflate::compress_to_vec_zlib(profile, 6));
+        writer.write_chunk(png::chunk::iCCP, &iccp)?;
+    }
+
+    if let Some(dpi) = metadata.dpi {
+        // The pixels per unit on both axes, then the unit (meters)
+        let pixels_per_meter = (f64::from(dpi) / METERS_PER_INCH).round() as u32;
+        let mut phys = Vec::new();
+        phys.extend(pixels_per_meter.to_be_bytes());
+        phys.extend(pixels_per_meter.to_be_bytes());
+        phys.push(1);
+        writer.write_chunk(png::chunk::pHYs, &phys)?;
+    }
+
+    if let Some(provenance) = metadata.provenance {
+        // The keyword, then no compression, the compression method, and empty language and
+        // translated keyword fields
+        let mut itxt = PROVENANCE_KEYWORD.to_vec();
+        itxt.extend(b"\0\0\0\0\0");
+        itxt.extend(provenance.description.as_bytes());
+        writer.write_chunk(png::chunk::iTXt, &itxt)?;
+        writer.write_chunk(PNG_THUMBNAIL_CHUNK, &provenance.thumbnail)?;
+    }
+    Ok(())
+}
+
+/// Save the image as a JPEG at the highest quality that fits in `max_bytes`, found by binary
+/// search
+fn save_jpeg_within(
+    image: &DynamicImage,
+    path: &Path,
+    metadata: Metadata,
+    max_bytes: u64,
+) -> Result<()> {
+    let fits = |jpeg: &Vec<u8>| jpeg.len() as u64 <= max_bytes;
+
+    let smallest = encode_jpeg(image, metadata, 1)?;
+    if !fits(&smallest) {
+        bail!(
+            "{} doesn't fit in {} KB even at the lowest quality ({} KB), try a smaller mosaic or \
+             tile size",
+            path.display(),
+            max_bytes / 1024,
+            smallest.len() / 1024
+        );
+    }
+
+    // The best quality known to fit, and the lowest known not to
+    let (mut best, mut too_big) = ((1, smallest), 101);
+    while too_big - best.0 > 1 {
+        let quality = (best.0 + too_big) / 2;
+        let jpeg = encode_jpeg(image, metadata, quality)?;
+        if fits(&jpeg) {
+            best = (quality, jpeg);
+        } else {
+            too_big = quality;
+        }
+    }
+
+    let (quality, jpeg) = best;
+    eprintln!(
+        "Saved {} at quality {} to fit in {} KB",
+        path.display(),
+        quality,
+        max_bytes / 1024
+    );
+    save_atomically(path, |partial| Ok(fs::write(partial, jpeg)?))
+}
+
+/// Encode the image as a JPEG of the given quality, from 1 to 100
+fn encode_jpeg(image: &DynamicImage, metadata: Metadata, quality: u8) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    let mut encoder = JpegEncoder::new_with_quality(&mut encoded, quality);
+    if let Some(dpi) = metadata.dpi {
+        encoder.set_pixel_density(PixelDensity::dpi(dpi.min(u16::MAX.into()) as u16));
+    }
+    encoder.encode_image(&DynamicImage::ImageRgb8(image.to_rgb8()))?;
+
+    if metadata.profile.is_none() && metadata.provenance.is_none() {
+        return Ok(encoded);
+    }
+
+    // The segments go right after the start of image marker, the profile split into numbered
+    // ones
+    let mut jpeg = encoded[..2].to_vec();
+    if let Some(profile) = metadata.profile {
+        let chunks = profile.chunks(JPEG_ICC_CHUNK).collect::<Vec<_>>();
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let len = 2 + JPEG_ICC_MARKER.len() + 2 + chunk.len();
+            jpeg.extend([0xFF, 0xE2]);
+            jpeg.extend((len as u16).to_be_bytes());
+            jpeg.extend(JPEG_ICC_MARKER);
+            jpeg.extend([idx as u8 + 1, chunks.len() as u8]);
+            jpeg.extend(*chunk);
+        }
+    }
+    if let Some(provenance) = metadata.provenance {
+        // A comment segment for the description, and an APP15 one for the thumbnail
+        let description = provenance.description.as_bytes();
+        let description = &description[..description.len().min(u16::MAX as usize - 2)];
+        jpeg.extend([0xFF, 0xFE]);
+        jpeg.extend((2 + description.len() as u16).to_be_bytes());
+        jpeg.extend(description);
+
+        let len = 2 + JPEG_THUMBNAIL_MARKER.len() + provenance.thumbnail.len();
+        if len <= u16::MAX as usize {
+            jpeg.extend([0xFF, 0xEF]);
+            jpeg.extend((len as u16).to_be_bytes());
+            jpeg.extend(JPEG_THUMBNAIL_MARKER);
+            jpeg.extend(&provenance.thumbnail);
+        } else {
+            eprintln!(
+                "warning: the source's thumbnail is too large for a JPEG segment, leaving it out"
+            );
+        }
+    }
+    jpeg.extend(&encoded[2..]);
+    Ok(jpeg)
+}
+
+fn save_tiff(image: &DynamicImage, path: &Path, metadata: Metadata) -> Result<()> {
+    let image = image.to_rgba8();
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+    let mut page = encoder.new_image::<colortype::RGBA8>(image.width(), image.height())?;
+    if let Some(profile) = metadata.profile {
+        page.encoder().write_tag(ICC_PROFILE, profile)?;
+    }
+    if let Some(dpi) = metadata.dpi {
+        page.resolution(ResolutionUnit::Inch, Rational { n: dpi, d: 1 });
+    }
+    if let Some(provenance) = metadata.provenance {
+        page.encoder()
+            .write_tag(Tag::ImageDescription, provenance.description.as_str())?;
+        page.encoder()
+            .write_tag(TIFF_THUMBNAIL, &provenance.thumbnail[..])?;
+    }
+    page.write_data(image.as_raw())?;
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Choosing the few tiles that reproduce an image best, for `--palette-size`
+//!
+//! This is the facility location problem, where every color of the image is served by the
+//! closest chosen tile, and it's solved greedily: tiles are chosen one by one, each time taking
+//! the one that lowers the image's total error the most given those chosen before it. That's not
+//! optimal, but it's never too far off, and it's deterministic.
+
+use std::collections::HashMap;
+
+use image::Rgba;
+use indicatif::ProgressIterator;
+use rayon::prelude::*;
+
+use crate::make_pbar;
+use crate::matching::{distance, ChannelWeights};
+use crate::tiles::Tile;
+
+/// The tiles chosen for an image and how well they reproduce it
+#[derive(Debug)]
+pub struct Palette {
+    /// The chosen tiles, as indices into the tileset, in the order they were chosen
+    pub tiles: Vec<usize>,
+
+    /// How many cells each chosen tile is the closest to, in the same order
+    pub cells: Vec<usize>,
+
+    /// The mean error of the cells with the closest of the chosen tiles
+    pub mean_error: f64,
+}
+
+/// Choose the `size` tiles whose average colors, each cell getting the closest of them,
+/// reproduce the given cells with the lowest total error
+pub fn choose_palette(
+    pixels: &[Rgba<u8>],
+    possible_tiles: &[Tile],
+    size: usize,
+    weights: ChannelWeights,
+) -> Palette {
+    let mut counts = HashMap::<Rgba<u8>, i64>::new();
+    for &pixel in pixels {
+        *counts.entry(pixel).or_default() += 1;
+    }
+    let (colors, counts): (Vec<_>, Vec<_>) = counts.into_iter().unzip();
+
+    // The error of every color with the closest tile chosen so far, once one has been
+    let mut best = vec![None::<i64>; colors.len()];
+    let mut chosen = Vec::new();
+    let size = size.min(possible_tiles.len());
+    for _ in (0..size).progress_with(make_pbar("palette tiles", size as _)) {
+        let next = possible_tiles
+            .par_iter()
+            .enumerate()
+            .filter(|(idx, _tile)| !chosen.contains(idx))
+            .map(|(idx, tile)| {
+                let total = colors
+                    .iter()
+                    .zip(&counts)
+                    .zip(&best)
+                    .map(|((&color, &count), best)| {
+                        let error = tile.weigh(distance(tile.average, color, weights));
+                        count * best.map_or(error, |best| best.min(error))
+                    })
+                    .sum::<i64>();
+                (total, idx)
+            })
+            .min();
+        let Some((_total, next)) = next else {
+            break;
+        };
+        let tile = &possible_tiles[next];
+        for (&color, best) in colors.iter().zip(&mut best) {
+            let error = tile.weigh(distance(tile.average, color, weights));
+            *best = Some(best.map_or(error, |best| best.min(error)));
+        }
+        chosen.push(next);
+    }
+
+    let mut cells = vec![0; chosen.len()];
+    let mut total_error = 0;
+    for (&color, &count) in colors.iter().zip(&counts) {
+        let closest = (0..chosen.len()).min_by_key(|&nth| {
+            let tile = &possible_tiles[chosen[nth]];
+            (tile.weigh(distance(tile.average, color, weights)), nth)
+        });
+        if let Some(nth) = closest {
+            cells[nth] += count as usize;
+            total_error += count * distance(possible_tiles[chosen[nth]].average, color, weights);
+        }
+    }
+    Palette {
+        tiles: chosen,
+        cells,
+        mean_error: total_error as f64 / pixels.len().max(1) as f64,
+    }
+}
@@ -0,0 +1,739 @@
+//! Assembling the mosaic out of the tiles chosen for each cell
+
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use eyre::{bail, Result, WrapErr};
+use image::imageops::FilterType;
+use image::{
+    imageops, DynamicImage, GenericImage, GenericImageView, GrayImage, Luma, Pixel, Rgba, RgbaImage,
+};
+use indicatif::ProgressIterator;
+
+use crate::make_pbar;
+use crate::matching::Placement;
+use crate::tiles::Tile;
+
+/// How tiles are placed in their cells
+#[derive(Clone, Copy)]
+pub struct PlacementOptions {
+    /// The side length of each cell
+    pub tile_size: u32,
+
+    /// Whether to flip the tiles in odd columns horizontally
+    pub mirror_cols: bool,
+
+    /// Whether to flip the tiles in odd rows vertically
+    pub mirror_rows: bool,
+
+    /// Whether to fill cells with their tile's average color instead of the tile itself
+    pub flat: bool,
+
+    /// Whether to shift odd rows right by half a cell, like bricks, wrapping the tile that
+    /// sticks out of the right edge around to the left one
+    pub offset_rows: bool,
+
+    /// How to recolor each tile towards its cell's color, if at all
+    pub recolor: Option<Recolor>,
+
+    /// The shape to cut each tile into, if any
+    pub shape: Option<TileShape>,
+
+    /// What to fill the mosaic with before placing the tiles, showing through wherever they're
+    /// transparent or cut into a shape
+    pub background: Option<Rgba<u8>>,
+
+    /// The most each tile may be randomly rotated by either way, in degrees
+    pub jitter_rotation: f64,
+
+    /// What the random rotations are derived from, so that they're the same on every run
+    pub seed: u64,
+
+    /// The alpha below which a cell is left empty, with only the background showing, instead
+    /// of getting its tile, if any
+    pub min_alpha: Option<u8>,
+
+    /// Whether to invert the colors of the tiles, like a photographic negative
+    pub invert: bool,
+
+    /// How to shrink each tile by the brightness of its cell, if at all
+    pub brightness_scale: Option<BrightnessScale>,
+}
+
+/// The largest `jitter_rotation` allowed, past which tiles stop looking like a grid at all
+const MAX_JITTER_ROTATION: f64 = 45.;
+
+/// A shape to cut tiles into, leaving the background to show around them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileShape {
+    /// The largest circle that fits in the cell
+    Circle,
+
+    /// A square with its corners rounded by the given radius, in pixels
+    Rounded(u32),
+}
+
+impl FromStr for TileShape {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split(|c: char| c == ':' || c.is_whitespace());
+        match (words.next(), words.next(), words.next()) {
+            (Some("circle"), None, None) => Ok(Self::Circle),
+            (Some("rounded"), Some(radius), None) => {
+                Ok(Self::Rounded(radius.parse().wrap_err_with(|| {
+                    format!("{:?} is not a valid corner radius", radius)
+                })?))
+            }
+            _ => bail!("expected `circle` or `rounded N`, got {:?}", s),
+        }
+    }
+}
+
+impl TileShape {
+    /// Compute how much of each pixel of a `side`x`side` cell the shape covers, antialiased by
+    /// sampling every pixel at several points
+    fn mask(self, side: u32) -> GrayImage {
+        const SAMPLES: u32 = 4;
+
+        let half = side as f32 / 2.;
+        let radius = match self {
+            Self::Circle => half,
+            Self::Rounded(radius) => (radius as f32).min(half),
+        };
+        // The shape is a square shrunk by the radius on every side, then grown back by it
+        let inner = half - radius;
+        let covers = |x: f32, y: f32| {
+            let dx = ((x - half).abs() - inner).max(0.);
+            let dy = ((y - half).abs() - inner).max(0.);
+            dx * dx + dy * dy <= radius * radius
+        };
+
+        GrayImage::from_fn(side, side, |x, y| {
+            let mut covered = 0;
+            for sy in 0..SAMPLES {
+                for sx in 0..SAMPLES {
+                    let px = x as f32 + (sx as f32 + 0.5) / SAMPLES as f32;
+                    let py = y as f32 + (sy as f32 + 0.5) / SAMPLES as f32;
+                    covered += u32::from(covers(px, py));
+                }
+            }
+            Luma([(covered * 255 / (SAMPLES * SAMPLES)) as u8])
+        })
+    }
+}
+
+/// How tiles are recolored towards the color of their cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recolor {
+    /// Give every pixel the cell's hue, keeping its own saturation and lightness so that the
+    /// tile's texture stays
+    Hue,
+
+    /// Give every pixel the cell's hue and saturation, keeping only its own lightness, so that
+    /// even gray tiles take on the cell's color
+    Color,
+}
+
+impl FromStr for Recolor {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hue" => Ok(Self::Hue),
+            "color" => Ok(Self::Color),
+            _ => bail!("unknown recolor mode {:?}", s),
+        }
+    }
+}
+
+/// Which cells get the largest tiles when scaling them by brightness
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LargerTiles {
+    /// The brightest cells, like white dots on a dark background
+    Bright,
+
+    /// The darkest cells, like ink dots on paper
+    Dark,
+}
+
+impl FromStr for LargerTiles {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bright" => Ok(Self::Bright),
+            "dark" => Ok(Self::Dark),
+            _ => bail!("unknown brightness {:?}", s),
+        }
+    }
+}
+
+/// Shrinking each tile by the brightness of its cell and centering it there, like the dots of a
+/// halftone
+#[derive(Debug, Clone, Copy)]
+pub struct BrightnessScale {
+    /// Which cells get the largest tiles
+    pub larger: LargerTiles,
+
+    /// The share of its cell the tile of the dimmest (or brightest) cell fills along each side
+    pub min: f64,
+
+    /// The share of its cell the tile of the brightest (or dimmest) cell fills along each side
+    pub max: f64,
+}
+
+impl BrightnessScale {
+    /// The side length of the tile for a cell of the given color, from 0 to `tile_size`
+    fn side(self, pixel: Rgba<u8>, tile_size: u32) -> u32 {
+        let (min, max) = (self.min.clamp(0., 1.), self.max.clamp(0., 1.));
+        let brightness = f64::from(pixel.to_luma()[0]) / 255.;
+        let brightness = match self.larger {
+            LargerTiles::Bright => brightness,
+            LargerTiles::Dark => 1. - brightness,
+        };
+        let scale = min + (max - min) * brightness;
+        (scale * f64::from(tile_size)).round() as u32
+    }
+}
+
+/// Convert a color to hue (in [0, 6)), saturation and lightness (in [0, 1]), or `None` for the
+/// hue of grays, which have none
+pub fn to_hsl(Rgba([r, g, b, _a]): Rgba<u8>) -> (Option<f32>, f32, f32) {
+    let [r, g, b] = [r, g, b].map(|c| f32::from(c) / 255.);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.;
+    let chroma = max - min;
+    if chroma == 0. {
+        return (None, 0., lightness);
+    }
+
+    let saturation = chroma / (1. - (2. * lightness - 1.).abs());
+    let hue = if max == r {
+        ((g - b) / chroma).rem_euclid(6.)
+    } else if max == g {
+        (b - r) / chroma + 2.
+    } else {
+        (r - g) / chroma + 4.
+    };
+    (Some(hue), saturation.min(1.), lightness)
+}
+
+/// Convert a hue (in [0, 6)), saturation and lightness (in [0, 1]) back to a color
+pub fn from_hsl(hue: f32, saturation: f32, lightness: f32, alpha: u8) -> Rgba<u8> {
+    let chroma = (1. - (2. * lightness - 1.).abs()) * saturation;
+    let x = chroma * (1. - (hue % 2. - 1.).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let m = lightness - chroma / 2.;
+    let [r, g, b] = [r, g, b].map(|c| ((c + m) * 255.).round().clamp(0., 255.) as u8);
+    Rgba([r, g, b, alpha])
+}
+
+/// Give every pixel of the tile the hue of the given color, unless it's a gray and so has none
+fn recolor_hue(tile: &DynamicImage, color: Rgba<u8>) -> Option<DynamicImage> {
+    let hue = to_hsl(color).0?;
+    let mut tile = tile.to_rgba8();
+    for pixel in tile.pixels_mut() {
+        let (_hue, saturation, lightness) = to_hsl(*pixel);
+        *pixel = from_hsl(hue, saturation, lightness, pixel[3]);
+    }
+    Some(DynamicImage::ImageRgba8(tile))
+}
+
+/// Give every pixel of the tile the hue and saturation of the given color, keeping its lightness
+fn recolor_color(tile: &DynamicImage, color: Rgba<u8>) -> DynamicImage {
+    let (hue, saturation, _lightness) = to_hsl(color);
+    let mut tile = tile.to_rgba8();
+    for pixel in tile.pixels_mut() {
+        let (_hue, _saturation, lightness) = to_hsl(*pixel);
+        *pixel = from_hsl(hue.unwrap_or(0.), saturation, lightness, pixel[3]);
+    }
+    DynamicImage::ImageRgba8(tile)
+}
+
+/// Resample the target image for the brick layout of `offset_rows`, so that the colors of odd
+/// rows are taken from where their shifted cells actually end up
+///
+/// `img` is the target image as resized to one pixel per cell, `source` the one it came from.
+pub fn stagger_rows(source: &DynamicImage, img: &DynamicImage) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    // Two pixels per cell horizontally, so that every half cell has its own color
+    let halves = source.thumbnail_exact(width * 2, height);
+    let mut staggered = img.to_rgba8();
+    for y in (1..height).step_by(2) {
+        for x in 0..width {
+            let Rgba(left) = halves.get_pixel(x * 2 + 1, y);
+            let Rgba(right) = halves.get_pixel((x * 2 + 2) % (width * 2), y);
+            let mut mean = [0; 4];
+            for ((mean, left), right) in mean.iter_mut().zip(left).zip(right) {
+                *mean = ((u16::from(left) + u16::from(right)) / 2) as u8;
+            }
+            staggered.put_pixel(x, y, Rgba(mean));
+        }
+    }
+    DynamicImage::ImageRgba8(staggered)
+}
+
+/// Cut the tile into the shape of the mask, by making it transparent wherever the mask is
+fn apply_mask(tile: &DynamicImage, mask: &GrayImage) -> DynamicImage {
+    let mut tile = tile.to_rgba8();
+    for (pixel, Luma([coverage])) in tile.pixels_mut().zip(mask.pixels()) {
+        pixel[3] = (u16::from(pixel[3]) * u16::from(*coverage) / 255) as u8;
+    }
+    DynamicImage::ImageRgba8(tile)
+}
+
+/// A pseudorandom number in [0, 1) for the cell at the given index, always the same for the
+/// same seed, with the SplitMix64 generator
+fn random_for_cell(seed: u64, idx: u64) -> f64 {
+    let mut z = seed.wrapping_add((idx + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    // The top 53 bits, as many as a f64 holds exactly
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Rotate the tile clockwise by the given angle about its center, onto a canvas just big enough
+/// to hold the whole rotated tile and transparent elsewhere
+///
+/// Pixels are sampled bilinearly with their colors premultiplied by their alpha, so that the
+/// edges are antialiased without dark fringes.
+fn rotate(tile: &DynamicImage, degrees: f64) -> DynamicImage {
+    let tile = tile.to_rgba8();
+    let (width, height) = tile.dimensions();
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let side = |a: u32, b: u32| {
+        (f64::from(a) * cos.abs() + f64::from(b) * sin.abs())
+            .ceil()
+            .max(1.) as u32
+    };
+    let (rotated_width, rotated_height) = (side(width, height), side(height, width));
+
+    // Outside of the tile everything is transparent
+    let premultiplied = |x: i64, y: i64| -> [f64; 4] {
+        if x < 0 || y < 0 || x >= i64::from(width) || y >= i64::from(height) {
+            return [0.; 4];
+        }
+        let Rgba([r, g, b, a]) = *tile.get_pixel(x as u32, y as u32);
+        let alpha = f64::from(a) / 255.;
+        [
+            f64::from(r) * alpha,
+            f64::from(g) * alpha,
+            f64::from(b) * alpha,
+            f64::from(a),
+        ]
+    };
+
+    let (center_x, center_y) = (f64::from(width) / 2., f64::from(height) / 2.);
+    let (rotated_center_x, rotated_center_y) = (
+        f64::from(rotated_width) / 2.,
+        f64::from(rotated_height) / 2.,
+    );
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(rotated_width, rotated_height, |x, y| {
+        // Map the center of the pixel back onto the tile, by rotating it the other way
+        let dx = f64::from(x) + 0.5 - rotated_center_x;
+        let dy = f64::from(y) + 0.5 - rotated_center_y;
+        let sx = dx * cos + dy * sin + center_x - 0.5;
+        let sy = -dx * sin + dy * cos + center_y - 0.5;
+
+        let (x0, y0) = (sx.floor(), sy.floor());
+        let (fx, fy) = (sx - x0, sy - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let mut sample = [0.; 4];
+        for (px, py, weight) in [
+            (x0, y0, (1. - fx) * (1. - fy)),
+            (x0 + 1, y0, fx * (1. - fy)),
+            (x0, y0 + 1, (1. - fx) * fy),
+            (x0 + 1, y0 + 1, fx * fy),
+        ] {
+            for (sample, c) in sample.iter_mut().zip(premultiplied(px, py)) {
+                *sample += c * weight;
+            }
+        }
+
+        let [r, g, b, a] = sample;
+        if a <= 0. {
+            return Rgba([0; 4]);
+        }
+        let unpremultiply = |c: f64| (c * 255. / a).round().clamp(0., 255.) as u8;
+        Rgba([
+            unpremultiply(r),
+            unpremultiply(g),
+            unpremultiply(b),
+            a.round().clamp(0., 255.) as u8,
+        ])
+    }))
+}
+
+/// Blend a tile over the mosaic at the given position, wrapping whatever sticks out of the
+/// right edge around to the left one if `wrap` is set
+fn overlay_at(mosaic: &mut DynamicImage, tile: &DynamicImage, x: i64, y: i64, wrap: bool) {
+    imageops::overlay(mosaic, tile, x, y);
+    if wrap && x + i64::from(tile.width()) > i64::from(mosaic.width()) {
+        imageops::overlay(mosaic, tile, x - i64::from(mosaic.width()), y);
+    }
+}
+
+/// Copy a tile into the mosaic at the given position, wrapping whatever sticks out of the right
+/// edge around to the left one
+fn copy_wrapping(mosaic: &mut DynamicImage, tile: &DynamicImage, x: u32, y: u32) -> Result<()> {
+    let fits = mosaic.width() - x;
+    if tile.width() <= fits {
+        mosaic.copy_from(tile, x, y)?;
+    } else {
+        mosaic.copy_from(&tile.crop_imm(0, 0, fits, tile.height()), x, y)?;
+        mosaic.copy_from(
+            &tile.crop_imm(fits, 0, tile.width() - fits, tile.height()),
+            0,
+            y,
+        )?;
+    }
+    Ok(())
+}
+
+/// Combine one mosaic per color channel into one, taking the red channel from the first, the
+/// green one from the second and the blue one from the third, and averaging their alpha
+pub fn combine_channels([red, green, blue]: [&DynamicImage; 3]) -> DynamicImage {
+    let (red, green, blue) = (red.to_rgba8(), green.to_rgba8(), blue.to_rgba8());
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(red.width(), red.height(), |x, y| {
+        let pixels = [
+            red.get_pixel(x, y),
+            green.get_pixel(x, y),
+            blue.get_pixel(x, y),
+        ];
+        let alpha = pixels.iter().map(|pixel| u16::from(pixel[3])).sum::<u16>() / 3;
+        Rgba([pixels[0][0], pixels[1][1], pixels[2][2], alpha as u8])
+    }))
+}
+
+/// Lay the mosaic's tiles over a copy of the source stretched to the mosaic's size, at the given
+/// opacity, so that the source shows through them, and wherever they're transparent
+pub fn over_source(mosaic: &DynamicImage, source: &DynamicImage, opacity: f32) -> DynamicImage {
+    let (width, height) = mosaic.dimensions();
+    let mut canvas = imageops::resize(source, width, height, FilterType::Triangle);
+    for (under, over) in canvas.pixels_mut().zip(mosaic.to_rgba8().pixels()) {
+        let over_alpha = f32::from(over[3]) / 255. * opacity;
+        let under_alpha = f32::from(under[3]) / 255. * (1. - over_alpha);
+        let alpha = over_alpha + under_alpha;
+        if alpha > 0. {
+            for channel in 0..3 {
+                let color = (f32::from(over[channel]) * over_alpha
+                    + f32::from(under[channel]) * under_alpha)
+                    / alpha;
+                under[channel] = color.round() as u8;
+            }
+        }
+        under[3] = (alpha * 255.).round() as u8;
+    }
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Make a mosaic tile seamlessly, by mirroring it into a 2x2 grid: as is in the top left, flipped
+/// horizontally in the top right, vertically in the bottom left and both ways in the bottom
+/// right. Every edge of the result then meets its mirror image when it's repeated, so there are
+/// no seams, at the cost of doubling each side and flipping the tiles in three quarters of it
+pub fn make_seamless(mosaic: &DynamicImage) -> DynamicImage {
+    let (width, height) = mosaic.dimensions();
+    let mut seamless = RgbaImage::new(width * 2, height * 2);
+    let quadrant = mosaic.to_rgba8();
+    let flipped = imageops::flip_horizontal(&quadrant);
+    imageops::replace(&mut seamless, &quadrant, 0, 0);
+    imageops::replace(&mut seamless, &flipped, i64::from(width), 0);
+    imageops::replace(
+        &mut seamless,
+        &imageops::flip_vertical(&quadrant),
+        0,
+        i64::from(height),
+    );
+    imageops::replace(
+        &mut seamless,
+        &imageops::flip_vertical(&flipped),
+        i64::from(width),
+        i64::from(height),
+    );
+    DynamicImage::ImageRgba8(seamless)
+}
+
+/// How far into each cell `blend_boundaries` blends it with its neighbors, as a fraction of the
+/// tile size
+const BLEND_BAND: f32 = 0.25;
+
+/// Soften the hard edges between the tiles of a mosaic laid out on a plain grid, by mixing
+/// every pixel near a cell's border with the pixels at the same position in the neighboring
+/// cells' tiles
+///
+/// Along each axis, a pixel in the outer `BLEND_BAND` of its cell takes up to half of its color
+/// from the neighbor on that side, half at the border itself and none at the band's inner edge,
+/// and near corners the weights of both axes multiply, bilinearly mixing four tiles. Cells on the
+/// mosaic's edges have no neighbors beyond it to blend with.
+pub fn blend_boundaries(mosaic: &DynamicImage, tile_size: u32) -> DynamicImage {
+    let mosaic = mosaic.to_rgba8();
+    let (width, height) = mosaic.dimensions();
+    let (columns, rows) = (width / tile_size, height / tile_size);
+    let band = (tile_size as f32 * BLEND_BAND).max(1.);
+
+    // The neighboring cell to blend with along one axis, and how much of it to take, given a
+    // pixel's position within its cell and the cell's
+    let neighbor = |offset: u32, cell: u32, cells: u32| {
+        let from_start = offset as f32 + 0.5;
+        let from_end = tile_size as f32 - from_start;
+        if from_start < from_end && cell > 0 {
+            (cell - 1, 0.5 * (1. - from_start / band).max(0.))
+        } else if from_end <= from_start && cell + 1 < cells {
+            (cell + 1, 0.5 * (1. - from_end / band).max(0.))
+        } else {
+            (cell, 0.)
+        }
+    };
+
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+        let (cell_x, cell_y) = (x / tile_size, y / tile_size);
+        // Leftover pixels past the last whole cell are left as they are
+        if cell_x >= columns || cell_y >= rows {
+            return *mosaic.get_pixel(x, y);
+        }
+        let (offset_x, offset_y) = (x % tile_size, y % tile_size);
+        let (other_x, weight_x) = neighbor(offset_x, cell_x, columns);
+        let (other_y, weight_y) = neighbor(offset_y, cell_y, rows);
+
+        let mut mixed = [0.; 4];
+        for (cell_x, cell_y, weight) in [
+            (cell_x, cell_y, (1. - weight_x) * (1. - weight_y)),
+            (other_x, cell_y, weight_x * (1. - weight_y)),
+            (cell_x, other_y, (1. - weight_x) * weight_y),
+            (other_x, other_y, weight_x * weight_y),
+        ] {
+            let Rgba(pixel) =
+                mosaic.get_pixel(cell_x * tile_size + offset_x, cell_y * tile_size + offset_y);
+            for (mixed, &c) in mixed.iter_mut().zip(pixel) {
+                *mixed += weight * f32::from(c);
+            }
+        }
+        Rgba(mixed.map(|c| c.round().clamp(0., 255.) as u8))
+    }))
+}
+
+/// Put every cell's tile in its place, given the target image the cells were matched against
+pub fn place_tiles(
+    img: &DynamicImage,
+    cells: &[Placement],
+    possible_tiles: &[Tile],
+    options: &PlacementOptions,
+) -> Result<DynamicImage> {
+    let (width, height) = (
+        img.width() * options.tile_size,
+        img.height() * options.tile_size,
+    );
+    let mut mosaic = match options.background {
+        Some(background) => {
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, background))
+        }
+        None => DynamicImage::new_rgba8(width, height),
+    };
+    place_cells(&mut mosaic, img, cells, None, possible_tiles, options)?;
+    Ok(mosaic)
+}
+
+/// Update a mosaic placed before with the same options, placing the tiles of only the cells
+/// marked as changed, in reading order, after clearing them to the background
+///
+/// Tiles must stay within their cells for this to work, so neither `jitter_rotation` nor
+/// `offset_rows` may be used.
+pub fn patch_tiles(
+    prior: &DynamicImage,
+    img: &DynamicImage,
+    cells: &[Placement],
+    changed: &[bool],
+    possible_tiles: &[Tile],
+    options: &PlacementOptions,
+) -> Result<DynamicImage> {
+    let tile_size = options.tile_size;
+    if prior.dimensions() != (img.width() * tile_size, img.height() * tile_size) {
+        bail!(
+            "the prior mosaic is {}x{}, but this one is {}x{}",
+            prior.width(),
+            prior.height(),
+            img.width() * tile_size,
+            img.height() * tile_size
+        );
+    }
+    if options.jitter_rotation > 0. || options.offset_rows {
+        bail!("mosaics with rotated or offset tiles can't be updated cell by cell");
+    }
+
+    let mut mosaic = DynamicImage::ImageRgba8(prior.to_rgba8());
+    let cleared = DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+        tile_size,
+        tile_size,
+        options.background.unwrap_or(Rgba([0, 0, 0, 0])),
+    ));
+    for (idx, (x, y, _pixel)) in img.pixels().enumerate() {
+        if changed[idx] {
+            mosaic.copy_from(&cleared, x * tile_size, y * tile_size)?;
+        }
+    }
+    place_cells(
+        &mut mosaic,
+        img,
+        cells,
+        Some(changed),
+        possible_tiles,
+        options,
+    )?;
+    Ok(mosaic)
+}
+
+/// Place the tiles of the cells onto the mosaic, or of only those marked as changed if given
+fn place_cells(
+    mosaic: &mut DynamicImage,
+    img: &DynamicImage,
+    cells: &[Placement],
+    changed: Option<&[bool]>,
+    possible_tiles: &[Tile],
+    options: &PlacementOptions,
+) -> Result<()> {
+    let PlacementOptions {
+        tile_size,
+        mirror_cols,
+        mirror_rows,
+        flat,
+        offset_rows,
+        recolor,
+        shape,
+        background: _,
+        jitter_rotation,
+        seed,
+        min_alpha,
+        invert,
+        brightness_scale,
+    } = *options;
+    let jitter_rotation = jitter_rotation.clamp(0., MAX_JITTER_ROTATION);
+
+    // Every cell is the same size, so the shape's mask can be shared
+    let mask = shape.map(|shape| shape.mask(tile_size));
+    for (idx, ((x, y, pixel), placement)) in img
+        .pixels()
+        .zip(cells)
+        .enumerate()
+        .progress_with(make_pbar("actual pixels", cells.len() as _))
+    {
+        if min_alpha.is_some_and(|min_alpha| pixel[3] < min_alpha)
+            || changed.is_some_and(|changed| !changed[idx])
+        {
+            continue;
+        }
+
+        let offset = if offset_rows && y % 2 == 1 {
+            tile_size / 2
+        } else {
+            0
+        };
+
+        let tile = &possible_tiles[placement.tile];
+        let image = if flat { None } else { Some(tile.image()?) };
+        let mut tile = if let Some(image) = &image {
+            let mut tile = placement.orientation.apply(image);
+            if mirror_cols && x % 2 == 1 {
+                tile = Cow::Owned(tile.fliph());
+            }
+            if mirror_rows && y % 2 == 1 {
+                tile = Cow::Owned(tile.flipv());
+            }
+            tile
+        } else {
+            let block = RgbaImage::from_pixel(tile_size, tile_size, tile.average);
+            Cow::Owned(DynamicImage::ImageRgba8(block))
+        };
+        if invert {
+            let mut inverted = tile.into_owned();
+            inverted.invert();
+            tile = Cow::Owned(inverted);
+        }
+        match recolor {
+            Some(Recolor::Hue) => {
+                if let Some(recolored) = recolor_hue(&tile, pixel) {
+                    tile = Cow::Owned(recolored);
+                }
+            }
+            Some(Recolor::Color) => tile = Cow::Owned(recolor_color(&tile, pixel)),
+            None => {}
+        }
+        if let Some(mask) = &mask {
+            tile = Cow::Owned(apply_mask(&tile, mask));
+        }
+        let side = brightness_scale.map_or(tile_size, |scale| scale.side(pixel, tile_size));
+        if side == 0 {
+            continue;
+        } else if side != tile_size {
+            tile = Cow::Owned(tile.resize_exact(side, side, FilterType::Triangle));
+        }
+
+        let (cell_x, cell_y) = (x * tile_size + offset, y * tile_size);
+        if jitter_rotation > 0. {
+            // Centered on the cell, overlapping its neighbors where it sticks out of it
+            let angle = (random_for_cell(seed, idx as u64) * 2. - 1.) * jitter_rotation;
+            let rotated = rotate(&tile, angle);
+            let inset = |rotated: u32| (i64::from(rotated) - i64::from(tile_size)) / 2;
+            overlay_at(
+                mosaic,
+                &rotated,
+                i64::from(cell_x) - inset(rotated.width()),
+                i64::from(cell_y) - inset(rotated.height()),
+                offset_rows,
+            );
+        } else if side != tile_size {
+            // Centered on the cell, with the background showing around it
+            let inset = (tile_size - side) / 2;
+            overlay_at(
+                mosaic,
+                &tile,
+                (cell_x + inset).into(),
+                (cell_y + inset).into(),
+                offset_rows,
+            );
+        } else if mask.is_some() {
+            overlay_at(mosaic, &tile, cell_x.into(), cell_y.into(), offset_rows);
+        } else {
+            copy_wrapping(mosaic, &tile, cell_x, cell_y)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brightness_scales_tiles_between_the_smallest_and_largest_size() {
+        let gray = |shade| Rgba([shade, shade, shade, 255]);
+        let scale = |larger, min, max| BrightnessScale { larger, min, max };
+
+        let bright = scale(LargerTiles::Bright, 0.2, 0.8);
+        assert_eq!(bright.side(gray(0), 20), 4);
+        assert_eq!(bright.side(gray(255), 20), 16);
+        assert_eq!(bright.side(gray(128), 20), 10);
+        let dark = scale(LargerTiles::Dark, 0.2, 0.8);
+        assert_eq!(dark.side(gray(0), 20), 16);
+        assert_eq!(dark.side(gray(255), 20), 4);
+
+        // Growing with brightness all the way, and never out of the cell
+        let clamped = scale(LargerTiles::Bright, -1., 2.);
+        assert_eq!(clamped.side(gray(0), 20), 0);
+        assert_eq!(clamped.side(gray(255), 20), 20);
+        let sides = (0..=255).map(|shade| bright.side(gray(shade), 20));
+        assert!(sides
+            .collect::<Vec<_>>()
+            .windows(2)
+            .all(|pair| pair[0] <= pair[1]));
+    }
+}
@@ -0,0 +1,93 @@
+//! Timing the phases of a run, for `--profile`
+//!
+//! Phases are timed with spans that nest into stacks, and the time spent in each stack is
+//! written in the folded format that flamegraph tools read: one line per stack, its phases
+//! separated by semicolons, followed by the microseconds spent in it but not in its children.
+//! Unless profiling is enabled, spans aren't even created.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use eyre::Result;
+
+/// Whether spans are being timed
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The total time spent in each stack, including its children
+static TIMINGS: Mutex<BTreeMap<String, Duration>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// The spans currently open on this thread, outermost first
+    static STACK: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A phase being timed, which ends when it's dropped
+pub struct Span {
+    start: Instant,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let stack = STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let folded = stack.join(";");
+            stack.pop();
+            folded
+        });
+        *TIMINGS
+            .lock()
+            .unwrap()
+            .entry(stack)
+            .or_insert(Duration::ZERO) += elapsed;
+    }
+}
+
+/// Start timing spans
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Start timing a phase, nested in whichever phase is currently being timed on this thread,
+/// until the returned span is dropped
+pub fn span(name: &'static str) -> Option<Span> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    STACK.with(|stack| stack.borrow_mut().push(name));
+    Some(Span {
+        start: Instant::now(),
+    })
+}
+
+/// Write the time spent in every stack to the given file, in the folded format
+pub fn write_folded(path: &Path) -> Result<()> {
+    let timings = TIMINGS.lock().unwrap();
+
+    // Every stack's own time is its total time minus that of its direct children
+    let mut own = timings.clone();
+    for (stack, &total) in timings.iter() {
+        if let Some((parent, _name)) = stack.rsplit_once(';') {
+            if let Some(parent) = own.get_mut(parent) {
+                *parent = parent.saturating_sub(total);
+            }
+        }
+    }
+
+    let folded = own
+        .iter()
+        .map(|(stack, duration)| format!("{} {}\n", stack, duration.as_micros()))
+        .collect::<String>();
+    fs::write(path, folded)?;
+    eprintln!(
+        "Saved the timings of {} phases to {}",
+        own.len(),
+        path.display()
+    );
+    Ok(())
+}
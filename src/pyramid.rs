@@ -0,0 +1,74 @@
+//! Slicing the mosaic into a pyramid of map tiles, for panning and zooming around huge mosaics
+//! in tiled viewers like Leaflet or OpenSeadragon rather than opening one enormous file
+//!
+//! The pyramid's levels are numbered from 0, where the whole mosaic fits in a single map tile,
+//! up to the mosaic at full size, each one twice as big as the one before. Every level is cut
+//! into `MAP_TILE_SIZE` pixel squares saved as `{level}/{column}/{row}.png`, with those on the
+//! right and bottom edges cropped to what's left of the mosaic.
+
+use std::fs;
+use std::path::Path;
+
+use eyre::Result;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+use indicatif::ProgressIterator;
+
+use crate::make_pbar;
+
+/// The side length of a map tile, the usual one for web maps
+const MAP_TILE_SIZE: u32 = 256;
+
+/// Save the mosaic as a pyramid of map tiles in the given directory, along with a
+/// `pyramid.json` describing it
+pub fn save_pyramid(mosaic: &DynamicImage, dir: &Path) -> Result<()> {
+    let (width, height) = mosaic.dimensions();
+    let mut max_level = 0;
+    while MAP_TILE_SIZE << max_level < width.max(height) {
+        max_level += 1;
+    }
+
+    let mut level_image = mosaic.clone();
+    for level in (0..=max_level).rev() {
+        let (level_width, level_height) = level_image.dimensions();
+        let columns = level_width.div_ceil(MAP_TILE_SIZE);
+        let rows = level_height.div_ceil(MAP_TILE_SIZE);
+        for column in (0..columns).progress_with(make_pbar("map tile columns", columns.into())) {
+            let column_dir = dir.join(level.to_string()).join(column.to_string());
+            fs::create_dir_all(&column_dir)?;
+            for row in 0..rows {
+                let (x, y) = (column * MAP_TILE_SIZE, row * MAP_TILE_SIZE);
+                level_image
+                    .crop_imm(
+                        x,
+                        y,
+                        MAP_TILE_SIZE.min(level_width - x),
+                        MAP_TILE_SIZE.min(level_height - y),
+                    )
+                    .save(column_dir.join(format!("{row}.png")))?;
+            }
+        }
+
+        if level > 0 {
+            level_image = level_image.resize_exact(
+                level_width.div_ceil(2),
+                level_height.div_ceil(2),
+                FilterType::Triangle,
+            );
+        }
+    }
+
+    fs::write(
+        dir.join("pyramid.json"),
+        format!(
+            "{{\"width\":{},\"height\":{},\"tile_size\":{},\"min_zoom\":0,\"max_zoom\":{},\"format\":\"png\",\"path\":\"{{z}}/{{x}}/{{y}}.png\"}}\n",
+            width, height, MAP_TILE_SIZE, max_level
+        ),
+    )?;
+    eprintln!(
+        "Saved a pyramid of {} levels to {}",
+        max_level + 1,
+        dir.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! Reducing the number of unique colors in the target image, so that fewer of them have to be
+//! matched
+
+use std::collections::{HashMap, HashSet};
+
+use image::{DynamicImage, Rgba};
+
+/// A box of colors, along with how many pixels have each of them
+struct ColorBox(Vec<([u8; 4], usize)>);
+
+impl ColorBox {
+    /// The channel along which the box's colors spread the most, and by how much
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..4)
+            .map(|channel| {
+                let values = self.0.iter().map(|(color, _count)| color[channel]);
+                let (min, max) = values.fold((u8::MAX, u8::MIN), |(min, max), value| {
+                    (min.min(value), max.max(value))
+                });
+                (channel, max.saturating_sub(min))
+            })
+            .max_by_key(|&(channel, range)| (range, std::cmp::Reverse(channel)))
+            .unwrap()
+    }
+
+    /// Split the box in two along its widest channel, at the median pixel
+    fn split(mut self) -> (Self, Self) {
+        let (channel, _range) = self.widest_channel();
+        self.0.sort_by_key(|(color, _count)| color[channel]);
+
+        let total = self.0.iter().map(|(_color, count)| count).sum::<usize>();
+        let mut seen = 0;
+        let median = self
+            .0
+            .iter()
+            .position(|(_color, count)| {
+                seen += count;
+                seen * 2 >= total
+            })
+            .unwrap();
+        // Both halves must keep at least one color
+        let at = (median + 1).clamp(1, self.0.len() - 1);
+        let rest = self.0.split_off(at);
+        (self, Self(rest))
+    }
+
+    /// The mean color of the box, weighed by how many pixels have each color
+    fn mean(&self) -> [u8; 4] {
+        let total = self.0.iter().map(|(_color, count)| count).sum::<usize>();
+        let mut sums = [0usize; 4];
+        for (color, count) in &self.0 {
+            for (sum, &c) in sums.iter_mut().zip(color) {
+                *sum += usize::from(c) * count;
+            }
+        }
+        sums.map(|sum| ((sum + total / 2) / total) as u8)
+    }
+}
+
+/// Quantize the image to at most `max_colors` unique colors with the median cut algorithm,
+/// returning it along with how many unique colors it had before and has after
+pub fn median_cut(image: &DynamicImage, max_colors: usize) -> (DynamicImage, usize, usize) {
+    let mut image = image.to_rgba8();
+    let mut counts = HashMap::new();
+    for pixel in image.pixels() {
+        *counts.entry(pixel.0).or_insert(0) += 1;
+    }
+    let before = counts.len();
+    if before <= max_colors {
+        return (DynamicImage::ImageRgba8(image), before, before);
+    }
+
+    // Sorted, so that the colors are split the same way on every run
+    let mut colors = counts.into_iter().collect::<Vec<_>>();
+    colors.sort_unstable();
+    let mut boxes = vec![ColorBox(colors)];
+    while boxes.len() < max_colors {
+        // Split the box whose colors spread the most, as long as any has more than one color
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_idx, colors)| colors.0.len() > 1)
+            .max_by_key(|(_idx, colors)| colors.widest_channel().1)
+            .map(|(idx, _colors)| idx);
+        let widest = match widest {
+            Some(widest) => widest,
+            None => break,
+        };
+        let (a, b) = boxes.swap_remove(widest).split();
+        boxes.push(a);
+        boxes.push(b);
+    }
+
+    let mut palette = HashMap::new();
+    for colors in &boxes {
+        let mean = colors.mean();
+        for (color, _count) in &colors.0 {
+            palette.insert(*color, mean);
+        }
+    }
+    for pixel in image.pixels_mut() {
+        *pixel = Rgba(palette[&pixel.0]);
+    }
+    let after = palette.values().collect::<HashSet<_>>().len();
+    (DynamicImage::ImageRgba8(image), before, after)
+}
@@ -0,0 +1,205 @@
+//! Downloading inputs and tiles over HTTP(S)
+//!
+//! Downloads are delegated to the system's `curl`, so that no HTTP or TLS stack needs to be
+//! compiled in.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use eyre::{bail, eyre, Result, WrapErr};
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+
+use crate::make_pbar;
+use crate::match_cache::{fnv1a, FNV_OFFSET};
+
+/// Check whether a command line argument is a URL rather than a path
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with("http://") || path.starts_with("https://"))
+}
+
+/// The directory downloads are cached in when no `--cache-dir` is given
+pub fn default_cache_dir() -> PathBuf {
+    std::env::temp_dir().join("themis-cache")
+}
+
+/// Hash a URL, to tell apart downloads of files with the same name
+///
+/// The hash must be the same with every build of themis, or each new one would download
+/// everything again into a cache of its own.
+fn url_hash(url: &str) -> String {
+    format!("{:016x}", fnv1a(FNV_OFFSET, url.as_bytes()))
+}
+
+/// Extract the file name from a URL, so that downloads keep their name and extension
+///
+/// URLs ending in `/`, `.` or `..` get a made up name instead, so that the download can't end up
+/// being the directory it's in or the one above.
+fn url_file_name(url: &str) -> &str {
+    url.split(['?', '#'])
+        .next()
+        .and_then(|url| url.rsplit('/').next())
+        .filter(|name| !matches!(*name, "" | "." | ".."))
+        .unwrap_or("download")
+}
+
+/// Where a download of the given URL is cached: under its name, in a subdirectory named after
+/// the URL's hash
+fn cached_path(url: &str, cache_dir: &Path) -> PathBuf {
+    cache_dir.join(url_hash(url)).join(url_file_name(url))
+}
+
+/// Download the given URL into the cache directory, unless it's already there, returning the
+/// path of the downloaded file
+pub fn download(url: &str, cache_dir: &Path) -> Result<PathBuf> {
+    let path = cached_path(url, cache_dir);
+    if !path.exists() {
+        download_to(url, &path)?;
+    }
+    Ok(path)
+}
+
+/// Download the given URL to the given path, replacing whatever is there
+fn download_to(url: &str, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Download to a temporary name first, so that an interrupted download isn't mistaken for a
+    // cached one on the next run
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(".part");
+    let output = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--output",
+        ])
+        .arg(&partial)
+        .arg(url)
+        .output()
+        .wrap_err("failed to run curl, is it installed?")?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&partial);
+        bail!(
+            "failed to download {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    fs::rename(&partial, path)?;
+
+    Ok(())
+}
+
+/// Download every tile listed in the manifest at the given URL, one URL per line, into a
+/// directory of their own, returning that directory
+///
+/// Blank lines and lines starting with `#` are ignored. Tiles that fail to download are skipped
+/// with a warning, just like tiles that fail to decode. Only the tiles are cached: the manifest
+/// is downloaded again every time, falling back to the cached copy if that fails, and tiles left
+/// in the directory that it no longer lists are removed.
+pub fn download_tiles(manifest_url: &str, cache_dir: &Path) -> Result<PathBuf> {
+    let manifest_path = cached_path(manifest_url, cache_dir);
+    if let Err(err) = download_to(manifest_url, &manifest_path) {
+        if !manifest_path.exists() {
+            return Err(err);
+        }
+        eprintln!("warning: {err}, using the tile manifest downloaded before");
+    }
+    let manifest = fs::read_to_string(manifest_path)
+        .wrap_err_with(|| eyre!("the tile manifest at {} is not valid UTF-8", manifest_url))?;
+    let urls = manifest
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+
+    let tiles_dir = cache_dir.join(url_hash(manifest_url)).join("tiles");
+    let paths = urls
+        .iter()
+        .map(|url| tiles_dir.join(format!("{}-{}", url_hash(url), url_file_name(url))))
+        .collect::<HashSet<_>>();
+    if tiles_dir.exists() {
+        for entry in fs::read_dir(&tiles_dir)? {
+            let path = entry?.path();
+            if !paths.contains(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+
+    let len = urls.len();
+    urls.into_par_iter()
+        .progress_with(make_pbar("tiles downloaded", len as _))
+        .for_each(|url| {
+            let path = tiles_dir.join(format!("{}-{}", url_hash(url), url_file_name(url)));
+            if path.exists() {
+                return;
+            }
+            if let Err(err) = download_to(url, &path) {
+                eprintln!("warning: {err}");
+            }
+        });
+
+    Ok(tiles_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_hashes_are_the_same_on_every_build() {
+        assert_eq!(
+            url_hash("https://example.com/tiles.txt"),
+            "c545d3a834e5860f"
+        );
+    }
+
+    #[test]
+    fn url_file_names_stay_inside_their_directory() {
+        assert_eq!(
+            url_file_name("https://example.com/cat.png?size=2"),
+            "cat.png"
+        );
+        assert_eq!(url_file_name("https://example.com/tiles/"), "download");
+        assert_eq!(url_file_name("https://example.com/."), "download");
+        assert_eq!(url_file_name("https://example.com/.."), "download");
+    }
+
+    #[test]
+    fn manifests_are_downloaded_again_every_time() {
+        let dir = std::env::temp_dir().join(format!("themis-remote-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["a.png", "b.png"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        let url = |name: &str| format!("file://{}", dir.join(name).display());
+        let cache_dir = dir.join("cache");
+
+        let tiles = |manifest: &str| {
+            fs::write(dir.join("tiles.txt"), manifest).unwrap();
+            let tiles_dir = download_tiles(&url("tiles.txt"), &cache_dir).unwrap();
+            let mut tiles = fs::read_dir(tiles_dir)
+                .unwrap()
+                .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+                .collect::<Vec<_>>();
+            tiles.sort();
+            tiles
+        };
+        assert_eq!(tiles(&url("a.png")), ["a.png"]);
+        assert_eq!(
+            tiles(&format!("{}\n{}", url("a.png"), url("b.png"))),
+            ["a.png", "b.png"]
+        );
+        assert_eq!(tiles(&url("b.png")), ["b.png"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
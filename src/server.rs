@@ -0,0 +1,249 @@
+//! Serving mosaics over HTTP, for the `serve` subcommand
+//!
+//! The tileset is loaded once, then every image POSTed to `/mosaic` is turned into a mosaic by
+//! matching each cell's average color and sent back as a PNG. Only as many requests as there are
+//! workers are handled at once, the others waiting for their turn in the listener's backlog, so
+//! that a burst of large uploads can't run the machine out of memory. Connections that go quiet
+//! for longer than the timeout are answered with a 408, so that idle or stalled clients can't
+//! hold on to every worker. The server is a bare
+//! HTTP/1.1 implementation on top of the standard library, handling one request per connection.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use eyre::{bail, eyre, Result};
+use image::{GenericImageView, ImageOutputFormat};
+use rayon::prelude::*;
+
+use crate::matching::{pick_image_for_pixel, ChannelWeights, Placement};
+use crate::placement::{self, PlacementOptions};
+use crate::tiles::Tile;
+
+/// The largest image accepted, in bytes
+const MAX_BODY: usize = 64 * 1024 * 1024;
+
+/// The largest `mosaic-size` accepted, in cells along each side
+const MAX_MOSAIC_SIZE: u32 = 1024;
+
+/// The settings every request starts from, before its query string overrides them
+pub struct ServeOptions {
+    pub mosaic_size: u32,
+    pub keep_aspect_ratio: bool,
+    pub channel_weights: ChannelWeights,
+    pub placement: PlacementOptions,
+
+    /// How long to wait for a client to send or receive anything before giving up on it
+    pub timeout: Duration,
+}
+
+/// An error to answer a request with, as its status code and message
+struct HttpError(u16, String);
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self(status, message.into())
+    }
+}
+
+/// The reason phrase of the status codes the server answers with
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Listen on `address`, handling up to `workers` requests at once, until the process is killed
+pub fn serve(
+    address: &str,
+    workers: usize,
+    possible_tiles: &[Tile],
+    options: &ServeOptions,
+) -> Result<()> {
+    if possible_tiles.is_empty() {
+        bail!("there are no tiles to serve mosaics of");
+    }
+    let listener = TcpListener::bind(address)?;
+    eprintln!(
+        "Serving mosaics of {} tiles on http://{}/mosaic",
+        possible_tiles.len(),
+        listener.local_addr()?
+    );
+    thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            let listener = listener.try_clone()?;
+            scope.spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => handle(stream, possible_tiles, options),
+                        Err(err) => eprintln!("warning: couldn't accept a connection: {err}"),
+                    }
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Answer a single request, logging any error writing the response
+fn handle(mut stream: TcpStream, possible_tiles: &[Tile], options: &ServeOptions) {
+    let timeouts = stream
+        .set_read_timeout(Some(options.timeout))
+        .and_then(|()| stream.set_write_timeout(Some(options.timeout)));
+    if let Err(err) = timeouts {
+        eprintln!("warning: couldn't set the connection's timeouts: {err}");
+        return;
+    }
+    let (status, content_type, body) = match respond(&mut stream, possible_tiles, options) {
+        Ok(png) => (200, "image/png", png),
+        Err(HttpError(status, message)) => (status, "text/plain", format!("{message}\n").into()),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len()
+    );
+    let written = stream
+        .write_all(head.as_bytes())
+        .and_then(|()| stream.write_all(&body));
+    if let Err(err) = written {
+        eprintln!("warning: couldn't send a response: {err}");
+    }
+}
+
+/// Read a request and build the mosaic it asks for, encoded as a PNG
+fn respond(
+    stream: &mut TcpStream,
+    possible_tiles: &[Tile],
+    options: &ServeOptions,
+) -> Result<Vec<u8>, HttpError> {
+    let bad_request = |err: std::io::Error| match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            HttpError::new(408, "the request took too long to arrive")
+        }
+        _ => HttpError::new(400, err.to_string()),
+    };
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).map_err(bad_request)?;
+    let mut words = request_line.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err(HttpError::new(400, "malformed request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/mosaic" {
+        return Err(HttpError::new(404, "mosaics are made at /mosaic"));
+    }
+    if method != "POST" {
+        return Err(HttpError::new(405, "POST the image to make a mosaic of"));
+    }
+
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(bad_request)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                let length = value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| HttpError::new(400, "malformed Content-Length"))?;
+                content_length = Some(length);
+            }
+        }
+    }
+    let content_length =
+        content_length.ok_or_else(|| HttpError::new(411, "the image needs a Content-Length"))?;
+    if content_length > MAX_BODY {
+        return Err(HttpError::new(
+            413,
+            format!("images can be at most {MAX_BODY} bytes"),
+        ));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(bad_request)?;
+
+    let (mosaic_size, flat) =
+        parse_query(query, options).map_err(|err| HttpError::new(400, err.to_string()))?;
+    build(&body, mosaic_size, flat, possible_tiles, options)
+        .map_err(|err| HttpError::new(400, format!("{err:#}")))
+}
+
+/// Read the settings a request overrides from its query string: `mosaic-size=N` and `flat`
+fn parse_query(query: &str, options: &ServeOptions) -> Result<(u32, bool)> {
+    let (mut mosaic_size, mut flat) = (options.mosaic_size, options.placement.flat);
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match name {
+            "mosaic-size" => {
+                mosaic_size = value
+                    .parse()
+                    .ok()
+                    .filter(|size| (1..=MAX_MOSAIC_SIZE).contains(size))
+                    .ok_or_else(|| {
+                        eyre!("mosaic-size must be from 1 to {MAX_MOSAIC_SIZE}, got {value:?}")
+                    })?;
+            }
+            "flat" => flat = matches!(value, "" | "1" | "true"),
+            _ => bail!("unknown option {:?}", name),
+        }
+    }
+    Ok((mosaic_size, flat))
+}
+
+/// Make a mosaic of an encoded image, encoded as a PNG
+fn build(
+    image: &[u8],
+    mosaic_size: u32,
+    flat: bool,
+    possible_tiles: &[Tile],
+    options: &ServeOptions,
+) -> Result<Vec<u8>> {
+    let source = image::load_from_memory(image)?;
+    let img = if options.keep_aspect_ratio {
+        source.thumbnail(mosaic_size, mosaic_size)
+    } else {
+        source.thumbnail_exact(mosaic_size, mosaic_size)
+    };
+
+    let unique_pixels = img
+        .pixels()
+        .map(|(_x, _y, pixel)| pixel)
+        .collect::<HashSet<_>>();
+    let tiles = unique_pixels
+        .into_par_iter()
+        .filter_map(|pixel| {
+            let tile = pick_image_for_pixel(pixel, possible_tiles, options.channel_weights, None)?;
+            Some((pixel, Placement::new(tile)))
+        })
+        .collect::<HashMap<_, _>>();
+    let cells = img
+        .pixels()
+        .map(|(_x, _y, pixel)| tiles[&pixel])
+        .collect::<Vec<_>>();
+
+    let placement = PlacementOptions {
+        flat,
+        ..options.placement
+    };
+    let mosaic = placement::place_tiles(&img, &cells, possible_tiles, &placement)?;
+    let mut png = Vec::new();
+    mosaic.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+    Ok(png)
+}
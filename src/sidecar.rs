@@ -0,0 +1,86 @@
+//! Overriding some settings for a single input, with a sidecar file next to it
+//!
+//! An input's sidecar is named after its whole file name followed by `.themis.toml`, e.g.
+//! `photo.jpg.themis.toml`, and holds a `setting = value` line for each setting it overrides,
+//! named like the command line option it overrides, e.g. `mosaic-size = 256`. Settings it
+//! doesn't mention keep the values given on the command line. Only the settings that don't
+//! change how the tiles are loaded can be overridden, since the tiles are loaded once for every
+//! input.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, eyre, Result, WrapErr};
+
+use crate::placement::Recolor;
+use crate::{parse_non_negative, parse_nonzero};
+
+/// The settings that a sidecar overrides, `None` for those it doesn't
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub mosaic_size: Option<u32>,
+    pub sharpen: Option<f32>,
+    pub pre_blur: Option<f32>,
+    pub coherence: Option<f64>,
+    pub usage_penalty: Option<u32>,
+    pub recolor: Option<Recolor>,
+}
+
+/// The path of the given input's sidecar
+fn sidecar_path(input: &Path) -> PathBuf {
+    let mut file_name = input.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".themis.toml");
+    input.with_file_name(file_name)
+}
+
+/// Check whether a file is a sidecar, rather than an input
+pub fn is_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(".themis.toml"))
+}
+
+/// Read the overrides in the given input's sidecar, none at all if it doesn't have one
+pub fn load_overrides(input: &Path) -> Result<Overrides> {
+    let path = sidecar_path(input);
+    if !path.is_file() {
+        return Ok(Overrides::default());
+    }
+
+    let mut overrides = Overrides::default();
+    for (line_number, line) in fs::read_to_string(&path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let context = || format!("line {} of {}", line_number + 1, path.display());
+        let (setting, value) = line
+            .split_once('=')
+            .ok_or_else(|| eyre!("{}: expected a setting, `=` and a value", context()))?;
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        match setting.trim().replace('_', "-").as_str() {
+            "mosaic-size" => overrides.mosaic_size = Some(parse_nonzero(value).wrap_err_with(context)?),
+            "sharpen" => {
+                overrides.sharpen = Some(parse_non_negative(value).wrap_err_with(context)? as f32)
+            }
+            "pre-blur" => {
+                overrides.pre_blur = Some(parse_non_negative(value).wrap_err_with(context)? as f32)
+            }
+            "coherence" => overrides.coherence = Some(parse_non_negative(value).wrap_err_with(context)?),
+            "usage-penalty" => {
+                overrides.usage_penalty = Some(value.parse().wrap_err_with(context)?)
+            }
+            "recolor" => overrides.recolor = Some(value.parse().wrap_err_with(context)?),
+            setting => bail!(
+                "{}: {:?} can't be overridden, only mosaic-size, sharpen, pre-blur, coherence, usage-penalty and recolor can",
+                context(),
+                setting
+            ),
+        }
+    }
+    eprintln!("Using the overrides in {}", path.display());
+    Ok(overrides)
+}
@@ -0,0 +1,141 @@
+//! Measuring how well a tileset covers an image's colors, without building the mosaic
+
+use std::collections::HashMap;
+
+use image::Rgba;
+
+use crate::{hex_color, json_string};
+
+/// How many of the worst covered colors to report
+const WORST_COLORS: usize = 5;
+
+/// How wide a range of each channel's values is grouped together when grouping the image's
+/// colors
+const BIN_SIZE: u8 = 32;
+
+/// A group of similar colors in the image, and how well they were matched
+pub struct ColorCoverage {
+    /// The color at the center of the group
+    pub color: Rgba<u8>,
+
+    /// How many cells have a color in the group
+    pub cells: usize,
+
+    /// The mean match error of those cells
+    pub mean_error: f64,
+}
+
+/// How well the tiles picked for an image's cells match them
+pub struct CoverageStats {
+    pub cells: usize,
+    pub mean_error: f64,
+    pub median_error: i64,
+    pub p90_error: i64,
+    pub max_error: i64,
+
+    /// The error below which a cell counts as well matched
+    pub threshold: i64,
+
+    /// The fraction of cells whose error is below the threshold
+    pub below_threshold: f64,
+
+    /// The groups of colors with the worst mean error, worst first
+    pub worst_colors: Vec<ColorCoverage>,
+}
+
+impl CoverageStats {
+    /// Summarize the match errors of each cell, given along with the cell's color
+    pub fn new(pixels: &[Rgba<u8>], errors: &[i64], threshold: i64) -> Self {
+        let cells = errors.len();
+        let mut sorted = errors.to_vec();
+        sorted.sort_unstable();
+        let percentile = |p: usize| sorted.get((cells * p / 100).min(cells.saturating_sub(1)));
+
+        let mut groups = HashMap::<_, (usize, i64)>::new();
+        for (&Rgba([r, g, b, _a]), &error) in pixels.iter().zip(errors) {
+            let group = groups.entry([r, g, b].map(|c| c / BIN_SIZE)).or_default();
+            group.0 += 1;
+            group.1 += error;
+        }
+        let mut worst_colors = groups
+            .into_iter()
+            .map(|(bin, (cells, total))| ColorCoverage {
+                color: {
+                    let [r, g, b] = bin.map(|c| c * BIN_SIZE + BIN_SIZE / 2);
+                    Rgba([r, g, b, 255])
+                },
+                cells,
+                mean_error: total as f64 / cells as f64,
+            })
+            .collect::<Vec<_>>();
+        worst_colors.sort_by(|a, b| {
+            b.mean_error
+                .total_cmp(&a.mean_error)
+                .then(a.color.0.cmp(&b.color.0))
+        });
+        worst_colors.truncate(WORST_COLORS);
+
+        Self {
+            cells,
+            mean_error: errors.iter().sum::<i64>() as f64 / cells.max(1) as f64,
+            median_error: percentile(50).copied().unwrap_or(0),
+            p90_error: percentile(90).copied().unwrap_or(0),
+            max_error: sorted.last().copied().unwrap_or(0),
+            threshold,
+            below_threshold: errors.iter().filter(|&&error| error < threshold).count() as f64
+                / cells.max(1) as f64,
+            worst_colors,
+        }
+    }
+
+    /// Print a human readable report
+    pub fn print_report(&self, name: &str) {
+        println!("{name}: {} cells", self.cells);
+        println!(
+            "  error: mean {:.0}, median {}, 90th percentile {}, max {}",
+            self.mean_error, self.median_error, self.p90_error, self.max_error
+        );
+        println!(
+            "  {:.1}% of cells have an error below {}",
+            self.below_threshold * 100.,
+            self.threshold
+        );
+        println!("  worst covered colors:");
+        for coverage in &self.worst_colors {
+            println!(
+                "    {} ({} cells, mean error {:.0})",
+                hex_color(coverage.color),
+                coverage.cells,
+                coverage.mean_error
+            );
+        }
+    }
+
+    /// Render the stats as a single line JSON object
+    pub fn to_json(&self, name: &str) -> String {
+        let worst_colors = self
+            .worst_colors
+            .iter()
+            .map(|coverage| {
+                format!(
+                    r#"{{"color":{},"cells":{},"mean_error":{:.1}}}"#,
+                    json_string(&hex_color(coverage.color)),
+                    coverage.cells,
+                    coverage.mean_error
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            r#"{{"input":{},"cells":{},"mean_error":{:.1},"median_error":{},"p90_error":{},"max_error":{},"threshold":{},"below_threshold":{:.4},"worst_colors":[{}]}}"#,
+            json_string(name),
+            self.cells,
+            self.mean_error,
+            self.median_error,
+            self.p90_error,
+            self.max_error,
+            self.threshold,
+            self.below_threshold,
+            worst_colors.join(",")
+        )
+    }
+}
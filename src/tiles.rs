@@ -0,0 +1,1253 @@
+//! Loading the tileset
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, DirEntry};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use eyre::{bail, eyre, Result, WrapErr};
+use image::imageops::FilterType;
+use image::{imageops, DynamicImage, GenericImageView, GrayImage, Pixel, Rgba};
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+
+use crate::decode;
+use crate::dominant::Dominant;
+use crate::make_pbar;
+use crate::matching::{distance, ChannelWeights};
+use crate::placement::to_hsl;
+
+/// A tile, ready to be placed in a mosaic
+#[derive(Clone)]
+pub struct Tile {
+    /// Where the tile was loaded from
+    pub path: PathBuf,
+
+    /// The tile itself, once it's been loaded, unless it's loaded lazily
+    image: OnceLock<Arc<DynamicImage>>,
+
+    /// Where the tile is kept once it's been loaded, if it's loaded lazily
+    cache: Option<Arc<TileCache>>,
+
+    /// The side length to resize the tile to when loading it
+    tile_side: u32,
+
+    /// Whether to balance the tile's white when loading it
+    place_normalized: bool,
+
+    /// How to resize the tile if it's smaller than a cell
+    small_tiles: SmallTiles,
+
+    /// The color that represents the tile when matching
+    pub average: Rgba<u8>,
+
+    /// How much the tile is favored when matching, as distances to it are divided by this
+    pub weight: f64,
+
+    /// How much is added to distances to the tile when matching, before weighing them
+    pub penalty: i64,
+
+    /// The average colors of the tile's top and bottom halves, when matching vertical gradients
+    pub halves: [Rgba<u8>; 2],
+
+    /// The average colors of each subregion of the tile, in every orientation it may be placed
+    /// in, when matching by structure
+    pub signatures: Vec<(Orientation, Vec<Rgba<u8>>)>,
+
+    /// The tile's dominant colors, when matching by them
+    pub dominants: Vec<Dominant>,
+
+    /// The tile's width over its height as it was loaded, before being resized into a square
+    /// cell
+    pub aspect: f64,
+}
+
+impl Tile {
+    /// A tile whose colors are already known, e.g. from an index, and whose image is only
+    /// loaded once it's needed
+    pub fn unloaded(
+        path: PathBuf,
+        average: Rgba<u8>,
+        halves: [Rgba<u8>; 2],
+        signatures: Vec<(Orientation, Vec<Rgba<u8>>)>,
+        aspect: f64,
+        options: &LoadOptions,
+    ) -> Self {
+        Self {
+            path,
+            image: OnceLock::new(),
+            cache: options.cache.clone(),
+            tile_side: options.tile_side,
+            place_normalized: options.normalize_white_balance && options.place_normalized,
+            small_tiles: options.small_tiles,
+            average,
+            weight: 1.,
+            penalty: 0,
+            halves,
+            signatures,
+            dominants: Vec::new(),
+            aspect,
+        }
+    }
+
+    /// Scale a distance to the tile by its weight, after adding its penalty
+    pub fn weigh(&self, distance: i64) -> i64 {
+        let distance = distance + self.penalty;
+        if self.weight == 1. {
+            distance
+        } else {
+            (distance as f64 / self.weight).round() as i64
+        }
+    }
+
+    /// Like `weigh`, for distances that aren't whole numbers
+    pub fn weigh_f64(&self, distance: f64) -> f64 {
+        (distance + self.penalty as f64) / self.weight
+    }
+
+    /// The tile itself, already resized to the mosaic's tile size, loading it first if needed
+    pub fn image(&self) -> Result<Arc<DynamicImage>> {
+        if let Some(image) = self.image.get() {
+            return Ok(Arc::clone(image));
+        }
+        if let Some(cache) = &self.cache {
+            return cache.get_or_load(&self.path, || self.load());
+        }
+        let image = Arc::new(self.load()?);
+        Ok(Arc::clone(self.image.get_or_init(|| image)))
+    }
+
+    /// Decode and resize the tile
+    fn load(&self) -> Result<DynamicImage> {
+        let image = decode::open(&self.path)
+            .wrap_err_with(|| format!("couldn't load the tile {}", self.path.display()))?;
+        let image = resize_tile(&image, self.tile_side, self.small_tiles);
+        Ok(if self.place_normalized {
+            gray_world(&image)
+        } else {
+            image
+        })
+    }
+}
+
+/// The images of the tiles loaded lazily that were used most recently, so that the tiles a
+/// mosaic uses over and over aren't decoded again every time
+pub struct TileCache {
+    /// The most images kept at once
+    capacity: usize,
+
+    entries: Mutex<CacheEntries>,
+}
+
+/// The images in a `TileCache`, found by their tiles' paths, and when each was last used
+#[derive(Default)]
+struct CacheEntries {
+    /// Each image along with when it was last used
+    images: HashMap<Arc<Path>, (Arc<DynamicImage>, u64)>,
+
+    /// The path of each image by when it was last used, the least recently used first
+    recency: BTreeMap<u64, Arc<Path>>,
+
+    /// When an image is used next, counting up every time one is
+    clock: u64,
+}
+
+impl CacheEntries {
+    /// Mark the image at the given path as the most recently used one, returning it if it's in
+    /// the cache
+    fn touch(&mut self, path: &Path) -> Option<Arc<DynamicImage>> {
+        let (image, used) = self.images.get_mut(path)?;
+        let path = self.recency.remove(used).unwrap();
+        *used = self.clock;
+        self.recency.insert(self.clock, path);
+        self.clock += 1;
+        Some(Arc::clone(image))
+    }
+}
+
+impl TileCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    /// Get the image of the tile at the given path, loading it if it isn't in the cache and
+    /// evicting the least recently used image to make room for it if need be
+    fn get_or_load(
+        &self,
+        path: &Path,
+        load: impl FnOnce() -> Result<DynamicImage>,
+    ) -> Result<Arc<DynamicImage>> {
+        if let Some(image) = self.entries.lock().unwrap().touch(path) {
+            return Ok(image);
+        }
+
+        // Decoded without holding the lock, so that other tiles can be loaded meanwhile
+        let image = Arc::new(load()?);
+        let mut entries = self.entries.lock().unwrap();
+        // Another thread may have loaded the same tile meanwhile
+        if let Some(image) = entries.touch(path) {
+            return Ok(image);
+        }
+        if entries.images.len() >= self.capacity {
+            if let Some((_used, evicted)) = entries.recency.pop_first() {
+                entries.images.remove(&evicted);
+            }
+        }
+        let (path, used) = (Arc::<Path>::from(path), entries.clock);
+        entries.clock += 1;
+        entries.recency.insert(used, Arc::clone(&path));
+        entries.images.insert(path, (Arc::clone(&image), used));
+        Ok(image)
+    }
+}
+
+/// How a tile is rotated and/or flipped before being placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Orientation {
+    #[default]
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose,
+    Transverse,
+}
+
+impl Orientation {
+    pub const ALL: [Self; 8] = [
+        Self::Identity,
+        Self::Rotate90,
+        Self::Rotate180,
+        Self::Rotate270,
+        Self::FlipHorizontal,
+        Self::FlipVertical,
+        Self::Transpose,
+        Self::Transverse,
+    ];
+
+    /// Rotate and/or flip the image accordingly
+    pub fn apply(self, image: &DynamicImage) -> Cow<'_, DynamicImage> {
+        Cow::Owned(match self {
+            Self::Identity => return Cow::Borrowed(image),
+            Self::Rotate90 => image.rotate90(),
+            Self::Rotate180 => image.rotate180(),
+            Self::Rotate270 => image.rotate270(),
+            Self::FlipHorizontal => image.fliph(),
+            Self::FlipVertical => image.flipv(),
+            Self::Transpose => image.rotate90().fliph(),
+            Self::Transverse => image.rotate270().fliph(),
+        })
+    }
+}
+
+/// How tiles should be loaded
+pub struct LoadOptions {
+    /// The side length to resize tiles to
+    pub tile_side: u32,
+
+    /// The percentage of each side, around the center, to compute the average color from
+    pub average_inset: f64,
+
+    /// The order to load the tiles in
+    pub sort: TileSort,
+
+    /// How many subregions per side to compute signatures for, if matching by structure
+    pub subregions: Option<u32>,
+
+    /// Whether to compute signatures for every orientation, rather than just the original one
+    pub variants: bool,
+
+    /// Whether to match tiles by their colors after balancing their white
+    pub normalize_white_balance: bool,
+
+    /// Whether to also place the white balanced tiles, rather than the original ones
+    pub place_normalized: bool,
+
+    /// What to do with tiles smaller than the tile size
+    pub small_tiles: SmallTiles,
+
+    /// The contrast below which tiles are left out of the tileset, as measured by `contrast`
+    pub min_contrast: f64,
+
+    /// Whether to compute each tile's average color from the region its focus mask selects, if
+    /// it has one
+    pub focus_masks: bool,
+
+    /// Where to keep the tiles once they've been placed, if they're to be loaded lazily: only
+    /// their colors are kept once they're loaded, and their images are decoded again whenever
+    /// they're needed and aren't in the cache
+    pub cache: Option<Arc<TileCache>>,
+}
+
+/// What to do with tiles smaller than the tile size along either side, which smooth upscaling
+/// turns into blurry blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmallTiles {
+    /// Upscale them smoothly like any other tile
+    Upscale,
+
+    /// Leave them out of the tileset
+    Skip,
+
+    /// Upscale them with nearest neighbor sampling, keeping their pixels crisp
+    Nearest,
+}
+
+/// Whether an image is smaller than a cell along either side
+fn is_small(image: &DynamicImage, side: u32) -> bool {
+    image.width() < side || image.height() < side
+}
+
+/// Resize a tile to fill a cell
+fn resize_tile(image: &DynamicImage, side: u32, small_tiles: SmallTiles) -> DynamicImage {
+    if small_tiles == SmallTiles::Nearest && is_small(image, side) {
+        image.resize_exact(side, side, FilterType::Nearest)
+    } else {
+        image.thumbnail_exact(side, side)
+    }
+}
+
+/// The order in which tiles are loaded
+///
+/// Whatever depends on the order of the tileset (e.g. which of two equally good tiles gets
+/// picked) is reproducible as long as this is, which `fs::read_dir`'s order is not.
+#[derive(Debug, Clone, Copy)]
+pub enum TileSort {
+    /// Lexicographically by path
+    Name,
+
+    /// By last modification time, oldest first
+    Modified,
+
+    /// By file size, smallest first
+    Size,
+}
+
+impl TileSort {
+    /// Sort the directory entries, breaking ties by path
+    ///
+    /// Entries whose metadata can't be read sort first, they'll most likely fail to load anyway.
+    fn sort(self, entries: &mut [DirEntry]) {
+        match self {
+            Self::Name => entries.sort_by_key(DirEntry::path),
+            Self::Modified => entries.sort_by_cached_key(|entry| {
+                let modified = entry.metadata().and_then(|meta| meta.modified()).ok();
+                (modified, entry.path())
+            }),
+            Self::Size => entries.sort_by_cached_key(|entry| {
+                (entry.metadata().map(|meta| meta.len()).ok(), entry.path())
+            }),
+        }
+    }
+}
+
+impl FromStr for TileSort {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "name" => Ok(Self::Name),
+            "modified" => Ok(Self::Modified),
+            "size" => Ok(Self::Size),
+            _ => bail!("unknown tile sort order {:?}", s),
+        }
+    }
+}
+
+/// Calculate the average color of a given image by averaging all of its pixels together (including alpha)
+pub fn average_color(image: &DynamicImage) -> Rgba<u8> {
+    let pixel_count = image.width() as f64 * image.height() as f64;
+
+    let (mut r, mut g, mut b, mut a) = (0., 0., 0., 0.);
+    for (_x, _y, Rgba([pr, pg, pb, pa])) in image.pixels() {
+        r += pr as f64 * pr as f64;
+        g += pg as f64 * pg as f64;
+        b += pb as f64 * pb as f64;
+        a += pa as f64 * pa as f64;
+    }
+    let r = (r / pixel_count).sqrt() as u8;
+    let g = (g / pixel_count).sqrt() as u8;
+    let b = (b / pixel_count).sqrt() as u8;
+    let a = (a / pixel_count).sqrt() as u8;
+    Rgba([r, g, b, a])
+}
+
+/// Measure how much texture an image has, as the standard deviation of its pixels' luminance,
+/// from 0 for a single flat color to 127.5 for half black and half white
+pub fn contrast(image: &DynamicImage) -> f64 {
+    let pixel_count = f64::from(image.width()) * f64::from(image.height());
+    let (sum, sum_of_squares) =
+        image
+            .pixels()
+            .fold((0., 0.), |(sum, sum_of_squares), (_x, _y, pixel)| {
+                let luma = f64::from(pixel.to_luma()[0]);
+                (sum + luma, sum_of_squares + luma * luma)
+            });
+    let mean = sum / pixel_count.max(1.);
+    (sum_of_squares / pixel_count.max(1.) - mean * mean)
+        .max(0.)
+        .sqrt()
+}
+
+/// Calculate the average color of an image like `average_color`, but weighing each pixel by the
+/// mask's brightness there, or `None` if the mask is entirely black
+fn masked_average_color(image: &DynamicImage, mask: &GrayImage) -> Option<Rgba<u8>> {
+    let mask = imageops::resize(mask, image.width(), image.height(), FilterType::Triangle);
+    let mut sums = [0.; 4];
+    let mut total = 0.;
+    for (x, y, pixel) in image.pixels() {
+        let weight = f64::from(mask.get_pixel(x, y)[0]);
+        for (sum, channel) in sums.iter_mut().zip(pixel.0) {
+            *sum += weight * f64::from(channel) * f64::from(channel);
+        }
+        total += weight;
+    }
+    (total > 0.).then(|| Rgba(sums.map(|sum| (sum / total).sqrt() as u8)))
+}
+
+/// The path of a tile's focus mask, a grayscale PNG named after it, e.g. `cat.mask.png` for
+/// `cat.jpg`
+fn focus_mask_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(".mask.png");
+    path.with_file_name(file_name)
+}
+
+/// Check whether a file is the focus mask of some tile, rather than a tile itself
+pub fn is_focus_mask(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(".mask.png"))
+}
+
+/// Crop an image to the central `percent`% of its area along each side
+fn central_region(image: &DynamicImage, percent: f64) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let inset_width = ((f64::from(width) * percent / 100.).round() as u32).clamp(1, width);
+    let inset_height = ((f64::from(height) * percent / 100.).round() as u32).clamp(1, height);
+    image.crop_imm(
+        (width - inset_width) / 2,
+        (height - inset_height) / 2,
+        inset_width,
+        inset_height,
+    )
+}
+
+/// Split an image into `side`x`side` subregions and compute each one's average color, row by row
+pub fn signature(image: &DynamicImage, side: u32) -> Vec<Rgba<u8>> {
+    let (width, height) = image.dimensions();
+    let bounds = |i: u32, len: u32| {
+        let start = i * len / side;
+        let end = ((i + 1) * len / side).clamp(start + 1, len);
+        (start, end - start)
+    };
+
+    (0..side)
+        .flat_map(|row| {
+            (0..side).map(move |col| {
+                let (x, width) = bounds(col, width);
+                let (y, height) = bounds(row, height);
+                average_color(&image.crop_imm(x, y, width, height))
+            })
+        })
+        .collect()
+}
+
+/// Compute the average colors of the top and bottom halves of an image
+///
+/// An image a single pixel tall has the same color in both halves.
+pub fn halves(image: &DynamicImage) -> [Rgba<u8>; 2] {
+    let (width, height) = image.dimensions();
+    let middle = height / 2;
+    let top = middle.max(1);
+    let bottom = middle.min(height - 1);
+    [
+        average_color(&image.crop_imm(0, 0, width, top)),
+        average_color(&image.crop_imm(0, bottom, width, height - bottom)),
+    ]
+}
+
+/// Balance an image's colors with the gray world assumption: scale each channel so that its
+/// mean becomes the mean of all three, so that the image's average color is neutral
+fn gray_world(image: &DynamicImage) -> DynamicImage {
+    let mut image = image.to_rgba8();
+    let pixel_count = f64::from(image.width() * image.height()).max(1.);
+    let mut means = [0.; 3];
+    for pixel in image.pixels() {
+        for (mean, &c) in means.iter_mut().zip(&pixel.0) {
+            *mean += f64::from(c) / pixel_count;
+        }
+    }
+
+    let gray = means.iter().sum::<f64>() / 3.;
+    let scales = means.map(|mean| if mean > 0. { gray / mean } else { 1. });
+    for pixel in image.pixels_mut() {
+        for (c, scale) in pixel.0.iter_mut().zip(scales) {
+            *c = (f64::from(*c) * scale).round().clamp(0., 255.) as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+/// Load a single tile, or `None` if it can't be decoded or is too small or too flat to keep
+fn load_tile(
+    path: PathBuf,
+    options: &LoadOptions,
+    skipped: &AtomicUsize,
+    flat: &AtomicUsize,
+) -> Option<Tile> {
+    let image = decode::open(&path).ok()?;
+    if options.small_tiles == SmallTiles::Skip && is_small(&image, options.tile_side) {
+        skipped.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+    let mask = options
+        .focus_masks
+        .then(|| decode::open(&focus_mask_path(&path)).ok())
+        .flatten()
+        .map(|mask| mask.to_luma8());
+    keep_contrasted(make_tile(path, image, mask, options), options, flat)
+}
+
+/// Keep a tile only if it has at least the minimum contrast, counting it otherwise
+///
+/// This is the last thing done with the image of a tile loaded lazily, which is then dropped.
+fn keep_contrasted(mut tile: Tile, options: &LoadOptions, flat: &AtomicUsize) -> Option<Tile> {
+    if options.min_contrast > 0. && contrast(&*tile.image().ok()?) < options.min_contrast {
+        flat.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+    if tile.cache.is_some() {
+        tile.image.take();
+    }
+    Some(tile)
+}
+
+/// Report how many tiles `keep_contrasted` left out, if any
+fn report_flat(flat: AtomicUsize, options: &LoadOptions) {
+    let flat = flat.into_inner();
+    if flat > 0 {
+        eprintln!(
+            "Dropped {} tiles with a contrast below {}",
+            flat, options.min_contrast
+        );
+    }
+}
+
+/// Turn an already decoded image into a tile, computing its average color from the region the
+/// focus mask selects if it has one
+fn make_tile(
+    path: PathBuf,
+    image: DynamicImage,
+    mask: Option<GrayImage>,
+    options: &LoadOptions,
+) -> Tile {
+    let LoadOptions {
+        tile_side,
+        average_inset,
+        sort: _,
+        subregions,
+        variants,
+        normalize_white_balance,
+        place_normalized,
+        small_tiles,
+        min_contrast: _,
+        focus_masks: _,
+        cache: _,
+    } = *options;
+
+    let aspect = f64::from(image.width()) / f64::from(image.height().max(1));
+    let image = resize_tile(&image, tile_side, small_tiles);
+
+    // The colors used for matching, which may differ from the ones that get placed
+    let normalized = if normalize_white_balance {
+        Cow::Owned(gray_world(&image))
+    } else {
+        Cow::Borrowed(&image)
+    };
+
+    let masked = mask.and_then(|mask| masked_average_color(&normalized, &mask));
+    let average = if let Some(masked) = masked {
+        masked
+    } else if average_inset < 100. {
+        average_color(&central_region(&normalized, average_inset))
+    } else {
+        average_color(&normalized)
+    };
+    let halves = halves(&normalized);
+    let orientations = if variants {
+        &Orientation::ALL[..]
+    } else {
+        &Orientation::ALL[..1]
+    };
+    let signatures = subregions.map_or_else(Vec::new, |side| {
+        orientations
+            .iter()
+            .map(|&orientation| {
+                (
+                    orientation,
+                    signature(&orientation.apply(&normalized), side),
+                )
+            })
+            .collect()
+    });
+
+    let place_normalized = normalize_white_balance && place_normalized;
+    let image = match normalized {
+        Cow::Owned(normalized) if place_normalized => normalized,
+        _ => image,
+    };
+    Tile {
+        path,
+        image: OnceLock::from(Arc::new(image)),
+        cache: options.cache.clone(),
+        tile_side,
+        place_normalized,
+        small_tiles,
+        average,
+        weight: 1.,
+        penalty: 0,
+        halves,
+        signatures,
+        dominants: Vec::new(),
+        aspect,
+    }
+}
+
+/// Load the tiles from the given directory
+///
+/// Each tile's average color is computed from its central `average_inset`% only, so that
+/// borders and frames don't skew it.
+pub fn load_images<P: AsRef<Path>>(dir: P, options: &LoadOptions) -> Result<Vec<Tile>> {
+    let mut dir = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    if options.focus_masks {
+        dir.retain(|entry| !is_focus_mask(&entry.path()));
+    }
+    options.sort.sort(&mut dir);
+    let len = dir.len();
+
+    let (skipped, flat) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    let tiles = dir
+        .into_par_iter()
+        .progress_with(make_pbar("images loaded", len as _))
+        .filter_map(|entry| load_tile(entry.path(), options, &skipped, &flat))
+        .collect::<Vec<_>>();
+    report_flat(flat, options);
+    let skipped = skipped.into_inner();
+    if skipped > 0 {
+        eprintln!(
+            "Skipped {} tiles smaller than {}x{}",
+            skipped, options.tile_side, options.tile_side
+        );
+    }
+    Ok(tiles)
+}
+
+/// Load the tiles from a single atlas image, by splitting it into a grid of `columns`x`rows`
+/// cells, row by row
+///
+/// If the atlas' sides aren't multiples of the grid's, the leftover pixels on the right and at
+/// the bottom are ignored. Each tile's path is the atlas' followed by `#X,Y`, its position in
+/// the grid.
+pub fn load_atlas(
+    path: &Path,
+    columns: u32,
+    rows: u32,
+    options: &LoadOptions,
+) -> Result<Vec<Tile>> {
+    let atlas = decode::open(path)?;
+    let (width, height) = atlas.dimensions();
+    let (cell_width, cell_height) = (width / columns, height / rows);
+    if cell_width == 0 || cell_height == 0 {
+        bail!(
+            "the atlas {} is only {}x{}, too small for a {}x{} grid",
+            path.display(),
+            width,
+            height,
+            columns,
+            rows
+        );
+    }
+    if options.small_tiles == SmallTiles::Skip
+        && (cell_width < options.tile_side || cell_height < options.tile_side)
+    {
+        bail!(
+            "the atlas {}'s cells are only {}x{}, smaller than the tile size, so every one would \
+             be skipped",
+            path.display(),
+            cell_width,
+            cell_height
+        );
+    }
+    if width % columns != 0 || height % rows != 0 {
+        eprintln!(
+            "warning: the atlas {} is {}x{}, which doesn't divide evenly into a {}x{} grid, \
+             ignoring the rightmost {} and bottom {} pixels",
+            path.display(),
+            width,
+            height,
+            columns,
+            rows,
+            width % columns,
+            height % rows
+        );
+    }
+
+    let cells = (0..rows)
+        .flat_map(|y| (0..columns).map(move |x| (x, y)))
+        .collect::<Vec<_>>();
+    let len = cells.len();
+    let flat = AtomicUsize::new(0);
+    let tiles = cells
+        .into_par_iter()
+        .progress_with(make_pbar("images loaded", len as _))
+        .filter_map(|(x, y)| {
+            let image = atlas.crop_imm(x * cell_width, y * cell_height, cell_width, cell_height);
+            let tile = make_tile(
+                format!("{}#{},{}", path.display(), x, y).into(),
+                image,
+                None,
+                options,
+            );
+            keep_contrasted(tile, options, &flat)
+        })
+        .collect();
+    report_flat(flat, options);
+    Ok(tiles)
+}
+
+/// Weigh the tiles according to a file listing a tile's file name and weight on each line, e.g.
+/// `sunset.jpg 2`, so that they're picked more or less often. Tiles that aren't listed weigh 1,
+/// and those that weigh 0 are left out of the tileset. Empty lines and lines starting with `#`
+/// are ignored
+pub fn apply_weights(tiles: Vec<Tile>, path: &Path) -> Result<Vec<Tile>> {
+    let mut weights = HashMap::new();
+    for (line_number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, weight) = line.rsplit_once(char::is_whitespace).ok_or_else(|| {
+            eyre!(
+                "line {} of {}: expected a file name and a weight",
+                line_number + 1,
+                path.display()
+            )
+        })?;
+        let weight = weight
+            .parse::<f64>()
+            .ok()
+            .filter(|weight| weight.is_finite() && *weight >= 0.)
+            .ok_or_else(|| {
+                eyre!(
+                    "line {} of {}: {:?} is not a non-negative weight",
+                    line_number + 1,
+                    path.display(),
+                    weight
+                )
+            })?;
+        weights.insert(name.trim_end().to_owned(), weight);
+    }
+
+    let mut unused = weights.keys().cloned().collect::<HashSet<_>>();
+    let tiles = tiles
+        .into_iter()
+        .filter_map(|mut tile| {
+            let name = tile.path.file_name()?.to_string_lossy().into_owned();
+            if let Some(&weight) = weights.get(&name) {
+                unused.remove(&name);
+                tile.weight = weight;
+            }
+            Some(tile).filter(|tile| tile.weight > 0.)
+        })
+        .collect();
+    for name in unused {
+        eprintln!(
+            "warning: {name} is weighed in {} but isn't in the tileset",
+            path.display()
+        );
+    }
+    Ok(tiles)
+}
+
+/// Penalize tiles whose shape is far from square, which get stretched the most to fill a cell,
+/// by `penalty` times the share of the cell they'd leave empty if they kept their shape
+/// instead, e.g. half of it for a tile twice as wide as it is tall
+pub fn penalize_aspect(tiles: &mut [Tile], penalty: f64) {
+    for tile in tiles {
+        let waste = 1. - tile.aspect.min(1. / tile.aspect);
+        tile.penalty += (penalty * waste).round() as i64;
+    }
+}
+
+/// The place of every tile along the tileset sorted like a rainbow, from 0 for the first to 1
+/// for the last: by hue, from red through green and blue to magenta, with grays at the end from
+/// dark to light
+pub fn rainbow_places(tiles: &[Tile]) -> Vec<f64> {
+    let mut order = (0..tiles.len()).collect::<Vec<_>>();
+    let key = |idx: usize| {
+        let (hue, _saturation, lightness) = to_hsl(tiles[idx].average);
+        hue.unwrap_or(6. + lightness)
+    };
+    order.sort_by(|&a, &b| key(a).total_cmp(&key(b)).then(a.cmp(&b)));
+    let mut places = vec![0.; tiles.len()];
+    for (rank, idx) in order.into_iter().enumerate() {
+        places[idx] = rank as f64 / (tiles.len() - 1).max(1) as f64;
+    }
+    places
+}
+
+/// Check whether a file name matches a pattern, where `*` stands for any run of characters and
+/// `?` for any single one
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (
+        pattern.chars().collect::<Vec<_>>(),
+        name.chars().collect::<Vec<_>>(),
+    );
+    // Where to resume after the last `*`, if it ends up having to cover more of the name
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, covered)) => {
+                    backtrack = Some((star, covered + 1));
+                    p = star + 1;
+                    n = covered + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Which tiles each input should be made of, according to a file pairing a pattern for the
+/// inputs' file names with a subdirectory of the tiles on each line, e.g.
+/// `"beach-*.jpg" = "summer"`, which is also valid TOML. Empty lines and lines starting with `#`
+/// are ignored
+pub struct Pairing {
+    /// Every pattern, along with the index of its subdirectory in `subdirs`
+    patterns: Vec<(String, usize)>,
+
+    /// Every subdirectory paired with some inputs, without repeats
+    subdirs: Vec<PathBuf>,
+}
+
+impl Pairing {
+    pub fn load(path: &Path) -> Result<Self> {
+        let unquote = |s: &str| {
+            let s = s.trim();
+            s.strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .unwrap_or(s)
+                .to_owned()
+        };
+
+        let mut pairing = Self {
+            patterns: Vec::new(),
+            subdirs: Vec::new(),
+        };
+        for (line_number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (pattern, subdir) = line.split_once('=').ok_or_else(|| {
+                eyre!(
+                    "line {} of {}: expected a pattern, `=` and a subdirectory",
+                    line_number + 1,
+                    path.display()
+                )
+            })?;
+            let subdir = PathBuf::from(unquote(subdir));
+            let idx = match pairing.subdirs.iter().position(|other| *other == subdir) {
+                Some(idx) => idx,
+                None => {
+                    pairing.subdirs.push(subdir);
+                    pairing.subdirs.len() - 1
+                }
+            };
+            pairing.patterns.push((unquote(pattern), idx));
+        }
+        Ok(pairing)
+    }
+
+    /// Every subdirectory paired with some inputs, without repeats
+    pub fn subdirs(&self) -> &[PathBuf] {
+        &self.subdirs
+    }
+
+    /// The index in `subdirs` of the subdirectory of the first pattern the input's file name
+    /// matches, if any
+    pub fn subdir_for(&self, input: &Path) -> Option<usize> {
+        let name = input.file_name()?.to_string_lossy();
+        self.patterns
+            .iter()
+            .find(|(pattern, _subdir)| matches_pattern(pattern, &name))
+            .map(|(_pattern, subdir)| *subdir)
+    }
+}
+
+/// Reduce the tileset to at most `max_tiles` tiles that cover its range of colors as well as
+/// possible, by clustering the tiles' average colors with k-means and keeping the tile closest
+/// to the center of each cluster
+///
+/// The clusters are seeded deterministically with farthest point sampling, so the same tileset
+/// is always reduced the same way.
+pub fn reduce_tileset(tiles: Vec<Tile>, max_tiles: usize, weights: ChannelWeights) -> Vec<Tile> {
+    const ITERATIONS: usize = 10;
+
+    if max_tiles == 0 || tiles.len() <= max_tiles {
+        return tiles;
+    }
+
+    let colors = tiles.iter().map(|tile| tile.average).collect::<Vec<_>>();
+    let nearest = |centers: &[Rgba<u8>], color: Rgba<u8>| {
+        centers
+            .iter()
+            .enumerate()
+            .map(|(idx, &center)| (distance(color, center, weights), idx))
+            .min()
+            .unwrap()
+    };
+
+    // Farthest point sampling: every new center is the color farthest from all current ones
+    let mut centers = vec![colors[0]];
+    let mut closest = colors
+        .iter()
+        .map(|&color| distance(color, colors[0], weights))
+        .collect::<Vec<_>>();
+    while centers.len() < max_tiles {
+        let (farthest, _distance) = closest
+            .iter()
+            .enumerate()
+            .max_by_key(|&(idx, &distance)| (distance, std::cmp::Reverse(idx)))
+            .unwrap();
+        let center = colors[farthest];
+        centers.push(center);
+        for (closest, &color) in closest.iter_mut().zip(&colors) {
+            *closest = (*closest).min(distance(color, center, weights));
+        }
+    }
+
+    let mut assignments = Vec::new();
+    for _ in 0..ITERATIONS {
+        assignments = colors
+            .par_iter()
+            .map(|&color| nearest(&centers, color).1)
+            .collect::<Vec<_>>();
+
+        let mut sums = vec![([0u64; 4], 0u64); centers.len()];
+        for (&color, &cluster) in colors.iter().zip(&assignments) {
+            let (sum, count) = &mut sums[cluster];
+            for (sum, c) in sum.iter_mut().zip(color.0) {
+                *sum += u64::from(c);
+            }
+            *count += 1;
+        }
+        for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+            if count > 0 {
+                *center = Rgba(sum.map(|sum| (sum / count) as u8));
+            }
+        }
+    }
+
+    // Keep the tile closest to each cluster's center
+    let mut representatives = vec![None; centers.len()];
+    for (idx, (&color, &cluster)) in colors.iter().zip(&assignments).enumerate() {
+        let score = distance(color, centers[cluster], weights);
+        match representatives[cluster] {
+            Some((best, _idx)) if best <= score => {}
+            _ => representatives[cluster] = Some((score, idx)),
+        }
+    }
+    let keep = representatives
+        .into_iter()
+        .flatten()
+        .map(|(_score, idx)| idx)
+        .collect::<HashSet<_>>();
+
+    tiles
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _tile)| keep.contains(idx))
+        .map(|(_idx, tile)| tile)
+        .collect()
+}
+
+#[cfg(test)]
+impl LoadOptions {
+    /// The options tiles are loaded with when no flag changes them, for tests
+    pub fn defaults(tile_side: u32) -> Self {
+        Self {
+            tile_side,
+            average_inset: 100.,
+            sort: TileSort::Name,
+            subregions: None,
+            variants: false,
+            normalize_white_balance: false,
+            place_normalized: false,
+            small_tiles: SmallTiles::Upscale,
+            min_contrast: 0.,
+            focus_masks: false,
+            cache: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Tile {
+    /// A tile of a single color, named after it, for tests
+    pub fn solid(color: Rgba<u8>) -> Self {
+        let Rgba([r, g, b, a]) = color;
+        let image = image::RgbaImage::from_pixel(4, 4, color);
+        Self::from_image(&format!("{r:02x}{g:02x}{b:02x}{a:02x}.png"), image)
+    }
+
+    /// A tile of the given image, loaded as it would be if no flag changed how, for tests
+    pub fn from_image(name: &str, image: image::RgbaImage) -> Self {
+        let options = LoadOptions::defaults(image.width());
+        make_tile(name.into(), DynamicImage::ImageRgba8(image), None, &options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::*;
+
+    #[test]
+    fn average_inset_ignores_a_tiles_border() {
+        let framed = RgbaImage::from_fn(20, 20, |x, y| {
+            let border = x < 4 || y < 4 || x >= 16 || y >= 16;
+            if border {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        let framed = DynamicImage::ImageRgba8(framed);
+
+        let options = LoadOptions::defaults(20);
+        let whole = make_tile("framed.png".into(), framed.clone(), None, &options);
+        assert!(whole.average[0] < 200, "{:?}", whole.average);
+
+        let options = LoadOptions {
+            average_inset: 50.,
+            ..LoadOptions::defaults(20)
+        };
+        let inset = make_tile("framed.png".into(), framed, None, &options);
+        assert_eq!(inset.average, Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn tiles_load_in_the_order_of_their_paths() {
+        let dir = std::env::temp_dir().join(format!("themis-tile-order-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let names = [
+            "m.png", "b.png", "z.png", "a10.png", "a2.png", "k.png", "c.png",
+        ];
+        for (i, name) in names.iter().enumerate() {
+            RgbaImage::from_pixel(4, 4, Rgba([i as u8 * 30, 0, 0, 255]))
+                .save(dir.join(name))
+                .unwrap();
+        }
+
+        let tiles = load_images(&dir, &LoadOptions::defaults(4)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut sorted = names.map(|name| dir.join(name));
+        sorted.sort();
+        let loaded = tiles
+            .iter()
+            .map(|tile| tile.path.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(loaded, sorted);
+    }
+
+    #[test]
+    fn normalizing_white_balance_neutralizes_a_color_cast() {
+        let bluish = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, _y| {
+            let shade = 180 + x as u8 * 8;
+            Rgba([shade - 40, shade - 20, shade + 10, 255])
+        }));
+        let options = LoadOptions {
+            normalize_white_balance: true,
+            ..LoadOptions::defaults(8)
+        };
+        let tile = make_tile("bluish.png".into(), bluish.clone(), None, &options);
+        let Rgba([r, g, b, _a]) = tile.average;
+        let spread = r.max(g).max(b) - r.min(g).min(b);
+        assert!(spread <= 2, "{:?}", tile.average);
+        // Only the matching colors change, the tile is placed as it was
+        assert_eq!(tile.image().unwrap().to_rgba8(), bluish.to_rgba8());
+    }
+
+    /// Run with `cargo test --release -- --ignored reduced_tileset_quality --nocapture`
+    #[test]
+    #[ignore]
+    fn reduced_tileset_quality() {
+        use std::time::Instant;
+
+        use crate::matching::pick_image_for_pixel_sequential;
+
+        // A landscape: a sky fading to the horizon, a sun, and a hill
+        let photo = RgbaImage::from_fn(128, 128, |x, y| {
+            let (fx, fy) = (x as f64 / 128., y as f64 / 128.);
+            let hill = 0.6 + 0.15 * (fx * 6.).sin();
+            let sun = ((fx - 0.75).powi(2) + (fy - 0.2).powi(2)).sqrt() < 0.08;
+            let [r, g, b] = if sun {
+                [255., 200., 60.]
+            } else if fy > hill {
+                [40. + 60. * fy, 120. + 50. * fx, 30.]
+            } else {
+                [90. + 120. * fy, 140. + 80. * fy, 230. - 20. * fy]
+            };
+            Rgba([r as u8, g as u8, b as u8, 255])
+        });
+        let pixels = photo.pixels().copied().collect::<Vec<_>>();
+
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let full = (0..4000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let [r, g, b, ..] = state.to_le_bytes();
+                Tile::solid(Rgba([r, g, b, 255]))
+            })
+            .collect::<Vec<_>>();
+        let weights = ChannelWeights::from_str("1,1,1").unwrap();
+
+        let mean_error = |tiles: &[Tile]| {
+            pixels
+                .par_iter()
+                .map(|&pixel| {
+                    let tile = pick_image_for_pixel_sequential(pixel, tiles, weights).unwrap();
+                    distance(pixel, tiles[tile].average, weights) as f64
+                })
+                .sum::<f64>()
+                / pixels.len() as f64
+        };
+
+        let start = Instant::now();
+        let error = mean_error(&full);
+        println!(
+            "{} tiles: matched in {:?}, mean error {:.1}",
+            full.len(),
+            start.elapsed(),
+            error
+        );
+        for max_tiles in [1000, 250, 64] {
+            let start = Instant::now();
+            let reduced = reduce_tileset(full.clone(), max_tiles, weights);
+            let reduce_time = start.elapsed();
+            let start = Instant::now();
+            let error = mean_error(&reduced);
+            let match_time = start.elapsed();
+            // Every n-th tile, which covers the colors only as well as chance has it
+            let strided = full
+                .iter()
+                .step_by(full.len() / max_tiles)
+                .cloned()
+                .collect::<Vec<_>>();
+            println!(
+                "{} tiles: reduced in {:?}, matched in {:?}, mean error {:.1} against {:.1} keeping one tile in {}",
+                reduced.len(),
+                reduce_time,
+                match_time,
+                error,
+                mean_error(&strided),
+                full.len() / max_tiles
+            );
+        }
+    }
+
+    #[test]
+    fn tile_cache_evicts_the_least_recently_used_tile() {
+        let cache = TileCache::new(2);
+        let loads = AtomicUsize::new(0);
+        let get = |path: &str| {
+            cache
+                .get_or_load(Path::new(path), || {
+                    loads.fetch_add(1, Ordering::Relaxed);
+                    Ok(DynamicImage::new_rgba8(1, 1))
+                })
+                .unwrap();
+            loads.load(Ordering::Relaxed)
+        };
+        assert_eq!(get("a.png"), 1);
+        assert_eq!(get("b.png"), 2);
+        assert_eq!(get("a.png"), 2);
+        // Evicts b.png, which was used longer ago than a.png
+        assert_eq!(get("c.png"), 3);
+        assert_eq!(get("a.png"), 3);
+        assert_eq!(get("b.png"), 4);
+        assert_eq!(get("a.png"), 4);
+        assert_eq!(get("c.png"), 5);
+    }
+
+    /// Run with `cargo test --release -- --ignored tile_cache_throughput --nocapture`
+    #[test]
+    #[ignore]
+    fn tile_cache_throughput() {
+        use std::time::Instant;
+
+        let paths = (0..8_192)
+            .map(|idx| PathBuf::from(format!("tiles/{idx:05}.png")))
+            .collect::<Vec<_>>();
+        // Mostly a few hundred tiles over and over, as mosaics of photos tend to, with some
+        // scattered across the rest
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let lookups = (0..1_000_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let idx = if state.is_multiple_of(4) {
+                    state >> 8
+                } else {
+                    (state >> 8) % 512
+                };
+                &paths[idx as usize % paths.len()]
+            })
+            .collect::<Vec<_>>();
+
+        for capacity in [256, 1_024, 4_096] {
+            let cache = TileCache::new(capacity);
+            let loads = AtomicUsize::new(0);
+            let start = Instant::now();
+            lookups.par_iter().for_each(|path| {
+                cache
+                    .get_or_load(path, || {
+                        loads.fetch_add(1, Ordering::Relaxed);
+                        Ok(DynamicImage::new_rgba8(1, 1))
+                    })
+                    .unwrap();
+            });
+            println!(
+                "{} lookups with room for {} tiles: {:?}, {} loads",
+                lookups.len(),
+                capacity,
+                start.elapsed(),
+                loads.into_inner()
+            );
+        }
+    }
+}
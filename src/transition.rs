@@ -0,0 +1,89 @@
+//! Animating the source image cross-fading into the finished mosaic, as an animated GIF or PNG
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use eyre::{bail, Result};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, DynamicImage, Frame, GenericImageView, RgbaImage};
+use rayon::prelude::*;
+
+use crate::blend_images;
+
+/// Blend the source into the mosaic over `frames` frames, the first being the source stretched
+/// to the mosaic's size and the last the mosaic itself
+fn make_frames(
+    source: &DynamicImage,
+    mosaic: &DynamicImage,
+    frames: u32,
+) -> Result<Vec<RgbaImage>> {
+    (0..frames)
+        .into_par_iter()
+        .map(|frame| {
+            let t = if frames > 1 {
+                frame as f32 / (frames - 1) as f32
+            } else {
+                1.
+            };
+            Ok(blend_images(mosaic, source, 1. - t, true)?.into_rgba8())
+        })
+        .collect()
+}
+
+/// How hard the GIF encoder tries to find the best palette for each frame, from 1 (slowest) to
+/// 30, since its default is far too slow for frames the size of a mosaic
+const GIF_SPEED: i32 = 10;
+
+/// Save an animation of the source turning into the mosaic, lasting `duration_ms` milliseconds
+/// in total and looping forever, as a GIF or an APNG depending on the path's extension
+pub fn save_transition(
+    source: &DynamicImage,
+    mosaic: &DynamicImage,
+    path: &Path,
+    frames: u32,
+    duration_ms: u32,
+) -> Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    let gif = match extension.as_deref() {
+        Some("gif") => true,
+        Some("png" | "apng") => false,
+        _ => bail!(
+            "don't know how to animate {}, the transition must be a .gif or .png",
+            path.display()
+        ),
+    };
+    let frame_ms = (duration_ms / frames).max(1);
+    let images = make_frames(source, mosaic, frames)?;
+    let writer = BufWriter::new(File::create(path)?);
+
+    if gif {
+        let mut encoder = GifEncoder::new_with_speed(writer, GIF_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(images.into_iter().map(|image| {
+            Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(frame_ms, 1))
+        }))?;
+    } else {
+        let (width, height) = mosaic.dimensions();
+        let mut encoder = png::Encoder::new(writer, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(frames, 0)?;
+        encoder.set_frame_delay(frame_ms.min(u16::MAX.into()) as u16, 1000)?;
+        let mut writer = encoder.write_header()?;
+        for image in &images {
+            writer.write_image_data(image.as_raw())?;
+        }
+        writer.finish()?;
+    }
+
+    eprintln!(
+        "Saved a transition of {} frames to {}",
+        frames,
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! Checking a tileset for problems before using it
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, Result};
+use image::ImageFormat;
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+
+use crate::decode;
+use crate::make_pbar;
+use crate::tiles::is_focus_mask;
+
+/// How far from square a tile can be before it's reported, as the ratio of its longest side to
+/// its shortest one
+const MAX_ASPECT_RATIO: f64 = 3.;
+
+/// Something wrong with a tile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Problem {
+    /// The tile couldn't be decoded, so it's silently skipped when building mosaics
+    Undecodable,
+
+    /// The tile is fully transparent, so placing it leaves a hole
+    Transparent,
+
+    /// The tile is so far from square that it gets badly squashed
+    ExtremeAspectRatio,
+
+    /// The tile is a single flat color
+    SingleColor,
+
+    /// The tile has the exact same pixels as another tile
+    Duplicate,
+
+    /// The file's extension isn't one of an image format, so it's most likely not meant to be a
+    /// tile
+    NotAnImage,
+}
+
+impl Problem {
+    /// Whether the problem is bad enough to fail validation
+    fn is_serious(self) -> bool {
+        matches!(self, Self::Undecodable | Self::Transparent)
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Undecodable => "failed to decode",
+            Self::Transparent => "fully transparent",
+            Self::ExtremeAspectRatio => "extreme aspect ratio",
+            Self::SingleColor => "single color",
+            Self::Duplicate => "duplicate",
+            Self::NotAnImage => "not an image",
+        }
+    }
+}
+
+/// Check a single tile, returning its problems and a hash of its pixels to find duplicates with
+fn check_tile(path: &Path) -> (Vec<Problem>, Option<u64>) {
+    if ImageFormat::from_path(path).is_err() {
+        return (vec![Problem::NotAnImage], None);
+    }
+    let image = match decode::open(path) {
+        Ok(image) => image.into_rgba8(),
+        Err(_) => return (vec![Problem::Undecodable], None),
+    };
+
+    let mut problems = Vec::new();
+    let (width, height) = image.dimensions();
+    if f64::from(width.max(height)) / f64::from(width.min(height).max(1)) > MAX_ASPECT_RATIO {
+        problems.push(Problem::ExtremeAspectRatio);
+    }
+    if image.pixels().all(|pixel| pixel.0[3] == 0) {
+        problems.push(Problem::Transparent);
+    } else if image.pixels().all(|pixel| *pixel == *image.get_pixel(0, 0)) {
+        problems.push(Problem::SingleColor);
+    }
+
+    let mut hasher = DefaultHasher::new();
+    (width, height).hash(&mut hasher);
+    image.as_raw().hash(&mut hasher);
+    (problems, Some(hasher.finish()))
+}
+
+/// Scan the tiles directory and report every problem found, failing if any of them are serious
+///
+/// Subdirectories are skipped like they are when loading tiles, and so are focus masks if
+/// `focus_masks` is set.
+pub fn validate_tiles(dir: &Path, focus_masks: bool) -> Result<()> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() || (focus_masks && is_focus_mask(&path)) {
+            continue;
+        }
+        paths.push(path);
+    }
+    paths.sort();
+    let len = paths.len();
+
+    let checked = paths
+        .into_par_iter()
+        .progress_with(make_pbar("tiles checked", len as _))
+        .map(|path| {
+            let (problems, hash) = check_tile(&path);
+            (path, problems, hash)
+        })
+        .collect::<Vec<_>>();
+
+    let mut by_hash = HashMap::<_, Vec<&Path>>::new();
+    let mut reports = Vec::<(&Path, Problem)>::new();
+    for (path, problems, hash) in &checked {
+        reports.extend(problems.iter().map(|&problem| (path.as_path(), problem)));
+        if let Some(hash) = hash {
+            by_hash.entry(hash).or_default().push(path);
+        }
+    }
+    for paths in by_hash.values().filter(|paths| paths.len() > 1) {
+        // The first copy is the original, the rest are the duplicates
+        reports.extend(paths[1..].iter().map(|&path| (path, Problem::Duplicate)));
+    }
+    reports.sort_by_key(|&(path, problem)| (problem, PathBuf::from(path)));
+
+    for (path, problem) in &reports {
+        eprintln!("{:<22} {}", problem.description(), path.display());
+    }
+
+    eprintln!();
+    eprintln!("{:<22} {:>6}", "problem", "tiles");
+    for problem in [
+        Problem::Undecodable,
+        Problem::Transparent,
+        Problem::ExtremeAspectRatio,
+        Problem::SingleColor,
+        Problem::Duplicate,
+        Problem::NotAnImage,
+    ] {
+        let count = reports.iter().filter(|(_path, p)| *p == problem).count();
+        eprintln!("{:<22} {:>6}", problem.description(), count);
+    }
+    eprintln!("{:<22} {:>6}", "checked", checked.len());
+
+    let serious = reports
+        .iter()
+        .filter(|(_path, problem)| problem.is_serious())
+        .count();
+    if serious > 0 {
+        bail!("found {} serious problem(s) in {}", serious, dir.display());
+    }
+    Ok(())
+}
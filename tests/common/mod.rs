@@ -0,0 +1,130 @@
+//! Running themis on images made up on the spot, for the integration tests
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use image::{Rgba, RgbaImage};
+
+/// A directory of tiles and inputs of a test's own, removed once the test is done
+pub struct Workspace {
+    pub dir: PathBuf,
+}
+
+impl Workspace {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("themis-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("tiles")).unwrap();
+        fs::create_dir_all(dir.join("input")).unwrap();
+        Self { dir }
+    }
+
+    pub fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    /// Save a tile to the tiles directory
+    pub fn tile(&self, name: &str, image: &RgbaImage) {
+        image.save(self.path("tiles").join(name)).unwrap();
+    }
+
+    /// Save a tile of every given color to the tiles directory, named after its position in
+    /// the list so that tiles load in the same order
+    pub fn solid_tiles(&self, colors: &[Rgba<u8>]) {
+        for (i, &color) in colors.iter().enumerate() {
+            self.tile(&format!("{i:03}.png"), &RgbaImage::from_pixel(4, 4, color));
+        }
+    }
+
+    /// Save an image to the input directory
+    pub fn input(&self, name: &str, image: &RgbaImage) {
+        image.save(self.path("input").join(name)).unwrap();
+    }
+
+    /// Run themis in the workspace, on its tiles and inputs
+    pub fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_themis"))
+            .current_dir(&self.dir)
+            .args(["--tiles-dir", "tiles", "--input-dir", "input"])
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    /// Run themis in the workspace and check that it succeeds
+    pub fn themis(&self, args: &[&str]) -> Output {
+        let output = self.run(args);
+        assert!(
+            output.status.success(),
+            "themis {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    /// Where `cells` saves the mosaics made with the given arguments
+    pub fn output_dir(&self, args: &[&str]) -> PathBuf {
+        self.path(format!("output-{}", args.join("").replace('-', "")))
+    }
+
+    /// Run themis with its mosaics and a CSV of their cells saved to `output_dir`, returning
+    /// the run's output and the tile chosen for each cell of the input called `name`, row by row
+    pub fn run_cells(&self, name: &str, output_dir: &str, args: &[&str]) -> (Output, Vec<String>) {
+        let output_dir = self.path(output_dir);
+        let output_dir = output_dir.to_str().unwrap();
+        let csv = format!("{output_dir}/cells.csv");
+        let mut all_args = vec!["--output-dir", output_dir, "--csv", csv.as_str()];
+        all_args.extend(args);
+        let output = self.themis(&all_args);
+
+        let stem = Path::new(name).file_stem().unwrap().to_str().unwrap();
+        let rows = read_csv(&Path::new(output_dir).join(format!("cells.{stem}.csv")));
+        (output, rows.into_iter().map(|row| row[5].clone()).collect())
+    }
+
+    /// Make a mosaic of the input called `name` with a single pixel per tile, returning the
+    /// tile chosen for each cell row by row, and the mosaic
+    pub fn cells(&self, name: &str, mosaic_size: u32, args: &[&str]) -> (Vec<String>, RgbaImage) {
+        let output_dir = self.output_dir(args);
+        let mosaic_size = mosaic_size.to_string();
+        let mut all_args = vec!["--mosaic-size", mosaic_size.as_str(), "--tile-size", "1"];
+        all_args.extend(args);
+        let (_output, tiles) = self.run_cells(name, output_dir.to_str().unwrap(), &all_args);
+
+        let stem = Path::new(name).file_stem().unwrap().to_str().unwrap();
+        let mosaic = output_dir.join(format!("{stem}.mosaic{mosaic_size}.png"));
+        (tiles, image::open(mosaic).unwrap().to_rgba8())
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Read a CSV without quoted fields, leaving out its header
+pub fn read_csv(path: &Path) -> Vec<Vec<String>> {
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').map(str::to_owned).collect())
+        .collect()
+}
+
+/// A xorshift generator, so that the tests' noise is the same on every run
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
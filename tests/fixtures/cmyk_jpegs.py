@@ -0,0 +1,79 @@
+"""Write the four channel JPEG fixtures used by decode.rs' tests
+
+Each one is 16x8, a patch of red ink (magenta and yellow) on the left and of cyan ink with a
+quarter black on the right, stored the three ways there are: inverted CMYK with an Adobe
+marker, inverted CMY as YCbCr with an Adobe marker (YCCK), and plain CMYK without a marker.
+Flat 8x8 blocks only need their DC coefficient, so this is a whole baseline encoder.
+
+Run from this directory with `python3 cmyk_jpegs.py`.
+"""
+
+import struct
+
+# The inks of each patch, as C, M, Y, K from 0 to 255
+PATCHES = [(0, 255, 255, 0), (255, 0, 0, 64)]
+
+
+def segment(marker, data):
+    return struct.pack(">BBH", 0xFF, marker, len(data) + 2) + data
+
+
+class BitWriter:
+    def __init__(self):
+        self.bits = []
+
+    def write(self, value, length):
+        self.bits += [(value >> i) & 1 for i in reversed(range(length))]
+
+    def finish(self):
+        self.bits += [1] * (-len(self.bits) % 8)
+        out = bytearray()
+        for i in range(0, len(self.bits), 8):
+            byte = int("".join(map(str, self.bits[i : i + 8])), 2)
+            out.append(byte)
+            if byte == 0xFF:
+                out.append(0)
+        return bytes(out)
+
+
+def encode(blocks, adobe_transform):
+    """Encode a row of flat blocks, each given as its four components' values"""
+    out = b"\xff\xd8"
+    if adobe_transform is not None:
+        out += segment(0xEE, b"Adobe" + struct.pack(">HHHB", 100, 0, 0, adobe_transform))
+    out += segment(0xDB, b"\x00" + b"\x01" * 64)
+    components = b"".join(bytes([i + 1, 0x11, 0]) for i in range(4))
+    out += segment(0xC0, struct.pack(">BHHB", 8, 8, 8 * len(blocks), 4) + components)
+    # DC: every size category from 0 to 11 gets a four bit code, AC: the end of block only
+    out += segment(0xC4, b"\x00" + bytes([0, 0, 0, 12] + [0] * 12) + bytes(range(12)))
+    out += segment(0xC4, b"\x10" + bytes([1] + [0] * 15) + b"\x00")
+    out += segment(0xDA, b"\x04" + b"".join(bytes([i + 1, 0]) for i in range(4)) + b"\x00\x3f\x00")
+
+    bits = BitWriter()
+    predictions = [0] * 4
+    for block in blocks:
+        for component, value in enumerate(block):
+            dc = 8 * (value - 128)
+            diff = dc - predictions[component]
+            predictions[component] = dc
+            size = abs(diff).bit_length()
+            bits.write(size, 4)
+            bits.write(diff if diff >= 0 else diff + (1 << size) - 1, size)
+            bits.write(0, 1)
+    return out + bits.finish() + b"\xff\xd9"
+
+
+def ycbcr(r, g, b):
+    y = 0.299 * r + 0.587 * g + 0.114 * b
+    cb = 128 - 0.168736 * r - 0.331264 * g + 0.5 * b
+    cr = 128 + 0.5 * r - 0.418688 * g - 0.081312 * b
+    return [min(255, max(0, round(c))) for c in (y, cb, cr)]
+
+
+with open("adobe-cmyk.jpg", "wb") as f:
+    f.write(encode([[255 - ink for ink in patch] for patch in PATCHES], 0))
+with open("adobe-ycck.jpg", "wb") as f:
+    blocks = [ycbcr(*(255 - ink for ink in patch[:3])) + [255 - patch[3]] for patch in PATCHES]
+    f.write(encode(blocks, 2))
+with open("plain-cmyk.jpg", "wb") as f:
+    f.write(encode(PATCHES, None))
@@ -0,0 +1,879 @@
+//! Making mosaics of small made up images and checking which tiles end up where
+
+mod common;
+
+use image::{Rgba, RgbaImage};
+
+use common::{Rng, Workspace};
+
+fn gray(shade: u8) -> Rgba<u8> {
+    Rgba([shade, shade, shade, 255])
+}
+
+/// How many cells got a different tile than the one to their left
+fn tile_changes(cells: &[String], columns: usize) -> usize {
+    cells
+        .chunks(columns)
+        .map(|row| row.windows(2).filter(|pair| pair[0] != pair[1]).count())
+        .sum()
+}
+
+#[test]
+fn pre_blur_makes_neighboring_cells_agree_on_noisy_images() {
+    let workspace = Workspace::new("pre-blur");
+    workspace.solid_tiles(&(0..16).map(|i| gray(i * 17)).collect::<Vec<_>>());
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let noisy = RgbaImage::from_fn(32, 32, |x, _y| {
+        let noise = (rng.next() % 81) as i32 - 40;
+        gray((x as i32 * 8 + noise).clamp(0, 255) as u8)
+    });
+    workspace.input("noisy.png", &noisy);
+
+    let (sharp, _mosaic) = workspace.cells("noisy.png", 32, &[]);
+    let (blurred, _mosaic) = workspace.cells("noisy.png", 32, &["--pre-blur", "1.5"]);
+    let (sharp, blurred) = (tile_changes(&sharp, 32), tile_changes(&blurred, 32));
+    assert!(
+        blurred * 3 < sharp * 2,
+        "{} changes blurred, {} without",
+        blurred,
+        sharp
+    );
+}
+
+#[test]
+fn unfinished_outputs_are_made_again() {
+    let workspace = Workspace::new("resume");
+    workspace.solid_tiles(&[gray(0), gray(255)]);
+    workspace.input("a.png", &RgbaImage::from_pixel(4, 4, gray(0)));
+    workspace.input("b.png", &RgbaImage::from_pixel(4, 4, gray(255)));
+    let args = [
+        "--mosaic-size",
+        "4",
+        "--tile-size",
+        "2",
+        "--output-dir",
+        "output",
+    ];
+    workspace.themis(&args);
+    let a = workspace.path("output/a.mosaic4.png");
+    let b = workspace.path("output/b.mosaic4.png");
+    let checkpoint = std::fs::read_to_string(workspace.path("output/.themis-checkpoint")).unwrap();
+    assert_eq!(checkpoint, "a.mosaic4.png\nb.mosaic4.png\n");
+
+    // As if the run had been killed while saving b, after a was done
+    std::fs::write(
+        workspace.path("output/.themis-checkpoint"),
+        "a.mosaic4.png\nb.mos",
+    )
+    .unwrap();
+    std::fs::write(&b, b"truncated").unwrap();
+    std::fs::write(&a, b"left alone").unwrap();
+    let output = workspace.themis(&args);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("b.mosaic4.png again"), "{}", stderr);
+    assert_eq!(std::fs::read(&a).unwrap(), b"left alone");
+    assert_eq!(
+        image::open(&b).unwrap().to_rgba8().get_pixel(0, 0),
+        &gray(255)
+    );
+    let checkpoint = std::fs::read_to_string(workspace.path("output/.themis-checkpoint")).unwrap();
+    assert_eq!(checkpoint, "a.mosaic4.png\nb.mos\nb.mosaic4.png\n");
+    for entry in std::fs::read_dir(workspace.path("output")).unwrap() {
+        let path = entry.unwrap().path();
+        assert_ne!(path.extension().unwrap_or_default(), "partial");
+    }
+}
+
+#[test]
+fn average_only_flags_refuse_flags_that_match_another_way() {
+    let workspace = Workspace::new("average-only");
+    workspace.solid_tiles(&[gray(0), gray(255)]);
+    workspace.input("a.png", &RgbaImage::from_pixel(4, 4, gray(0)));
+    let expr = "(r1-r2)^2 + (g1-g2)^2 + (b1-b2)^2";
+
+    for flag in [
+        ["--distance-expr", expr],
+        ["--delta-e-threshold", "2.3"],
+        ["--temporal-jitter", "100"],
+        ["--band-tiles", "0-180,180-360"],
+    ] {
+        for other in [
+            &["--max-reuse", "2"][..],
+            &["--coherence", "2"],
+            &["--dither", "serpentine"],
+        ] {
+            let mut args = vec![flag[0], flag[1], "--output-dir", "output"];
+            args.extend(other);
+            let output = workspace.run(&args);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(!output.status.success());
+            // Clap's own conflicts name the arguments in either order, with their values
+            assert!(
+                stderr.contains("cannot be used with")
+                    && stderr.contains(&format!("'{}", flag[0]))
+                    && stderr.contains(&format!("'{}", other[0])),
+                "{}",
+                stderr
+            );
+        }
+    }
+
+    std::fs::write(workspace.path("input/a.png.themis.toml"), "coherence = 2\n").unwrap();
+    let output = workspace.run(&["--distance-expr", expr, "--output-dir", "output"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("sidecar"), "{}", stderr);
+}
+
+/// The mean color of a region of an image
+fn mean_color(image: &RgbaImage, x: u32, y: u32, width: u32, height: u32) -> [f64; 3] {
+    let mut sum = [0.; 3];
+    for y in y..y + height {
+        for x in x..x + width {
+            for (sum, &c) in sum.iter_mut().zip(&image.get_pixel(x, y).0) {
+                *sum += f64::from(c) / f64::from(width * height);
+            }
+        }
+    }
+    sum
+}
+
+#[test]
+fn tone_then_color_paints_a_monochrome_tileset() {
+    let workspace = Workspace::new("tone-then-color");
+    workspace.solid_tiles(&(0..16).map(|i| gray(i * 17)).collect::<Vec<_>>());
+    let colorful = RgbaImage::from_fn(8, 8, |x, _y| {
+        if x < 4 {
+            Rgba([200, 30, 30, 255])
+        } else {
+            Rgba([30, 60, 200, 255])
+        }
+    });
+    workspace.input("colorful.png", &colorful);
+
+    let (_cells, gray_mosaic) = workspace.cells("colorful.png", 8, &[]);
+    let [r, g, b] = mean_color(&gray_mosaic, 0, 0, 4, 8);
+    assert!((r - g).abs() < 1. && (g - b).abs() < 1., "{:?}", [r, g, b]);
+
+    let (_cells, mosaic) = workspace.cells("colorful.png", 8, &["--match", "tone-then-color"]);
+    let [r, g, b] = mean_color(&mosaic, 0, 0, 4, 8);
+    assert!(r > 3. * g.max(b), "the red half is {:?}", [r, g, b]);
+    let [r, g, b] = mean_color(&mosaic, 4, 0, 4, 8);
+    assert!(b > 2. * r.max(g), "the blue half is {:?}", [r, g, b]);
+}
+
+#[test]
+fn temporal_jitter_holds_back_the_same_tile_when_the_palette_changes() {
+    let workspace = Workspace::new("temporal-jitter");
+    workspace.solid_tiles(&[gray(0), gray(100), gray(104)]);
+    let frame = |top: [u8; 2], bottom: [u8; 2]| {
+        RgbaImage::from_fn(2, 2, |x, y| gray([top, bottom][y as usize][x as usize]))
+    };
+    // The first frame is made of the black and 100 tiles, the second one of the 100 and 104
+    // ones, so that each tile is at a different index of the tiles picked from
+    workspace.input("a.png", &frame([0, 101], [0, 101]));
+    workspace.input("b.png", &frame([101, 101], [106, 106]));
+
+    let args = ["--palette-size", "2", "--temporal-jitter", "100"];
+    let (cells, _mosaic) = workspace.cells("b.png", 2, &args);
+    assert_eq!(
+        cells,
+        [
+            "tiles/001.png",
+            "tiles/002.png",
+            "tiles/002.png",
+            "tiles/002.png"
+        ]
+    );
+}
+
+#[test]
+fn band_tiles_make_each_band_out_of_its_hues() {
+    let workspace = Workspace::new("band-tiles");
+    let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+    workspace.solid_tiles(&[red, blue]);
+    // Purple is about as close to either, but the top band only has red and the bottom one blue
+    workspace.input(
+        "a.png",
+        &RgbaImage::from_pixel(2, 4, Rgba([128, 0, 128, 255])),
+    );
+
+    let (cells, _mosaic) = workspace.cells("a.png", 4, &["--band-tiles", "300-60,180-300"]);
+    let top = ["tiles/000.png"; 8];
+    let bottom = ["tiles/001.png"; 8];
+    assert_eq!(cells, [&top[..], &bottom].concat());
+}
+
+#[test]
+fn runs_make_the_same_bytes_whatever_the_ties() {
+    let workspace = Workspace::new("deterministic");
+    // Every color twice, so that every cell has a tie between its two tiles
+    let colors = (0..12)
+        .map(|i| Rgba([(i % 6) * 50, 255 - (i % 6) * 50, (i % 3) * 120, 255]))
+        .collect::<Vec<_>>();
+    workspace.solid_tiles(&colors);
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let noise = RgbaImage::from_fn(32, 32, |_x, _y| {
+        let [r, g, b, ..] = rng.next().to_le_bytes();
+        Rgba([r, g, b, 255])
+    });
+    workspace.input("noise.png", &noise);
+
+    let run = |output_dir: &str, args: &[&str]| {
+        let mut all_args = vec!["--mosaic-size", "16", "--tile-size", "2", "--deterministic"];
+        all_args.extend(args);
+        let (_output, tiles) = workspace.run_cells("noise.png", output_dir, &all_args);
+        let mosaic = std::fs::read(workspace.path(output_dir).join("noise.mosaic16.png")).unwrap();
+        (mosaic, tiles)
+    };
+    let first = run("first", &[]);
+    assert!(first == run("second", &[]), "the two runs differ");
+    assert!(
+        first == run("coarse", &["--coarse-bins", "4"]),
+        "the coarse index breaks ties differently"
+    );
+
+    // Ties go to the tile loaded first
+    for tile in &first.1 {
+        assert!(tile.as_str() < "tiles/006.png", "{}", tile);
+    }
+}
+
+#[test]
+fn respect_source_alpha_leaves_transparent_cells_empty() {
+    let workspace = Workspace::new("source-alpha");
+    workspace.solid_tiles(&[Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255])]);
+    // A red square on a transparent background
+    let logo = RgbaImage::from_fn(8, 8, |x, y| {
+        if (2..6).contains(&x) && (2..6).contains(&y) {
+            Rgba([250, 10, 10, 255])
+        } else {
+            Rgba([0, 0, 250, 0])
+        }
+    });
+    workspace.input("logo.png", &logo);
+
+    let (_cells, mosaic) = workspace.cells("logo.png", 8, &["--respect-source-alpha"]);
+    assert_eq!(mosaic.dimensions(), (8, 8));
+    for (x, y, pixel) in mosaic.enumerate_pixels() {
+        if logo.get_pixel(x, y)[3] == 0 {
+            assert_eq!(pixel[3], 0, "({}, {}) isn't transparent", x, y);
+        } else {
+            assert_eq!(*pixel, Rgba([255, 0, 0, 255]), "({}, {})", x, y);
+        }
+    }
+
+    let output = workspace.themis(&[
+        "--respect-source-alpha",
+        "--mosaic-size",
+        "8",
+        "--tile-size",
+        "1",
+        "--output-dir",
+        "jpeg",
+        "--output-template",
+        "{stem}.jpg",
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("can't be transparent"), "{}", stderr);
+}
+
+#[test]
+fn dominant_colors_give_a_two_tone_cell_a_two_tone_tile() {
+    let workspace = Workspace::new("dominant-colors");
+    let two_tone = RgbaImage::from_fn(8, 8, |x, _y| gray(if x < 4 { 0 } else { 255 }));
+    workspace.tile("000.png", &RgbaImage::from_pixel(8, 8, gray(128)));
+    workspace.tile("001.png", &two_tone);
+    workspace.input("cell.png", &two_tone);
+
+    // Tiles are described at their placed size, so they need to be large enough to have two tones
+    let tile = |output_dir: &str, args: &[&str]| {
+        let mut all_args = vec!["--mosaic-size", "1", "--tile-size", "8"];
+        all_args.extend(args);
+        workspace.run_cells("cell.png", output_dir, &all_args).1[0].clone()
+    };
+    assert_eq!(tile("average", &[]), "tiles/000.png");
+    assert_eq!(
+        tile("dominant", &["--dominant-colors", "2"]),
+        "tiles/001.png"
+    );
+}
+
+#[test]
+fn inverting_an_inverted_image_matches_like_the_original() {
+    let workspace = Workspace::new("invert-target");
+    workspace.solid_tiles(
+        &(0..8)
+            .map(|i| Rgba([i * 32, 255 - i * 32, i * 16, 255]))
+            .collect::<Vec<_>>(),
+    );
+    let mut rng = Rng(0x1234_5678_9abc_def1);
+    let original = RgbaImage::from_fn(8, 8, |_x, _y| {
+        let [r, g, b, ..] = rng.next().to_le_bytes();
+        Rgba([r, g, b, 255])
+    });
+    let mut inverted = original.clone();
+    image::imageops::invert(&mut inverted);
+    workspace.input("original.png", &original);
+    workspace.input("inverted.png", &inverted);
+
+    let (cells, mosaic) = workspace.cells("original.png", 8, &[]);
+    let (once, _mosaic) = workspace.cells("inverted.png", 8, &[]);
+    assert_ne!(once, cells);
+    let (twice, twice_mosaic) = workspace.cells("inverted.png", 8, &["--invert-target"]);
+    assert_eq!(twice, cells);
+    assert_eq!(twice_mosaic, mosaic);
+}
+
+#[test]
+fn seamless_mosaics_meet_themselves_at_every_edge() {
+    let workspace = Workspace::new("seamless");
+    // Tiles that aren't symmetric, so that it shows if they're the wrong way around at an edge
+    for i in 0..6 {
+        let tile = RgbaImage::from_fn(4, 4, |x, y| Rgba([i * 40, x as u8 * 60, y as u8 * 60, 255]));
+        workspace.tile(&format!("{i:03}.png"), &tile);
+    }
+    let mut rng = Rng(0x0dd_ba11_cafe_f00d);
+    let noise = RgbaImage::from_fn(6, 6, |_x, _y| {
+        let [r, g, b, ..] = rng.next().to_le_bytes();
+        Rgba([r, g, b, 255])
+    });
+    workspace.input("noise.png", &noise);
+
+    workspace.themis(&[
+        "--mosaic-size",
+        "6",
+        "--tile-size",
+        "4",
+        "--seamless",
+        "--output-dir",
+        "output",
+    ]);
+    let mosaic = image::open(workspace.path("output/noise.mosaic6.png"))
+        .unwrap()
+        .to_rgba8();
+    let (width, height) = mosaic.dimensions();
+    assert_eq!((width, height), (48, 48));
+    for y in 0..height {
+        assert_eq!(
+            mosaic.get_pixel(0, y),
+            mosaic.get_pixel(width - 1, y),
+            "row {}",
+            y
+        );
+    }
+    for x in 0..width {
+        assert_eq!(
+            mosaic.get_pixel(x, 0),
+            mosaic.get_pixel(x, height - 1),
+            "column {}",
+            x
+        );
+    }
+}
+
+#[test]
+fn match_cache_gives_the_same_tiles_on_the_next_run() {
+    let workspace = Workspace::new("match-cache");
+    workspace.solid_tiles(
+        &(0..16)
+            .map(|i| Rgba([i * 16, 255 - i * 16, 128, 255]))
+            .collect::<Vec<_>>(),
+    );
+    let mut rng = Rng(0x5eed_1e55_ba5e_ba11);
+    let noise = RgbaImage::from_fn(16, 16, |_x, _y| {
+        let [r, g, b, ..] = rng.next().to_le_bytes();
+        Rgba([r, g, b, 255])
+    });
+    workspace.input("noise.png", &noise);
+
+    let run = |output_dir: &str| {
+        let args = [
+            "--mosaic-size",
+            "16",
+            "--tile-size",
+            "1",
+            "--match-cache",
+            "matches",
+        ];
+        let (output, tiles) = workspace.run_cells("noise.png", output_dir, &args);
+        (String::from_utf8_lossy(&output.stderr).into_owned(), tiles)
+    };
+    let (first, first_tiles) = run("first");
+    assert!(
+        first.contains("0 of 256 colors were in the match cache"),
+        "{}",
+        first
+    );
+    let (second, second_tiles) = run("second");
+    assert!(second.contains("(100.0%)"), "{}", second);
+    assert!(first_tiles == second_tiles, "the cached matches differ");
+}
+
+#[test]
+fn brightness_scale_centers_smaller_tiles_over_the_background() {
+    let workspace = Workspace::new("brightness-scale");
+    // As large as they're placed, so that they aren't resampled
+    for (name, shade) in [("000.png", 0), ("001.png", 255)] {
+        workspace.tile(name, &RgbaImage::from_pixel(10, 10, gray(shade)));
+    }
+    workspace.input(
+        "a.png",
+        &RgbaImage::from_fn(2, 2, |x, _y| gray(if x == 0 { 0 } else { 255 })),
+    );
+
+    workspace.themis(&[
+        "--mosaic-size",
+        "2",
+        "--tile-size",
+        "10",
+        "--brightness-scale",
+        "bright",
+        "--min-tile-scale",
+        "0.4",
+        "--background",
+        "ff0000",
+        "--output-dir",
+        "output",
+    ]);
+    let mosaic = image::open(workspace.path("output/a.mosaic2.png"))
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(mosaic.dimensions(), (20, 20));
+    let red = Rgba([255, 0, 0, 255]);
+    for (x, y, pixel) in mosaic.enumerate_pixels() {
+        // The black cells get 4x4 tiles in their middle, the white ones fill their cells
+        let expected = if x >= 10 {
+            gray(255)
+        } else if (3..7).contains(&x) && (3..7).contains(&(y % 10)) {
+            gray(0)
+        } else {
+            red
+        };
+        assert_eq!(*pixel, expected, "({}, {})", x, y);
+    }
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn serve_answers_with_the_mosaic_of_a_posted_image() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+    use std::process::{Command, Stdio};
+
+    let workspace = Workspace::new("serve");
+    workspace.solid_tiles(&[Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255])]);
+    let mut server = Command::new(env!("CARGO_BIN_EXE_themis"))
+        .current_dir(&workspace.dir)
+        .args(["--mosaic-size", "4", "--tile-size", "2"])
+        .args(["serve", "--tiles-dir", "tiles", "--address", "127.0.0.1:0"])
+        .args(["--workers", "1", "--timeout", "1"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Find out which port it got from what it says once it's listening
+    let mut stderr = BufReader::new(server.stderr.take().unwrap());
+    let address = loop {
+        let mut line = String::new();
+        assert!(
+            stderr.read_line(&mut line).unwrap() > 0,
+            "the server stopped"
+        );
+        if let Some(url) = line
+            .trim()
+            .strip_prefix("Serving mosaics of 2 tiles on http://")
+        {
+            break url.trim_end_matches("/mosaic").to_owned();
+        }
+    };
+    let request = |head: &str, body: &[u8]| {
+        let mut stream = TcpStream::connect(&address).unwrap();
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = String::from_utf8_lossy(&response[..split]).into_owned();
+        (status, response[split + 4..].to_vec())
+    };
+
+    // A client that never sends anything is given up on rather than holding on to the only
+    // worker, which the requests after it would wait on forever
+    let mut idle = TcpStream::connect(&address).unwrap();
+    let mut png = Vec::new();
+    RgbaImage::from_pixel(8, 8, Rgba([0, 10, 240, 255]))
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let head = format!(
+        "POST /mosaic?mosaic-size=3 HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+        png.len()
+    );
+    let (status, body) = request(&head, &png);
+    let (not_found, _body) = request("GET /elsewhere HTTP/1.1\r\n\r\n", &[]);
+    let mut timed_out = String::new();
+    idle.read_to_string(&mut timed_out).unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+    assert!(status.contains("Content-Type: image/png"), "{}", status);
+    let mosaic = image::load_from_memory(&body).unwrap().to_rgba8();
+    assert_eq!(mosaic.dimensions(), (6, 6));
+    assert!(mosaic
+        .pixels()
+        .all(|&pixel| pixel == Rgba([0, 0, 255, 255])));
+    assert!(not_found.starts_with("HTTP/1.1 404"), "{}", not_found);
+    assert!(timed_out.starts_with("HTTP/1.1 408"), "{}", timed_out);
+}
+
+#[test]
+fn focus_masks_match_tiles_by_their_subject() {
+    let workspace = Workspace::new("focus-masks");
+    // A small red subject on a blue background, which averages out to mostly blue
+    let subject = |x: u32, y: u32| (3..5).contains(&x) && (3..5).contains(&y);
+    let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+    workspace.tile(
+        "000.png",
+        &RgbaImage::from_fn(8, 8, |x, y| if subject(x, y) { red } else { blue }),
+    );
+    workspace.tile(
+        "000.mask.png",
+        &RgbaImage::from_fn(8, 8, |x, y| gray(if subject(x, y) { 255 } else { 0 })),
+    );
+    workspace.tile(
+        "001.png",
+        &RgbaImage::from_pixel(8, 8, Rgba([200, 0, 160, 255])),
+    );
+    workspace.input("red.png", &RgbaImage::from_pixel(8, 8, red));
+
+    let run = |output_dir: &str, args: &[&str]| {
+        let mut all_args = vec!["--mosaic-size", "1", "--tile-size", "8"];
+        all_args.extend(args);
+        let (_output, tiles) = workspace.run_cells("red.png", output_dir, &all_args);
+        let mosaic = image::open(workspace.path(output_dir).join("red.mosaic1.png"))
+            .unwrap()
+            .to_rgba8();
+        (tiles[0].clone(), mosaic)
+    };
+    let (tile, _mosaic) = run("whole", &[]);
+    assert_eq!(tile, "tiles/001.png");
+    let (tile, mosaic) = run("masked", &["--focus-masks"]);
+    assert_eq!(tile, "tiles/000.png");
+    // The whole tile is placed, background and all
+    assert_eq!(*mosaic.get_pixel(0, 0), blue);
+    assert_eq!(*mosaic.get_pixel(3, 3), red);
+}
+
+#[test]
+fn tile_indexes_refuse_runs_that_load_a_different_tileset() {
+    let workspace = Workspace::new("index-options");
+    workspace.tile("000.png", &RgbaImage::from_pixel(8, 8, gray(0)));
+    workspace.tile("000.mask.png", &RgbaImage::from_pixel(8, 8, gray(255)));
+    workspace.input("a.png", &RgbaImage::from_pixel(8, 8, gray(0)));
+
+    let args = |options: &[&'static str], rest: &[&'static str]| {
+        [&["--tile-size", "8"], options, rest].concat()
+    };
+    let with_index = ["--mosaic-size", "1", "--tile-index", "idx.bin"];
+    for (indexed, used) in [
+        (&[][..], &["--focus-masks"][..]),
+        (&["--focus-masks"], &[]),
+        (&[], &["--min-contrast", "8"]),
+        (&["--no-upscale"], &["--upscale-nearest"]),
+    ] {
+        workspace.themis(&args(
+            indexed,
+            &["index", "--tiles-dir", "tiles", "--output", "idx.bin"],
+        ));
+        let output = workspace.run(&args(used, &with_index));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{:?} used {:?}", used, indexed);
+        assert!(stderr.contains("different options"), "{}", stderr);
+        workspace.themis(&args(indexed, &with_index));
+    }
+}
+
+#[test]
+fn aspect_penalty_knows_the_shape_of_indexed_tiles() {
+    let workspace = Workspace::new("index-aspect");
+    let purple = Rgba([160, 0, 160, 255]);
+    workspace.tile(
+        "000.png",
+        &RgbaImage::from_pixel(4, 4, Rgba([120, 0, 120, 255])),
+    );
+    workspace.tile("001.png", &RgbaImage::from_pixel(16, 4, purple));
+    workspace.input("purple.png", &RgbaImage::from_pixel(1, 1, purple));
+    workspace.themis(&[
+        "--tile-size",
+        "1",
+        "index",
+        "--tiles-dir",
+        "tiles",
+        "--output",
+        "idx.bin",
+    ]);
+
+    let penalty = ["--aspect-penalty", "100000"];
+    let (loaded, _mosaic) = workspace.cells("purple.png", 1, &penalty);
+    assert_eq!(loaded, ["tiles/000.png"]);
+    let (indexed, _mosaic) = workspace.cells(
+        "purple.png",
+        1,
+        &[&penalty[..], &["--tile-index", "idx.bin"]].concat(),
+    );
+    assert_eq!(indexed, ["tiles/000.png"]);
+}
+
+#[test]
+fn tile_indexes_move_along_with_their_tiles() {
+    let workspace = Workspace::new("index-paths");
+    workspace.solid_tiles(&[gray(0), gray(255)]);
+    let input = RgbaImage::from_fn(1, 2, |_x, y| gray(if y == 0 { 0 } else { 255 }));
+    workspace.input("a.png", &input);
+    std::fs::create_dir(workspace.path("indexes")).unwrap();
+    workspace.themis(&[
+        "--tile-size",
+        "1",
+        "index",
+        "--tiles-dir",
+        "tiles",
+        "--output",
+        "indexes/idx.bin",
+    ]);
+
+    let (cells, _mosaic) = workspace.cells("a.png", 2, &["--tile-index", "indexes/idx.bin"]);
+    let cells = [&cells[0], &cells[2]];
+    assert_eq!(
+        cells,
+        ["indexes/../tiles/000.png", "indexes/../tiles/001.png"]
+    );
+
+    // Moving the tiles and the index together keeps it working, the tiles being found again
+    // relative to it
+    std::fs::create_dir(workspace.path("moved")).unwrap();
+    for path in ["tiles", "indexes"] {
+        std::fs::rename(workspace.path(path), workspace.path("moved").join(path)).unwrap();
+    }
+    std::fs::create_dir(workspace.path("tiles")).unwrap();
+    let args = ["--tile-index", "moved/indexes/idx.bin"];
+    let (moved, mosaic) = workspace.cells("a.png", 2, &args);
+    let moved = [&moved[0], &moved[2]];
+    assert_eq!(
+        moved,
+        [
+            "moved/indexes/../tiles/000.png",
+            "moved/indexes/../tiles/001.png"
+        ]
+    );
+    assert_eq!(
+        mosaic,
+        image::imageops::resize(&input, 2, 2, image::imageops::Nearest)
+    );
+}
+
+#[test]
+fn cell_average_handles_the_cells_sticking_out_of_the_image() {
+    let workspace = Workspace::new("edge-cells");
+    workspace.solid_tiles(&[gray(0), gray(255)]);
+    // Every pixel's red is its column and its green its row, so that each cell's average color
+    // tells which pixels it covered
+    workspace.input(
+        "a.png",
+        &RgbaImage::from_fn(100, 100, |x, y| Rgba([x as u8, y as u8, 0, 255])),
+    );
+
+    // 7 cells of 15 pixels cover 105, so the last ones stick out by 5: `shrink` averages the 10
+    // they cover, `clamp` counts the last pixel 5 more times, and `crop` leaves them out
+    let full = [7, 22, 37, 52, 67, 82];
+    for (edge, last) in [("shrink", Some(95)), ("clamp", Some(96)), ("crop", None)] {
+        let expected = full.iter().copied().chain(last).collect::<Vec<u8>>();
+        let args = ["--cell-average", "--edge-cells", edge];
+        workspace.cells("a.png", 7, &args);
+        let rows = common::read_csv(&workspace.output_dir(&args).join("cells.a.csv"));
+        assert_eq!(rows.len(), expected.len() * expected.len(), "{}", edge);
+        for row in rows {
+            let [x, y, r, g] = [0, 1, 2, 3].map(|field| row[field].parse::<usize>().unwrap());
+            assert_eq!(r, usize::from(expected[x]), "{} at column {}", edge, x);
+            assert_eq!(g, usize::from(expected[y]), "{} at row {}", edge, y);
+        }
+    }
+}
+
+#[test]
+fn embedded_provenance_reads_back_from_every_format() {
+    let workspace = Workspace::new("provenance");
+    workspace.solid_tiles(&[gray(0), gray(255)]);
+    workspace.input("a.png", &RgbaImage::from_pixel(8, 8, gray(200)));
+
+    for extension in ["png", "jpg", "tiff"] {
+        let template = format!("{{stem}}.{extension}");
+        workspace.themis(&[
+            "--mosaic-size",
+            "8",
+            "--tile-size",
+            "1",
+            "--embed-provenance",
+            "--output-template",
+            &template,
+        ]);
+        let mosaic = format!("output/a.{extension}");
+        let output = workspace.themis(&[
+            "provenance",
+            "--mosaic",
+            &mosaic,
+            "--thumbnail",
+            "thumbnail.png",
+        ]);
+        let description = String::from_utf8_lossy(&output.stdout);
+        assert!(description.contains("input/a.png"), "{}", description);
+        assert!(description.contains(&template), "{}", description);
+        let thumbnail = image::open(workspace.path("thumbnail.png")).unwrap();
+        assert_eq!(thumbnail.to_rgba8().get_pixel(0, 0), &gray(200));
+        std::fs::remove_file(workspace.path("thumbnail.png")).unwrap();
+    }
+
+    // The text chunk is an ordinary one that any PNG decoder can read
+    let png = std::fs::File::open(workspace.path("output/a.png")).unwrap();
+    let reader = png::Decoder::new(png).read_info().unwrap();
+    let chunk = reader
+        .info()
+        .utf8_text
+        .iter()
+        .find(|chunk| chunk.keyword == "themis:provenance")
+        .expect("no provenance chunk");
+    assert!(chunk.get_text().unwrap().contains("input/a.png"));
+}
+
+#[test]
+fn update_from_reads_back_tile_paths_that_need_quoting() {
+    let workspace = Workspace::new("quoted-csv");
+    workspace.tile("000.png", &RgbaImage::from_pixel(4, 4, gray(0)));
+    workspace.tile("a, \"b\"\nc.png", &RgbaImage::from_pixel(4, 4, gray(255)));
+    let input = RgbaImage::from_fn(4, 4, |x, _y| gray(if x < 2 { 0 } else { 255 }));
+    workspace.input("a.png", &input);
+
+    let args = [
+        "--mosaic-size",
+        "4",
+        "--tile-size",
+        "1",
+        "--csv",
+        "cells.csv",
+    ];
+    workspace.themis(&args);
+    let output = workspace.themis(
+        &[
+            &args[..],
+            &["--update-from", "output", "--prior-csv", "cells.csv"],
+        ]
+        .concat(),
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("0 of 16 cells changed"), "{}", stderr);
+}
+
+#[test]
+fn snap_grid_rounds_every_aspect_ratio_to_the_closest_grid() {
+    let workspace = Workspace::new("snap-grid");
+    workspace.solid_tiles(&[gray(0), gray(255)]);
+    // The image's size, then the grid without and with `--snap-grid`
+    let sizes = [
+        ((160, 90), (100, 56), (96, 54)),
+        ((90, 160), (56, 100), (54, 96)),
+        ((120, 90), (100, 75), (100, 75)),
+        ((150, 100), (100, 67), (99, 66)),
+        ((210, 90), (100, 43), (98, 42)),
+        ((100, 100), (100, 100), (100, 100)),
+        ((300, 7), (100, 2), (90, 2)),
+    ];
+    for &((width, height), ..) in &sizes {
+        workspace.input(
+            &format!("{width}x{height}.png"),
+            &RgbaImage::from_pixel(width, height, gray(200)),
+        );
+    }
+
+    for snap_grid in [false, true] {
+        let output_dir = format!("output-{snap_grid}");
+        let mut args = vec![
+            "--mosaic-size",
+            "100",
+            "--tile-size",
+            "1",
+            "--keep-aspect-ratio",
+            "--output-dir",
+            &output_dir,
+        ];
+        if snap_grid {
+            args.push("--snap-grid");
+        }
+        workspace.themis(&args);
+
+        for &((width, height), plain, snapped) in &sizes {
+            let mosaic = workspace
+                .path(&output_dir)
+                .join(format!("{width}x{height}.mosaic100.png"));
+            let expected = if snap_grid { snapped } else { plain };
+            assert_eq!(
+                image::image_dimensions(mosaic).unwrap(),
+                expected,
+                "{}x{} with {:?}",
+                width,
+                height,
+                args
+            );
+        }
+    }
+}
+
+#[test]
+fn color_temperature_picks_warmer_tiles_the_higher_it_is() {
+    let workspace = Workspace::new("color-temperature");
+    workspace.solid_tiles(&[
+        Rgba([150, 170, 230, 255]),
+        gray(180),
+        Rgba([230, 175, 130, 255]),
+    ]);
+    workspace.input("gray.png", &RgbaImage::from_pixel(2, 2, gray(180)));
+
+    let tile = |kelvin: &str| {
+        let (cells, _mosaic) = workspace.cells("gray.png", 2, &["--color-temperature", kelvin]);
+        cells[0].clone()
+    };
+    assert_eq!(tile("6500"), "tiles/001.png");
+    assert_eq!(tile("12000"), "tiles/002.png");
+    assert_eq!(tile("3500"), "tiles/000.png");
+}
+
+#[test]
+fn validate_tiles_skips_what_isnt_a_tile() {
+    let workspace = Workspace::new("validate");
+    workspace.tile(
+        "000.png",
+        &RgbaImage::from_fn(4, 4, |x, y| gray((x * 16 + y) as u8)),
+    );
+    workspace.tile("000.mask.png", &RgbaImage::from_pixel(4, 4, gray(255)));
+    std::fs::create_dir(workspace.path("tiles/pairs")).unwrap();
+    std::fs::write(workspace.path("tiles/README.txt"), "not a tile").unwrap();
+
+    let output = workspace.themis(&["--focus-masks", "validate-tiles", "--tiles-dir", "tiles"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("not an image"), "{}", stderr);
+    assert!(stderr.contains("README.txt"), "{}", stderr);
+    assert!(!stderr.contains("pairs"), "{}", stderr);
+    assert!(!stderr.contains("000.mask.png"), "{}", stderr);
+
+    // A broken image still fails the check
+    std::fs::write(workspace.path("tiles/001.png"), "not a PNG").unwrap();
+    let output = workspace.run(&["validate-tiles", "--tiles-dir", "tiles"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", stderr);
+    assert!(
+        stderr
+            .lines()
+            .any(|line| line.starts_with("failed to decode") && line.ends_with("001.png")),
+        "{}",
+        stderr
+    );
+}
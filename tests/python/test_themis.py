@@ -0,0 +1,92 @@
+"""Tests for the Python wrapper, run with `pytest tests/python` (or `python -m unittest` from that
+directory) after `cargo build`"""
+
+import struct
+import sys
+import unittest
+import zlib
+from pathlib import Path
+from subprocess import CalledProcessError
+from tempfile import TemporaryDirectory
+
+ROOT = Path(__file__).resolve().parents[2]
+sys.path.insert(0, str(ROOT))
+
+import themis  # noqa: E402
+
+BINARY = ROOT / "target" / "debug" / "themis"
+
+
+def png(width: int, height: int, color: tuple) -> bytes:
+    """An RGB PNG of a single color"""
+
+    def chunk(kind: bytes, data: bytes) -> bytes:
+        body = kind + data
+        return struct.pack(">I", len(data)) + body + struct.pack(">I", zlib.crc32(body))
+
+    row = b"\x00" + bytes(color) * width
+    return (
+        b"\x89PNG\r\n\x1a\n"
+        + chunk(b"IHDR", struct.pack(">IIBBBBB", width, height, 8, 2, 0, 0, 0))
+        + chunk(b"IDAT", zlib.compress(row * height))
+        + chunk(b"IEND", b"")
+    )
+
+
+def size(encoded: bytes) -> tuple:
+    """The width and height of a PNG"""
+    return struct.unpack(">II", encoded[16:24])
+
+
+class Extension(unittest.TestCase):
+    def test_formats(self):
+        self.assertEqual(themis.extension(png(1, 1, (0, 0, 0))), "png")
+        self.assertEqual(themis.extension(b"\xff\xd8\xff\xe0\x00\x10JFIF"), "jpg")
+        self.assertEqual(themis.extension(b"GIF89a"), "gif")
+        self.assertEqual(themis.extension(b"RIFF\x24\x00\x00\x00WEBPVP8 "), "webp")
+        self.assertEqual(themis.extension(b"MM\x00*\x00\x00\x00\x08"), "tiff")
+
+    def test_unrecognized(self):
+        with self.assertRaises(ValueError):
+            themis.extension(b"RIFF\x24\x00\x00\x00WAVEfmt ")
+
+
+class Options(unittest.TestCase):
+    def test_values_flags_and_omissions(self):
+        self.assertEqual(
+            themis.options(
+                {"mosaic_size": 4, "deterministic": True, "dither": False, "seed": None}
+            ),
+            ["--mosaic-size", "4", "--deterministic"],
+        )
+
+
+@unittest.skipUnless(BINARY.exists(), "needs `cargo build` first")
+class BuildMosaic(unittest.TestCase):
+    def setUp(self):
+        themis.THEMIS = str(BINARY)
+        self.temp_dir = TemporaryDirectory()
+        self.tiles = Path(self.temp_dir.name)
+        for name, color in (("red", (255, 0, 0)), ("blue", (0, 0, 255))):
+            (self.tiles / f"{name}.png").write_bytes(png(2, 2, color))
+
+    def tearDown(self):
+        self.temp_dir.cleanup()
+
+    def test_mosaic_of_the_closest_tiles(self):
+        mosaic = themis.build_mosaic(
+            png(4, 4, (240, 10, 10)), self.tiles, mosaic_size=2, tile_size=2
+        )
+        self.assertEqual(themis.extension(mosaic), "png")
+        self.assertEqual(size(mosaic), (4, 4))
+        self.assertEqual(mosaic, themis.build_mosaic(
+            png(4, 4, (250, 0, 0)), self.tiles, mosaic_size=2, tile_size=2
+        ))
+
+    def test_failures_raise(self):
+        with self.assertRaises(CalledProcessError):
+            themis.build_mosaic(png(4, 4, (0, 0, 0)), self.tiles, mosaic_size=0)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,86 @@
+"""Build mosaics from Python, e.g. in a notebook, by running the themis binary
+
+    from pathlib import Path
+    from themis import build_mosaic
+
+    png = build_mosaic(Path("photo.jpg").read_bytes(), "tiles", mosaic_size=64, tile_size=16)
+    Path("mosaic.png").write_bytes(png)
+
+Keyword arguments are passed on as the command line options of the same name, with underscores
+turned into dashes: `True` passes a flag, `False` and `None` leave the option out, and anything
+else is passed as the option's value.
+"""
+
+from os import PathLike
+from pathlib import Path
+from subprocess import run
+from tempfile import TemporaryDirectory
+from typing import Union
+
+# The themis binary to run, which defaults to the one on the PATH
+THEMIS = "themis"
+
+# The extensions of the formats themis decodes, by how their files start, as it goes by a file's
+# extension to tell which format it's in
+SIGNATURES = (
+    (b"\x89PNG\r\n\x1a\n", "png"),
+    (b"\xff\xd8\xff", "jpg"),
+    (b"GIF8", "gif"),
+    (b"BM", "bmp"),
+    (b"II*\x00", "tiff"),
+    (b"MM\x00*", "tiff"),
+    (b"\x00\x00\x01\x00", "ico"),
+    (b"v/1\x01", "exr"),
+)
+
+
+def extension(data: bytes) -> str:
+    """The extension of the format `data` is encoded in"""
+    if data[:4] == b"RIFF" and data[8:12] == b"WEBP":
+        return "webp"
+    for signature, extension in SIGNATURES:
+        if data.startswith(signature):
+            return extension
+    raise ValueError("Unrecognized image format")
+
+
+def options(opts: dict) -> list:
+    """The command line options passing on the keyword arguments `opts`"""
+    args = []
+    for name, value in opts.items():
+        option = "--" + name.replace("_", "-")
+        if value is True:
+            args.append(option)
+        elif value is not False and value is not None:
+            args.extend((option, str(value)))
+    return args
+
+
+def build_mosaic(
+    input_bytes: bytes,
+    tile_dir: Union[str, PathLike],
+    output_format: str = "png",
+    **opts: object,
+) -> bytes:
+    """Turn an encoded image into a mosaic of the tiles in `tile_dir`, returning it encoded in
+    `output_format`"""
+    with TemporaryDirectory() as temp_dir:
+        temp_dir = Path(temp_dir)
+        input_path = temp_dir / f"input.{extension(input_bytes)}"
+        input_path.write_bytes(input_bytes)
+        output = f"mosaic.{output_format}"
+
+        args = [
+            THEMIS,
+            "--input-dir",
+            input_path,
+            "--tiles-dir",
+            tile_dir,
+            "--output-dir",
+            temp_dir,
+            "--output-template",
+            output,
+            *options(opts),
+        ]
+        run(args, check=True, capture_output=True)
+        return (temp_dir / output).read_bytes()